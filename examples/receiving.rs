@@ -10,7 +10,10 @@ async fn main() -> Result<()> {
     })
     .await?;
 
-    println!("Started tunnel with address {}", tunnel.receiver_address());
+    println!(
+        "Started tunnel with address {}",
+        tunnel.receiver_address().unwrap()
+    );
     println!("Press Ctrl+C to exit");
 
     match tokio::signal::ctrl_c().await {
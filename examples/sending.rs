@@ -8,7 +8,10 @@ use tunnel::Tunnel;
 async fn main() -> Result<()> {
     let tunnel = Tunnel::new(|_, _| {}).await?;
 
-    println!("Started tunnel with address {}", tunnel.receiver_address());
+    println!(
+        "Started tunnel with address {}",
+        tunnel.receiver_address().unwrap()
+    );
     println!("Enter the target address below:");
 
     let mut address_str = String::new();
@@ -22,7 +25,7 @@ async fn main() -> Result<()> {
             .await?;
     }
 
-    tunnel.destroy().await;
+    tunnel.destroy().await?;
 
     Ok(())
 }
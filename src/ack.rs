@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use iroh::{
+    endpoint::{Connection, ReadError, ReadExactError, ReadToEndError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+
+use crate::{
+    Bytes, MESSAGE_TOO_LARGE_CODE, PublicKey, RecvStream, SendStream, TunnelError, TunnelProtocol,
+    connection_error, write_error,
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of acknowledged
+/// connections, whose bidirectional streams each carry a message and its
+/// acknowledgement.
+const ACKED_ALPN_SUFFIX: &[u8] = b"/acked";
+
+/// The acknowledgement of a message which was processed by a handler.
+const PROCESSED: u8 = 0;
+
+/// The acknowledgement of a message whose handler failed or panicked.
+const HANDLER_FAILED: u8 = 1;
+
+/// The acknowledgement of a message which was discarded, as no handler was
+/// able to process it.
+const NOT_HANDLED: u8 = 2;
+
+/// Returns the ALPN of the acknowledged connections of a tunnel using `alpn`.
+pub(crate) fn acked_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, ACKED_ALPN_SUFFIX].concat()
+}
+
+/// What happened to a message sent with
+/// [Tunnel::send_acked](crate::Tunnel::send_acked), as reported by the
+/// receiving tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The message was received, and its handler returned.
+    Processed,
+    /// The message was received, but its handler returned an error or
+    /// panicked.
+    HandlerFailed,
+    /// The message was received, but discarded, as the receiving tunnel has
+    /// no handler, or a [StreamingDataHandler](crate::StreamingDataHandler).
+    NotHandled,
+}
+
+/// Sends a message over a bidirectional stream of an acknowledged connection,
+/// and waits for its acknowledgement.
+pub(crate) async fn exchange(
+    peer: PublicKey,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    data: Bytes,
+) -> Result<Delivery, TunnelError> {
+    // Handing the buffer over avoids copying it into the stream.
+    send.write_chunk(data)
+        .await
+        .map_err(|e| write_error(peer, e))?;
+    send.finish().map_err(|e| write_error(peer, e.into()))?;
+
+    let mut ack = [0];
+
+    match recv.read_exact(&mut ack).await {
+        Ok(()) => {}
+        Err(ReadExactError::ReadError(ReadError::Reset(code)))
+            if code.into_inner() == u64::from(MESSAGE_TOO_LARGE_CODE) =>
+        {
+            return Err(TunnelError::MessageTooLarge { peer });
+        }
+        Err(ReadExactError::ReadError(ReadError::ConnectionLost(e))) => {
+            return Err(connection_error(peer, e));
+        }
+        Err(_) => return Err(TunnelError::NoAck { peer }),
+    }
+
+    match ack[0] {
+        PROCESSED => Ok(Delivery::Processed),
+        HANDLER_FAILED => Ok(Delivery::HandlerFailed),
+        NOT_HANDLED => Ok(Delivery::NotHandled),
+        _ => Err(TunnelError::NoAck { peer }),
+    }
+}
+
+/// Accepts the acknowledged connections of a tunnel, and hands their messages
+/// to its [TunnelProtocol], acknowledging each once handled.
+///
+/// Unlike regular messages, messages from the same connection are always
+/// handled in order, one at a time.
+#[derive(Debug)]
+pub(crate) struct AckedProtocol(pub Arc<TunnelProtocol>);
+
+impl AckedProtocol {
+    async fn handle_stream(&self, sender: PublicKey, mut send: SendStream, mut recv: RecvStream) {
+        let data = match recv.read_to_end(self.0.max_message_size()).await {
+            Ok(data) => data,
+            Err(ReadToEndError::TooLong) => {
+                let _ = recv.stop(MESSAGE_TOO_LARGE_CODE.into());
+                let _ = send.reset(MESSAGE_TOO_LARGE_CODE.into());
+                self.0.message_too_large(sender);
+                return;
+            }
+            Err(ReadToEndError::Read(e)) => {
+                self.0.stream_error(sender, e);
+                return;
+            }
+        };
+
+        // Never buffered, so the sender learns that nothing handled it.
+        let ack = match self.0.dispatch_unbuffered(sender, data.into()).await {
+            Ok(true) => PROCESSED,
+            Ok(false) => NOT_HANDLED,
+            Err(e) => {
+                self.0.handler_error(sender, e);
+                HANDLER_FAILED
+            }
+        };
+
+        if send.write_all(&[ack]).await.is_ok() {
+            let _ = send.finish();
+        }
+    }
+}
+
+impl ProtocolHandler for AckedProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        // Acknowledged messages are never buffered, so only handlers count.
+        if self.0.refuses_unhandled(&connection, self.0.has_handler()) {
+            return Ok(());
+        }
+
+        let Some(_disconnect) = self.0.admit(&connection) else {
+            return Ok(());
+        };
+
+        loop {
+            let paused = self.0.pause.is_paused();
+
+            tokio::select! {
+                streams = connection.accept_bi(), if !paused => {
+                    let Ok((send, recv)) = streams else {
+                        break;
+                    };
+
+                    // Discarded if the tunnel started shutting down meanwhile.
+                    let Some(_active) = self.0.activity.enter() else {
+                        continue;
+                    };
+
+                    self.handle_stream(connection.remote_id(), send, recv).await;
+                }
+                _ = self.0.pause.resumed(), if paused => {}
+                _ = self.0.activity.closed() => {
+                    self.0.go_away(&connection).await;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use iroh::endpoint::Connection;
+use n0_future::time::Instant;
+use tokio::sync::Mutex;
+
+use crate::{
+    ALPN, Compression, DEFAULT_COMPRESSION_THRESHOLD, PublicKey, SendStream, framing,
+    lifecycle::PeerCallbacks, map::ConnMap, version,
+};
+
+/// A connection stored in a [ConnectionCache].
+#[derive(Debug, Clone)]
+pub(crate) struct CachedConn {
+    pub conn: Connection,
+    /// Increases every time a connection is inserted into the cache, so a
+    /// newer connection to the same peer is never mistaken for an older one.
+    pub generation: u64,
+    /// When the connection was inserted into the cache.
+    pub created: Instant,
+    /// How many sends are currently using the connection.
+    pub in_flight: Arc<AtomicUsize>,
+    /// When a send last started using the connection, in milliseconds since
+    /// it was created.
+    pub last_used: Arc<AtomicU64>,
+    /// The stream carrying the frames sent over the connection, once opened.
+    /// Only used by framed tunnels.
+    pub frames: Arc<Mutex<Option<SendStream>>>,
+    /// The protocol version both sides announced. Only set for versioned
+    /// connections.
+    pub version: Option<u8>,
+}
+
+impl CachedConn {
+    /// Marks the connection as used by a send until the returned guard is
+    /// dropped.
+    pub fn track(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.last_used
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        InFlight(Arc::clone(&self.in_flight))
+    }
+
+    /// Returns when a send last started using the connection, or when it was
+    /// created if it was never used.
+    pub fn last_used(&self) -> Instant {
+        self.created + Duration::from_millis(self.last_used.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether no send is currently using the connection.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::Acquire) == 0
+    }
+}
+
+/// A guard returned by [CachedConn::track].
+#[derive(Debug)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// How the connections of a [ConnectionCache] are dialed.
+#[derive(Debug, Clone)]
+pub(crate) struct DialOptions {
+    pub alpn: Vec<u8>,
+    /// How long dialing waits for the connection to be established. `None`
+    /// leaves it to the timeouts of the endpoint.
+    pub connect_timeout: Option<Duration>,
+    /// Whether connections are framed, i.e. carry many messages per stream.
+    pub framed: bool,
+    /// How the messages sent over connections are compressed. `None` sends
+    /// them as they are.
+    pub compression: Option<Compression>,
+    /// The size from which messages are compressed, in bytes.
+    pub compression_threshold: usize,
+    /// The protocol version announced over new connections. `None` dials
+    /// unversioned connections.
+    pub version: Option<u8>,
+}
+
+impl DialOptions {
+    /// Returns the ALPN connections are dialed with.
+    pub fn connect_alpn(&self) -> Vec<u8> {
+        let alpn = if self.has_stream_kinds() {
+            framing::framed_alpn(&self.alpn)
+        } else {
+            self.alpn.clone()
+        };
+
+        match self.version {
+            Some(_) => version::versioned_alpn(&alpn),
+            None => alpn,
+        }
+    }
+
+    /// Returns whether connections are dialed with the framed ALPN, whose
+    /// streams start with their kind.
+    pub fn has_stream_kinds(&self) -> bool {
+        self.framed || self.compression.is_some()
+    }
+}
+
+impl Default for DialOptions {
+    fn default() -> Self {
+        Self {
+            alpn: ALPN.to_vec(),
+            connect_timeout: None,
+            framed: false,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            version: None,
+        }
+    }
+}
+
+/// Limits on the connections kept by a [ConnectionCache]. See
+/// [TunnelBuilder::idle_timeout](crate::TunnelBuilder::idle_timeout) and
+/// [TunnelBuilder::max_cached_connections](crate::TunnelBuilder::max_cached_connections).
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CacheLimits {
+    pub idle_timeout: Option<Duration>,
+    pub max_connections: Option<usize>,
+}
+
+/// The cache of outgoing connections of a tunnel.
+///
+/// Paths which remove a connection because something went wrong with it
+/// should use [ConnectionCache::remove_if_same], so they never remove a newer
+/// connection which replaced it in the meantime.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionCache {
+    connections: ConnMap<PublicKey, CachedConn>,
+    next_generation: AtomicU64,
+    dial: DialOptions,
+    limits: CacheLimits,
+    callbacks: PeerCallbacks,
+}
+
+impl ConnectionCache {
+    /// Creates an empty cache, whose connections are dialed with `dial` and
+    /// evicted according to `limits`. `callbacks` are notified when
+    /// connections are inserted into it and when they are closed.
+    pub fn new(dial: DialOptions, limits: CacheLimits, callbacks: PeerCallbacks) -> Self {
+        Self {
+            dial,
+            limits,
+            callbacks,
+            ..Default::default()
+        }
+    }
+
+    pub fn dial_options(&self) -> &DialOptions {
+        &self.dial
+    }
+
+    pub fn limits(&self) -> CacheLimits {
+        self.limits
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the connections which should be evicted to respect the limits
+    /// of the cache: the ones unused for longer than the idle timeout, then
+    /// the least recently used ones while there are too many connections.
+    ///
+    /// Connections currently used by a send are never returned.
+    pub fn evictable(&self) -> Vec<(PublicKey, CachedConn)> {
+        let mut idle: Vec<_> = self
+            .connections
+            .entries()
+            .into_iter()
+            .filter(|(_, cached)| cached.is_idle())
+            .collect();
+
+        idle.sort_by_key(|(_, cached)| cached.last_used());
+
+        let excess = self
+            .limits
+            .max_connections
+            .map_or(0, |max| self.len().saturating_sub(max));
+
+        idle.into_iter()
+            .enumerate()
+            .filter(|(index, (_, cached))| {
+                *index < excess
+                    || self
+                        .limits
+                        .idle_timeout
+                        .is_some_and(|timeout| cached.last_used().elapsed() >= timeout)
+            })
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+
+    pub fn get(&self, address: &PublicKey) -> Option<CachedConn> {
+        self.connections.get(address)
+    }
+
+    /// Caches a connection, unless one to the same address is already cached.
+    /// `version` is the protocol version negotiated over the connection, if
+    /// any.
+    ///
+    /// Returns the connection which ends up cached and whether it is the
+    /// provided one.
+    pub fn insert_or_get(
+        &self,
+        address: PublicKey,
+        conn: Connection,
+        version: Option<u8>,
+    ) -> (CachedConn, bool) {
+        let (cached, inserted) = self.connections.get_or_insert_with(address, || CachedConn {
+            conn,
+            generation: self.next_generation.fetch_add(1, Ordering::Relaxed),
+            created: Instant::now(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            last_used: Arc::new(AtomicU64::new(0)),
+            frames: Arc::new(Mutex::new(None)),
+            version,
+        });
+
+        if inserted {
+            self.callbacks.outgoing(address, &cached.conn);
+        }
+
+        (cached, inserted)
+    }
+
+    pub fn remove(&self, address: &PublicKey) -> Option<CachedConn> {
+        self.connections.remove(address)
+    }
+
+    /// Removes the connection to an address, but only if it still is the
+    /// connection of the given generation.
+    pub fn remove_if_same(&self, address: &PublicKey, generation: u64) -> Option<CachedConn> {
+        self.connections
+            .remove_if(address, |cached| cached.generation == generation)
+    }
+
+    pub fn entries(&self) -> Vec<(PublicKey, CachedConn)> {
+        self.connections.entries()
+    }
+
+    /// Returns the address and generation of every cached connection.
+    pub fn generations(&self) -> Vec<(PublicKey, u64)> {
+        self.connections
+            .entries()
+            .into_iter()
+            .map(|(address, cached)| (address, cached.generation))
+            .collect()
+    }
+
+    /// Returns every cached connection which is older than the given age.
+    pub fn older_than(&self, age: Duration) -> Vec<(PublicKey, CachedConn)> {
+        self.connections
+            .entries()
+            .into_iter()
+            .filter(|(_, cached)| cached.created.elapsed() >= age)
+            .collect()
+    }
+
+    /// Removes and returns every cached connection.
+    pub fn drain(&self) -> Vec<CachedConn> {
+        self.connections
+            .keys()
+            .iter()
+            .filter_map(|address| self.remove(address))
+            .collect()
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{Bytes, metrics::Metrics};
+
+/// The first byte of a message of a compressed stream whose payload is not
+/// compressed.
+const UNCOMPRESSED: u8 = 0;
+
+/// The first byte of a message of a compressed stream whose payload is
+/// compressed with Zstandard.
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+
+/// The first byte of a message of a compressed stream whose payload is
+/// compressed with LZ4, and prefixed with its uncompressed size as a little
+/// endian `u32`.
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 2;
+
+/// A compression algorithm applied to the messages sent by a tunnel. See
+/// [TunnelBuilder::compression](crate::TunnelBuilder::compression).
+///
+/// Each algorithm is only available when the cargo feature of the same name
+/// is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, with the given compression level. Level `0` uses the
+    /// default level of the algorithm.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// LZ4, which compresses less than Zstandard but is much faster.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// Compresses `data`, prefixed with the byte identifying the algorithm.
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd(level) => {
+                let mut payload = vec![ZSTD];
+                zstd::stream::copy_encode(data, &mut payload, level).ok()?;
+                Some(payload)
+            }
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                let mut payload = vec![LZ4];
+                payload.extend(lz4_flex::compress_prepend_size(data));
+                Some(payload)
+            }
+        }
+    }
+}
+
+/// Encodes a message for a compressed stream.
+///
+/// Messages smaller than `threshold` are sent as they are, as are messages
+/// which do not get smaller once compressed, e.g. already compressed data.
+pub(crate) fn encode(
+    compression: Compression,
+    threshold: usize,
+    data: &[u8],
+    metrics: &Metrics,
+) -> Vec<u8> {
+    if data.len() >= threshold
+        && let Some(payload) = compression.compress(data)
+        && payload.len() < data.len()
+    {
+        metrics.record_compression(data.len(), payload.len());
+
+        return payload;
+    }
+
+    [&[UNCOMPRESSED], data].concat()
+}
+
+/// An error which happened while decoding a message of a compressed stream.
+pub(crate) enum DecodeError {
+    /// The decompressed message is larger than the maximum message size.
+    TooLarge,
+    /// The message is empty, corrupt, or compressed with an algorithm which
+    /// is not enabled.
+    Invalid,
+}
+
+/// Decodes a message of a compressed stream, which must not be larger than
+/// `max_size` once decompressed.
+pub(crate) fn decode(payload: Vec<u8>, max_size: usize) -> Result<Bytes, DecodeError> {
+    let Some(&algorithm) = payload.first() else {
+        return Err(DecodeError::Invalid);
+    };
+
+    match algorithm {
+        UNCOMPRESSED => {
+            if payload.len() - 1 > max_size {
+                return Err(DecodeError::TooLarge);
+            }
+
+            // Sliced rather than shifted, so the data is not copied.
+            Ok(Bytes::from(payload).slice(1..))
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            use std::io::Read;
+
+            let mut data = Vec::new();
+
+            // Reading one byte past the maximum is enough to tell that the
+            // message is too large, without decompressing all of it.
+            zstd::stream::read::Decoder::with_buffer(&payload[1..])
+                .map_err(|_| DecodeError::Invalid)?
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut data)
+                .map_err(|_| DecodeError::Invalid)?;
+
+            if data.len() > max_size {
+                return Err(DecodeError::TooLarge);
+            }
+
+            Ok(data.into())
+        }
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            let payload = &payload[1..];
+
+            let size = payload
+                .first_chunk()
+                .map(|size| u32::from_le_bytes(*size) as usize)
+                .ok_or(DecodeError::Invalid)?;
+
+            // Checked before decompressing, as the buffer is allocated from
+            // the size.
+            if size > max_size {
+                return Err(DecodeError::TooLarge);
+            }
+
+            lz4_flex::decompress_size_prepended(payload)
+                .map(Bytes::from)
+                .map_err(|_| DecodeError::Invalid)
+        }
+        _ => Err(DecodeError::Invalid),
+    }
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "lz4")))]
+mod tests {
+    use super::{Compression, decode, encode};
+    use crate::{metrics::Metrics, testing};
+
+    fn algorithms() -> Vec<Compression> {
+        let mut algorithms = Vec::new();
+
+        #[cfg(feature = "zstd")]
+        algorithms.push(Compression::Zstd(0));
+        #[cfg(feature = "lz4")]
+        algorithms.push(Compression::Lz4);
+
+        algorithms
+    }
+
+    fn round_trip(compression: Compression, data: &[u8], metrics: &Metrics) -> Vec<u8> {
+        let payload = encode(compression, 0, data, metrics);
+
+        match decode(payload, data.len()) {
+            Ok(decoded) => decoded.to_vec(),
+            Err(_) => panic!("{compression:?} could not decode {} bytes", data.len()),
+        }
+    }
+
+    #[test]
+    fn compressible_data_round_trips_compressed() {
+        let data = b"{\"key\": \"value\"}".repeat(1024);
+
+        for compression in algorithms() {
+            let metrics = Metrics::default();
+            assert_eq!(round_trip(compression, &data, &metrics), data);
+
+            let snapshot = metrics.snapshot();
+            assert_eq!(snapshot.bytes_before_compression, data.len() as u64);
+            assert!(snapshot.bytes_after_compression < data.len() as u64 / 10);
+        }
+    }
+
+    #[test]
+    fn incompressible_data_round_trips_uncompressed() {
+        let data = testing::incompressible(64 * 1024);
+
+        for compression in algorithms() {
+            let metrics = Metrics::default();
+            assert_eq!(round_trip(compression, &data, &metrics), data);
+            assert_eq!(metrics.snapshot().bytes_before_compression, 0);
+        }
+    }
+
+    #[test]
+    fn empty_data_round_trips() {
+        for compression in algorithms() {
+            let metrics = Metrics::default();
+            assert_eq!(round_trip(compression, &[], &metrics), Vec::<u8>::new());
+        }
+    }
+
+    #[test]
+    fn empty_and_unknown_payloads_are_invalid() {
+        assert!(decode(Vec::new(), 1024).is_err());
+        assert!(decode(vec![0xff, 1, 2, 3], 1024).is_err());
+    }
+}
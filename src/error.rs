@@ -0,0 +1,524 @@
+use std::{
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use iroh::endpoint::{ConnectError, ConnectionError, ReadError, SendDatagramError, WriteError};
+
+use crate::{MAX_TOPIC_LENGTH, Mode, PublicKey, RelayUrl};
+
+/// The stage of a tunnel's creation at which an error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupStage {
+    /// Binding the sender endpoint, including its discovery and relay setup.
+    SenderBind,
+    /// Binding the receiver endpoint, including its discovery and relay setup.
+    ReceiverBind,
+    /// Spawning the router which accepts incoming connections on the receiver
+    /// endpoint.
+    RouterSpawn,
+    /// Waiting for the endpoints to reach a relay and publish their addresses,
+    /// which only fails once the timeout set with
+    /// [TunnelBuilder::online_timeout](crate::TunnelBuilder::online_timeout)
+    /// is exceeded.
+    Discovery,
+}
+
+impl Display for SetupStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SenderBind => write!(f, "binding the sender endpoint"),
+            Self::ReceiverBind => write!(f, "binding the receiver endpoint"),
+            Self::RouterSpawn => write!(f, "spawning the receiver router"),
+            Self::Discovery => write!(f, "waiting for the endpoints to go online"),
+        }
+    }
+}
+
+/// An error which can happen while using a tunnel.
+#[derive(Debug)]
+pub enum TunnelError {
+    /// The tunnel could not be created.
+    ///
+    /// Any endpoint which was bound before the failure is closed before this
+    /// error is returned.
+    Setup {
+        /// The stage at which the creation failed.
+        stage: SetupStage,
+        /// The sockets which were bound before the failure happened.
+        bound_sockets: Vec<SocketAddr>,
+        /// The relay servers the endpoints were set up to contact. Empty if
+        /// relays are disabled.
+        relays: Vec<RelayUrl>,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The tunnel could not be created, as the ALPN set with
+    /// [TunnelBuilder::alpn](crate::TunnelBuilder::alpn) is empty.
+    EmptyAlpn,
+    /// The operation needs a direction which the [Mode] of the tunnel does
+    /// not support, e.g. sending data through a receive-only tunnel.
+    ///
+    /// This error is returned before any network activity happens.
+    WrongMode {
+        /// The mode of the tunnel.
+        mode: Mode,
+    },
+    /// A request was sent to a tunnel which has no request handler.
+    NoRequestHandler {
+        /// The address of the tunnel which refused the request.
+        peer: PublicKey,
+    },
+    /// A request was not answered in time.
+    RequestTimeout {
+        /// The address of the tunnel the request was sent to.
+        peer: PublicKey,
+        timeout: Duration,
+    },
+    /// A connection to another tunnel could not be established.
+    Connect {
+        /// The address of the tunnel which was dialed.
+        peer: PublicKey,
+        source: ConnectError,
+    },
+    /// The connection to another tunnel was lost, or closed before a stream
+    /// could be opened over it.
+    ConnectionLost {
+        /// The address of the tunnel the connection led to.
+        peer: PublicKey,
+        source: ConnectionError,
+    },
+    /// Another tunnel refused the connection of this tunnel, as its
+    /// [AcceptPolicy](crate::AcceptPolicy) does not allow it.
+    Refused {
+        /// The address of the tunnel which refused the connection.
+        peer: PublicKey,
+    },
+    /// Another tunnel closed the connection of this tunnel, as it has no
+    /// handler to give the messages to. See
+    /// [NoHandlerPolicy::Refuse](crate::NoHandlerPolicy::Refuse).
+    NoHandler {
+        /// The address of the tunnel without a handler.
+        peer: PublicKey,
+    },
+    /// Data could not be written to a stream to another tunnel.
+    Write {
+        /// The address of the tunnel the data was sent to.
+        peer: PublicKey,
+        source: WriteError,
+    },
+    /// A datagram could not be sent to another tunnel, e.g. as it is larger
+    /// than [Tunnel::max_datagram_size](crate::Tunnel::max_datagram_size).
+    Datagram {
+        /// The address of the tunnel the datagram was sent to.
+        peer: PublicKey,
+        source: SendDatagramError,
+    },
+    /// Data could not be read from a stream to another tunnel.
+    Read {
+        /// The address of the tunnel the data was read from.
+        peer: PublicKey,
+        source: ReadError,
+    },
+    /// Another tunnel stopped a stream before receiving all of its data.
+    RemoteStopped {
+        /// The address of the tunnel which stopped the stream.
+        peer: PublicKey,
+        /// The error code given by the other tunnel.
+        code: u64,
+    },
+    /// The reader given to [Tunnel::send_large](crate::Tunnel::send_large)
+    /// did not provide as many bytes as announced. The transfer is cancelled.
+    LengthMismatch {
+        /// The announced length.
+        expected: u64,
+        /// How many bytes the reader provided. `None` if it provided more
+        /// than announced.
+        actual: Option<u64>,
+    },
+    /// A typed message could not be serialized, e.g. by
+    /// `Tunnel::send_typed`.
+    Serialize {
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The data of a message could not be read from its source, e.g. by
+    /// [Tunnel::send_stream](crate::Tunnel::send_stream).
+    Source { source: std::io::Error },
+    /// A topic is empty or longer than [MAX_TOPIC_LENGTH] bytes, see
+    /// [Tunnel::send_on](crate::Tunnel::send_on).
+    InvalidTopic { topic: String },
+    /// A message is too large to be sent as a frame by a framed tunnel.
+    FrameTooLarge {
+        /// The size of the message, in bytes.
+        size: usize,
+    },
+    /// A message sent with [Tunnel::send_acked](crate::Tunnel::send_acked)
+    /// was not acknowledged in time. It may still be processed.
+    AckTimeout {
+        /// The address of the tunnel the message was sent to.
+        peer: PublicKey,
+        timeout: Duration,
+    },
+    /// The stream of a message sent with
+    /// [Tunnel::send_acked](crate::Tunnel::send_acked) ended without a valid
+    /// acknowledgement.
+    NoAck {
+        /// The address of the tunnel the message was sent to.
+        peer: PublicKey,
+    },
+    /// Another tunnel did not answer a ping in time, see
+    /// [Tunnel::ping](crate::Tunnel::ping).
+    PingTimeout {
+        /// The address of the tunnel which was pinged.
+        peer: PublicKey,
+        timeout: Duration,
+    },
+    /// Another tunnel answered a ping with an invalid response.
+    InvalidPong {
+        /// The address of the tunnel which was pinged.
+        peer: PublicKey,
+    },
+    /// The response to a request is larger than the maximum message size of
+    /// this tunnel, and was discarded.
+    ResponseTooLarge {
+        /// The address of the tunnel which sent the response.
+        peer: PublicKey,
+    },
+    /// A send did not complete in time.
+    SendTimeout {
+        /// The address of the tunnel the data was sent to.
+        peer: PublicKey,
+        timeout: Duration,
+    },
+    /// The background sends waited for by [Tunnel::flush](crate::Tunnel::flush)
+    /// did not complete in time.
+    FlushTimeout {
+        /// The address the sends were waited for, if not all of them.
+        peer: Option<PublicKey>,
+        timeout: Duration,
+    },
+    /// The endpoints of the tunnel did not go online in time, see
+    /// [Tunnel::wait_online](crate::Tunnel::wait_online).
+    OnlineTimeout { timeout: Duration },
+    /// A connection to another tunnel was not established in time.
+    ConnectTimeout {
+        /// The address of the tunnel which was dialed.
+        peer: PublicKey,
+        timeout: Duration,
+    },
+    /// The message was refused by the receiving tunnel, as it is larger than
+    /// its maximum message size.
+    MessageTooLarge {
+        /// The address of the tunnel which refused the message.
+        peer: PublicKey,
+    },
+    /// Another tunnel uses a different protocol version than this tunnel. See
+    /// [TunnelBuilder::protocol_version](crate::TunnelBuilder::protocol_version).
+    VersionMismatch {
+        /// The address of the tunnel which was dialed.
+        peer: PublicKey,
+        /// The version of this tunnel.
+        local: u8,
+        /// The version announced by the other tunnel. `None` if it did not
+        /// announce one.
+        remote: Option<u8>,
+    },
+    /// The receiver endpoint could not be shut down cleanly by
+    /// [Tunnel::shutdown](crate::Tunnel::shutdown). It is closed nonetheless.
+    Shutdown {
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The tunnel was destroyed or shut down, so it cannot start new
+    /// background work.
+    Destroyed,
+}
+
+impl Display for TunnelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Setup {
+                stage,
+                bound_sockets,
+                relays,
+                source,
+            } => {
+                write!(f, "Failed to create tunnel while {stage}: {source}.")?;
+
+                if bound_sockets.is_empty() {
+                    write!(f, " No sockets were bound.")?;
+                } else {
+                    write!(f, " Sockets bound before the failure: {bound_sockets:?}.")?;
+                }
+
+                if relays.is_empty() {
+                    write!(f, " Relays are disabled.")
+                } else {
+                    let relays: Vec<String> = relays.iter().map(ToString::to_string).collect();
+                    write!(f, " Relays contacted: {}.", relays.join(", "))
+                }
+            }
+            Self::EmptyAlpn => write!(f, "Failed to create tunnel: the ALPN is empty."),
+            Self::WrongMode { mode } => match mode {
+                Mode::SendOnly => write!(f, "This tunnel is send-only and cannot receive data."),
+                Mode::ReceiveOnly => write!(f, "This tunnel is receive-only and cannot send data."),
+                Mode::SendReceive => write!(f, "This tunnel does not support this operation."),
+            },
+            Self::NoRequestHandler { peer } => {
+                write!(f, "The tunnel {peer} does not handle requests.")
+            }
+            Self::RequestTimeout { peer, timeout } => {
+                write!(
+                    f,
+                    "The request to {peer} was not answered within {timeout:?}."
+                )
+            }
+            Self::Connect { peer, source } => write!(f, "Failed to connect to {peer}: {source}."),
+            Self::ConnectionLost { peer, source } => {
+                write!(f, "The connection to {peer} was lost: {source}.")
+            }
+            Self::Refused { peer } => write!(f, "The tunnel {peer} refused the connection."),
+            Self::NoHandler { peer } => {
+                write!(f, "The tunnel {peer} has no handler for incoming data.")
+            }
+            Self::Write { peer, source } => write!(f, "Failed to send data to {peer}: {source}."),
+            Self::Datagram { peer, source } => {
+                write!(f, "Failed to send a datagram to {peer}: {source}.")
+            }
+            Self::Read { peer, source } => write!(f, "Failed to read data from {peer}: {source}."),
+            Self::RemoteStopped { peer, code } => {
+                write!(
+                    f,
+                    "The tunnel {peer} stopped the stream. Error code: {code}."
+                )
+            }
+            Self::LengthMismatch {
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "The reader provided {actual} bytes instead of the announced {expected}."
+            ),
+            Self::LengthMismatch {
+                expected,
+                actual: None,
+            } => write!(
+                f,
+                "The reader provided more than the announced {expected} bytes."
+            ),
+            Self::Serialize { source } => write!(f, "Failed to serialize the message: {source}."),
+            Self::Source { source } => write!(f, "Failed to read the data to send: {source}."),
+            Self::InvalidTopic { topic } => write!(
+                f,
+                "The topic {topic:?} is empty or longer than {MAX_TOPIC_LENGTH} bytes."
+            ),
+            Self::FrameTooLarge { size } => {
+                write!(
+                    f,
+                    "The message of {size} bytes is too large to be sent as a frame."
+                )
+            }
+            Self::AckTimeout { peer, timeout } => {
+                write!(
+                    f,
+                    "The message to {peer} was not acknowledged within {timeout:?}."
+                )
+            }
+            Self::NoAck { peer } => write!(f, "The tunnel {peer} did not acknowledge the message."),
+            Self::PingTimeout { peer, timeout } => {
+                write!(
+                    f,
+                    "The tunnel {peer} did not answer the ping within {timeout:?}."
+                )
+            }
+            Self::InvalidPong { peer } => {
+                write!(
+                    f,
+                    "The tunnel {peer} answered the ping with an invalid response."
+                )
+            }
+            Self::ResponseTooLarge { peer } => {
+                write!(
+                    f,
+                    "The response from {peer} is larger than the maximum message size."
+                )
+            }
+            Self::SendTimeout { peer, timeout } => {
+                write!(f, "The send to {peer} did not complete within {timeout:?}.")
+            }
+            Self::FlushTimeout {
+                peer: Some(peer),
+                timeout,
+            } => write!(
+                f,
+                "The sends to {peer} did not complete within {timeout:?}."
+            ),
+            Self::FlushTimeout {
+                peer: None,
+                timeout,
+            } => write!(f, "The pending sends did not complete within {timeout:?}."),
+            Self::OnlineTimeout { timeout } => {
+                write!(f, "The tunnel did not go online within {timeout:?}.")
+            }
+            Self::ConnectTimeout { peer, timeout } => {
+                write!(f, "Failed to connect to {peer} within {timeout:?}.")
+            }
+            Self::MessageTooLarge { peer } => {
+                write!(
+                    f,
+                    "The message is larger than the maximum message size of {peer}."
+                )
+            }
+            Self::VersionMismatch {
+                peer,
+                local,
+                remote: Some(remote),
+            } => write!(
+                f,
+                "The tunnel {peer} uses version {remote}, this tunnel uses {local}."
+            ),
+            Self::VersionMismatch {
+                peer,
+                local,
+                remote: None,
+            } => {
+                write!(
+                    f,
+                    "The tunnel {peer} did not announce a version, this tunnel uses {local}."
+                )
+            }
+            Self::Shutdown { source } => write!(f, "Failed to shut down the tunnel: {source}."),
+            Self::Destroyed => write!(f, "The tunnel was destroyed."),
+        }
+    }
+}
+
+impl Error for TunnelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Setup { source, .. } => Some(source.as_ref()),
+            Self::Connect { source, .. } => Some(source),
+            Self::ConnectionLost { source, .. } => Some(source),
+            Self::Write { source, .. } => Some(source),
+            Self::Datagram { source, .. } => Some(source),
+            Self::Read { source, .. } => Some(source),
+            Self::Serialize { source } => Some(source.as_ref()),
+            Self::Shutdown { source } => Some(source.as_ref()),
+            Self::Source { source } => Some(source),
+            Self::EmptyAlpn
+            | Self::WrongMode { .. }
+            | Self::NoRequestHandler { .. }
+            | Self::RequestTimeout { .. }
+            | Self::Refused { .. }
+            | Self::NoHandler { .. }
+            | Self::RemoteStopped { .. }
+            | Self::LengthMismatch { .. }
+            | Self::InvalidTopic { .. }
+            | Self::FrameTooLarge { .. }
+            | Self::AckTimeout { .. }
+            | Self::NoAck { .. }
+            | Self::PingTimeout { .. }
+            | Self::InvalidPong { .. }
+            | Self::ResponseTooLarge { .. }
+            | Self::SendTimeout { .. }
+            | Self::FlushTimeout { .. }
+            | Self::OnlineTimeout { .. }
+            | Self::ConnectTimeout { .. }
+            | Self::MessageTooLarge { .. }
+            | Self::VersionMismatch { .. }
+            | Self::Destroyed => None,
+        }
+    }
+}
+
+/// An error returned by a [DataHandler](crate::DataHandler) or an
+/// [AsyncDataHandler](crate::AsyncDataHandler) which failed to process a
+/// message. What happens next is decided by the [HandlerErrorPolicy] of the
+/// tunnel.
+///
+/// Any error can be converted into a [HandlerError], so handlers can use the
+/// `?` operator. Handlers which panic are treated as if they returned a
+/// [HandlerError].
+pub struct HandlerError {
+    source: Box<dyn Error + Send + Sync>,
+    panicked: bool,
+}
+
+impl HandlerError {
+    /// Creates a [HandlerError] from any error, or from a message.
+    pub fn new(source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self {
+            source: source.into(),
+            panicked: false,
+        }
+    }
+
+    /// The error of a handler which panicked.
+    pub(crate) fn panicked() -> Self {
+        Self {
+            source: "The handler panicked.".into(),
+            panicked: true,
+        }
+    }
+
+    /// Returns whether the handler panicked, rather than returning an error.
+    pub fn is_panic(&self) -> bool {
+        self.panicked
+    }
+
+    /// Returns the error the handler failed with.
+    pub fn into_inner(self) -> Box<dyn Error + Send + Sync> {
+        self.source
+    }
+}
+
+// Not implementing `Error` is what allows converting any error into a
+// `HandlerError`, as the conversion would otherwise conflict with the
+// reflexive `From` implementation.
+impl<E: Into<Box<dyn Error + Send + Sync>>> From<E> for HandlerError {
+    fn from(source: E) -> Self {
+        Self::new(source)
+    }
+}
+
+impl Debug for HandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.source, f)
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The handler failed to process the message: {}",
+            self.source
+        )
+    }
+}
+
+/// What a tunnel does when its handler fails to process a message, see
+/// [TunnelBuilder::handler_error_policy](crate::TunnelBuilder::handler_error_policy).
+///
+/// Whatever the policy, the error is first given to the function set with
+/// [TunnelBuilder::on_handler_error](crate::TunnelBuilder::on_handler_error).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandlerErrorPolicy {
+    /// The message is discarded, and the following messages are handled as
+    /// usual.
+    #[default]
+    Continue,
+    /// The stream of the message is stopped with
+    /// [HANDLER_ERROR_CODE](crate::HANDLER_ERROR_CODE). A sending tunnel which
+    /// is still waiting for its data to be acknowledged sees this as
+    /// [TunnelError::RemoteStopped], but data is usually acknowledged before
+    /// the handler runs, so senders which need to know should use
+    /// [Tunnel::send_acked](crate::Tunnel::send_acked). The streams of
+    /// [TunnelBuilder::framed](crate::TunnelBuilder::framed) tunnels carry
+    /// many messages, and are stopped as a whole. Datagrams have no stream,
+    /// so their errors are handled like with [HandlerErrorPolicy::Continue].
+    StopStream,
+    /// The connection the message came from is closed with
+    /// [HANDLER_ERROR_CODE](crate::HANDLER_ERROR_CODE).
+    CloseConnection,
+}
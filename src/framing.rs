@@ -0,0 +1,306 @@
+use std::sync::Arc;
+
+use iroh::{
+    endpoint::{Connection, ReadExactError, ReadToEndError, WriteError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+
+use crate::{
+    Bytes, INVALID_PAYLOAD_CODE, MESSAGE_TOO_LARGE_CODE, PublicKey, RecvStream, SendStream,
+    TunnelProtocol,
+    compression::{self, DecodeError},
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of framed connections.
+/// Compressed connections use it too, as they also need streams to start with
+/// their kind.
+const FRAMED_ALPN_SUFFIX: &[u8] = b"/framed";
+
+/// The first byte of a stream of a framed connection which carries a single
+/// message, like the streams of regular connections.
+pub(crate) const MESSAGE_STREAM: u8 = 0;
+
+/// The first byte of a stream of a framed connection which carries many
+/// messages, each prefixed with its length as a big endian `u32`.
+pub(crate) const FRAMED_STREAM: u8 = 1;
+
+/// The first byte of a stream of a framed connection which carries a single
+/// message, starting with the byte identifying its compression.
+pub(crate) const COMPRESSED_MESSAGE_STREAM: u8 = 2;
+
+/// The first byte of a stream of a framed connection which carries many
+/// messages like a [FRAMED_STREAM], each starting with the byte identifying
+/// its compression.
+pub(crate) const COMPRESSED_FRAMED_STREAM: u8 = 3;
+
+/// The error code used when stopping streams of framed connections which
+/// start with an unknown byte.
+const UNKNOWN_STREAM_CODE: u32 = 0;
+
+/// Returns the ALPN of the framed connections of a tunnel using `alpn`.
+pub(crate) fn framed_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, FRAMED_ALPN_SUFFIX].concat()
+}
+
+/// The maximum size of a frame, as its length is written as a `u32`.
+pub(crate) const MAX_FRAME_SIZE: usize = u32::MAX as usize;
+
+/// Writes a frame made of the concatenation of `chunks` to a stream opened
+/// with [FRAMED_STREAM], without copying them. The frame must not be larger
+/// than [MAX_FRAME_SIZE].
+pub(crate) async fn write_frame(
+    stream: &mut SendStream,
+    chunks: &[Bytes],
+) -> Result<(), WriteError> {
+    let len: usize = chunks.iter().map(Bytes::len).sum();
+
+    stream.write_all(&(len as u32).to_be_bytes()).await?;
+    stream.write_all_chunks(&mut chunks.to_vec()).await
+}
+
+/// An error which happened while reading a frame.
+enum FrameError {
+    /// The frame is larger than the maximum message size.
+    TooLong,
+    /// The stream ended in the middle of a frame, was reset or the connection
+    /// was lost.
+    Read(ReadExactError),
+}
+
+impl From<ReadExactError> for FrameError {
+    fn from(error: ReadExactError) -> Self {
+        Self::Read(error)
+    }
+}
+
+/// Reads the next frame of a stream. Returns `None` once the stream ended
+/// between two frames.
+async fn read_frame(
+    stream: &mut RecvStream,
+    max_message_size: usize,
+) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut length = [0; 4];
+
+    match stream.read_exact(&mut length).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > max_message_size {
+        return Err(FrameError::TooLong);
+    }
+
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await?;
+
+    Ok(Some(frame))
+}
+
+/// Accepts the framed connections of a tunnel, and hands their messages to
+/// its [TunnelProtocol].
+///
+/// Unlike regular connections, each stream is handled by its own task, as
+/// framed streams are kept open for as long as the connection is.
+#[derive(Debug)]
+pub(crate) struct FramedProtocol(pub Arc<TunnelProtocol>);
+
+impl FramedProtocol {
+    async fn handle_stream(
+        protocol: Arc<TunnelProtocol>,
+        connection: Connection,
+        mut stream: RecvStream,
+    ) {
+        let sender = connection.remote_id();
+        let mut kind = [0];
+
+        if let Err(e) = stream.read_exact(&mut kind).await {
+            Self::read_failed(&protocol, sender, e);
+            return;
+        }
+
+        let max_message_size = protocol.max_message_size();
+
+        // Framed streams stay open between messages, so only the handling of
+        // each frame keeps a shutting down tunnel waiting.
+        let _active = match kind[0] {
+            FRAMED_STREAM | COMPRESSED_FRAMED_STREAM => None,
+            _ => match protocol.activity.enter() {
+                Some(active) => Some(active),
+                None => return,
+            },
+        };
+
+        match kind[0] {
+            MESSAGE_STREAM => protocol.handle_message(&connection, stream).await,
+            COMPRESSED_MESSAGE_STREAM => {
+                // Uncompressed messages are one byte larger than their data.
+                let payload = match stream.read_to_end(max_message_size.saturating_add(1)).await {
+                    Ok(payload) => payload,
+                    Err(ReadToEndError::TooLong) => {
+                        let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+                        protocol.message_too_large(sender);
+                        return;
+                    }
+                    Err(ReadToEndError::Read(e)) => {
+                        protocol.stream_error(sender, e);
+                        return;
+                    }
+                };
+
+                if let Some(message) = Self::decode(&protocol, sender, &mut stream, payload) {
+                    protocol
+                        .dispatch_stream(&connection, &mut stream, message)
+                        .await;
+                }
+            }
+            kind @ (FRAMED_STREAM | COMPRESSED_FRAMED_STREAM) => {
+                let compressed = kind == COMPRESSED_FRAMED_STREAM;
+                let max_frame_size = max_message_size.saturating_add(compressed.into());
+
+                loop {
+                    // The next frame is not read until receiving is resumed.
+                    if protocol.pause.is_paused() {
+                        tokio::select! {
+                            _ = protocol.pause.resumed() => {}
+                            _ = protocol.activity.closed() => break,
+                        }
+                    }
+
+                    let frame = match read_frame(&mut stream, max_frame_size).await {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(FrameError::Read(e)) => {
+                            Self::read_failed(&protocol, sender, e);
+                            break;
+                        }
+                        // The rest of the stream cannot be read without
+                        // reading the frame, so the whole stream is stopped.
+                        Err(FrameError::TooLong) => {
+                            let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+                            protocol.message_too_large(sender);
+                            break;
+                        }
+                    };
+
+                    // Discarded if the tunnel started shutting down meanwhile.
+                    let Some(_active) = protocol.activity.enter() else {
+                        break;
+                    };
+
+                    let message = if compressed {
+                        match Self::decode(&protocol, sender, &mut stream, frame) {
+                            Some(message) => message,
+                            None => break,
+                        }
+                    } else {
+                        frame.into()
+                    };
+
+                    if !protocol
+                        .dispatch_stream(&connection, &mut stream, message)
+                        .await
+                    {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                let _ = stream.stop(UNKNOWN_STREAM_CODE.into());
+            }
+        }
+    }
+
+    /// Reports a failed read of a stream, unless it only failed as the stream
+    /// ended too early, which is not an error of the stream itself.
+    fn read_failed(protocol: &TunnelProtocol, sender: PublicKey, error: ReadExactError) {
+        if let ReadExactError::ReadError(e) = error {
+            protocol.stream_error(sender, e);
+        }
+    }
+
+    /// Decodes a message of a compressed stream. If it cannot be decoded, the
+    /// stream is stopped, and `None` is returned.
+    fn decode(
+        protocol: &TunnelProtocol,
+        sender: PublicKey,
+        stream: &mut RecvStream,
+        payload: Vec<u8>,
+    ) -> Option<Bytes> {
+        match compression::decode(payload, protocol.max_message_size()) {
+            Ok(message) => Some(message),
+            Err(DecodeError::TooLarge) => {
+                let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+                protocol.message_too_large(sender);
+                None
+            }
+            Err(DecodeError::Invalid) => {
+                let _ = stream.stop(INVALID_PAYLOAD_CODE.into());
+                None
+            }
+        }
+    }
+}
+
+impl ProtocolHandler for FramedProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        if self.0.refuses_unhandled(&connection, self.0.has_handler()) {
+            return Ok(());
+        }
+
+        let Some(_disconnect) = self.0.admit(&connection) else {
+            return Ok(());
+        };
+
+        loop {
+            let paused = self.0.pause.is_paused();
+
+            tokio::select! {
+                stream = connection.accept_uni(), if !paused => {
+                    let Ok(stream) = stream else {
+                        break;
+                    };
+
+                    n0_future::task::spawn(Self::handle_stream(
+                        Arc::clone(&self.0),
+                        connection.clone(),
+                        stream,
+                    ));
+                }
+                datagram = connection.read_datagram(), if !paused => {
+                    let Ok(datagram) = datagram else {
+                        break;
+                    };
+
+                    let Some(active) = self.0.activity.enter() else {
+                        continue;
+                    };
+
+                    let protocol = Arc::clone(&self.0);
+                    let connection = connection.clone();
+
+                    n0_future::task::spawn(async move {
+                        protocol.dispatch_datagram(&connection, datagram).await;
+                        drop(active);
+                    });
+                }
+                _ = self.0.pause.resumed(), if paused => {}
+                streams = connection.accept_bi() => {
+                    let Ok((send, recv)) = streams else {
+                        break;
+                    };
+
+                    self.0.handle_bi(connection.remote_id(), send, recv);
+                }
+                _ = self.0.activity.closed() => {
+                    self.0.go_away(&connection).await;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,177 +1,4508 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    error::Error,
+    fmt::Debug,
+    future::Future,
+    net::SocketAddrV4,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use anyhow::{Result, anyhow};
-use dashmap::DashMap;
 use iroh::{
-    Endpoint,
-    endpoint::Connection,
+    Endpoint, Watcher,
+    endpoint::{
+        BindError, Connection, ConnectionError, ConnectionType, ReadToEndError, SendDatagramError,
+        WriteError,
+    },
     protocol::{AcceptError, ProtocolHandler, Router},
 };
-use tokio::sync::RwLock;
+use n0_future::{
+    BufferedStreamExt, FutureExt, FuturesUnordered, StreamExt, join_all, time::Instant,
+};
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::{Notify, RwLock},
+};
+
+mod ack;
+mod cache;
+mod compression;
+mod error;
+mod framing;
+mod lifecycle;
+mod map;
+mod metrics;
+mod ping;
+mod policy;
+mod progress;
+mod receiver;
+mod request;
+mod schedule;
+mod stream;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod topic;
+mod transfer;
+#[cfg(feature = "serde")]
+mod typed;
+mod unhandled;
+mod version;
+
+use ack::AckedProtocol;
+use cache::{CacheLimits, CachedConn, ConnectionCache, DialOptions};
+use framing::FramedProtocol;
+use lifecycle::{DisconnectGuard, PeerCallbacks};
+use map::ConnMap;
+use metrics::Metrics;
+use ping::PingProtocol;
+use policy::AcceptCounters;
+use request::RequestProtocol;
+use schedule::Scheduler;
+use stream::BoxedStreamingDataHandler;
+use tasks::{Activity, Pause, TaskRegistry};
+use topic::{TopicProtocol, Topics};
+use transfer::{BoxedTransferHandler, TransferProtocol};
+use unhandled::Backlog;
+use version::VersionedProtocol;
+
+pub use ack::Delivery;
+pub use compression::Compression;
+pub use error::{HandlerError, HandlerErrorPolicy, SetupStage, TunnelError};
+pub use metrics::{MetricsDelta, MetricsSnapshot, PeerStats};
+pub use policy::{
+    AcceptPolicy, AcceptStats, ConnectionOrigin, PeerFilter, RequireDirectForUnknown,
+};
+pub use receiver::TunnelReceiver;
+pub use request::RequestHandler;
+pub use stream::{StreamingDataHandler, TunnelRecvStream, TunnelSendStream};
+pub use topic::MAX_TOPIC_LENGTH;
+pub use transfer::{IncomingTransfer, TransferError, TransferHandler};
+#[cfg(feature = "serde")]
+pub use typed::{DeserializeError, Format, Json, Postcard, TypedHandler};
+pub use unhandled::NoHandlerPolicy;
 
 pub const ALPN: &[u8] = b"brasonite/tunnel/v1";
 
+/// The error code used when closing connections with [Tunnel::close],
+/// [Tunnel::close_all] and [Tunnel::close_all_graceful].
+pub const USER_CLOSE_CODE: u32 = 0;
+
+/// The error code used when closing connections refused by an [AcceptPolicy].
+/// Sends over such connections fail with [TunnelError::Refused].
+pub const REFUSED_CLOSE_CODE: u32 = 1;
+
+/// The error code used when closing connections which reached the maximum age
+/// set by [TunnelBuilder::max_connection_age].
+pub const ROTATED_CLOSE_CODE: u32 = 2;
+
+/// The error code used when stopping incoming streams which are larger than
+/// the maximum message size of a tunnel.
+pub const MESSAGE_TOO_LARGE_CODE: u32 = 3;
+
+/// The error code used when stopping incoming bidirectional streams which
+/// arrive at a tunnel without a [BiStreamHandler] or [RequestHandler].
+pub const NO_STREAM_HANDLER_CODE: u32 = 4;
+
+/// The error code used when closing connections evicted from the connection
+/// cache, see [TunnelBuilder::idle_timeout] and
+/// [TunnelBuilder::max_cached_connections].
+pub const IDLE_CLOSE_CODE: u32 = 5;
+
+/// The error code used when closing versioned connections whose sides use
+/// different protocol versions, see [TunnelBuilder::protocol_version].
+pub const VERSION_MISMATCH_CODE: u32 = 6;
+
+/// The error code used when stopping incoming streams whose messages cannot
+/// be decompressed, e.g. as the cargo feature of their compression algorithm
+/// is disabled.
+pub const INVALID_PAYLOAD_CODE: u32 = 7;
+
+/// The error code used when closing connections because the tunnel is shut
+/// down, see [Tunnel::shutdown].
+pub const GOING_AWAY_CLOSE_CODE: u32 = 8;
+
+/// The error code used when closing connections to tunnels which stopped
+/// answering keep-alive pings, see [TunnelBuilder::keep_alive].
+pub const UNRESPONSIVE_CLOSE_CODE: u32 = 9;
+
+/// The error code used when stopping streams or closing connections whose
+/// messages the handler failed to process, see [HandlerErrorPolicy].
+pub const HANDLER_ERROR_CODE: u32 = 10;
+
+/// The error code used when closing connections to a tunnel without any
+/// handler, or stopping the incoming streams whose messages no handler can
+/// process, see [NoHandlerPolicy::Refuse]. Sends over such connections or
+/// streams fail with [TunnelError::NoHandler].
+pub const NO_HANDLER_CLOSE_CODE: u32 = 11;
+
+/// The error code used when resetting outgoing streams whose message is
+/// abandoned before it is complete, e.g. as the reader given to
+/// [Tunnel::send_stream] failed. The receiver discards the partial message.
+pub const ABANDONED_STREAM_CODE: u32 = 12;
+
+/// The default maximum size of incoming messages, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The default maximum number of messages read and handled concurrently per
+/// connection. See [TunnelBuilder::max_concurrent_streams].
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: usize = 16;
+
+/// The default size from which messages are compressed, in bytes. See
+/// [TunnelBuilder::compression_threshold].
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The default size of the chunks written by [Tunnel::send_with_progress],
+/// [Tunnel::send_stream] and [Tunnel::send_large], in bytes. See
+/// [TunnelBuilder::chunk_size].
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The version of the format of [Tunnel::debug_dump]. Increased whenever a
+/// field is removed or changes meaning.
+const DEBUG_DUMP_VERSION: u32 = 2;
+
+/// The reason given when closing connections with [USER_CLOSE_CODE].
+const USER_CLOSE_REASON: &[u8] = b"user_request";
+
+/// How many times a dead cached connection is replaced by default before a
+/// send fails.
+const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 1;
+
+/// How long [Tunnel::request] waits for a response by default.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum amount of time [Tunnel::ping] waits for an answer.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default maximum amount of time [Tunnel::send_acked] waits for.
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long [Tunnel::destroy] waits for running handlers and sends.
+const DESTROY_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long [Tunnel::shutdown] waits for connections to close gracefully.
+const DESTROY_CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often a closing connection is checked for its close frame having been
+/// sent, see [Tunnel::close_all_graceful].
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long [Tunnel::shutdown] waits for background tasks to stop.
+const DESTROY_TASK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a rotated connection is kept open for the sends still using it.
+const ROTATION_GRACE: Duration = Duration::from_secs(5);
+
+/// How often a rotated connection is checked for sends still using it.
+const ROTATION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The maximum interval between two checks for connections to rotate.
+const ROTATION_MAX_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The minimum interval between two progress reports.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
+
+pub type Bytes = bytes::Bytes;
 pub type PublicKey = iroh::PublicKey;
+pub type EndpointAddr = iroh::EndpointAddr;
+pub type SecretKey = iroh::SecretKey;
+pub type RelayMode = iroh::RelayMode;
+pub type RelayUrl = iroh::RelayUrl;
+pub type SendStream = iroh::endpoint::SendStream;
+pub type RecvStream = iroh::endpoint::RecvStream;
+pub type ReadError = iroh::endpoint::ReadError;
 
 /// A trait implemented for objects which can handle incoming data from a tunnel.
 ///
+/// The data is given as [Bytes], which can be cloned and sliced without
+/// copying it.
+///
+/// A handler which fails to process a message returns a [HandlerError], which
+/// is dealt with according to the [HandlerErrorPolicy] of the tunnel.
+///
 /// For convenience's sake, this trait is implemented for function pointers. As
-/// such, any function which takes a [PublicKey] and a `Vec<u8>` in this order
-/// can be used as a [DataHandler].
+/// such, any function which takes a [PublicKey] and a `Vec<u8>` in this order,
+/// and returns either nothing or a `Result<(), E>` whose error converts into a
+/// [HandlerError], can be used as a [DataHandler]. The data is only copied
+/// into the `Vec<u8>` if the handler does not own all of it, which it does for
+/// most messages.
 pub trait DataHandler: 'static + Send + Sync {
-    fn process_incoming_data(&mut self, sender: PublicKey, data: Vec<u8>);
+    fn process_incoming_data(&mut self, sender: PublicKey, data: Bytes)
+    -> Result<(), HandlerError>;
+
+    /// Handles a datagram sent with [Tunnel::send_datagram]. By default,
+    /// datagrams are handled like any other data.
+    fn process_incoming_datagram(
+        &mut self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Result<(), HandlerError> {
+        self.process_incoming_data(sender, data)
+    }
 }
 
-impl<Func> DataHandler for Func
+impl<Func, R> DataHandler for Func
 where
-    Func: 'static + Send + Sync + FnMut(PublicKey, Vec<u8>) -> (),
+    Func: 'static + Send + Sync + FnMut(PublicKey, Vec<u8>) -> R,
+    R: IntoHandlerResult,
 {
-    fn process_incoming_data(&mut self, sender: PublicKey, data: Vec<u8>) {
-        self(sender, data)
+    fn process_incoming_data(
+        &mut self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Result<(), HandlerError> {
+        self(sender, data.into()).into_handler_result()
     }
 }
 
-pub struct TunnelProtocol {
-    pub handler: Option<Arc<RwLock<dyn DataHandler>>>,
+/// The values which functions used as a [DataHandler] or an
+/// [AsyncDataHandler] can return: either nothing, or a `Result<(), E>` whose
+/// error converts into a [HandlerError].
+pub trait IntoHandlerResult {
+    fn into_handler_result(self) -> Result<(), HandlerError>;
 }
 
-impl TunnelProtocol {
-    pub fn new() -> Self {
-        Self { handler: None }
+impl IntoHandlerResult for () {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        Ok(())
     }
+}
 
-    pub fn with_handler(mut self, handler: Arc<RwLock<dyn DataHandler>>) -> Self {
-        self.handler = Some(handler);
-        self
+impl<E: Into<HandlerError>> IntoHandlerResult for Result<(), E> {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        self.map_err(Into::into)
     }
 }
 
-impl ProtocolHandler for TunnelProtocol {
-    async fn accept(&self, connection: Connection) -> std::result::Result<(), AcceptError> {
-        let mut handler = match &self.handler {
-            Some(handler) => handler.write().await,
-            None => return Ok(()),
-        };
+/// Identifies a [DataHandler] added with [Tunnel::add_handler], so it can be
+/// removed with [Tunnel::remove_handler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
 
-        while let Ok(mut stream) = connection.accept_uni().await {
-            let data = stream.read_to_end(usize::MAX).await.unwrap();
-            handler.process_incoming_data(connection.remote_id(), data);
-        }
+/// A trait implemented for objects which can handle incoming data from a
+/// tunnel asynchronously.
+///
+/// The returned future is awaited by the accept loop of the connection the
+/// data came from. Up to [TunnelBuilder::max_concurrent_streams] messages
+/// from the same connection are handled concurrently, in no particular order
+/// unless [TunnelBuilder::ordered] is set, and a slow handler applies
+/// backpressure to that connection once the limit is reached.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [PublicKey] and a `Vec<u8>` in this order
+/// and returns a future can be used as an [AsyncDataHandler], as long as the
+/// future outputs a value which a [DataHandler] function could return. Like
+/// for [DataHandler], the data is given as [Bytes] otherwise.
+pub trait AsyncDataHandler: 'static + Send + Sync {
+    fn process_incoming_data(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send;
 
-        Ok(())
+    /// Handles a datagram sent with [Tunnel::send_datagram]. By default,
+    /// datagrams are handled like any other data.
+    fn process_incoming_datagram(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self.process_incoming_data(sender, data)
     }
 }
 
-impl Debug for TunnelProtocol {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Tunnel").finish()
+impl<Func, Fut> AsyncDataHandler for Func
+where
+    Func: 'static + Send + Sync + Fn(PublicKey, Vec<u8>) -> Fut,
+    Fut: Future + Send,
+    Fut::Output: IntoHandlerResult,
+{
+    fn process_incoming_data(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        let result = self(sender, data.into());
+        async move { result.await.into_handler_result() }
     }
 }
 
-/// A tunnel used to send and receive data.
-#[derive(Debug)]
-pub struct Tunnel {
-    pub sender: Endpoint,
-    pub receiver: Router,
+/// An object safe version of [AsyncDataHandler], so it can be stored in a
+/// [TunnelProtocol].
+trait BoxedAsyncDataHandler: 'static + Send + Sync {
+    fn process_incoming_data_boxed(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send + '_>>;
 
-    connections: DashMap<PublicKey, Connection>,
+    fn process_incoming_datagram_boxed(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send + '_>>;
 }
 
-impl Tunnel {
-    /// Creates a new tunnel using the provided [DataHandler] object.
-    pub async fn new<T: DataHandler>(handler: T) -> Result<Self> {
-        let sender = Endpoint::bind().await?;
-        let receiver_endpoint = Endpoint::bind().await?;
+impl<T: AsyncDataHandler> BoxedAsyncDataHandler for T {
+    fn process_incoming_data_boxed(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send + '_>> {
+        Box::pin(self.process_incoming_data(sender, data))
+    }
 
-        let protocol = Arc::new(TunnelProtocol::new().with_handler(Arc::new(RwLock::new(handler))));
+    fn process_incoming_datagram_boxed(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send + '_>> {
+        Box::pin(self.process_incoming_datagram(sender, data))
+    }
+}
 
-        let receiver = Router::builder(receiver_endpoint)
-            .accept(ALPN, Arc::clone(&protocol))
-            .spawn();
+/// The handler of the incoming data of a [TunnelProtocol].
+#[derive(Clone)]
+enum IncomingHandler {
+    Sync(Arc<RwLock<dyn DataHandler>>),
+    Async(Arc<dyn BoxedAsyncDataHandler>),
+    Streaming(Arc<dyn BoxedStreamingDataHandler>),
+}
 
-        sender.online().await;
-        receiver.endpoint().online().await;
+/// A trait implemented for objects which can handle incoming bidirectional
+/// streams from a tunnel.
+///
+/// This is useful for implementing custom subprotocols on top of a tunnel. The
+/// handler is called from the accept loop, so it should hand the streams off
+/// (e.g. by spawning a task) instead of doing long-running work.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [PublicKey], a [SendStream] and a
+/// [RecvStream] in this order can be used as a [BiStreamHandler].
+pub trait BiStreamHandler: 'static + Send + Sync {
+    fn process_incoming_stream(&self, sender: PublicKey, send: SendStream, recv: RecvStream);
+}
 
-        Ok(Self {
-            sender,
-            receiver,
+impl<Func> BiStreamHandler for Func
+where
+    Func: 'static + Send + Sync + Fn(PublicKey, SendStream, RecvStream) -> (),
+{
+    fn process_incoming_stream(&self, sender: PublicKey, send: SendStream, recv: RecvStream) {
+        self(sender, send, recv)
+    }
+}
 
-            connections: DashMap::new(),
-        })
+/// A function called with the address of a tunnel whose message was discarded
+/// for being larger than the maximum message size.
+type MessageTooLargeCallback = Arc<dyn Fn(PublicKey) + Send + Sync>;
+
+/// A function called with the address of a tunnel whose message could not be
+/// read, and the error which happened.
+type StreamErrorCallback = Arc<dyn Fn(PublicKey, ReadError) + Send + Sync>;
+
+/// A function called with the address of a tunnel whose message the handler
+/// failed to process, and the error of the handler.
+type HandlerErrorCallback = Arc<dyn Fn(PublicKey, HandlerError) + Send + Sync>;
+
+pub struct TunnelProtocol {
+    handler: Mutex<Option<IncomingHandler>>,
+    /// The handlers added with [TunnelProtocol::add_handler], in the order
+    /// they were added.
+    added_handlers: Mutex<Vec<(HandlerId, Arc<RwLock<dyn DataHandler>>)>>,
+    next_handler_id: AtomicU64,
+    pub bi_handler: Option<Arc<dyn BiStreamHandler>>,
+    request_handler: Option<Arc<dyn RequestHandler>>,
+    transfer_handler: Option<Arc<dyn BoxedTransferHandler>>,
+
+    accept_policy: Option<(Arc<dyn AcceptPolicy>, Endpoint)>,
+    accept_counters: AcceptCounters,
+    activity: Arc<Activity>,
+    /// The background tasks of the [Tunnel] this protocol belongs to, which
+    /// answer requests.
+    tasks: Arc<TaskRegistry>,
+    pause: Pause,
+    topics: Topics,
+    metrics: Arc<Metrics>,
+    max_message_size: usize,
+    max_concurrent_streams: usize,
+    on_message_too_large: Option<MessageTooLargeCallback>,
+    on_stream_error: Option<StreamErrorCallback>,
+    on_handler_error: Option<HandlerErrorCallback>,
+    handler_error_policy: HandlerErrorPolicy,
+    no_handler_policy: NoHandlerPolicy,
+    /// The messages kept while no handler is set, see
+    /// [NoHandlerPolicy::Buffer].
+    backlog: Backlog,
+    peer_callbacks: PeerCallbacks,
+}
+
+impl TunnelProtocol {
+    pub fn new() -> Self {
+        Self {
+            handler: Mutex::new(None),
+            added_handlers: Mutex::new(Vec::new()),
+            next_handler_id: AtomicU64::new(0),
+            bi_handler: None,
+            request_handler: None,
+            transfer_handler: None,
+
+            accept_policy: None,
+            accept_counters: AcceptCounters::default(),
+            activity: Arc::default(),
+            tasks: Arc::default(),
+            pause: Pause::default(),
+            topics: Topics::default(),
+            metrics: Arc::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            on_message_too_large: None,
+            on_stream_error: None,
+            on_handler_error: None,
+            handler_error_policy: HandlerErrorPolicy::default(),
+            no_handler_policy: NoHandlerPolicy::default(),
+            backlog: Backlog::default(),
+            peer_callbacks: PeerCallbacks::default(),
+        }
     }
 
-    /// Sends some data to another tunnel, given the provided address is valid.
+    pub fn with_handler(self, handler: Arc<RwLock<dyn DataHandler>>) -> Self {
+        self.set_handler(Some(handler));
+        self
+    }
+
+    pub fn with_async_handler<T: AsyncDataHandler>(self, handler: T) -> Self {
+        self.set_async_handler(handler);
+        self
+    }
+
+    /// Returns the currently active [DataHandler], if any.
     ///
-    /// **Note:** if a tunnel is not currently connected to the receiver, it
-    /// will first attempt to estabilish a connection.
+    /// Returns `None` if an [AsyncDataHandler] is active instead.
+    pub fn handler(&self) -> Option<Arc<RwLock<dyn DataHandler>>> {
+        match self.incoming_handler() {
+            Some(IncomingHandler::Sync(handler)) => Some(handler),
+            _ => None,
+        }
+    }
+
+    /// Returns whether any handler of incoming data is active, including the
+    /// handlers added with [TunnelProtocol::add_handler].
+    pub fn has_handler(&self) -> bool {
+        self.incoming_handler().is_some() || !self.added_handlers.lock().unwrap().is_empty()
+    }
+
+    /// Adds a [DataHandler] which is called for every message, after the
+    /// active handler. See [Tunnel::add_handler].
+    pub fn add_handler(&self, handler: Arc<RwLock<dyn DataHandler>>) -> HandlerId {
+        let id = HandlerId(self.next_handler_id.fetch_add(1, Ordering::Relaxed));
+        self.added_handlers.lock().unwrap().push((id, handler));
+        self.backlog.handler_set();
+        id
+    }
+
+    /// Removes a handler added with [TunnelProtocol::add_handler], returning
+    /// it. Returns `None` if it was already removed.
+    pub fn remove_handler(&self, id: HandlerId) -> Option<Arc<RwLock<dyn DataHandler>>> {
+        let mut handlers = self.added_handlers.lock().unwrap();
+        let index = handlers.iter().position(|(added, _)| *added == id)?;
+
+        Some(handlers.remove(index).1)
+    }
+
+    /// Replaces the active handler with a [DataHandler], returning the
+    /// previous one if it was also a [DataHandler].
     ///
-    /// # Arguments
+    /// Data which is already being dispatched is still delivered to the
+    /// previous handler. If `None` is provided, incoming data is handled
+    /// according to the [NoHandlerPolicy].
+    pub fn set_handler(
+        &self,
+        handler: Option<Arc<RwLock<dyn DataHandler>>>,
+    ) -> Option<Arc<RwLock<dyn DataHandler>>> {
+        let is_set = handler.is_some();
+        let previous = std::mem::replace(
+            &mut *self.handler.lock().unwrap(),
+            handler.map(IncomingHandler::Sync),
+        );
+
+        if is_set {
+            self.backlog.handler_set();
+        }
+
+        match previous {
+            Some(IncomingHandler::Sync(handler)) => Some(handler),
+            _ => None,
+        }
+    }
+
+    /// Replaces the active handler with an [AsyncDataHandler].
     ///
-    /// - `address`: The **receiver address** of the tunnel to send data to.
-    ///  Can be any value which can be converted to a [PublicKey].
-    /// - `data`: The data to be sent.
-    /// This data can be anything representable as a slice of bytes.
-    pub async fn send(&self, address: impl Into<PublicKey>, data: impl AsRef<[u8]>) -> Result<()> {
-        let address = address.into();
+    /// Data which is already being dispatched is still delivered to the
+    /// previous handler.
+    pub fn set_async_handler<T: AsyncDataHandler>(&self, handler: T) {
+        *self.handler.lock().unwrap() = Some(IncomingHandler::Async(Arc::new(handler)));
+        self.backlog.handler_set();
+    }
 
-        let receiver = if let Some(connection) = self.connections.get(&address) {
-            connection
-        } else {
-            let connection = self.sender.connect(address, ALPN).await?;
-            self.connections.insert(address, connection);
+    /// Replaces the active handler with a [StreamingDataHandler].
+    ///
+    /// Data which is already being dispatched is still delivered to the
+    /// previous handler.
+    pub fn set_streaming_handler<T: StreamingDataHandler>(&self, handler: T) {
+        *self.handler.lock().unwrap() = Some(IncomingHandler::Streaming(Arc::new(handler)));
+        self.backlog.handler_set();
+    }
 
-            self.connections.get(&address).unwrap()
-        };
+    /// Stops reading incoming messages, until [TunnelProtocol::resume_receiving]
+    /// is called. See [Tunnel::pause_receiving].
+    pub fn pause_receiving(&self) {
+        self.pause.set(true);
+    }
 
-        let mut stream = receiver.open_uni().await?;
-        stream.write_all(data.as_ref()).await?;
-        stream.finish()?;
+    /// Resumes reading incoming messages.
+    pub fn resume_receiving(&self) {
+        self.pause.set(false);
+    }
 
-        if let Some(error) = stream.stopped().await? {
-            return Err(anyhow!("Failed to send data. Error code: {}", error));
-        }
+    /// Returns whether reading incoming messages is paused.
+    pub fn is_receiving_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
 
+    /// Subscribes an [AsyncDataHandler] to a topic. See [Tunnel::subscribe].
+    pub fn subscribe<T: AsyncDataHandler>(
+        &self,
+        topic: impl Into<String>,
+        handler: T,
+    ) -> Result<(), TunnelError> {
+        let topic = topic.into();
+        topic::validate(&topic)?;
+
+        self.topics.subscribe(topic, Arc::new(handler));
         Ok(())
     }
 
-    /// Closes both the sender and the receiver endpoint and consumes this object.
-    ///
-    /// Ideally, this should be called before the execution of the program ends
-    /// or before a tunnel is discarded.
-    pub async fn destroy(self) {
-        self.sender.close().await;
-        self.receiver.shutdown().await.unwrap();
+    /// Removes the handler subscribed to a topic. Returns whether one was
+    /// subscribed.
+    pub fn unsubscribe(&self, topic: &str) -> bool {
+        self.topics.unsubscribe(topic)
     }
 
-    /// Closes a connection to another tunnel, if it exists.
-    pub fn close(&self, address: PublicKey) {
-        self.connections
-            .remove(&address)
-            .inspect(|(_, connection)| connection.close(0u32.into(), b"user_request"));
+    fn incoming_handler(&self) -> Option<IncomingHandler> {
+        self.handler.lock().unwrap().clone()
     }
 
-    /// Closes all connections between this tunnel and other tunnels.
-    pub fn close_all(&self) {
-        self.connections
-            .iter()
-            .for_each(|connection| connection.close(0u32.into(), b"user_request"));
+    pub fn with_bi_handler(mut self, handler: Arc<dyn BiStreamHandler>) -> Self {
+        self.bi_handler = Some(handler);
+        self
+    }
+
+    /// Sets the [RequestHandler] used to answer the requests arriving over
+    /// request connections. Without one, requests fail with
+    /// [TunnelError::NoRequestHandler].
+    pub fn with_request_handler<T: RequestHandler>(mut self, handler: T) -> Self {
+        self.request_handler = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn with_transfer_handler<T: TransferHandler>(mut self, handler: T) -> Self {
+        self.transfer_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets the [AcceptPolicy] used to decide whether incoming connections are
+    /// accepted. The origin of connections is observed through `endpoint`,
+    /// which should be the endpoint accepting them.
+    pub fn with_accept_policy(mut self, policy: Arc<dyn AcceptPolicy>, endpoint: Endpoint) -> Self {
+        self.accept_policy = Some((policy, endpoint));
+        self
+    }
+
+    /// Returns how many incoming connections were accepted and refused.
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.accept_counters.stats()
+    }
 
-        self.connections.clear();
+    /// Returns how many messages this protocol received, and how many were
+    /// sent by the [Tunnel] it belongs to.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
     }
 
-    /// Returns the address of the sender endpoint of this tunnel.
+    /// Sets the maximum size of incoming messages, in bytes. Defaults to
+    /// [DEFAULT_MAX_MESSAGE_SIZE].
     ///
-    /// The sender enpoint is responsible for sending data to other tunnels.
-    /// As such, when sending data, this address will be cited as the source.
-    pub fn sender_address(&self) -> PublicKey {
-        self.sender.id()
+    /// Streams which exceed it are stopped with [MESSAGE_TOO_LARGE_CODE], and
+    /// their data is discarded.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Returns the maximum size of incoming messages, in bytes.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Sets the function called when an incoming message is discarded for
+    /// exceeding the maximum message size.
+    pub fn with_message_too_large_callback(
+        mut self,
+        callback: impl Fn(PublicKey) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_message_too_large = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called when an incoming message cannot be read. See
+    /// [TunnelBuilder::on_stream_error].
+    pub fn with_stream_error_callback(
+        mut self,
+        callback: impl Fn(PublicKey, ReadError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_stream_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called when the handler fails to process a message.
+    /// See [TunnelBuilder::on_handler_error].
+    pub fn with_handler_error_callback(
+        mut self,
+        callback: impl Fn(PublicKey, HandlerError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_handler_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets what happens when the handler fails to process a message.
+    /// Defaults to [HandlerErrorPolicy::Continue].
+    pub fn with_handler_error_policy(mut self, policy: HandlerErrorPolicy) -> Self {
+        self.handler_error_policy = policy;
+        self
     }
 
-    /// Returns the address of the receiver endpoint of this tunnel.
+    /// Sets what happens to incoming messages while no handler is set.
+    /// Defaults to [NoHandlerPolicy::Refuse].
     ///
-    /// The receiver enpoint is responsible for receiving data from other tunnels.
-    /// As such, senders should send data to this address.
-    pub fn receiver_address(&self) -> PublicKey {
-        self.receiver.endpoint().id()
+    /// [NoHandlerPolicy::Buffer] only takes effect once the protocol is
+    /// served by a [Tunnel], which delivers the buffered messages.
+    pub fn with_no_handler_policy(mut self, policy: NoHandlerPolicy) -> Self {
+        self.no_handler_policy = policy;
+        self
+    }
+
+    /// Sets the function called with the address of the remote tunnel when its
+    /// first incoming connection is accepted. See
+    /// [TunnelBuilder::on_peer_connected].
+    pub fn with_connect_callback(
+        mut self,
+        callback: impl Fn(PublicKey) + Send + Sync + 'static,
+    ) -> Self {
+        self.peer_callbacks.connected = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called once the last accepted connection of a remote
+    /// tunnel is no longer handled. See [TunnelBuilder::on_peer_disconnected].
+    pub fn with_disconnect_callback(
+        mut self,
+        callback: impl Fn(PublicKey, ConnectionError) + Send + Sync + 'static,
+    ) -> Self {
+        self.peer_callbacks.disconnected = Some(Arc::new(callback));
+        self
     }
 }
+
+impl TunnelProtocol {
+    /// Applies the [AcceptPolicy] to a new connection. If the connection is
+    /// accepted, returns a guard to keep while it is handled.
+    fn admit(&self, connection: &Connection) -> Option<DisconnectGuard> {
+        if !self.allows(connection) {
+            return None;
+        }
+
+        Some(self.peer_callbacks.incoming(connection))
+    }
+
+    /// Applies the [AcceptPolicy] to a new connection, without notifying the
+    /// peer callbacks. Refused connections are closed.
+    fn allows(&self, connection: &Connection) -> bool {
+        let allowed = match &self.accept_policy {
+            Some((policy, endpoint)) => {
+                policy.allow(&ConnectionOrigin::observe(endpoint, connection.remote_id()))
+            }
+            None => true,
+        };
+
+        self.accept_counters.record(allowed);
+
+        if !allowed {
+            connection.close(REFUSED_CLOSE_CODE.into(), b"refused");
+        }
+
+        allowed
+    }
+
+    /// Closes a new connection if nothing could handle its messages,
+    /// following [NoHandlerPolicy::Refuse]. `handled` is whether anything
+    /// handles the messages of its ALPN. Returns whether it was closed.
+    fn refuses_unhandled(&self, connection: &Connection, handled: bool) -> bool {
+        let unhandled = self.no_handler_policy == NoHandlerPolicy::Refuse && !handled;
+
+        if unhandled {
+            connection.close(NO_HANDLER_CLOSE_CODE.into(), b"no handler");
+        }
+
+        unhandled
+    }
+
+    /// Closes an incoming connection once the tunnel is shutting down, after
+    /// every stream which was already accepted has been handled.
+    async fn go_away(&self, connection: &Connection) {
+        self.activity.idle().await;
+        connection.close(GOING_AWAY_CLOSE_CODE.into(), b"going away");
+    }
+
+    /// Handles a unidirectional stream which carries a single message.
+    async fn handle_message(&self, connection: &Connection, mut stream: RecvStream) {
+        let sender = connection.remote_id();
+
+        // Streaming handlers read the stream themselves.
+        if let Some(IncomingHandler::Streaming(handler)) = self.incoming_handler() {
+            self.metrics.record_message_received(sender);
+
+            let stream = TunnelRecvStream::new(stream, sender, Arc::clone(&self.metrics));
+            handler.process_incoming_stream_boxed(sender, stream).await;
+
+            return;
+        }
+
+        // Stopped before being read, so the sender is told the message was
+        // not handled instead of it being discarded silently. This also
+        // covers connections accepted while a handler was set.
+        if !self.accepts_messages() {
+            let _ = stream.stop(NO_HANDLER_CLOSE_CODE.into());
+            return;
+        }
+
+        let data = match stream.read_to_end(self.max_message_size).await {
+            Ok(data) => data,
+            Err(ReadToEndError::TooLong) => {
+                let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+                self.message_too_large(sender);
+                return;
+            }
+            // The stream was reset or the connection was lost.
+            Err(ReadToEndError::Read(e)) => {
+                self.stream_error(sender, e);
+                return;
+            }
+        };
+
+        self.dispatch_stream(connection, &mut stream, data.into())
+            .await;
+    }
+
+    /// Gives a message read from `stream` to the active handler, like
+    /// [TunnelProtocol::dispatch].
+    ///
+    /// If no handler processed it, e.g. as the handler was removed while the
+    /// message was read, the stream is stopped with [NO_HANDLER_CLOSE_CODE].
+    /// If the handler failed to, the [HandlerErrorPolicy] is applied.
+    ///
+    /// Returns whether the stream can still be read.
+    async fn dispatch_stream(
+        &self,
+        connection: &Connection,
+        stream: &mut RecvStream,
+        data: Bytes,
+    ) -> bool {
+        match self.dispatch(connection.remote_id(), data).await {
+            Ok(true) => true,
+            Ok(false) => {
+                let _ = stream.stop(NO_HANDLER_CLOSE_CODE.into());
+                false
+            }
+            Err(e) => self.handler_failed(connection, Some(stream), e),
+        }
+    }
+
+    /// Returns whether incoming messages can currently be handled, or kept
+    /// until a handler is set.
+    fn accepts_messages(&self) -> bool {
+        self.has_handler() || matches!(self.no_handler_policy, NoHandlerPolicy::Buffer { .. })
+    }
+
+    /// Gives a complete message to the active handler. Returns whether a
+    /// handler processed it, or the error of the handler which failed to.
+    ///
+    /// [StreamingDataHandler]s only handle whole streams, so the message is
+    /// discarded if one is active. If no handler is set, the message is kept
+    /// according to [NoHandlerPolicy::Buffer], in which case `true` is
+    /// returned.
+    async fn dispatch(&self, sender: PublicKey, data: Bytes) -> Result<bool, HandlerError> {
+        let NoHandlerPolicy::Buffer { max_messages } = self.no_handler_policy else {
+            return self.dispatch_unbuffered(sender, data).await;
+        };
+
+        if self.has_handler() {
+            return self.dispatch_unbuffered(sender, data).await;
+        }
+
+        self.metrics.record_message_received(sender);
+        self.metrics.record_bytes_received(sender, data.len());
+
+        let kept = self.backlog.push(sender, data, max_messages);
+
+        // The backlog may have been delivered between the check and the push,
+        // in which case the message would wait for the next handler.
+        if kept && self.has_handler() {
+            self.backlog.handler_set();
+        }
+
+        Ok(kept)
+    }
+
+    /// Gives a complete message to the active handler like
+    /// [TunnelProtocol::dispatch], but never keeps it for later, so whether
+    /// it was processed is known right away.
+    async fn dispatch_unbuffered(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Result<bool, HandlerError> {
+        self.metrics.record_message_received(sender);
+        self.metrics.record_bytes_received(sender, data.len());
+
+        self.deliver(sender, data, false).await
+    }
+
+    /// Gives a datagram received over `connection` to the active handler,
+    /// like [TunnelProtocol::dispatch].
+    async fn dispatch_datagram(&self, connection: &Connection, data: Bytes) {
+        let sender = connection.remote_id();
+
+        // Datagrams are bounded by the path MTU, but a smaller maximum message
+        // size still applies to them.
+        if data.len() > self.max_message_size {
+            self.message_too_large(sender);
+            return;
+        }
+
+        self.metrics.record_message_received(sender);
+        self.metrics.record_bytes_received(sender, data.len());
+
+        if let Err(e) = self.deliver(sender, data, true).await {
+            self.handler_failed(connection, None, e);
+        }
+    }
+
+    /// Gives a message to the active handler, then to the handlers added with
+    /// [TunnelProtocol::add_handler]. Returns whether any handler processed
+    /// it.
+    ///
+    /// Every handler is given the message, even if one before it failed. The
+    /// first error is returned.
+    async fn deliver(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+        datagram: bool,
+    ) -> Result<bool, HandlerError> {
+        let added = self.added_handlers.lock().unwrap().clone();
+
+        // The data is only shared if other handlers need it too, so the
+        // active handler can usually take it without copying it.
+        let (first, rest) = if added.is_empty() {
+            (data, None)
+        } else {
+            (data.clone(), Some(data))
+        };
+
+        let mut result = match self.incoming_handler() {
+            Some(IncomingHandler::Sync(handler)) => call_handler(&handler, sender, first, datagram)
+                .await
+                .map(|()| true),
+            Some(IncomingHandler::Async(handler)) => {
+                call_async_handler(&*handler, sender, first, datagram)
+                    .await
+                    .map(|()| true)
+            }
+            Some(IncomingHandler::Streaming(_)) | None => Ok(false),
+        };
+
+        let Some(data) = rest else {
+            return result;
+        };
+
+        for (_, handler) in added {
+            let added_result = call_handler(&handler, sender, data.clone(), datagram).await;
+
+            if result.is_ok() {
+                result = added_result.map(|()| true);
+            }
+        }
+
+        result
+    }
+
+    fn handle_bi(&self, sender: PublicKey, mut send: SendStream, mut recv: RecvStream) {
+        match &self.bi_handler {
+            Some(bi_handler) => bi_handler.process_incoming_stream(sender, send, recv),
+            // Refused right away, so the other end does not wait for a
+            // response which never comes.
+            None => {
+                let _ = send.reset(NO_STREAM_HANDLER_CODE.into());
+                let _ = recv.stop(NO_STREAM_HANDLER_CODE.into());
+            }
+        }
+    }
+
+    fn message_too_large(&self, sender: PublicKey) {
+        if let Some(callback) = &self.on_message_too_large {
+            callback(sender);
+        }
+    }
+
+    /// Reports that an incoming message could not be read. Only its stream is
+    /// affected, so the following messages are still handled.
+    fn stream_error(&self, sender: PublicKey, error: ReadError) {
+        if let Some(callback) = &self.on_stream_error {
+            callback(sender, error);
+        }
+    }
+
+    /// Reports that the handler failed to process a message, without applying
+    /// the [HandlerErrorPolicy].
+    fn handler_error(&self, sender: PublicKey, error: HandlerError) {
+        if let Some(callback) = &self.on_handler_error {
+            callback(sender, error);
+        }
+    }
+
+    /// Reports that the handler failed to process a message received over
+    /// `connection`, then applies the [HandlerErrorPolicy]. `stream` is the
+    /// stream the message was read from, if any.
+    ///
+    /// Returns whether the stream can still be read.
+    fn handler_failed(
+        &self,
+        connection: &Connection,
+        stream: Option<&mut RecvStream>,
+        error: HandlerError,
+    ) -> bool {
+        self.handler_error(connection.remote_id(), error);
+
+        match (self.handler_error_policy, stream) {
+            (HandlerErrorPolicy::Continue, _) | (HandlerErrorPolicy::StopStream, None) => true,
+            (HandlerErrorPolicy::StopStream, Some(stream)) => {
+                let _ = stream.stop(HANDLER_ERROR_CODE.into());
+                false
+            }
+            (HandlerErrorPolicy::CloseConnection, _) => {
+                connection.close(HANDLER_ERROR_CODE.into(), b"handler error");
+                false
+            }
+        }
+    }
+}
+
+impl ProtocolHandler for TunnelProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        if self.refuses_unhandled(&connection, self.has_handler() || self.bi_handler.is_some()) {
+            return Ok(());
+        }
+
+        let Some(_disconnect) = self.admit(&connection) else {
+            return Ok(());
+        };
+
+        // The messages being read and handled. No stream is accepted while
+        // the limit is reached, which applies backpressure to the connection.
+        let mut handling = FuturesUnordered::new();
+        let limit = self.max_concurrent_streams;
+
+        let closing = loop {
+            let paused = self.pause.is_paused();
+
+            tokio::select! {
+                stream = connection.accept_uni(), if !paused && handling.len() < limit => {
+                    let Ok(stream) = stream else {
+                        break false;
+                    };
+
+                    // Discarded if the tunnel started shutting down meanwhile.
+                    let Some(active) = self.activity.enter() else {
+                        continue;
+                    };
+
+                    let connection = &connection;
+
+                    handling.push(async move {
+                        self.handle_message(connection, stream).await;
+                        drop(active);
+                    });
+                }
+                datagram = connection.read_datagram(), if !paused && handling.len() < limit => {
+                    let Ok(datagram) = datagram else {
+                        break false;
+                    };
+
+                    let Some(active) = self.activity.enter() else {
+                        continue;
+                    };
+
+                    let connection = &connection;
+
+                    handling.push(async move {
+                        self.dispatch_datagram(connection, datagram).await;
+                        drop(active);
+                    });
+                }
+                _ = self.pause.resumed(), if paused => {}
+                Some(()) = handling.next(), if !handling.is_empty() => {}
+                streams = connection.accept_bi() => {
+                    let Ok((send, recv)) = streams else {
+                        break false;
+                    };
+
+                    self.handle_bi(connection.remote_id(), send, recv);
+                }
+                _ = self.activity.closed() => break true,
+            }
+        };
+
+        // Messages which were already accepted are still handled, e.g. when
+        // the other tunnel closed the connection right after sending them.
+        while handling.next().await.is_some() {}
+
+        if closing {
+            self.go_away(&connection).await;
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for TunnelProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tunnel").finish()
+    }
+}
+
+/// The directions in which a tunnel can transfer data.
+///
+/// Using a direction which is not supported by the mode of a tunnel (e.g.
+/// calling [Tunnel::send] on a receive-only tunnel) fails immediately with
+/// [TunnelError::WrongMode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// The tunnel can both send and receive data.
+    #[default]
+    SendReceive,
+    /// The tunnel can only send data. No receiver endpoint is bound.
+    SendOnly,
+    /// The tunnel can only receive data. No sender endpoint is bound.
+    ReceiveOnly,
+}
+
+impl Mode {
+    /// Returns whether tunnels in this mode can send data.
+    pub fn can_send(&self) -> bool {
+        *self != Self::ReceiveOnly
+    }
+
+    /// Returns whether tunnels in this mode can receive data.
+    pub fn can_receive(&self) -> bool {
+        *self != Self::SendOnly
+    }
+}
+
+/// A tunnel used to send and receive data.
+///
+/// Tunnels are cheap to clone, and every clone uses the same endpoints and
+/// connections, so a tunnel can be shared by many tasks without being
+/// wrapped in an [Arc]. The tunnel is closed once it is destroyed through any
+/// of its clones (see [Tunnel::shutdown]), or once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Tunnel {
+    inner: Arc<TunnelInner>,
+}
+
+/// The state shared by the clones of a [Tunnel].
+#[derive(Debug)]
+struct TunnelInner {
+    /// The sender endpoint. Absent if the tunnel is in [Mode::ReceiveOnly].
+    sender: Option<Endpoint>,
+    /// The receiver router. Absent if the tunnel is in [Mode::SendOnly].
+    receiver: Option<Router>,
+
+    mode: Mode,
+    protocol: Arc<TunnelProtocol>,
+    connections: Arc<ConnectionCache>,
+    /// The connections used by [Tunnel::send_acked], which are separate from
+    /// the regular ones.
+    acked_connections: Arc<ConnectionCache>,
+    /// The connections used by [Tunnel::ping] and keep-alive pings.
+    ping_connections: Arc<ConnectionCache>,
+    /// The connections used by [Tunnel::send_large].
+    transfer_connections: Arc<ConnectionCache>,
+    /// The connections used by [Tunnel::send_on].
+    topic_connections: Arc<ConnectionCache>,
+    /// The connections used by [Tunnel::request].
+    request_connections: Arc<ConnectionCache>,
+    pending: Arc<PendingSends>,
+    tasks: Arc<TaskRegistry>,
+    request_timeout: Duration,
+    send_timeout: Option<Duration>,
+    ack_timeout: Duration,
+    /// The size of the chunks written by the sends which report progress.
+    chunk_size: usize,
+    /// Shares the bandwidth of the chunked sends across peers.
+    scheduler: Scheduler,
+    max_reconnect_attempts: u32,
+    relays: bool,
+    /// The configuration of the tunnel, with secrets masked, reported by
+    /// [Tunnel::debug_dump].
+    config: serde_json::Value,
+    /// Set by the first call to [Tunnel::shutdown], so the tunnel is only
+    /// shut down once.
+    shut_down: AtomicBool,
+}
+
+/// A summary of the shutdown of a tunnel, returned by [Tunnel::shutdown] and
+/// [Tunnel::destroy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// How many connections were closed cleanly.
+    pub closed_connections: usize,
+    /// How many background tasks did not stop in time. Such a task is stopped
+    /// at its next await point.
+    pub unstopped_tasks: usize,
+    /// Whether every running handler and send completed before the timeout
+    /// of [Tunnel::shutdown] expired.
+    pub drained: bool,
+}
+
+/// The state of the cached connection to another tunnel, returned by
+/// [Tunnel::connection_status].
+#[derive(Debug, Clone)]
+pub struct ConnectionStatus {
+    /// Whether the connection is still open. A connection closed by the other
+    /// tunnel stays cached until the next send evicts it.
+    pub alive: bool,
+    /// How long ago the connection was established.
+    pub age: Duration,
+    /// How the other tunnel is reached, as observed by the sender endpoint.
+    /// `None` if the sender endpoint has no information about it.
+    pub conn_type: Option<ConnectionType>,
+}
+
+impl ConnectionStatus {
+    /// Returns whether the other tunnel is reached over a direct path only,
+    /// without going through a relay.
+    pub fn is_direct(&self) -> bool {
+        matches!(self.conn_type, Some(ConnectionType::Direct(_)))
+    }
+}
+
+impl Tunnel {
+    /// Creates a new tunnel using the provided [DataHandler] object.
+    ///
+    /// This is a shorthand for `Tunnel::builder().handler(handler).spawn()`.
+    pub async fn new<T: DataHandler>(handler: T) -> Result<Self, TunnelError> {
+        Self::builder().handler(handler).spawn().await
+    }
+
+    /// Creates a new tunnel using the provided [AsyncDataHandler] object.
+    ///
+    /// This is a shorthand for `Tunnel::builder().async_handler(handler).spawn()`.
+    pub async fn new_async<T: AsyncDataHandler>(handler: T) -> Result<Self, TunnelError> {
+        Self::builder().async_handler(handler).spawn().await
+    }
+
+    /// Creates a new tunnel using the provided [DataHandler] object, which
+    /// sends and receives data through a single endpoint, so its sender and
+    /// receiver addresses are the same.
+    ///
+    /// This is a shorthand for
+    /// `Tunnel::builder().handler(handler).single_endpoint(true).spawn()`.
+    /// See [TunnelBuilder::single_endpoint].
+    pub async fn single_endpoint<T: DataHandler>(handler: T) -> Result<Self, TunnelError> {
+        Self::builder()
+            .handler(handler)
+            .single_endpoint(true)
+            .spawn()
+            .await
+    }
+
+    /// Creates a new tunnel whose incoming data is returned by a
+    /// [TunnelReceiver], instead of being passed to a handler.
+    ///
+    /// This is a shorthand for `Tunnel::builder().receiver(capacity)`, followed
+    /// by spawning the builder.
+    ///
+    /// # Arguments
+    ///
+    /// - `capacity`: How many messages the receiver buffers before the tunnel
+    /// stops reading incoming data.
+    pub async fn new_with_receiver(capacity: usize) -> Result<(Self, TunnelReceiver), TunnelError> {
+        let (builder, receiver) = Self::builder().receiver(capacity);
+        Ok((builder.spawn().await?, receiver))
+    }
+
+    /// Returns a [TunnelBuilder], which can be used to configure a new tunnel.
+    pub fn builder() -> TunnelBuilder {
+        TunnelBuilder::default()
+    }
+
+    /// Sends some data to another tunnel, given the provided address is valid.
+    ///
+    /// **Note:** if a tunnel is not currently connected to the receiver, it
+    /// will first attempt to estabilish a connection.
+    ///
+    /// If the receiver refuses the data for exceeding its maximum message
+    /// size, this fails with [TunnelError::MessageTooLarge].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes], which is
+    /// written as it is, without being copied.
+    pub async fn send(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Result<(), TunnelError> {
+        self.send_timeout_opt(address.into(), data.into(), self.inner.send_timeout)
+            .await
+    }
+
+    /// Sends some data to another tunnel, like [Tunnel::send], dialing it at
+    /// the given socket addresses and relay if no connection is cached.
+    ///
+    /// This allows reaching tunnels without any discovery service, e.g. on a
+    /// local network, given their full address was shared out of band. See
+    /// [Tunnel::receiver_addr].
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The full **receiver address** of the tunnel to send data to.
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    pub async fn send_to(
+        &self,
+        addr: EndpointAddr,
+        data: impl Into<Bytes>,
+    ) -> Result<(), TunnelError> {
+        let address = addr.id;
+
+        self.connect_to(addr)
+            .await
+            .inspect_err(|e| self.inner.protocol.metrics.record_send_error(address, e))?;
+
+        self.send(address, data).await
+    }
+
+    /// Sends some data to another tunnel on a topic, so it is given to the
+    /// handler the other tunnel subscribed to that topic with
+    /// [Tunnel::subscribe]. If it has none, the data is given to its regular
+    /// handler, like with [Tunnel::send].
+    ///
+    /// Messages on topics use their own connection to the other tunnel, and
+    /// are never compressed. Fails with [TunnelError::InvalidTopic] if the
+    /// topic is empty or longer than [MAX_TOPIC_LENGTH] bytes.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `topic`: The topic to send the data on.
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes], which is
+    /// written after the topic as it is, without being copied.
+    pub async fn send_on(
+        &self,
+        address: impl Into<PublicKey>,
+        topic: &str,
+        data: impl Into<Bytes>,
+    ) -> Result<(), TunnelError> {
+        let address = address.into();
+        let data = data.into();
+        let size = data.len();
+        let sender = self.sender()?;
+
+        topic::validate(topic)?;
+
+        let result = send_data_timeout(
+            sender,
+            &self.inner.topic_connections,
+            self.inner.max_reconnect_attempts,
+            address,
+            &[topic::header(topic), data],
+            self.inner.send_timeout,
+        )
+        .await;
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Sends some data to another tunnel, like [Tunnel::send], giving up if
+    /// the send does not complete in time.
+    ///
+    /// On timeout, [TunnelError::SendTimeout] is returned, and the connection
+    /// to the other tunnel is evicted from the cache, so the next send dials
+    /// a new one.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    /// - `timeout`: The maximum amount of time the send may take, including
+    /// establishing a connection and waiting for the acknowledgement.
+    pub async fn send_timeout(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+        timeout: Duration,
+    ) -> Result<(), TunnelError> {
+        self.send_timeout_opt(address.into(), data.into(), Some(timeout))
+            .await
+    }
+
+    /// Sends a message with an optional timeout, and records it in the
+    /// metrics of the tunnel.
+    async fn send_timeout_opt(
+        &self,
+        address: PublicKey,
+        data: Bytes,
+        timeout: Option<Duration>,
+    ) -> Result<(), TunnelError> {
+        let (data, size) = self.encode_shared(data);
+
+        self.send_encoded(address, data, size, timeout).await
+    }
+
+    /// Sends a message which was already encoded with [Tunnel::encode], and
+    /// records it in the metrics of the tunnel. `size` is the size of the
+    /// message before it was encoded.
+    async fn send_encoded(
+        &self,
+        address: PublicKey,
+        data: Bytes,
+        size: usize,
+        timeout: Option<Duration>,
+    ) -> Result<(), TunnelError> {
+        let sender = self.sender()?;
+
+        let result = send_data_timeout(
+            sender,
+            &self.inner.connections,
+            self.inner.max_reconnect_attempts,
+            address,
+            &[data],
+            timeout,
+        )
+        .await;
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Encodes a message for the compressed connections of this tunnel.
+    /// Returns `None` if the tunnel does not compress its messages.
+    fn encode(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let dial = self.inner.connections.dial_options();
+
+        dial.compression.map(|compression| {
+            compression::encode(
+                compression,
+                dial.compression_threshold,
+                data,
+                &self.inner.protocol.metrics,
+            )
+        })
+    }
+
+    /// Encodes a message like [Tunnel::encode], keeping it as it is if the
+    /// tunnel does not compress its messages. Returns the message to send,
+    /// along with its size before it was encoded.
+    fn encode_shared(&self, data: Bytes) -> (Bytes, usize) {
+        let size = data.len();
+        (self.encode(&data).map_or(data, Bytes::from), size)
+    }
+
+    /// Establishes a connection to another tunnel ahead of time, so the next
+    /// send to it does not wait for the connection to be established.
+    ///
+    /// Does nothing if this tunnel already has a live connection to the other
+    /// tunnel. The connection is cached like those established by sends, so
+    /// it is subject to the same limits and timeouts.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to connect to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    pub async fn connect(&self, address: impl Into<PublicKey>) -> Result<(), TunnelError> {
+        self.connect_to(EndpointAddr::from(address.into())).await
+    }
+
+    /// Establishes a connection to another tunnel ahead of time, like
+    /// [Tunnel::connect], dialing it at the given socket addresses and relay.
+    ///
+    /// The endpoint remembers these addresses, so later reconnections to the
+    /// same tunnel do not need a discovery service either.
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The full **receiver address** of the tunnel to connect to.
+    pub async fn connect_to(&self, addr: EndpointAddr) -> Result<(), TunnelError> {
+        let address = addr.id;
+        let sender = self.sender()?;
+
+        // A connection which was closed in the meantime is replaced.
+        if let Some(cached) = self.inner.connections.get(&address)
+            && cached.conn.close_reason().is_some()
+        {
+            self.inner
+                .connections
+                .remove_if_same(&address, cached.generation);
+        }
+
+        connection(sender, &self.inner.connections, addr).await?;
+        Ok(())
+    }
+
+    /// Establishes connections to many tunnels concurrently, like
+    /// [Tunnel::connect]. Returns the result of each connection, in the same
+    /// order as `addresses`.
+    ///
+    /// # Arguments
+    ///
+    /// - `addresses`: The **receiver addresses** of the tunnels to connect to.
+    pub async fn preconnect(
+        &self,
+        addresses: impl IntoIterator<Item = PublicKey>,
+    ) -> Vec<(PublicKey, Result<(), TunnelError>)> {
+        let connects = addresses
+            .into_iter()
+            .map(|address| async move { (address, self.connect(address).await) });
+
+        join_all(connects).await
+    }
+
+    /// Checks that another tunnel is reachable and responsive, and returns the
+    /// round-trip time of the check.
+    ///
+    /// Pings use their own connection to the other tunnel, so they neither
+    /// wait for nor delay the messages sent to it. If no answer arrives within
+    /// 10 seconds, this fails with [TunnelError::PingTimeout].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to ping.
+    ///  Can be any value which can be converted to a [PublicKey].
+    pub async fn ping(&self, address: impl Into<PublicKey>) -> Result<Duration, TunnelError> {
+        let address = address.into();
+
+        let ping = ping::ping(
+            self.sender()?,
+            &self.inner.ping_connections,
+            self.inner.max_reconnect_attempts,
+            address,
+        );
+
+        n0_future::time::timeout(PING_TIMEOUT, ping)
+            .await
+            .unwrap_or_else(|_| {
+                Err(TunnelError::PingTimeout {
+                    peer: address,
+                    timeout: PING_TIMEOUT,
+                })
+            })
+    }
+
+    /// Sends some data to another tunnel, and waits for it to be processed by
+    /// the handler of the other tunnel.
+    ///
+    /// Unlike [Tunnel::send], which completes once the data was received,
+    /// this reports whether the handler ran to completion, see [Delivery].
+    /// If no acknowledgement arrives within the timeout set with
+    /// [TunnelBuilder::ack_timeout], this fails with
+    /// [TunnelError::AckTimeout].
+    ///
+    /// Acknowledged messages use their own connection to the other tunnel,
+    /// over which they are handled one at a time, and are never compressed.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes], which is
+    /// written as it is, without being copied.
+    pub async fn send_acked(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Result<Delivery, TunnelError> {
+        let address = address.into();
+        let data = data.into();
+        let size = data.len();
+        let sender = self.sender()?;
+        let timeout = self.inner.ack_timeout;
+
+        let send = async {
+            let (cached, (send, recv)) = open_stream(
+                sender,
+                &self.inner.acked_connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                |conn| async move { conn.open_bi().await },
+            )
+            .await?;
+
+            let _in_flight = cached.track();
+            ack::exchange(address, send, recv, data).await
+        };
+
+        let result = n0_future::time::timeout(timeout, send)
+            .await
+            .unwrap_or_else(|_| {
+                Err(TunnelError::AckTimeout {
+                    peer: address,
+                    timeout,
+                })
+            });
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Sends some data to another tunnel as an unreliable datagram, without
+    /// opening a stream.
+    ///
+    /// Datagrams have less overhead than [Tunnel::send], which makes them
+    /// suited to real-time data, but they may be lost, duplicated or received
+    /// out of order, and completing the send does not mean that the datagram
+    /// was received. They are handled by
+    /// [DataHandler::process_incoming_datagram] and are never compressed.
+    ///
+    /// Datagrams larger than [Tunnel::max_datagram_size] fail with
+    /// [TunnelError::Datagram].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    pub async fn send_datagram(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Result<(), TunnelError> {
+        let address = address.into();
+        let data = data.into();
+        let size = data.len();
+        let sender = self.sender()?;
+
+        let result = async {
+            // No stream is opened, so this only replaces a dead connection.
+            let (cached, ()) = open_stream(
+                sender,
+                &self.inner.connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                |_| async { Ok(()) },
+            )
+            .await?;
+
+            cached
+                .conn
+                .send_datagram(data)
+                .map_err(|source| match source {
+                    SendDatagramError::ConnectionLost(e) => {
+                        self.inner
+                            .connections
+                            .remove_if_same(&address, cached.generation);
+                        connection_error(address, e)
+                    }
+                    source => TunnelError::Datagram {
+                        peer: address,
+                        source,
+                    },
+                })
+        }
+        .await;
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Returns the size of the largest datagram which can currently be sent
+    /// to another tunnel with [Tunnel::send_datagram], in bytes.
+    ///
+    /// The size depends on the path to the other tunnel, so it is only known
+    /// once a connection is established, e.g. with [Tunnel::connect], and may
+    /// change over time. Returns `None` if no connection is cached, or if the
+    /// other tunnel does not accept datagrams.
+    pub fn max_datagram_size(&self, address: &PublicKey) -> Option<usize> {
+        self.inner
+            .connections
+            .get(address)
+            .and_then(|cached| cached.conn.max_datagram_size())
+    }
+
+    /// Sends the same data to many tunnels concurrently.
+    ///
+    /// Connections are reused and cached like with [Tunnel::send]. Returns the
+    /// result of each send, in the same order as `addresses`.
+    ///
+    /// The data is compressed at most once, and every send shares the same
+    /// buffer.
+    ///
+    /// # Arguments
+    ///
+    /// - `addresses`: The **receiver addresses** of the tunnels to send data to.
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    pub async fn broadcast(
+        &self,
+        addresses: impl IntoIterator<Item = PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Vec<(PublicKey, Result<(), TunnelError>)> {
+        let (data, size) = self.encode_shared(data.into());
+        let timeout = self.inner.send_timeout;
+
+        let sends = addresses.into_iter().map(|address| {
+            let data = data.clone();
+            async move {
+                (
+                    address,
+                    self.send_encoded(address, data, size, timeout).await,
+                )
+            }
+        });
+
+        join_all(sends).await
+    }
+
+    /// Sends the same data to many tunnels, with at most `max_concurrency`
+    /// sends in progress at once.
+    ///
+    /// Like [Tunnel::broadcast], a failed send does not stop the others, and
+    /// the result of each send is returned in the same order as `addresses`.
+    ///
+    /// # Arguments
+    ///
+    /// - `addresses`: The **receiver addresses** of the tunnels to send data to.
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    /// - `max_concurrency`: The maximum amount of concurrent sends. Values
+    /// below 1 are treated as 1.
+    pub async fn broadcast_with_concurrency(
+        &self,
+        addresses: impl IntoIterator<Item = PublicKey>,
+        data: impl Into<Bytes>,
+        max_concurrency: usize,
+    ) -> Vec<(PublicKey, Result<(), TunnelError>)> {
+        let (data, size) = self.encode_shared(data.into());
+        let timeout = self.inner.send_timeout;
+
+        n0_future::stream::iter(addresses)
+            .map(|address| {
+                let data = data.clone();
+                async move {
+                    (
+                        address,
+                        self.send_encoded(address, data, size, timeout).await,
+                    )
+                }
+            })
+            .buffered_ordered(max_concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Sends some data to another tunnel, reporting the progress of the write.
+    ///
+    /// The data is written in chunks of [TunnelBuilder::chunk_size] bytes, and
+    /// `progress` is called with the amount of bytes written so far and the
+    /// total amount of bytes, at most once every 50 milliseconds. Once all of
+    /// the data is written, `progress` is called exactly once with both values
+    /// being equal, before the returned future resolves.
+    ///
+    /// Like [Tunnel::send], the returned future then waits for the receiver
+    /// to acknowledge the message, so written data is not mistaken for
+    /// delivered data.
+    ///
+    /// `progress` is called from its own task, so a slow callback never
+    /// delays the write: the progress made in the meantime is reported by
+    /// its next call instead.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes], whose
+    /// chunks are written without being copied.
+    /// - `progress`: The function which receives the progress of the write.
+    pub async fn send_with_progress(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+        progress: impl Fn(u64, u64) + Send + Sync + 'static,
+    ) -> Result<(), TunnelError> {
+        let address = address.into();
+        let data = data.into();
+        let size = data.len();
+        let sender = self.sender()?;
+
+        let result = progress::with_progress(progress, |progress| async move {
+            let (cached, mut stream) = open_message_stream(
+                sender,
+                &self.inner.connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                framing::MESSAGE_STREAM,
+            )
+            .await?;
+
+            let _in_flight = cached.track();
+
+            let total = data.len() as u64;
+            let mut written = 0;
+            let mut lane = self.inner.scheduler.lane(address);
+
+            for start in (0..data.len()).step_by(self.inner.chunk_size) {
+                let end = data.len().min(start + self.inner.chunk_size);
+                let chunk = data.slice(start..end);
+
+                let len = chunk.len() as u64;
+
+                lane.turn(chunk.len()).await;
+                stream
+                    .write_chunk(chunk)
+                    .await
+                    .map_err(|e| write_error(address, e))?;
+                written += len;
+
+                // The last update is made once, after the loop.
+                if written < total {
+                    progress.update(written, total);
+                }
+            }
+
+            drop(lane);
+            progress.update(total, total);
+
+            finish_stream(stream, address).await
+        })
+        .await;
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Sends all of the data read from `reader` to another tunnel, as a
+    /// single message, without holding all of it in memory.
+    ///
+    /// Like [Tunnel::send], this waits for the receiver to acknowledge the
+    /// message. Returns how many bytes were sent. If reading fails, the
+    /// message is abandoned and [TunnelError::Source] is returned.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `reader`: The source of the data, e.g. a file or a socket.
+    pub async fn send_stream(
+        &self,
+        address: impl Into<PublicKey>,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<u64, TunnelError> {
+        let address = address.into();
+        let sender = self.sender()?;
+
+        let result = async {
+            let (cached, mut stream) = open_message_stream(
+                sender,
+                &self.inner.connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                framing::MESSAGE_STREAM,
+            )
+            .await?;
+
+            let _in_flight = cached.track();
+
+            let mut buffer = vec![0; self.inner.chunk_size];
+            let mut sent = 0;
+            let mut lane = self.inner.scheduler.lane(address);
+
+            loop {
+                let read = match reader.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(source) => {
+                        let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+                        return Err(TunnelError::Source { source });
+                    }
+                };
+
+                lane.turn(read).await;
+                stream
+                    .write_all(&buffer[..read])
+                    .await
+                    .map_err(|e| write_error(address, e))?;
+
+                sent += read as u64;
+            }
+
+            drop(lane);
+            finish_stream(stream, address).await?;
+
+            Ok(sent)
+        }
+        .await;
+
+        let sent = result.as_ref().map_or(0, |sent| *sent as usize);
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, sent, &result);
+        result
+    }
+
+    /// Sends a payload of known length, read from `reader`, to the
+    /// [TransferHandler] of another tunnel, reporting the progress of the
+    /// write like [Tunnel::send_with_progress].
+    ///
+    /// Unlike [Tunnel::send_stream], the length of the payload is announced
+    /// before it is sent, so the receiver can report its own progress and
+    /// refuse payloads which are too large before reading them. The payload is
+    /// sent over its own connection, so it never delays regular messages.
+    ///
+    /// If `reader` does not provide exactly `len` bytes, the transfer is
+    /// cancelled and [TunnelError::LengthMismatch] is returned. Dropping the
+    /// returned future also cancels the transfer, which the receiver observes
+    /// as an incomplete transfer.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `reader`: The source of the payload, e.g. a file.
+    /// - `len`: The length of the payload, in bytes. May be zero.
+    /// - `progress`: The function which receives the progress of the write.
+    pub async fn send_large(
+        &self,
+        address: impl Into<PublicKey>,
+        reader: impl AsyncRead + Unpin,
+        len: u64,
+        progress: impl Fn(u64, u64) + Send + Sync + 'static,
+    ) -> Result<(), TunnelError> {
+        let address = address.into();
+        let sender = self.sender()?;
+
+        let result = progress::with_progress(progress, |progress| async move {
+            let (cached, stream) = open_stream(
+                sender,
+                &self.inner.transfer_connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                |conn| async move { conn.open_uni().await },
+            )
+            .await?;
+
+            let _in_flight = cached.track();
+
+            transfer::send(
+                address,
+                stream,
+                reader,
+                len,
+                self.inner.chunk_size,
+                &mut self.inner.scheduler.lane(address),
+                &progress,
+            )
+            .await
+        })
+        .await;
+
+        // Transfers may be larger than the address space of 32-bit targets,
+        // such as wasm32, in which case the byte counter saturates.
+        let size = usize::try_from(len).unwrap_or(usize::MAX);
+
+        self.inner
+            .protocol
+            .metrics
+            .record_send(address, size, &result);
+        result
+    }
+
+    /// Opens a stream to another tunnel, through which a single message can
+    /// be sent one chunk at a time, without holding all of it in memory.
+    ///
+    /// The message is received as a whole by a [DataHandler] (within the
+    /// maximum message size of the receiver), or as it arrives by a
+    /// [StreamingDataHandler].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    pub async fn open_send_stream(
+        &self,
+        address: impl Into<PublicKey>,
+    ) -> Result<TunnelSendStream, TunnelError> {
+        let address = address.into();
+        let metrics = &self.inner.protocol.metrics;
+
+        let (cached, stream) = open_message_stream(
+            self.sender()?,
+            &self.inner.connections,
+            self.inner.max_reconnect_attempts,
+            address,
+            framing::MESSAGE_STREAM,
+        )
+        .await
+        .inspect_err(|e| metrics.record_send_error(address, e))?;
+
+        Ok(TunnelSendStream::new(
+            stream,
+            address,
+            cached.track(),
+            Arc::clone(metrics),
+        ))
+    }
+
+    /// Sends some data to another tunnel in the background, without waiting
+    /// for the receiver to acknowledge the stream.
+    ///
+    /// Errors which happen while sending are discarded. Use [Tunnel::flush] to
+    /// wait until every background send has completed. The only errors
+    /// returned directly are [TunnelError::WrongMode], if the tunnel is
+    /// receive-only, and [TunnelError::Destroyed], if another clone of the
+    /// tunnel was destroyed.
+    ///
+    /// **Note:** on native targets, this function must be called from within a
+    /// Tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The data to be sent.
+    /// This data can be anything which can be converted to [Bytes].
+    pub fn send_nowait(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Result<(), TunnelError> {
+        let address = address.into();
+
+        let sender = self.sender()?.clone();
+        let (data, size) = self.encode_shared(data.into());
+        let connections = Arc::clone(&self.inner.connections);
+        let max_reconnect_attempts = self.inner.max_reconnect_attempts;
+        let timeout = self.inner.send_timeout;
+        let metrics = Arc::clone(&self.inner.protocol.metrics);
+
+        if self.inner.tasks.is_cancelled() {
+            return Err(TunnelError::Destroyed);
+        }
+
+        // Released when the task completes, or when it is dropped because the
+        // tunnel was destroyed before or while it runs.
+        let pending = PendingSends::start(&self.inner.pending, address);
+
+        let spawned = self.inner.tasks.spawn(async move {
+            let result = send_data_timeout(
+                &sender,
+                &connections,
+                max_reconnect_attempts,
+                address,
+                &[data],
+                timeout,
+            )
+            .await;
+
+            metrics.record_send(address, size, &result);
+
+            drop(pending);
+        });
+
+        if spawned {
+            Ok(())
+        } else {
+            Err(TunnelError::Destroyed)
+        }
+    }
+
+    /// Waits until all background sends started by [Tunnel::send_nowait] have
+    /// completed.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: If provided, only waits for sends to this address.
+    /// - `timeout`: If provided, the maximum amount of time to wait for. An
+    /// error is returned if the sends did not complete in time.
+    pub async fn flush(
+        &self,
+        address: Option<PublicKey>,
+        timeout: Option<Duration>,
+    ) -> Result<(), TunnelError> {
+        match timeout {
+            Some(timeout) => n0_future::time::timeout(timeout, self.inner.pending.wait(address))
+                .await
+                .map_err(|_| TunnelError::FlushTimeout {
+                    peer: address,
+                    timeout,
+                }),
+            None => {
+                self.inner.pending.wait(address).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits until the endpoints of this tunnel are online, i.e. connected to
+    /// a relay and aware of their direct addresses.
+    ///
+    /// This is only needed if the tunnel was created with
+    /// [TunnelBuilder::wait_online] disabled. If relays are disabled, this
+    /// returns right away, as the endpoints never connect to one.
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: If provided, the maximum amount of time to wait for. An
+    /// error is returned if the endpoints did not go online in time.
+    pub async fn wait_online(&self, timeout: Option<Duration>) -> Result<(), TunnelError> {
+        if !self.inner.relays {
+            return Ok(());
+        }
+
+        let online = async {
+            if let Some(sender) = &self.inner.sender {
+                sender.online().await;
+            }
+
+            if let Some(receiver) = &self.inner.receiver {
+                receiver.endpoint().online().await;
+            }
+        };
+
+        match timeout {
+            Some(timeout) => n0_future::time::timeout(timeout, online)
+                .await
+                .map_err(|_| TunnelError::OnlineTimeout { timeout }),
+            None => {
+                online.await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns how many background sends started by [Tunnel::send_nowait] have
+    /// not completed yet.
+    pub fn pending_sends(&self) -> usize {
+        self.inner.pending.count(None)
+    }
+
+    /// Returns how many background tasks spawned by this tunnel are still
+    /// running.
+    pub fn task_count(&self) -> usize {
+        self.inner.tasks.count()
+    }
+
+    /// Sets the share of the outbound bandwidth given to another tunnel, while
+    /// chunked sends to several tunnels are in progress. Every tunnel has a
+    /// weight of 1 by default, and a weight of 0 is treated as 1.
+    ///
+    /// The chunks written by [Tunnel::send_with_progress], [Tunnel::send_stream]
+    /// and [Tunnel::send_large] take turns across tunnels, so a tunnel with a
+    /// weight of 3 gets about three times as many bytes through as a tunnel
+    /// with a weight of 1. Other sends are not paced, so small messages are
+    /// never delayed behind bulk transfers.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `weight`: The weight of the tunnel.
+    pub fn set_peer_weight(&self, address: impl Into<PublicKey>, weight: u32) {
+        self.inner.scheduler.set_weight(address.into(), weight);
+    }
+
+    /// Returns the weight set for another tunnel with
+    /// [Tunnel::set_peer_weight], or 1 if none was set.
+    pub fn peer_weight(&self, address: impl Into<PublicKey>) -> u32 {
+        self.inner.scheduler.weight(&address.into())
+    }
+
+    /// Opens a bidirectional stream to another tunnel, given the provided
+    /// address is valid.
+    ///
+    /// The receiving tunnel must have a [BiStreamHandler] configured, or the
+    /// stream is stopped with [NO_STREAM_HANDLER_CODE].
+    ///
+    /// **Note:** if a tunnel is not currently connected to the receiver, it
+    /// will first attempt to estabilish a connection. Also, the receiver is
+    /// only notified of the stream once some data is written to it.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to open a stream to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    pub async fn open_bi(
+        &self,
+        address: impl Into<PublicKey>,
+    ) -> Result<(SendStream, RecvStream), TunnelError> {
+        let (_, streams) = open_stream(
+            self.sender()?,
+            &self.inner.connections,
+            self.inner.max_reconnect_attempts,
+            address.into(),
+            |conn| async move { conn.open_bi().await },
+        )
+        .await?;
+
+        Ok(streams)
+    }
+
+    /// Sends a request to another tunnel and waits for its response.
+    ///
+    /// The request is sent over a bidirectional stream, and answered by the
+    /// [RequestHandler] of the other tunnel. If the other tunnel has no
+    /// request handler, [TunnelError::NoRequestHandler] is returned. If no
+    /// response arrives within the request timeout of this tunnel (see
+    /// [TunnelBuilder::request_timeout]), [TunnelError::RequestTimeout] is
+    /// returned.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send the
+    /// request to. Can be any value which can be converted to a [PublicKey].
+    /// - `data`: The request.
+    /// This data can be anything which can be converted to [Bytes], which is
+    /// written as it is, without being copied.
+    pub async fn request(
+        &self,
+        address: impl Into<PublicKey>,
+        data: impl Into<Bytes>,
+    ) -> Result<Vec<u8>, TunnelError> {
+        let address = address.into();
+        let data = data.into();
+        let timeout = self.inner.request_timeout;
+
+        let request = async {
+            let (cached, (send, recv)) = open_stream(
+                self.sender()?,
+                &self.inner.request_connections,
+                self.inner.max_reconnect_attempts,
+                address,
+                |conn| async move { conn.open_bi().await },
+            )
+            .await?;
+
+            let _in_flight = cached.track();
+            request::exchange(address, send, recv, data, self.max_message_size()).await
+        };
+
+        n0_future::time::timeout(timeout, request)
+            .await
+            .map_err(|_| TunnelError::RequestTimeout {
+                peer: address,
+                timeout,
+            })?
+    }
+
+    /// Replaces the [DataHandler] used by this tunnel, returning the previous
+    /// one.
+    ///
+    /// The swap takes effect for already open connections. Data which is
+    /// already being dispatched is still delivered to the previous handler.
+    /// If `None` is provided, incoming data is handled according to the
+    /// [NoHandlerPolicy] until a new handler is set.
+    pub fn set_handler(
+        &self,
+        handler: Option<Arc<RwLock<dyn DataHandler>>>,
+    ) -> Option<Arc<RwLock<dyn DataHandler>>> {
+        self.inner.protocol.set_handler(handler)
+    }
+
+    /// Adds a [DataHandler] to this tunnel, alongside its active handler.
+    ///
+    /// Every message is given to the active handler first, then to each added
+    /// handler, in the order they were added. All of them see every message:
+    /// no handler can stop the others from receiving it. Messages read by a
+    /// [StreamingDataHandler] are not given to the added handlers.
+    ///
+    /// This allows composing behaviors, e.g. logging or metrics, without
+    /// wrapping the handler of the application.
+    pub fn add_handler(&self, handler: Arc<RwLock<dyn DataHandler>>) -> HandlerId {
+        self.inner.protocol.add_handler(handler)
+    }
+
+    /// Removes a handler added with [Tunnel::add_handler], returning it.
+    /// Returns `None` if it was already removed.
+    ///
+    /// Data which is already being dispatched may still be delivered to it.
+    pub fn remove_handler(&self, id: HandlerId) -> Option<Arc<RwLock<dyn DataHandler>>> {
+        self.inner.protocol.remove_handler(id)
+    }
+
+    /// Replaces the handler used by this tunnel with a [StreamingDataHandler].
+    ///
+    /// The swap takes effect for already open connections. Data which is
+    /// already being dispatched is still delivered to the previous handler.
+    pub fn set_streaming_handler<T: StreamingDataHandler>(&self, handler: T) {
+        self.inner.protocol.set_streaming_handler(handler);
+    }
+
+    /// Stops delivering incoming messages to the handler of this tunnel,
+    /// without closing any connection, until [Tunnel::resume_receiving] is
+    /// called.
+    ///
+    /// While paused, incoming messages are not buffered by this tunnel: they
+    /// are not read off the connections at all. Once the stream limits of a
+    /// connection are reached, sends from the other tunnel wait, so the pause
+    /// applies backpressure to it. Messages which were already being read
+    /// when receiving was paused are still handled.
+    ///
+    /// Requests, bidirectional streams and pings are still answered.
+    pub fn pause_receiving(&self) {
+        self.inner.protocol.pause_receiving();
+    }
+
+    /// Resumes delivering incoming messages after [Tunnel::pause_receiving],
+    /// starting with the ones which were waiting.
+    pub fn resume_receiving(&self) {
+        self.inner.protocol.resume_receiving();
+    }
+
+    /// Returns whether delivering incoming messages is paused. See
+    /// [Tunnel::pause_receiving].
+    pub fn is_receiving_paused(&self) -> bool {
+        self.inner.protocol.is_receiving_paused()
+    }
+
+    /// Subscribes an [AsyncDataHandler] to a topic, so it receives the
+    /// messages sent to this tunnel with [Tunnel::send_on] on that topic.
+    /// Replaces the handler previously subscribed to the topic, if any.
+    ///
+    /// Messages on topics without a subscribed handler are given to the
+    /// regular handler of this tunnel, like messages sent with [Tunnel::send].
+    ///
+    /// Fails with [TunnelError::InvalidTopic] if the topic is empty or longer
+    /// than [MAX_TOPIC_LENGTH] bytes.
+    pub fn subscribe<T: AsyncDataHandler>(
+        &self,
+        topic: impl Into<String>,
+        handler: T,
+    ) -> Result<(), TunnelError> {
+        self.inner.protocol.subscribe(topic, handler)
+    }
+
+    /// Removes the handler subscribed to a topic, so its messages are given to
+    /// the regular handler again. Returns whether a handler was subscribed.
+    pub fn unsubscribe(&self, topic: &str) -> bool {
+        self.inner.protocol.unsubscribe(topic)
+    }
+
+    /// Replaces the handler used by this tunnel with an [AsyncDataHandler].
+    ///
+    /// The swap takes effect for already open connections. Data which is
+    /// already being dispatched is still delivered to the previous handler.
+    pub fn set_async_handler<T: AsyncDataHandler>(&self, handler: T) {
+        self.inner.protocol.set_async_handler(handler);
+    }
+
+    /// Replaces the handler used by this tunnel with a [TunnelReceiver],
+    /// through which incoming data is returned as a stream.
+    ///
+    /// While the receiver is full, the tunnel stops reading from the
+    /// connection the next message came from, so nothing is dropped and the
+    /// sender eventually waits. Like with [Tunnel::set_handler], the swap
+    /// takes effect for already open connections.
+    ///
+    /// # Arguments
+    ///
+    /// - `capacity`: How many messages the receiver buffers before the tunnel
+    /// stops reading incoming data. Must be greater than 0.
+    pub fn incoming(&self, capacity: usize) -> TunnelReceiver {
+        let (handler, receiver) = receiver::channel(capacity);
+        self.set_async_handler(handler);
+        receiver
+    }
+
+    /// Closes both the sender and the receiver endpoint and consumes this object.
+    ///
+    /// Ideally, this should be called before the execution of the program ends
+    /// or before a tunnel is discarded. A tunnel which is dropped instead is
+    /// closed abruptly: its connections are closed right away, and its
+    /// endpoints are only closed if a Tokio runtime is running.
+    ///
+    /// This is a shorthand for [Tunnel::shutdown] which waits up to 5 seconds
+    /// for running handlers and sends.
+    pub async fn destroy(self) -> Result<ShutdownReport, TunnelError> {
+        self.shutdown(DESTROY_DRAIN_TIMEOUT).await
+    }
+
+    /// Shuts this tunnel down gracefully, closing both the sender and the
+    /// receiver endpoint, and consumes this object.
+    ///
+    /// The tunnel first stops accepting new incoming streams, and waits for
+    /// the handlers already processing messages and for the sends still in
+    /// progress (including background sends started by
+    /// [Tunnel::send_nowait]) to complete, including the requests being
+    /// answered by the [RequestHandler]. Other bidirectional streams are
+    /// handed to their [BiStreamHandler] right away, so they are not waited
+    /// for.
+    ///
+    /// Background tasks are then cancelled, and every connection is closed
+    /// with [GOING_AWAY_CLOSE_CODE], waiting a short time for each of them to
+    /// close.
+    ///
+    /// Fails with [TunnelError::Shutdown] if the receiver endpoint could not
+    /// be shut down cleanly, in which case it is still closed.
+    ///
+    /// The tunnel is shut down for all of its clones, whose sends fail with
+    /// [TunnelError::Destroyed] from then on. Sends which already started are
+    /// waited for as described above. Only the first call through any clone
+    /// shuts the tunnel down: later calls return an empty [ShutdownReport]
+    /// right away.
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: The maximum amount of time to wait for handlers and sends.
+    /// Whether they completed in time is reported by
+    /// [ShutdownReport::drained].
+    pub async fn shutdown(self, timeout: Duration) -> Result<ShutdownReport, TunnelError> {
+        if self.inner.shut_down.swap(true, Ordering::AcqRel) {
+            return Ok(ShutdownReport::default());
+        }
+
+        self.inner.protocol.activity.close();
+
+        let drained = n0_future::time::timeout(timeout, self.idle()).await.is_ok();
+
+        let unstopped_tasks = self.inner.tasks.shutdown(DESTROY_TASK_TIMEOUT).await;
+        let closed_connections = self
+            .close_caches_graceful(GOING_AWAY_CLOSE_CODE, b"going away", DESTROY_CLOSE_TIMEOUT)
+            .await;
+
+        // A sender endpoint shared with the receiver is closed along with it.
+        if let Some(sender) = &self.inner.sender
+            && self.receiver_address() != Some(sender.id())
+        {
+            sender.close().await;
+        }
+
+        if let Some(receiver) = &self.inner.receiver {
+            receiver
+                .shutdown()
+                .await
+                .map_err(|e| TunnelError::Shutdown {
+                    source: Box::new(e),
+                })?;
+        }
+
+        Ok(ShutdownReport {
+            closed_connections,
+            unstopped_tasks,
+            drained,
+        })
+    }
+
+    /// Waits until no incoming message is being handled, and no send is in
+    /// progress.
+    async fn idle(&self) {
+        let sends = async {
+            self.inner.pending.wait(None).await;
+
+            // Sends through streams returned by open_send_stream are only
+            // tracked by their connection.
+            while self
+                .caches()
+                .iter()
+                .flat_map(|connections| connections.entries())
+                .any(|(_, cached)| !cached.is_idle())
+            {
+                n0_future::time::sleep(ROTATION_POLL_INTERVAL).await;
+            }
+        };
+
+        n0_future::future::zip(sends, self.inner.protocol.activity.idle()).await;
+    }
+
+    /// Closes a connection to another tunnel, if it exists, with
+    /// [USER_CLOSE_CODE].
+    ///
+    /// Returns whether a connection was closed.
+    pub fn close(&self, address: PublicKey) -> bool {
+        self.close_with(address, USER_CLOSE_CODE, USER_CLOSE_REASON)
+    }
+
+    /// Closes a connection to another tunnel, if it exists, with the given
+    /// error code and reason, which the other tunnel observes as the reason
+    /// the connection was closed.
+    ///
+    /// Returns whether a connection was closed.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    /// - `code`: An application-specific error code. Should not be one of the
+    /// codes used by this crate, such as [REFUSED_CLOSE_CODE].
+    /// - `reason`: A short, human-readable reason.
+    pub fn close_with(&self, address: PublicKey, code: u32, reason: &[u8]) -> bool {
+        let mut closed = false;
+
+        for connections in self.caches() {
+            if let Some(cached) = connections.remove(&address) {
+                cached.conn.close(code.into(), reason);
+                closed = true;
+            }
+        }
+
+        closed
+    }
+
+    /// Closes all connections between this tunnel and other tunnels, with
+    /// [USER_CLOSE_CODE].
+    pub fn close_all(&self) {
+        self.close_all_with(USER_CLOSE_CODE, USER_CLOSE_REASON);
+    }
+
+    /// Closes all connections between this tunnel and other tunnels, with the
+    /// given error code and reason. See [Tunnel::close_with].
+    pub fn close_all_with(&self, code: u32, reason: &[u8]) {
+        self.caches()
+            .iter()
+            .flat_map(|connections| connections.drain())
+            .for_each(|cached| cached.conn.close(code.into(), reason));
+    }
+
+    /// Closes all connections between this tunnel and other tunnels, waiting
+    /// for each of them to close.
+    ///
+    /// Unlike [Tunnel::close_all], this only returns once the close of every
+    /// connection was sent to the other tunnel, or the timeout expired, so
+    /// the other tunnel observes an application close even if the process
+    /// exits right after. Returns how many connections were closed cleanly,
+    /// i.e. were still open and had their close sent in time.
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: The maximum amount of time to wait for each connection.
+    pub async fn close_all_graceful(&self, timeout: Duration) -> usize {
+        self.close_caches_graceful(USER_CLOSE_CODE, USER_CLOSE_REASON, timeout)
+            .await
+    }
+
+    /// Closes every cached connection with the given code and reason, waiting
+    /// for each of them to close. See [Tunnel::close_all_graceful].
+    async fn close_caches_graceful(&self, code: u32, reason: &[u8], timeout: Duration) -> usize {
+        let connections: Vec<_> = self
+            .caches()
+            .iter()
+            .flat_map(|connections| connections.drain())
+            .collect();
+
+        let closes = connections.iter().map(|cached| async move {
+            let open = cached.conn.close_reason().is_none();
+            cached.conn.close(code.into(), reason);
+
+            // Closing only queues the close frame, which the endpoint sends
+            // later on, so the connection reports being closed right away.
+            let sent = async {
+                while cached.conn.stats().frame_tx.connection_close == 0 {
+                    n0_future::time::sleep(CLOSE_POLL_INTERVAL).await;
+                }
+            };
+
+            open && n0_future::time::timeout(timeout, sent).await.is_ok()
+        });
+
+        join_all(closes)
+            .await
+            .into_iter()
+            .filter(|clean| *clean)
+            .count()
+    }
+
+    /// Returns the caches of the regular, acknowledged, ping, transfer, topic
+    /// and request connections.
+    fn caches(&self) -> [&ConnectionCache; 6] {
+        [
+            &self.inner.connections,
+            &self.inner.acked_connections,
+            &self.inner.ping_connections,
+            &self.inner.transfer_connections,
+            &self.inner.topic_connections,
+            &self.inner.request_connections,
+        ]
+    }
+
+    /// Returns the maximum size of the messages this tunnel can receive, in
+    /// bytes. See [TunnelBuilder::max_message_size].
+    pub fn max_message_size(&self) -> usize {
+        self.inner.protocol.max_message_size()
+    }
+
+    /// Returns how many incoming connections this tunnel accepted, and how
+    /// many were refused by its [AcceptPolicy].
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.inner.protocol.accept_stats()
+    }
+
+    /// Returns how many messages and bytes this tunnel sent and received, and
+    /// how many of its sends failed.
+    ///
+    /// The counters are updated without synchronizing with each other, so a
+    /// snapshot taken while messages are in transit may be slightly
+    /// inconsistent, e.g. count the bytes of a message but not the message.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.inner.protocol.metrics()
+    }
+
+    /// Returns the counters of this tunnel like [Tunnel::metrics], to be
+    /// compared with a later snapshot with [MetricsSnapshot::diff].
+    ///
+    /// The counters are never reset, so no increment is lost or counted twice
+    /// between consecutive snapshots. This is suited to collecting what was
+    /// exchanged since the previous collection, e.g. for billing.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.inner.protocol.metrics()
+    }
+
+    /// Returns how much data this tunnel exchanged with another tunnel, along
+    /// with the round-trip time of the cached connection to it. `None` if no
+    /// data was ever exchanged with it.
+    ///
+    /// Like [Tunnel::metrics], the counters are updated without synchronizing
+    /// with each other.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel for data
+    /// sent to it, or its **sender address** for data received from it. See
+    /// [PeerStats].
+    pub fn stats(&self, address: &PublicKey) -> Option<PeerStats> {
+        let stats = self.inner.protocol.metrics.peer_snapshot(address)?;
+
+        Some(self.with_rtt(*address, stats))
+    }
+
+    /// Returns how much data this tunnel exchanged with another tunnel since
+    /// the previous call for it, like [Tunnel::stats], and resets its counters.
+    /// `None` if no data was ever exchanged with it.
+    ///
+    /// Each counter is reset atomically, so data exchanged while it is reset
+    /// is either returned by this call or by the next one, but never lost or
+    /// counted twice. The other tunnel is kept, even once disconnected, so
+    /// [Tunnel::stats] then only reports what happened since the reset.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The address of the other tunnel, as for [Tunnel::stats].
+    pub fn take_and_reset_peer_counters(&self, address: &PublicKey) -> Option<PeerStats> {
+        let stats = self.inner.protocol.metrics.take_peer_counters(address)?;
+
+        Some(self.with_rtt(*address, stats))
+    }
+
+    /// Returns how much data this tunnel exchanged with every tunnel it ever
+    /// exchanged data with. See [Tunnel::stats].
+    pub fn stats_all(&self) -> Vec<(PublicKey, PeerStats)> {
+        self.inner
+            .protocol
+            .metrics
+            .peer_snapshots()
+            .into_iter()
+            .map(|(address, stats)| (address, self.with_rtt(address, stats)))
+            .collect()
+    }
+
+    /// Fills in the round-trip time of the cached connection to `address`.
+    fn with_rtt(&self, address: PublicKey, stats: PeerStats) -> PeerStats {
+        PeerStats {
+            rtt: self
+                .inner
+                .connections
+                .get(&address)
+                .map(|cached| cached.conn.rtt()),
+            ..stats
+        }
+    }
+
+    /// Returns the generation of the cached connection to another tunnel, if
+    /// it exists.
+    ///
+    /// Every new connection gets a higher generation than the previous ones,
+    /// which is useful for telling whether a connection was re-estabilished.
+    pub fn connection_generation(&self, address: &PublicKey) -> Option<u64> {
+        self.inner
+            .connections
+            .get(address)
+            .map(|cached| cached.generation)
+    }
+
+    /// Returns the protocol version negotiated over the cached connection to
+    /// another tunnel. `None` if there is no such connection, or if this
+    /// tunnel is unversioned. See [TunnelBuilder::protocol_version].
+    pub fn connection_version(&self, address: &PublicKey) -> Option<u8> {
+        self.inner
+            .connections
+            .get(address)
+            .and_then(|cached| cached.version)
+    }
+
+    /// Returns a JSON report describing the state of this tunnel, meant to be
+    /// attached to bug reports.
+    ///
+    /// The report includes the crate version and enabled features, the
+    /// configuration of the builder, the addresses, bound sockets and home
+    /// relays of both endpoints, a summary of the connection cache, the
+    /// metrics and the last few send errors. Secret keys and data are never
+    /// included. The keys of the report are sorted, so that reports can be
+    /// diffed.
+    pub fn debug_dump(&self) -> String {
+        let features: Vec<&str> = [
+            ("dashmap", cfg!(feature = "dashmap")),
+            ("small-map", cfg!(feature = "small-map")),
+            ("zstd", cfg!(feature = "zstd")),
+            ("lz4", cfg!(feature = "lz4")),
+            ("serde", cfg!(feature = "serde")),
+        ]
+        .into_iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(feature))
+        .collect();
+
+        let mut connections = self.inner.connections.generations();
+        connections.sort_by_key(|(_, generation)| *generation);
+
+        let connections: Vec<_> = connections
+            .into_iter()
+            .map(|(address, generation)| {
+                json!({
+                    "address": address.to_string(),
+                    "generation": generation,
+                })
+            })
+            .collect();
+
+        let accept = self.accept_stats();
+        let metrics = self.metrics();
+
+        let errors: Vec<_> = self
+            .inner
+            .protocol
+            .metrics
+            .recent_errors()
+            .into_iter()
+            .map(|event| {
+                json!({
+                    "peer": event.peer.to_string(),
+                    "error": event.message,
+                    "age_ms": event.at.elapsed().as_millis() as u64,
+                })
+            })
+            .collect();
+
+        let endpoint = |endpoint: &Endpoint| {
+            json!({
+                "address": endpoint.id().to_string(),
+                "bound_sockets": endpoint.bound_sockets(),
+                "home_relay": endpoint.addr().relay_urls().next().map(ToString::to_string),
+            })
+        };
+
+        let report = json!({
+            "dump_version": DEBUG_DUMP_VERSION,
+            "crate_version": env!("CARGO_PKG_VERSION"),
+            "features": features,
+            "config": self.inner.config,
+            "sender": self.inner.sender.as_ref().map(endpoint),
+            "receiver": self.inner.receiver.as_ref().map(|receiver| endpoint(receiver.endpoint())),
+            "handlers": {
+                "data": self.inner.protocol.has_handler(),
+                "bi_stream": self.inner.protocol.bi_handler.is_some(),
+                "request": self.inner.protocol.request_handler.is_some(),
+                "accept_policy": self.inner.protocol.accept_policy.is_some(),
+            },
+            "accept": {
+                "accepted": accept.accepted,
+                "refused": accept.refused,
+            },
+            "metrics": {
+                "messages_sent": metrics.messages_sent,
+                "bytes_sent": metrics.bytes_sent,
+                "messages_received": metrics.messages_received,
+                "bytes_received": metrics.bytes_received,
+                "send_errors": metrics.send_errors,
+                "bytes_before_compression": metrics.bytes_before_compression,
+                "bytes_after_compression": metrics.bytes_after_compression,
+            },
+            "connections": connections,
+            "errors": errors,
+            "pending_sends": self.pending_sends(),
+            "tasks": self.task_count(),
+        });
+
+        serde_json::to_string_pretty(&report).unwrap()
+    }
+
+    /// Returns the addresses of the tunnels this tunnel has a live outgoing
+    /// connection to.
+    ///
+    /// Cached connections which were already closed (e.g. by the other
+    /// tunnel) are skipped, until the next send evicts them.
+    pub fn list_connections(&self) -> Vec<PublicKey> {
+        self.inner
+            .connections
+            .entries()
+            .into_iter()
+            .filter(|(_, cached)| cached.conn.close_reason().is_none())
+            .map(|(address, _)| address)
+            .collect()
+    }
+
+    /// Returns whether this tunnel has a live outgoing connection to another
+    /// tunnel. See [Tunnel::list_connections].
+    pub fn is_connected(&self, address: &PublicKey) -> bool {
+        self.inner
+            .connections
+            .get(address)
+            .is_some_and(|cached| cached.conn.close_reason().is_none())
+    }
+
+    /// Returns the state of the outgoing connection to another tunnel, if one
+    /// is cached.
+    ///
+    /// Connections closed with [Tunnel::close] or [Tunnel::close_all] are
+    /// removed from the cache right away, so `None` is returned for them.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    pub fn connection_status(&self, address: &PublicKey) -> Option<ConnectionStatus> {
+        let cached = self.inner.connections.get(address)?;
+
+        let conn_type = self
+            .inner
+            .sender
+            .as_ref()
+            .and_then(|sender| sender.conn_type(*address))
+            .map(|mut conn_type| conn_type.get());
+
+        Some(ConnectionStatus {
+            alive: cached.conn.close_reason().is_none(),
+            age: cached.created.elapsed(),
+            conn_type,
+        })
+    }
+
+    /// Returns how many outgoing connections are currently cached.
+    pub fn cached_connection_count(&self) -> usize {
+        self.inner.connections.len()
+    }
+
+    /// Returns the [Mode] of this tunnel.
+    pub fn mode(&self) -> Mode {
+        self.inner.mode
+    }
+
+    /// Returns the address of the sender endpoint of this tunnel, if it has one.
+    ///
+    /// The sender enpoint is responsible for sending data to other tunnels.
+    /// As such, when sending data, this address will be cited as the source.
+    pub fn sender_address(&self) -> Option<PublicKey> {
+        self.inner.sender.as_ref().map(|sender| sender.id())
+    }
+
+    /// Returns the address of the receiver endpoint of this tunnel, if it has
+    /// one.
+    ///
+    /// The receiver enpoint is responsible for receiving data from other tunnels.
+    /// As such, senders should send data to this address.
+    pub fn receiver_address(&self) -> Option<PublicKey> {
+        self.inner
+            .receiver
+            .as_ref()
+            .map(|receiver| receiver.endpoint().id())
+    }
+
+    /// Returns the full address of the receiver endpoint of this tunnel, if it
+    /// has one: its **receiver address**, along with the socket addresses and
+    /// relay it can currently be reached at.
+    ///
+    /// Other tunnels can use it with [Tunnel::send_to] to reach this tunnel
+    /// without a discovery service, once it was shared out of band.
+    pub fn receiver_addr(&self) -> Option<EndpointAddr> {
+        self.inner
+            .receiver
+            .as_ref()
+            .map(|receiver| receiver.endpoint().addr())
+    }
+
+    /// Returns whether the endpoints of this tunnel use relay servers, i.e.
+    /// relays were not disabled with [TunnelBuilder::relay_mode].
+    pub fn uses_relays(&self) -> bool {
+        self.inner.relays
+    }
+
+    /// Returns the secret key of the receiver endpoint of this tunnel, if it
+    /// has one. It can be given to [TunnelBuilder::secret_key] to recreate a
+    /// tunnel with the same receiver address.
+    pub fn secret_key(&self) -> Option<&SecretKey> {
+        self.inner
+            .receiver
+            .as_ref()
+            .map(|receiver| receiver.endpoint().secret_key())
+    }
+
+    /// Returns the sender endpoint of this tunnel. `None` if the tunnel is in
+    /// [Mode::ReceiveOnly].
+    pub fn sender_endpoint(&self) -> Option<&Endpoint> {
+        self.inner.sender.as_ref()
+    }
+
+    /// Returns the router of the receiver endpoint of this tunnel. `None` if
+    /// the tunnel is in [Mode::SendOnly].
+    pub fn receiver_router(&self) -> Option<&Router> {
+        self.inner.receiver.as_ref()
+    }
+
+    /// Returns the secret key of the sender endpoint of this tunnel, if it
+    /// has one. It can be given to [TunnelBuilder::sender_secret_key].
+    pub fn sender_secret_key(&self) -> Option<&SecretKey> {
+        self.inner.sender.as_ref().map(|sender| sender.secret_key())
+    }
+
+    /// Returns the sender endpoint, or a [TunnelError::WrongMode] if the
+    /// tunnel is receive-only, or a [TunnelError::Destroyed] once it started
+    /// shutting down.
+    fn sender(&self) -> Result<&Endpoint, TunnelError> {
+        let sender = self.inner.sender.as_ref().ok_or(TunnelError::WrongMode {
+            mode: self.inner.mode,
+        })?;
+
+        if self.inner.shut_down.load(Ordering::Acquire) {
+            return Err(TunnelError::Destroyed);
+        }
+
+        Ok(sender)
+    }
+}
+
+impl Drop for TunnelInner {
+    /// Closes the connections and endpoints of a tunnel which was not
+    /// destroyed, once its last clone is dropped, without waiting for
+    /// anything.
+    ///
+    /// Endpoints can only be closed asynchronously, so they are closed in a
+    /// task spawned on the current Tokio runtime. Outside of a runtime, they
+    /// stay open until the process exits.
+    fn drop(&mut self) {
+        self.protocol.activity.close();
+        self.tasks.cancel();
+
+        [
+            &self.connections,
+            &self.acked_connections,
+            &self.ping_connections,
+            &self.transfer_connections,
+            &self.topic_connections,
+            &self.request_connections,
+        ]
+        .iter()
+        .flat_map(|connections| connections.drain())
+        .for_each(|cached| cached.conn.close(USER_CLOSE_CODE.into(), USER_CLOSE_REASON));
+
+        // A sender endpoint shared with the receiver is closed along with it.
+        let receiver_address = self
+            .receiver
+            .as_ref()
+            .map(|receiver| receiver.endpoint().id());
+        let sender = self
+            .sender
+            .take()
+            .filter(|sender| !sender.is_closed() && receiver_address != Some(sender.id()));
+        let receiver = self
+            .receiver
+            .take()
+            .filter(|receiver| !receiver.is_shutdown());
+
+        if (sender.is_some() || receiver.is_some())
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move {
+                if let Some(sender) = sender {
+                    sender.close().await;
+                }
+
+                if let Some(receiver) = receiver {
+                    let _ = receiver.shutdown().await;
+                }
+            });
+        }
+    }
+}
+
+/// A builder used to configure and create a [Tunnel].
+#[derive(Default)]
+pub struct TunnelBuilder {
+    handler: Option<IncomingHandler>,
+    bi_handler: Option<Arc<dyn BiStreamHandler>>,
+    request_handler: Option<Arc<dyn RequestHandler>>,
+    transfer_handler: Option<Arc<dyn BoxedTransferHandler>>,
+    request_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    ack_timeout: Option<Duration>,
+    keep_alive: Option<(Duration, u32)>,
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    dial: DialOptions,
+    secret_key: Option<SecretKey>,
+    sender_secret_key: Option<SecretKey>,
+    mode: Mode,
+    single_endpoint: bool,
+    relay_mode: Option<RelayMode>,
+    bind_addr: Option<SocketAddrV4>,
+    sender_bind_addr: Option<SocketAddrV4>,
+    skip_online_wait: bool,
+    online_timeout: Option<Duration>,
+    max_connection_age: Option<Duration>,
+    cache_limits: CacheLimits,
+    max_message_size: Option<usize>,
+    max_concurrent_streams: Option<usize>,
+    chunk_size: Option<usize>,
+    ordered: bool,
+    on_message_too_large: Option<MessageTooLargeCallback>,
+    on_stream_error: Option<StreamErrorCallback>,
+    on_handler_error: Option<HandlerErrorCallback>,
+    handler_error_policy: HandlerErrorPolicy,
+    no_handler_policy: NoHandlerPolicy,
+    peer_callbacks: PeerCallbacks,
+    max_reconnect_attempts: Option<u32>,
+}
+
+impl TunnelBuilder {
+    /// Sets the [Mode] of the tunnel. Defaults to [Mode::SendReceive].
+    ///
+    /// One-directional tunnels only bind a single endpoint, which saves a
+    /// socket and the time spent waiting for the other endpoint to go online.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether [TunnelBuilder::spawn] waits for the endpoints to go
+    /// online before returning. Defaults to `true`.
+    ///
+    /// If disabled, the tunnel is returned as soon as its endpoints are bound,
+    /// and [Tunnel::wait_online] can be used to wait for connectivity later.
+    pub fn wait_online(mut self, wait: bool) -> Self {
+        self.skip_online_wait = !wait;
+        self
+    }
+
+    /// Sets how long [TunnelBuilder::spawn] waits for the endpoints to go
+    /// online. By default, it waits until they do.
+    ///
+    /// If the timeout is exceeded, e.g. because no relay can be reached, the
+    /// endpoints are closed and [TunnelError::Setup] is returned with
+    /// [SetupStage::Discovery]. Ignored if waiting is disabled with
+    /// [TunnelBuilder::wait_online].
+    pub fn online_timeout(mut self, timeout: Duration) -> Self {
+        self.online_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the [DataHandler] object used to handle incoming data, replacing
+    /// any handler set before.
+    pub fn handler<T: DataHandler>(mut self, handler: T) -> Self {
+        self.handler = Some(IncomingHandler::Sync(Arc::new(RwLock::new(handler))));
+        self
+    }
+
+    /// Sets the [AsyncDataHandler] object used to handle incoming data,
+    /// replacing any handler set before.
+    pub fn async_handler<T: AsyncDataHandler>(mut self, handler: T) -> Self {
+        self.handler = Some(IncomingHandler::Async(Arc::new(handler)));
+        self
+    }
+
+    /// Makes the tunnel return incoming data through a [TunnelReceiver],
+    /// replacing any handler set before.
+    ///
+    /// # Arguments
+    ///
+    /// - `capacity`: How many messages the receiver buffers before the tunnel
+    /// stops reading incoming data. Must be greater than 0.
+    pub fn receiver(self, capacity: usize) -> (Self, TunnelReceiver) {
+        let (handler, receiver) = receiver::channel(capacity);
+        (self.async_handler(handler), receiver)
+    }
+
+    /// Sets the [StreamingDataHandler] object used to handle incoming data as
+    /// it arrives, replacing any handler set before.
+    pub fn streaming_handler<T: StreamingDataHandler>(mut self, handler: T) -> Self {
+        self.handler = Some(IncomingHandler::Streaming(Arc::new(handler)));
+        self
+    }
+
+    /// Sets the [BiStreamHandler] object used to handle incoming
+    /// bidirectional streams. Requests are carried by their own connections,
+    /// so it can be combined with a [RequestHandler].
+    pub fn bi_handler<T: BiStreamHandler>(mut self, handler: T) -> Self {
+        self.bi_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets the [RequestHandler] object used to answer incoming requests.
+    ///
+    /// Requests are carried by their own connections, so this can be combined
+    /// with a [BiStreamHandler].
+    pub fn request_handler<T: RequestHandler>(mut self, handler: T) -> Self {
+        self.request_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets the [TransferHandler] object used to receive the payloads sent
+    /// with [Tunnel::send_large]. Without one, such payloads are refused with
+    /// [NO_HANDLER_CLOSE_CODE] under [NoHandlerPolicy::Refuse], or with
+    /// [NO_STREAM_HANDLER_CODE] otherwise.
+    pub fn transfer_handler<T: TransferHandler>(mut self, handler: T) -> Self {
+        self.transfer_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets how long [Tunnel::request] waits for a response. Defaults to 30
+    /// seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether the tunnel uses a single endpoint to both send and
+    /// receive data. Defaults to `false`.
+    ///
+    /// With a single endpoint, [Tunnel::sender_address] and
+    /// [Tunnel::receiver_address] are the same, so the address given to a
+    /// handler along with incoming data can be used to reply directly. It
+    /// also halves the sockets and the hole punching done by the tunnel.
+    ///
+    /// This only applies to tunnels in [Mode::SendReceive], as other modes
+    /// already bind a single endpoint.
+    pub fn single_endpoint(mut self, single_endpoint: bool) -> Self {
+        self.single_endpoint = single_endpoint;
+        self
+    }
+
+    /// Sets the secret key of the receiver endpoint, which determines the
+    /// **receiver address** of the tunnel. By default, a random key is
+    /// generated, so the address changes every time a tunnel is created.
+    ///
+    /// Reusing a key makes the address of a tunnel stable across restarts.
+    /// The key must not be used by two running tunnels at once. To persist a
+    /// generated key, store the bytes of [Tunnel::secret_key] and restore it
+    /// with [SecretKey::from_bytes].
+    pub fn secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.secret_key = Some(secret_key);
+        self
+    }
+
+    /// Sets which relay servers the endpoints of the tunnel use. By default,
+    /// the public relays of n0 are used.
+    ///
+    /// Relays let tunnels reach each other when no direct connection can be
+    /// established, e.g. behind restrictive NATs. [RelayMode::Disabled]
+    /// restricts the tunnel to direct connections, which suits LAN-only
+    /// deployments. In that case, [TunnelBuilder::spawn] does not wait for
+    /// the endpoints to connect to a relay.
+    pub fn relay_mode(mut self, relay_mode: RelayMode) -> Self {
+        self.relay_mode = Some(relay_mode);
+        self
+    }
+
+    /// Makes the endpoints of the tunnel use a single relay server, e.g. a
+    /// self-hosted one. This is a shorthand for [TunnelBuilder::relay_mode]
+    /// with [RelayMode::Custom].
+    pub fn relay_url(self, url: RelayUrl) -> Self {
+        self.relay_mode(RelayMode::Custom(url.into()))
+    }
+
+    /// Sets the IPv4 address the receiver endpoint binds to. By default, it
+    /// binds to a random port on all interfaces.
+    ///
+    /// Binding to a fixed port makes the direct address of the tunnel stable,
+    /// e.g. to open it in a firewall. If the port is already in use, a random
+    /// one is chosen instead.
+    pub fn bind_addr(mut self, addr: SocketAddrV4) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Sets the IPv4 address the sender endpoint binds to. By default, it
+    /// binds to a random port on all interfaces.
+    ///
+    /// Ignored when [TunnelBuilder::single_endpoint] is enabled, as the
+    /// receiver endpoint is used to send data.
+    pub fn sender_bind_addr(mut self, addr: SocketAddrV4) -> Self {
+        self.sender_bind_addr = Some(addr);
+        self
+    }
+
+    /// Sets the secret key of the sender endpoint, which determines the
+    /// **sender address** of the tunnel. By default, a random key is
+    /// generated.
+    ///
+    /// Ignored when [TunnelBuilder::single_endpoint] is enabled, as the
+    /// receiver endpoint is used to send data.
+    pub fn sender_secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.sender_secret_key = Some(secret_key);
+        self
+    }
+
+    /// Sets the ALPN of the tunnel protocol. Defaults to [ALPN].
+    ///
+    /// Tunnels only connect to tunnels which use the same ALPN, so this can
+    /// be used to keep separate applications, or incompatible versions of
+    /// one, from exchanging data. The ALPN must not be empty, otherwise
+    /// [TunnelBuilder::spawn] fails with [TunnelError::EmptyAlpn].
+    pub fn alpn(mut self, alpn: impl AsRef<[u8]>) -> Self {
+        self.dial.alpn = alpn.as_ref().to_vec();
+        self
+    }
+
+    /// Sets whether the tunnel sends its messages as frames. Defaults to
+    /// `false`.
+    ///
+    /// By default, every message is sent over its own stream, which is only
+    /// considered sent once the other tunnel acknowledges it. Framed tunnels
+    /// instead keep one stream open per connection, and write each message
+    /// to it prefixed with its length, which is cheaper for high rates of
+    /// small messages. A framed send completes once the message is written,
+    /// without waiting for an acknowledgement.
+    ///
+    /// Every tunnel accepts framed connections, so this only needs to be set
+    /// on the sending side. [Tunnel::send_with_progress] and
+    /// [Tunnel::open_send_stream] still use a stream per message.
+    ///
+    /// **Note:** framed messages are discarded by a receiver which uses a
+    /// [StreamingDataHandler].
+    pub fn framed(mut self, framed: bool) -> Self {
+        self.dial.framed = framed;
+        self
+    }
+
+    /// Sets the algorithm used to compress the messages sent by the tunnel.
+    /// By default, messages are not compressed.
+    ///
+    /// Compression applies to [Tunnel::send], [Tunnel::broadcast] and
+    /// background sends. Messages smaller than the threshold set with
+    /// [TunnelBuilder::compression_threshold], or which do not get smaller
+    /// once compressed, are sent as they are. Each message is marked with
+    /// how it is compressed, so receivers need no configuration, but must
+    /// have the cargo feature of the algorithm enabled. Otherwise, its
+    /// messages are refused, and the sends fail with
+    /// [TunnelError::RemoteStopped] with [INVALID_PAYLOAD_CODE].
+    ///
+    /// How much compression saves is reported by [Tunnel::metrics].
+    ///
+    /// **Note:** like framed messages, compressed messages are discarded by
+    /// a receiver which uses a [StreamingDataHandler].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.dial.compression = Some(compression);
+        self
+    }
+
+    /// Sets the size from which messages are compressed, in bytes. Defaults
+    /// to [DEFAULT_COMPRESSION_THRESHOLD]. See [TunnelBuilder::compression].
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.dial.compression_threshold = threshold;
+        self
+    }
+
+    /// Sets the version of the application protocol spoken over the tunnel.
+    /// By default, tunnels are unversioned.
+    ///
+    /// Versioned tunnels exchange their versions when connecting, and only
+    /// send data to tunnels with the same version. Otherwise, sends fail with
+    /// [TunnelError::VersionMismatch], which reports both versions. The
+    /// negotiated version of a connection is returned by
+    /// [Tunnel::connection_version].
+    ///
+    /// Every tunnel accepts versioned connections, and an unversioned tunnel
+    /// answers as version `0`. Unversioned connections are unchanged, so
+    /// tunnels which do not set a version stay compatible with each other.
+    pub fn protocol_version(mut self, version: u8) -> Self {
+        self.dial.version = Some(version);
+        self
+    }
+
+    /// Sets how long establishing a connection to another tunnel may take
+    /// before a send fails with [TunnelError::ConnectTimeout]. By default,
+    /// the timeouts of the underlying endpoint apply.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.dial.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the size of the chunks written by [Tunnel::send_with_progress],
+    /// [Tunnel::send_stream] and [Tunnel::send_large], in bytes. Defaults to
+    /// [DEFAULT_CHUNK_SIZE]. A size of 0 is treated as 1.
+    ///
+    /// Smaller chunks report progress more smoothly, at the cost of more
+    /// writes. Progress is still reported at most once every 50 milliseconds.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size.max(1));
+        self
+    }
+
+    /// Sets how long sends may take before failing with
+    /// [TunnelError::SendTimeout]. This applies to [Tunnel::send],
+    /// [Tunnel::broadcast] and background sends. By default, sends wait for
+    /// as long as the connection stays alive.
+    ///
+    /// See [Tunnel::send_timeout] to set the timeout of a single send.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long [Tunnel::send_acked] waits for the other tunnel to
+    /// process the message before failing with [TunnelError::AckTimeout].
+    /// Defaults to 30 seconds.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables keep-alive pings, which keep the network paths to the tunnels
+    /// this tunnel is connected to open while the connections are idle, and
+    /// detect the tunnels which stopped responding. Disabled by default.
+    ///
+    /// Every `interval`, each tunnel this tunnel has a cached connection to is
+    /// pinged like with [Tunnel::ping]. Once a tunnel missed `max_missed`
+    /// pings in a row, its connection is closed with
+    /// [UNRESPONSIVE_CLOSE_CODE], which notifies the callback set with
+    /// [TunnelBuilder::on_peer_disconnected]. The next send to it dials a new
+    /// connection.
+    ///
+    /// Keep-alive pings are stopped when the tunnel is destroyed.
+    ///
+    /// # Arguments
+    ///
+    /// - `interval`: The interval between two pings. A ping which is not
+    /// answered within this interval is missed.
+    /// - `max_missed`: How many pings in a row a tunnel can miss before its
+    /// connection is closed. At least 1.
+    pub fn keep_alive(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.keep_alive = Some((interval, max_missed.max(1)));
+        self
+    }
+
+    /// Sets the [AcceptPolicy] object used to decide whether incoming
+    /// connections are accepted, e.g. [RequireDirectForUnknown] or a
+    /// [PeerFilter]. By default, every connection is accepted.
+    pub fn accept_policy<T: AcceptPolicy>(mut self, policy: T) -> Self {
+        self.accept_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets the maximum size of incoming messages, in bytes. Defaults to
+    /// [DEFAULT_MAX_MESSAGE_SIZE].
+    ///
+    /// Larger messages are discarded, and their streams are stopped with
+    /// [MESSAGE_TOO_LARGE_CODE], which makes the send fail on the other end.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// Sets how many incoming messages from the same connection are read and
+    /// handled concurrently. Defaults to [DEFAULT_MAX_CONCURRENT_STREAMS].
+    ///
+    /// Reading messages concurrently keeps a large or slow message from
+    /// delaying the ones sent after it, so handlers are not called in the
+    /// order the messages were sent (see [TunnelBuilder::ordered]). Up to
+    /// this many messages of the maximum message size may be buffered per
+    /// connection.
+    ///
+    /// # Arguments
+    ///
+    /// - `max_concurrent_streams`: The maximum number of messages handled at
+    /// once per connection. Must be greater than 0.
+    pub fn max_concurrent_streams(mut self, max_concurrent_streams: usize) -> Self {
+        self.max_concurrent_streams = Some(max_concurrent_streams);
+        self
+    }
+
+    /// Sets whether incoming messages from the same connection are handled
+    /// one at a time, in the order they were sent. Disabled by default.
+    ///
+    /// This overrides [TunnelBuilder::max_concurrent_streams], so a large or
+    /// slow message delays every message sent after it. Messages sent with
+    /// [Tunnel::send_acked] and through a framed tunnel are always handled in
+    /// order.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Sets the function called with the address of the sending tunnel
+    /// whenever an incoming message or request is discarded for exceeding
+    /// the maximum message size. By default, such messages are discarded
+    /// silently.
+    ///
+    /// The function is called from the accept loop of the connection, so it
+    /// should return quickly.
+    pub fn on_message_too_large(
+        mut self,
+        callback: impl Fn(PublicKey) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_message_too_large = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called with the address of the sending tunnel and
+    /// the error whenever an incoming message cannot be read, e.g. as its
+    /// stream was reset midway or the connection was lost. By default, such
+    /// messages are discarded silently.
+    ///
+    /// Only the affected message is discarded: the messages which follow it
+    /// over the same connection are still handled. Like
+    /// [TunnelBuilder::on_message_too_large], the function should return
+    /// quickly.
+    pub fn on_stream_error(
+        mut self,
+        callback: impl Fn(PublicKey, ReadError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_stream_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called with the address of the sending tunnel and
+    /// the error whenever the handler fails to process a message, including
+    /// when it panics. By default, such errors are discarded silently.
+    ///
+    /// The function is called before the [HandlerErrorPolicy] is applied.
+    /// Like [TunnelBuilder::on_stream_error], it should return quickly.
+    pub fn on_handler_error(
+        mut self,
+        callback: impl Fn(PublicKey, HandlerError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_handler_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets what the tunnel does when its handler fails to process a message.
+    /// Defaults to [HandlerErrorPolicy::Continue].
+    ///
+    /// Messages sent with [Tunnel::send_acked] report the failure in their
+    /// acknowledgement instead, see [Delivery::HandlerFailed], so the policy
+    /// does not apply to them.
+    pub fn handler_error_policy(mut self, policy: HandlerErrorPolicy) -> Self {
+        self.handler_error_policy = policy;
+        self
+    }
+
+    /// Sets what the tunnel does with incoming messages while it has no
+    /// handler, e.g. before [Tunnel::set_handler] is first called. Defaults
+    /// to [NoHandlerPolicy::Refuse], so other tunnels get an error rather
+    /// than having their messages silently discarded.
+    pub fn no_handler_policy(mut self, policy: NoHandlerPolicy) -> Self {
+        self.no_handler_policy = policy;
+        self
+    }
+
+    /// Sets the function called with the address of a peer whenever a
+    /// connection with it is established while none was open. By default,
+    /// nothing is notified.
+    ///
+    /// Each kind of send (e.g. [Tunnel::send_acked] or [Tunnel::send_on])
+    /// uses its own connections, but a peer is only notified again once all
+    /// of its connections were closed.
+    ///
+    /// Incoming connections are notified once accepted, with the **sender
+    /// address** of the peer, while outgoing connections are notified once
+    /// dialed, with the **receiver address** of the peer.
+    ///
+    /// The function is called from the task which handles the connection, so
+    /// it should return quickly.
+    pub fn on_peer_connected(
+        mut self,
+        callback: impl Fn(PublicKey) + Send + Sync + 'static,
+    ) -> Self {
+        self.peer_callbacks.connected = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets the function called with the address of a peer and the reason
+    /// whenever a connection with it is closed, whatever closed it. By
+    /// default, nothing is notified.
+    ///
+    /// It is called exactly once for every notification of the function set
+    /// with [TunnelBuilder::on_peer_connected], with the same address, once
+    /// the last connection with the peer is closed. The reason is the one of
+    /// that last connection. Connections closed by this tunnel (e.g. by
+    /// [Tunnel::close]) are reported with [ConnectionError::LocallyClosed].
+    pub fn on_peer_disconnected(
+        mut self,
+        callback: impl Fn(PublicKey, ConnectionError) + Send + Sync + 'static,
+    ) -> Self {
+        self.peer_callbacks.disconnected = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets how many times a dead cached connection (e.g. because the other
+    /// tunnel restarted) is replaced by a fresh one before a send fails.
+    /// Defaults to 1. Use 0 to disable reconnecting.
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Sets the maximum age of outgoing connections. By default, connections
+    /// are kept open until they are closed or fail.
+    ///
+    /// Connections which reach the maximum age are rotated: new sends use a
+    /// new connection, while sends which already use the old one are given a
+    /// few seconds to finish before it is closed with [ROTATED_CLOSE_CODE].
+    /// If there are background sends queued for the peer, a new connection
+    /// is established right away.
+    ///
+    /// **Note:** connections are checked periodically, so they may be rotated
+    /// up to a quarter of the maximum age (at most a minute) late.
+    pub fn max_connection_age(mut self, max_age: Duration) -> Self {
+        self.max_connection_age = Some(max_age);
+        self
+    }
+
+    /// Sets how long an outgoing connection may stay unused before it is
+    /// closed with [IDLE_CLOSE_CODE]. By default, connections are kept open
+    /// until they are closed or fail.
+    ///
+    /// Connections are never evicted while a send uses them, and a send
+    /// which picked a connection right as it was evicted reconnects (see
+    /// [TunnelBuilder::max_reconnect_attempts]). The disconnect callback is
+    /// notified of evicted connections like of any other.
+    ///
+    /// **Note:** connections are checked periodically, so they may be closed
+    /// up to a quarter of the idle timeout (at most a minute) late.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.cache_limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum amount of cached outgoing connections. By default,
+    /// there is no limit.
+    ///
+    /// When a new connection exceeds the limit, the least recently used
+    /// connections which are not in use are closed with [IDLE_CLOSE_CODE].
+    /// As connections in use are never evicted, the limit may be exceeded
+    /// while many sends are in progress.
+    pub fn max_cached_connections(mut self, max: usize) -> Self {
+        self.cache_limits.max_connections = Some(max);
+        self
+    }
+
+    /// Creates a new tunnel using the configuration of this builder.
+    ///
+    /// If the creation fails, the returned error identifies the failing
+    /// [SetupStage]. Endpoints bound before the failure are closed.
+    pub async fn spawn(self) -> Result<Tunnel, TunnelError> {
+        if self.dial.alpn.is_empty() {
+            return Err(TunnelError::EmptyAlpn);
+        }
+
+        let shared_endpoint = self.single_endpoint && self.mode == Mode::SendReceive;
+        let relays = self.relay_urls();
+        let config = self.config(&relays);
+
+        let sender = if self.mode.can_send() && !shared_endpoint {
+            let endpoint = bind_endpoint(
+                self.sender_secret_key,
+                self.relay_mode.clone(),
+                self.sender_bind_addr,
+            )
+            .await;
+
+            match endpoint {
+                Ok(endpoint) => Some(endpoint),
+                Err(e) => {
+                    return Err(setup_failed(SetupStage::SenderBind, relays, &[], e).await);
+                }
+            }
+        } else {
+            None
+        };
+
+        let receiver_endpoint = if self.mode.can_receive() {
+            match bind_endpoint(self.secret_key, self.relay_mode.clone(), self.bind_addr).await {
+                Ok(endpoint) => Some(endpoint),
+                Err(e) => {
+                    let bound: Vec<_> = sender.iter().collect();
+                    return Err(setup_failed(SetupStage::ReceiverBind, relays, &bound, e).await);
+                }
+            }
+        } else {
+            None
+        };
+
+        let sender = if shared_endpoint {
+            receiver_endpoint.clone()
+        } else {
+            sender
+        };
+
+        let mut protocol = TunnelProtocol::new();
+
+        protocol.handler = Mutex::new(self.handler);
+        protocol.on_message_too_large = self.on_message_too_large.clone();
+        protocol.on_stream_error = self.on_stream_error;
+        protocol.on_handler_error = self.on_handler_error;
+        protocol.handler_error_policy = self.handler_error_policy;
+        protocol.no_handler_policy = self.no_handler_policy;
+        protocol.max_concurrent_streams = if self.ordered {
+            1
+        } else {
+            self.max_concurrent_streams
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_STREAMS)
+        };
+        protocol.peer_callbacks = self.peer_callbacks.clone();
+        protocol.bi_handler = self.bi_handler;
+        protocol.request_handler = self.request_handler;
+        protocol.transfer_handler = self.transfer_handler;
+
+        if let Some(max_message_size) = self.max_message_size {
+            protocol = protocol.with_max_message_size(max_message_size);
+        }
+
+        if let (Some(policy), Some(endpoint)) = (self.accept_policy, &receiver_endpoint) {
+            protocol = protocol.with_accept_policy(policy, endpoint.clone());
+        }
+
+        let protocol = Arc::new(protocol);
+
+        let receiver = receiver_endpoint.map(|endpoint| {
+            let framed_alpn = framing::framed_alpn(&self.dial.alpn);
+            let acked_alpn = ack::acked_alpn(&self.dial.alpn);
+            let transfer_alpn = transfer::transfer_alpn(&self.dial.alpn);
+            let topic_alpn = topic::topic_alpn(&self.dial.alpn);
+            let request_alpn = request::request_alpn(&self.dial.alpn);
+            let version = self.dial.version.unwrap_or(0);
+
+            Router::builder(endpoint)
+                .accept(self.dial.alpn.clone(), Arc::clone(&protocol))
+                .accept(framed_alpn.clone(), FramedProtocol(Arc::clone(&protocol)))
+                .accept(
+                    version::versioned_alpn(&self.dial.alpn),
+                    VersionedProtocol {
+                        inner: Arc::clone(&protocol),
+                        version,
+                    },
+                )
+                .accept(
+                    version::versioned_alpn(&framed_alpn),
+                    VersionedProtocol {
+                        inner: FramedProtocol(Arc::clone(&protocol)),
+                        version,
+                    },
+                )
+                .accept(acked_alpn.clone(), AckedProtocol(Arc::clone(&protocol)))
+                .accept(
+                    version::versioned_alpn(&acked_alpn),
+                    VersionedProtocol {
+                        inner: AckedProtocol(Arc::clone(&protocol)),
+                        version,
+                    },
+                )
+                .accept(
+                    ping::ping_alpn(&self.dial.alpn),
+                    PingProtocol(Arc::clone(&protocol)),
+                )
+                .accept(
+                    transfer_alpn.clone(),
+                    TransferProtocol(Arc::clone(&protocol)),
+                )
+                .accept(
+                    version::versioned_alpn(&transfer_alpn),
+                    VersionedProtocol {
+                        inner: TransferProtocol(Arc::clone(&protocol)),
+                        version,
+                    },
+                )
+                .accept(topic_alpn.clone(), TopicProtocol(Arc::clone(&protocol)))
+                .accept(
+                    version::versioned_alpn(&topic_alpn),
+                    VersionedProtocol {
+                        inner: TopicProtocol(Arc::clone(&protocol)),
+                        version,
+                    },
+                )
+                .accept(request_alpn.clone(), RequestProtocol(Arc::clone(&protocol)))
+                .accept(
+                    version::versioned_alpn(&request_alpn),
+                    VersionedProtocol {
+                        inner: RequestProtocol(Arc::clone(&protocol)),
+                        version,
+                    },
+                )
+                .spawn()
+        });
+
+        // The router stops accepting right away if its endpoint was closed
+        // while it was being spawned.
+        if let Some(router) = &receiver
+            && router.is_shutdown()
+        {
+            let bound: Vec<_> = sender.iter().chain(Some(router.endpoint())).collect();
+            let source = std::io::Error::other("the router stopped accepting connections");
+
+            return Err(setup_failed(SetupStage::RouterSpawn, relays, &bound, source).await);
+        }
+
+        let acked_dial = DialOptions {
+            alpn: ack::acked_alpn(&self.dial.alpn),
+            framed: false,
+            compression: None,
+            ..self.dial.clone()
+        };
+
+        let transfer_dial = DialOptions {
+            alpn: transfer::transfer_alpn(&self.dial.alpn),
+            framed: false,
+            compression: None,
+            ..self.dial.clone()
+        };
+
+        let topic_dial = DialOptions {
+            alpn: topic::topic_alpn(&self.dial.alpn),
+            framed: false,
+            compression: None,
+            ..self.dial.clone()
+        };
+
+        let request_dial = DialOptions {
+            alpn: request::request_alpn(&self.dial.alpn),
+            framed: false,
+            compression: None,
+            ..self.dial.clone()
+        };
+
+        // Ping connections are never versioned, as pings do not depend on the
+        // application protocol.
+        let ping_dial = DialOptions {
+            alpn: ping::ping_alpn(&self.dial.alpn),
+            framed: false,
+            compression: None,
+            version: None,
+            ..self.dial.clone()
+        };
+
+        let inner = TunnelInner {
+            sender,
+            receiver,
+
+            mode: self.mode,
+            protocol,
+            connections: Arc::new(ConnectionCache::new(
+                self.dial,
+                self.cache_limits,
+                self.peer_callbacks,
+            )),
+            // Peers are only notified of regular connections, so they are
+            // not notified twice.
+            acked_connections: Arc::new(ConnectionCache::new(
+                acked_dial,
+                self.cache_limits,
+                PeerCallbacks::default(),
+            )),
+            ping_connections: Arc::new(ConnectionCache::new(
+                ping_dial,
+                self.cache_limits,
+                PeerCallbacks::default(),
+            )),
+            transfer_connections: Arc::new(ConnectionCache::new(
+                transfer_dial,
+                self.cache_limits,
+                PeerCallbacks::default(),
+            )),
+            topic_connections: Arc::new(ConnectionCache::new(
+                topic_dial,
+                self.cache_limits,
+                PeerCallbacks::default(),
+            )),
+            request_connections: Arc::new(ConnectionCache::new(
+                request_dial,
+                self.cache_limits,
+                PeerCallbacks::default(),
+            )),
+            pending: Arc::new(PendingSends::default()),
+            tasks: Arc::clone(&protocol.tasks),
+            request_timeout: self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            send_timeout: self.send_timeout,
+            ack_timeout: self.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT),
+            chunk_size: self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            scheduler: Scheduler::default(),
+            max_reconnect_attempts: self
+                .max_reconnect_attempts
+                .unwrap_or(DEFAULT_MAX_RECONNECT_ATTEMPTS),
+            relays: !matches!(self.relay_mode, Some(RelayMode::Disabled)),
+            config,
+            shut_down: AtomicBool::new(false),
+        };
+
+        if let (Some(max_age), Some(sender)) = (self.max_connection_age, &inner.sender) {
+            inner.tasks.spawn(rotate_connections(
+                sender.clone(),
+                Arc::clone(&inner.connections),
+                Arc::clone(&inner.pending),
+                max_age,
+            ));
+        }
+
+        if let Some(idle_timeout) = self.cache_limits.idle_timeout {
+            for connections in [
+                &inner.connections,
+                &inner.acked_connections,
+                &inner.ping_connections,
+                &inner.transfer_connections,
+                &inner.topic_connections,
+                &inner.request_connections,
+            ] {
+                inner.tasks.spawn(evict_idle_connections(
+                    Arc::clone(connections),
+                    idle_timeout,
+                ));
+            }
+        }
+
+        if let NoHandlerPolicy::Buffer { .. } = self.no_handler_policy {
+            inner
+                .tasks
+                .spawn(unhandled::deliver_backlog(Arc::clone(&inner.protocol)));
+        }
+
+        if let (Some((interval, max_missed)), Some(sender)) = (self.keep_alive, &inner.sender) {
+            inner.tasks.spawn(ping::keep_alive(
+                sender.clone(),
+                Arc::clone(&inner.connections),
+                Arc::clone(&inner.ping_connections),
+                interval,
+                max_missed,
+            ));
+        }
+
+        let tunnel = Tunnel {
+            inner: Arc::new(inner),
+        };
+
+        if !self.skip_online_wait
+            && let Err(e) = tunnel.wait_online(self.online_timeout).await
+        {
+            let bound: Vec<_> = tunnel
+                .inner
+                .sender
+                .iter()
+                .chain(tunnel.inner.receiver.as_ref().map(Router::endpoint))
+                .collect();
+
+            let error = setup_failed(SetupStage::Discovery, relays, &bound, e).await;
+            let _ = tunnel.destroy().await;
+
+            return Err(error);
+        }
+
+        Ok(tunnel)
+    }
+
+    /// Returns the configuration reported by [Tunnel::debug_dump]. Secret keys
+    /// are masked, and callbacks are only reported as set or not.
+    fn config(&self, relays: &[RelayUrl]) -> serde_json::Value {
+        let millis = |duration: Option<Duration>| duration.map(|d| d.as_millis() as u64);
+        let secret = |key: &Option<SecretKey>| key.as_ref().map(|_| "<redacted>");
+
+        json!({
+            "mode": format!("{:?}", self.mode),
+            "single_endpoint": self.single_endpoint,
+            "secret_key": secret(&self.secret_key),
+            "sender_secret_key": secret(&self.sender_secret_key),
+            "relays": relays.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "bind_addr": self.bind_addr,
+            "sender_bind_addr": self.sender_bind_addr,
+            "wait_online": !self.skip_online_wait,
+            "online_timeout_ms": millis(self.online_timeout),
+            "alpn": String::from_utf8_lossy(&self.dial.alpn),
+            "framed": self.dial.framed,
+            "compression": self.dial.compression.map(|c| format!("{c:?}")),
+            "compression_threshold": self.dial.compression_threshold,
+            "protocol_version": self.dial.version,
+            "connect_timeout_ms": millis(self.dial.connect_timeout),
+            "request_timeout_ms": millis(self.request_timeout),
+            "send_timeout_ms": millis(self.send_timeout),
+            "ack_timeout_ms": millis(self.ack_timeout),
+            "keep_alive": self.keep_alive.map(|(interval, max_missed)| json!({
+                "interval_ms": interval.as_millis() as u64,
+                "max_missed": max_missed,
+            })),
+            "max_message_size": self.max_message_size,
+            "max_concurrent_streams": self.max_concurrent_streams,
+            "chunk_size": self.chunk_size,
+            "ordered": self.ordered,
+            "max_connection_age_ms": millis(self.max_connection_age),
+            "idle_timeout_ms": millis(self.cache_limits.idle_timeout),
+            "max_cached_connections": self.cache_limits.max_connections,
+            "max_reconnect_attempts": self.max_reconnect_attempts,
+            "handler_error_policy": format!("{:?}", self.handler_error_policy),
+            "no_handler_policy": format!("{:?}", self.no_handler_policy),
+            "handlers": {
+                "data": self.handler.is_some(),
+                "request": self.request_handler.is_some(),
+                "bi_stream": self.bi_handler.is_some(),
+                "transfer": self.transfer_handler.is_some(),
+                "accept_policy": self.accept_policy.is_some(),
+            },
+        })
+    }
+
+    /// Returns the relay servers the endpoints of the tunnel are set up to
+    /// contact.
+    fn relay_urls(&self) -> Vec<RelayUrl> {
+        self.relay_mode
+            .clone()
+            .unwrap_or(RelayMode::Default)
+            .relay_map()
+            .urls()
+    }
+}
+
+impl Debug for TunnelBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TunnelBuilder").finish()
+    }
+}
+
+/// Keeps track of the background sends which have not completed yet.
+#[derive(Debug, Default)]
+struct PendingSends {
+    counts: ConnMap<PublicKey, usize>,
+    notify: Notify,
+}
+
+impl PendingSends {
+    /// Counts a background send to `address` until the returned guard is
+    /// dropped.
+    fn start(this: &Arc<Self>, address: PublicKey) -> PendingSend {
+        this.counts.update(address, 0, |count| *count += 1);
+
+        PendingSend {
+            sends: Arc::clone(this),
+            address,
+        }
+    }
+
+    fn remove(&self, address: PublicKey) {
+        self.counts.remove_if(&address, |count| {
+            *count -= 1;
+            *count == 0
+        });
+
+        self.notify.notify_waiters();
+    }
+
+    fn count(&self, address: Option<PublicKey>) -> usize {
+        match address {
+            Some(address) => self.counts.get(&address).unwrap_or(0),
+            None => self.counts.values().iter().sum(),
+        }
+    }
+
+    async fn wait(&self, address: Option<PublicKey>) {
+        loop {
+            let notified = self.notify.notified();
+
+            if self.count(address) == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+/// A guard returned by [PendingSends::start].
+struct PendingSend {
+    sends: Arc<PendingSends>,
+    address: PublicKey,
+}
+
+impl Drop for PendingSend {
+    fn drop(&mut self) {
+        self.sends.remove(self.address);
+    }
+}
+
+/// Binds an endpoint with the provided secret key, or a random one.
+async fn bind_endpoint(
+    secret_key: Option<SecretKey>,
+    relay_mode: Option<RelayMode>,
+    bind_addr: Option<SocketAddrV4>,
+) -> Result<Endpoint, BindError> {
+    let mut builder = Endpoint::builder();
+
+    if let Some(secret_key) = secret_key {
+        builder = builder.secret_key(secret_key);
+    }
+
+    if let Some(relay_mode) = relay_mode {
+        builder = builder.relay_mode(relay_mode);
+    }
+
+    if let Some(bind_addr) = bind_addr {
+        builder = builder.bind_addr_v4(bind_addr);
+    }
+
+    builder.bind().await
+}
+
+/// Builds the error returned when the creation of a tunnel fails at `stage`,
+/// after closing the endpoints which were already bound.
+async fn setup_failed(
+    stage: SetupStage,
+    relays: Vec<RelayUrl>,
+    bound: &[&Endpoint],
+    source: impl Into<Box<dyn Error + Send + Sync>>,
+) -> TunnelError {
+    let mut bound_sockets = Vec::new();
+
+    for (i, endpoint) in bound.iter().enumerate() {
+        // A shared endpoint is both the sender and the receiver endpoint.
+        if bound[..i].iter().any(|other| other.id() == endpoint.id()) {
+            continue;
+        }
+
+        bound_sockets.extend(endpoint.bound_sockets());
+        endpoint.close().await;
+    }
+
+    TunnelError::Setup {
+        stage,
+        bound_sockets,
+        relays,
+        source: source.into(),
+    }
+}
+
+async fn connection(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    addr: impl Into<EndpointAddr>,
+) -> Result<CachedConn, TunnelError> {
+    let addr = addr.into();
+    let address = addr.id;
+
+    if let Some(cached) = connections.get(&address) {
+        return Ok(cached);
+    }
+
+    let dial = connections.dial_options();
+
+    // The version is negotiated within the connect timeout, as the connection
+    // cannot be used before.
+    let connect = async {
+        let connection = sender
+            .connect(addr, &dial.connect_alpn())
+            .await
+            .map_err(|source| TunnelError::Connect {
+                peer: address,
+                source,
+            })?;
+
+        if let Some(version) = dial.version {
+            version::negotiate(address, &connection, version).await?;
+        }
+
+        Ok(connection)
+    };
+
+    let connection = match dial.connect_timeout {
+        Some(timeout) => n0_future::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| TunnelError::ConnectTimeout {
+                peer: address,
+                timeout,
+            })?,
+        None => connect.await,
+    }?;
+
+    let (cached, inserted) = connections.insert_or_get(address, connection.clone(), dial.version);
+
+    // Another task connected to the same address in the meantime.
+    if !inserted {
+        connection.close(0u32.into(), b"duplicate");
+    }
+
+    if connections
+        .limits()
+        .max_connections
+        .is_some_and(|max| connections.len() > max)
+    {
+        // The new connection is about to be used, so it is never evicted.
+        evict_connections(connections, Some(address));
+    }
+
+    Ok(cached)
+}
+
+/// Opens a stream to another tunnel using `open`, reconnecting if the cached
+/// connection turns out to be dead.
+///
+/// A dead connection is evicted from the cache, and a fresh one is dialed up
+/// to `max_reconnect_attempts` times before the error is returned. Failing to
+/// dial is not retried.
+async fn open_stream<S, F, Fut>(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+    open: F,
+) -> Result<(CachedConn, S), TunnelError>
+where
+    F: Fn(Connection) -> Fut,
+    Fut: Future<Output = Result<S, ConnectionError>>,
+{
+    let mut attempts = 0;
+
+    loop {
+        let cached = connection(sender, connections, address).await?;
+
+        let error = match cached.conn.close_reason() {
+            Some(reason) => reason,
+            None => match open(cached.conn.clone()).await {
+                Ok(stream) => return Ok((cached, stream)),
+                Err(error) => error,
+            },
+        };
+
+        connections.remove_if_same(&address, cached.generation);
+
+        let error = connection_error(address, error);
+
+        // A refused connection would be refused again.
+        if attempts >= max_reconnect_attempts
+            || matches!(
+                error,
+                TunnelError::Refused { .. } | TunnelError::NoHandler { .. }
+            )
+        {
+            return Err(error);
+        }
+
+        attempts += 1;
+    }
+}
+
+/// Sends a message made of the concatenation of `data` to another tunnel.
+async fn send_data(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+    data: &[Bytes],
+) -> Result<(), TunnelError> {
+    if connections.dial_options().framed {
+        return send_frame(sender, connections, max_reconnect_attempts, address, data).await;
+    }
+
+    let kind = match connections.dial_options().compression {
+        Some(_) => framing::COMPRESSED_MESSAGE_STREAM,
+        None => framing::MESSAGE_STREAM,
+    };
+
+    let (cached, mut stream) =
+        open_message_stream(sender, connections, max_reconnect_attempts, address, kind).await?;
+
+    let _in_flight = cached.track();
+
+    // Handing the buffers over avoids copying them into the stream.
+    stream
+        .write_all_chunks(&mut data.to_vec())
+        .await
+        .map_err(|e| write_error(address, e))?;
+
+    finish_stream(stream, address).await
+}
+
+/// Sends data with [send_data], giving up once `timeout` expires if provided.
+async fn send_data_timeout(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+    data: &[Bytes],
+    timeout: Option<Duration>,
+) -> Result<(), TunnelError> {
+    let Some(timeout) = timeout else {
+        return send_data(sender, connections, max_reconnect_attempts, address, data).await;
+    };
+
+    // The connection is looked up first, so a timeout only evicts the
+    // connection this send used, and never one which replaced it since.
+    let mut generation = None;
+
+    let send = async {
+        generation = Some(connection(sender, connections, address).await?.generation);
+        send_data(sender, connections, max_reconnect_attempts, address, data).await
+    };
+
+    let Ok(result) = n0_future::time::timeout(timeout, send).await else {
+        // The connection may be stalled, so the next send dials a new one.
+        // Sends which still use it keep it open until they complete.
+        if let Some(generation) = generation {
+            connections.remove_if_same(&address, generation);
+        }
+
+        return Err(TunnelError::SendTimeout {
+            peer: address,
+            timeout,
+        });
+    };
+
+    result
+}
+
+/// Opens a unidirectional stream to another tunnel, which carries a single
+/// message. `kind` is only written if the connection is framed, see
+/// [framing::MESSAGE_STREAM].
+async fn open_message_stream(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+    kind: u8,
+) -> Result<(CachedConn, SendStream), TunnelError> {
+    let (cached, mut stream) = open_stream(
+        sender,
+        connections,
+        max_reconnect_attempts,
+        address,
+        |conn| async move { conn.open_uni().await },
+    )
+    .await?;
+
+    // Streams of framed connections start with their kind.
+    if connections.dial_options().has_stream_kinds() {
+        stream
+            .write_all(&[kind])
+            .await
+            .map_err(|e| write_error(address, e))?;
+    }
+
+    Ok((cached, stream))
+}
+
+/// Sends data as a frame over the framed stream of the connection to another
+/// tunnel. The stream is opened on first use, and opened again if it broke.
+async fn send_frame(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+    data: &[Bytes],
+) -> Result<(), TunnelError> {
+    let size: usize = data.iter().map(Bytes::len).sum();
+
+    if size > framing::MAX_FRAME_SIZE {
+        return Err(TunnelError::FrameTooLarge { size });
+    }
+
+    let cached = connection(sender, connections, address).await?;
+    let _in_flight = cached.track();
+
+    // Held until the frame is written, so frames are never interleaved.
+    let mut frames = cached.frames.lock().await;
+
+    if let Some(stream) = frames.as_mut() {
+        match framing::write_frame(stream, data).await {
+            Ok(()) => return Ok(()),
+            Err(WriteError::Stopped(code))
+                if code.into_inner() == u64::from(MESSAGE_TOO_LARGE_CODE) =>
+            {
+                *frames = None;
+                return Err(TunnelError::MessageTooLarge { peer: address });
+            }
+            // The receiver discards the partial frame along with the stream.
+            Err(_) => {
+                let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+                *frames = None;
+            }
+        }
+    }
+
+    let (fresh, mut stream) = open_stream(
+        sender,
+        connections,
+        max_reconnect_attempts,
+        address,
+        |conn| async move { conn.open_uni().await },
+    )
+    .await?;
+
+    let _fresh_in_flight = fresh.track();
+
+    let kind = match connections.dial_options().compression {
+        Some(_) => framing::COMPRESSED_FRAMED_STREAM,
+        None => framing::FRAMED_STREAM,
+    };
+
+    stream
+        .write_all(&[kind])
+        .await
+        .map_err(|e| write_error(address, e))?;
+
+    framing::write_frame(&mut stream, data)
+        .await
+        .map_err(|e| write_error(address, e))?;
+
+    if fresh.generation == cached.generation {
+        *frames = Some(stream);
+    } else {
+        // The connection was replaced, so the stream belongs to the new one.
+        let mut fresh_frames = fresh.frames.lock().await;
+
+        if fresh_frames.is_none() {
+            *fresh_frames = Some(stream);
+        } else {
+            let _ = stream.finish();
+        }
+    }
+
+    Ok(())
+}
+
+/// Closes and removes the cached connections which exceed the limits of the
+/// cache, except the one to `keep`. See [ConnectionCache::evictable].
+fn evict_connections(connections: &ConnectionCache, keep: Option<PublicKey>) {
+    for (address, cached) in connections.evictable() {
+        if Some(address) == keep {
+            continue;
+        }
+
+        // A send may have picked the connection in the meantime, in which
+        // case it reconnects once the connection is closed.
+        if connections
+            .remove_if_same(&address, cached.generation)
+            .is_some()
+        {
+            cached.conn.close(IDLE_CLOSE_CODE.into(), b"idle");
+        }
+    }
+}
+
+/// Periodically evicts the cached connections which were not used for
+/// `idle_timeout`. See [TunnelBuilder::idle_timeout].
+async fn evict_idle_connections(connections: Arc<ConnectionCache>, idle_timeout: Duration) {
+    let interval = (idle_timeout / 4).clamp(ROTATION_POLL_INTERVAL, ROTATION_MAX_CHECK_INTERVAL);
+
+    loop {
+        n0_future::time::sleep(interval).await;
+        evict_connections(&connections, None);
+    }
+}
+
+/// Periodically rotates the cached connections which are older than
+/// `max_age`. See [TunnelBuilder::max_connection_age].
+async fn rotate_connections(
+    sender: Endpoint,
+    connections: Arc<ConnectionCache>,
+    pending: Arc<PendingSends>,
+    max_age: Duration,
+) {
+    let interval = (max_age / 4).clamp(ROTATION_POLL_INTERVAL, ROTATION_MAX_CHECK_INTERVAL);
+
+    loop {
+        n0_future::time::sleep(interval).await;
+
+        let rotations = connections
+            .older_than(max_age)
+            .into_iter()
+            .map(|(address, cached)| {
+                rotate_connection(&sender, &connections, &pending, address, cached)
+            });
+
+        join_all(rotations).await;
+    }
+}
+
+async fn rotate_connection(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    pending: &PendingSends,
+    address: PublicKey,
+    cached: CachedConn,
+) {
+    // New sends use a new connection from now on.
+    if connections
+        .remove_if_same(&address, cached.generation)
+        .is_none()
+    {
+        return;
+    }
+
+    // Sends which already use the old connection are allowed to finish.
+    let started = Instant::now();
+
+    loop {
+        n0_future::time::sleep(ROTATION_POLL_INTERVAL).await;
+
+        if cached.is_idle() || started.elapsed() >= ROTATION_GRACE {
+            break;
+        }
+    }
+
+    cached.conn.close(ROTATED_CLOSE_CODE.into(), b"rotated");
+
+    if pending.count(Some(address)) > 0 {
+        let _ = connection(sender, connections, address).await;
+    }
+}
+
+/// Finishes a stream and waits for the receiver to acknowledge it.
+async fn finish_stream(mut stream: SendStream, peer: PublicKey) -> Result<(), TunnelError> {
+    stream.finish().map_err(|e| write_error(peer, e.into()))?;
+
+    match stream.stopped().await {
+        Ok(None) => Ok(()),
+        Ok(Some(code)) => Err(write_error(peer, WriteError::Stopped(code))),
+        Err(e) => Err(write_error(peer, e.into())),
+    }
+}
+
+/// Converts an error which happened while writing to a stream read by `peer`.
+fn write_error(peer: PublicKey, error: WriteError) -> TunnelError {
+    match error {
+        WriteError::Stopped(code) if code.into_inner() == u64::from(MESSAGE_TOO_LARGE_CODE) => {
+            TunnelError::MessageTooLarge { peer }
+        }
+        WriteError::Stopped(code) if code.into_inner() == u64::from(NO_HANDLER_CLOSE_CODE) => {
+            TunnelError::NoHandler { peer }
+        }
+        WriteError::Stopped(code) => TunnelError::RemoteStopped {
+            peer,
+            code: code.into_inner(),
+        },
+        WriteError::ConnectionLost(source) => connection_error(peer, source),
+        source => TunnelError::Write { peer, source },
+    }
+}
+
+/// Converts an error which happened while reading data from `peer`.
+fn read_error(peer: PublicKey, error: ReadError) -> TunnelError {
+    match error {
+        ReadError::Reset(code) => TunnelError::RemoteStopped {
+            peer,
+            code: code.into_inner(),
+        },
+        ReadError::ConnectionLost(source) => connection_error(peer, source),
+        source => TunnelError::Read { peer, source },
+    }
+}
+
+/// Calls a [DataHandler], treating a panic as an error.
+async fn call_handler(
+    handler: &RwLock<dyn DataHandler>,
+    sender: PublicKey,
+    data: Bytes,
+    datagram: bool,
+) -> Result<(), HandlerError> {
+    let mut handler = handler.write().await;
+
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        if datagram {
+            handler.process_incoming_datagram(sender, data)
+        } else {
+            handler.process_incoming_data(sender, data)
+        }
+    }))
+    .unwrap_or_else(|_| Err(HandlerError::panicked()))
+}
+
+/// Calls an [AsyncDataHandler], treating a panic as an error.
+async fn call_async_handler(
+    handler: &dyn BoxedAsyncDataHandler,
+    sender: PublicKey,
+    data: Bytes,
+    datagram: bool,
+) -> Result<(), HandlerError> {
+    // Creating the future may already call the handler, so it is caught too.
+    let call = async move {
+        if datagram {
+            handler.process_incoming_datagram_boxed(sender, data).await
+        } else {
+            handler.process_incoming_data_boxed(sender, data).await
+        }
+    };
+
+    AssertUnwindSafe(call)
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(HandlerError::panicked()))
+}
+
+/// Converts the reason the connection to `peer` was lost, identifying
+/// connections refused by the [AcceptPolicy] of the other tunnel, or as it
+/// has no handler.
+fn connection_error(peer: PublicKey, error: ConnectionError) -> TunnelError {
+    match &error {
+        ConnectionError::ApplicationClosed(close)
+            if close.error_code.into_inner() == u64::from(REFUSED_CLOSE_CODE) =>
+        {
+            TunnelError::Refused { peer }
+        }
+        ConnectionError::ApplicationClosed(close)
+            if close.error_code.into_inner() == u64::from(NO_HANDLER_CLOSE_CODE) =>
+        {
+            TunnelError::NoHandler { peer }
+        }
+        _ => TunnelError::ConnectionLost {
+            peer,
+            source: error,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,110 @@
+use std::{fmt::Debug, sync::Arc};
+
+use iroh::endpoint::{Connection, ConnectionError};
+
+use crate::{PublicKey, map::ConnMap};
+
+type ConnectedCallback = Arc<dyn Fn(PublicKey) + Send + Sync>;
+type DisconnectedCallback = Arc<dyn Fn(PublicKey, ConnectionError) + Send + Sync>;
+
+/// The callbacks notified when connections of a tunnel are established and
+/// closed, set with [TunnelBuilder::on_peer_connected](crate::TunnelBuilder::on_peer_connected)
+/// and [TunnelBuilder::on_peer_disconnected](crate::TunnelBuilder::on_peer_disconnected).
+///
+/// A tunnel uses a separate connection per kind of send, so a peer is only
+/// notified as connected when its first connection is established, and as
+/// disconnected when its last one is closed.
+#[derive(Clone, Default)]
+pub(crate) struct PeerCallbacks {
+    pub connected: Option<ConnectedCallback>,
+    pub disconnected: Option<DisconnectedCallback>,
+    /// How many connections with each peer are open, shared by the clones.
+    connections: Arc<ConnMap<PublicKey, usize>>,
+}
+
+impl PeerCallbacks {
+    /// Notifies that an incoming connection is being handled. The returned
+    /// guard notifies that it was closed once dropped, so it does so exactly
+    /// once however the handling of the connection ends.
+    pub fn incoming(&self, conn: &Connection) -> DisconnectGuard {
+        self.opened(conn.remote_id());
+
+        DisconnectGuard {
+            conn: conn.clone(),
+            callbacks: self.clone(),
+        }
+    }
+
+    /// Notifies that an outgoing connection was established, and that it was
+    /// closed once it is, whatever closes it.
+    pub fn outgoing(&self, address: PublicKey, conn: &Connection) {
+        if self.connected.is_none() && self.disconnected.is_none() {
+            return;
+        }
+
+        self.opened(address);
+
+        let callbacks = self.clone();
+        let conn = conn.clone();
+
+        n0_future::task::spawn(async move {
+            callbacks.closed(address, conn.closed().await);
+        });
+    }
+
+    /// Counts a new connection with `address`, notifying it as connected if
+    /// it is the only one.
+    fn opened(&self, address: PublicKey) {
+        let mut first = false;
+
+        self.connections.update(address, 0, |count| {
+            first = *count == 0;
+            *count += 1;
+        });
+
+        if first && let Some(connected) = &self.connected {
+            connected(address);
+        }
+    }
+
+    /// Counts a closed connection with `address`, notifying it as
+    /// disconnected if it was the last one.
+    fn closed(&self, address: PublicKey, reason: ConnectionError) {
+        let last = self
+            .connections
+            .remove_if(&address, |count| {
+                *count -= 1;
+                *count == 0
+            })
+            .is_some();
+
+        if last && let Some(disconnected) = &self.disconnected {
+            disconnected(address, reason);
+        }
+    }
+}
+
+impl Debug for PeerCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerCallbacks").finish()
+    }
+}
+
+/// A guard returned by [PeerCallbacks::incoming].
+pub(crate) struct DisconnectGuard {
+    conn: Connection,
+    callbacks: PeerCallbacks,
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        // The handling of the connection may be aborted while the connection
+        // is still open, e.g. when the tunnel is destroyed.
+        let reason = self
+            .conn
+            .close_reason()
+            .unwrap_or(ConnectionError::LocallyClosed);
+
+        self.callbacks.closed(self.conn.remote_id(), reason);
+    }
+}
@@ -0,0 +1,377 @@
+//! The concurrent map used for the per-peer state of a tunnel.
+//!
+//! By default, this is backed by a [DashMap](dashmap::DashMap). With the
+//! `small-map` feature, it is instead backed by a single `RwLock<HashMap>`,
+//! which has less overhead when a tunnel only talks to a few peers.
+
+#[cfg(not(any(feature = "dashmap", feature = "small-map")))]
+compile_error!("Either the `dashmap` or the `small-map` feature must be enabled.");
+
+#[cfg(all(feature = "dashmap", not(feature = "small-map")))]
+pub(crate) use sharded::ConnMap;
+
+#[cfg(feature = "small-map")]
+pub(crate) use small::ConnMap;
+
+#[cfg(all(feature = "dashmap", not(feature = "small-map")))]
+mod sharded {
+    use std::hash::Hash;
+
+    use dashmap::{DashMap, mapref::entry::Entry};
+
+    #[derive(Debug)]
+    pub(crate) struct ConnMap<K: Eq + Hash, V>(DashMap<K, V>);
+
+    impl<K: Eq + Hash + Clone, V: Clone> ConnMap<K, V> {
+        pub fn get(&self, key: &K) -> Option<V> {
+            self.0.get(key).map(|value| value.clone())
+        }
+
+        /// Inserts a value unless the key is already present.
+        ///
+        /// Returns the value which ends up in the map and whether it was
+        /// inserted by this call.
+        pub fn get_or_insert_with(&self, key: K, value: impl FnOnce() -> V) -> (V, bool) {
+            match self.0.entry(key) {
+                Entry::Occupied(entry) => (entry.get().clone(), false),
+                Entry::Vacant(entry) => (entry.insert(value()).clone(), true),
+            }
+        }
+
+        /// Modifies the value of a key, inserting `default` first if the key
+        /// is not present.
+        pub fn update(&self, key: K, default: V, f: impl FnOnce(&mut V)) {
+            f(&mut self.0.entry(key).or_insert(default));
+        }
+
+        pub fn remove(&self, key: &K) -> Option<V> {
+            self.0.remove(key).map(|(_, value)| value)
+        }
+
+        /// Modifies the value of a key, then removes it if `f` returns `true`.
+        pub fn remove_if(&self, key: &K, f: impl FnOnce(&mut V) -> bool) -> Option<V> {
+            self.0
+                .remove_if_mut(key, |_, value| f(value))
+                .map(|(_, value)| value)
+        }
+
+        pub fn keys(&self) -> Vec<K> {
+            self.0.iter().map(|entry| entry.key().clone()).collect()
+        }
+
+        pub fn values(&self) -> Vec<V> {
+            self.0.iter().map(|entry| entry.value().clone()).collect()
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        pub fn entries(&self) -> Vec<(K, V)> {
+            self.0
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect()
+        }
+    }
+
+    impl<K: Eq + Hash, V> Default for ConnMap<K, V> {
+        fn default() -> Self {
+            Self(DashMap::new())
+        }
+    }
+}
+
+#[cfg(feature = "small-map")]
+mod small {
+    use std::{collections::HashMap, hash::Hash, sync::RwLock};
+
+    #[derive(Debug)]
+    pub(crate) struct ConnMap<K, V>(RwLock<HashMap<K, V>>);
+
+    impl<K: Eq + Hash + Clone, V: Clone> ConnMap<K, V> {
+        pub fn get(&self, key: &K) -> Option<V> {
+            self.0.read().unwrap().get(key).cloned()
+        }
+
+        /// Inserts a value unless the key is already present.
+        ///
+        /// Returns the value which ends up in the map and whether it was
+        /// inserted by this call.
+        pub fn get_or_insert_with(&self, key: K, value: impl FnOnce() -> V) -> (V, bool) {
+            let mut map = self.0.write().unwrap();
+
+            match map.get(&key) {
+                Some(existing) => (existing.clone(), false),
+                None => {
+                    let value = value();
+                    map.insert(key, value.clone());
+                    (value, true)
+                }
+            }
+        }
+
+        /// Modifies the value of a key, inserting `default` first if the key
+        /// is not present.
+        pub fn update(&self, key: K, default: V, f: impl FnOnce(&mut V)) {
+            f(self.0.write().unwrap().entry(key).or_insert(default));
+        }
+
+        pub fn remove(&self, key: &K) -> Option<V> {
+            self.0.write().unwrap().remove(key)
+        }
+
+        /// Modifies the value of a key, then removes it if `f` returns `true`.
+        pub fn remove_if(&self, key: &K, f: impl FnOnce(&mut V) -> bool) -> Option<V> {
+            let mut map = self.0.write().unwrap();
+
+            let remove = match map.get_mut(key) {
+                Some(value) => f(value),
+                None => false,
+            };
+
+            if remove { map.remove(key) } else { None }
+        }
+
+        pub fn keys(&self) -> Vec<K> {
+            self.0.read().unwrap().keys().cloned().collect()
+        }
+
+        pub fn values(&self) -> Vec<V> {
+            self.0.read().unwrap().values().cloned().collect()
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.read().unwrap().len()
+        }
+
+        pub fn entries(&self) -> Vec<(K, V)> {
+            self.0
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }
+    }
+
+    impl<K, V> Default for ConnMap<K, V> {
+        fn default() -> Self {
+            Self(RwLock::new(HashMap::new()))
+        }
+    }
+}
+
+/// Runs against whichever implementation is enabled, so both pass the same
+/// suite: `cargo test` covers the `dashmap` one, and
+/// `cargo test --no-default-features --features small-map` the other.
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Instant,
+    };
+
+    use super::ConnMap;
+
+    const THREADS: usize = 8;
+    const KEYS: usize = 1000;
+
+    #[test]
+    fn get_or_insert_with_keeps_the_first_value() {
+        let map = ConnMap::default();
+
+        assert_eq!(map.get_or_insert_with(1, || "first"), ("first", true));
+        assert_eq!(map.get_or_insert_with(1, || "second"), ("first", false));
+        assert_eq!(map.get(&1), Some("first"));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn update_inserts_the_default_first() {
+        let map = ConnMap::default();
+
+        map.update(1, 10, |value| *value += 1);
+        map.update(1, 10, |value| *value += 1);
+
+        assert_eq!(map.get(&1), Some(12));
+    }
+
+    #[test]
+    fn remove_if_only_removes_when_asked() {
+        let map = ConnMap::default();
+        map.update(1, 2, |_| {});
+
+        let decrement = |value: &mut i32| {
+            *value -= 1;
+            *value == 0
+        };
+
+        assert_eq!(map.remove_if(&1, decrement), None);
+        assert_eq!(map.get(&1), Some(1));
+        assert_eq!(map.remove_if(&1, decrement), Some(0));
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.remove_if(&1, decrement), None);
+        assert_eq!(map.remove(&1), None);
+    }
+
+    #[test]
+    fn iteration_returns_every_entry() {
+        let map = ConnMap::default();
+
+        for key in 0..10 {
+            map.update(key, key * 2, |_| {});
+        }
+
+        let mut keys = map.keys();
+        let mut values = map.values();
+        let mut entries = map.entries();
+        keys.sort();
+        values.sort();
+        entries.sort();
+
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+        assert_eq!(values, (0..10).map(|key| key * 2).collect::<Vec<_>>());
+        assert_eq!(
+            entries,
+            (0..10).map(|key| (key, key * 2)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let map = Arc::new(ConnMap::default());
+        let barrier = Arc::new(Barrier::new(THREADS));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let map = Arc::clone(&map);
+                let barrier = Arc::clone(&barrier);
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    for key in 0..KEYS {
+                        map.update(key, 0, |count| *count += 1);
+                        map.get_or_insert_with(key + KEYS, || 0);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 2 * KEYS);
+        assert!((0..KEYS).all(|key| map.get(&key) == Some(THREADS)));
+    }
+
+    #[test]
+    fn concurrent_removals_happen_once() {
+        let map = Arc::new(ConnMap::default());
+        let barrier = Arc::new(Barrier::new(THREADS));
+
+        for key in 0..KEYS {
+            map.update(key, THREADS, |_| {});
+        }
+
+        // Every thread decrements every key, and iterates meanwhile, so each
+        // key must be removed exactly once, by the last decrement.
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let map = Arc::clone(&map);
+                let barrier = Arc::clone(&barrier);
+
+                thread::spawn(move || {
+                    barrier.wait();
+                    let mut removed = 0;
+
+                    for key in 0..KEYS {
+                        let last = map.remove_if(&key, |count| {
+                            *count -= 1;
+                            *count == 0
+                        });
+                        removed += usize::from(last.is_some());
+
+                        if key % 100 == 0 {
+                            assert!(map.entries().len() <= KEYS);
+                        }
+                    }
+
+                    removed
+                })
+            })
+            .collect();
+
+        let removed: usize = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .sum();
+
+        assert_eq!(removed, KEYS);
+        assert_eq!(map.len(), 0);
+    }
+
+    /// Measures the per-peer operations of a tunnel with `peers` peers, from
+    /// `THREADS` threads at once, and prints how long they took.
+    fn bench(peers: usize) {
+        const ROUNDS: usize = 10_000;
+
+        let map = Arc::new(ConnMap::default());
+        let barrier = Arc::new(Barrier::new(THREADS));
+
+        for peer in 0..peers {
+            map.update(peer, 0u64, |_| {});
+        }
+
+        let start = Instant::now();
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let map = Arc::clone(&map);
+                let barrier = Arc::clone(&barrier);
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    for round in 0..ROUNDS {
+                        let peer = (thread + round) % peers;
+
+                        // Sends look a connection up and update counters,
+                        // while metrics and close_all iterate every peer.
+                        map.get(&peer);
+                        map.update(peer, 0, |count| *count += 1);
+
+                        if round % 100 == 0 {
+                            map.entries();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let per_op = start.elapsed() / (THREADS * ROUNDS) as u32;
+        println!("{peers} peers: {per_op:?} per operation");
+    }
+
+    /// Compare the implementations by running
+    /// `cargo test --release bench -- --ignored --nocapture` with and without
+    /// `--no-default-features --features small-map`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_two_peers() {
+        bench(2);
+    }
+
+    /// See [bench_two_peers].
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_two_hundred_peers() {
+        bench(200);
+    }
+}
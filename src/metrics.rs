@@ -0,0 +1,327 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use n0_future::time::Instant;
+
+use crate::{PublicKey, TunnelError, map::ConnMap};
+
+/// How much data a tunnel sent and received, returned by
+/// [Tunnel::metrics](crate::Tunnel::metrics) and
+/// [Tunnel::metrics_snapshot](crate::Tunnel::metrics_snapshot).
+///
+/// The counters only ever increase, so what happened between two snapshots
+/// is given by [MetricsSnapshot::diff].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Increases with every snapshot taken of the same tunnel, so snapshots
+    /// can be ordered.
+    pub id: u64,
+    /// When the snapshot was taken.
+    pub taken_at: Instant,
+    /// Messages whose receipt was acknowledged by the other tunnel. Messages
+    /// sent with [Tunnel::send_nowait](crate::Tunnel::send_nowait) are counted
+    /// once their background send completes.
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    /// Messages read by this tunnel, whether or not a handler was set to
+    /// process them. Requests and bidirectional streams are not counted.
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Sends which failed, including those which timed out.
+    pub send_errors: u64,
+    /// The size of the messages which were sent compressed, before being
+    /// compressed. See [TunnelBuilder::compression](crate::TunnelBuilder::compression).
+    pub bytes_before_compression: u64,
+    /// The size of the same messages, once compressed.
+    pub bytes_after_compression: u64,
+}
+
+impl MetricsSnapshot {
+    /// Returns what happened between an earlier snapshot of the same tunnel
+    /// and this one.
+    ///
+    /// Every increment is part of exactly one of the deltas between
+    /// consecutive snapshots, so they add up to the totals of the last one.
+    pub fn diff(&self, earlier: &MetricsSnapshot) -> MetricsDelta {
+        MetricsDelta {
+            messages_sent: self.messages_sent.saturating_sub(earlier.messages_sent),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            messages_received: self
+                .messages_received
+                .saturating_sub(earlier.messages_received),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            send_errors: self.send_errors.saturating_sub(earlier.send_errors),
+            bytes_before_compression: self
+                .bytes_before_compression
+                .saturating_sub(earlier.bytes_before_compression),
+            bytes_after_compression: self
+                .bytes_after_compression
+                .saturating_sub(earlier.bytes_after_compression),
+            elapsed: self.taken_at.saturating_duration_since(earlier.taken_at),
+        }
+    }
+}
+
+/// What happened between two [MetricsSnapshot]s, returned by
+/// [MetricsSnapshot::diff]. See [MetricsSnapshot] for the meaning of each
+/// counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetricsDelta {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub send_errors: u64,
+    pub bytes_before_compression: u64,
+    pub bytes_after_compression: u64,
+    /// The time between the two snapshots.
+    pub elapsed: Duration,
+}
+
+/// How much data a tunnel exchanged with another tunnel, returned by
+/// [Tunnel::stats](crate::Tunnel::stats).
+///
+/// The counters accumulate over every connection to the other tunnel,
+/// including those which were re-established, until they are reset with
+/// [Tunnel::take_and_reset_peer_counters](crate::Tunnel::take_and_reset_peer_counters).
+/// Data sent is counted under the **receiver address** of the other tunnel,
+/// and data received under its **sender address**. Both are the same for
+/// tunnels created with [Tunnel::single_endpoint](crate::Tunnel::single_endpoint).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Messages whose receipt was acknowledged by the other tunnel.
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    /// Messages read from the other tunnel, whether or not a handler was set
+    /// to process them.
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Sends to the other tunnel which failed, including those which timed
+    /// out.
+    pub send_errors: u64,
+    /// When data was last sent to or received from the other tunnel.
+    pub last_activity: Instant,
+    /// The current round-trip time of the cached connection to the other
+    /// tunnel. `None` if no connection is cached.
+    pub rtt: Option<Duration>,
+}
+
+/// The counters behind [PeerStats].
+#[derive(Debug)]
+struct PeerCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    send_errors: AtomicU64,
+    /// When the counters were created.
+    created: Instant,
+    /// When data was last exchanged, in milliseconds since `created`.
+    last_activity: AtomicU64,
+}
+
+impl PeerCounters {
+    fn new() -> Self {
+        Self {
+            messages_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+            created: Instant::now(),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> PeerStats {
+        PeerStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            last_activity: self.last_activity(),
+            rtt: None,
+        }
+    }
+
+    /// Returns the counters and resets them to zero. Each counter is swapped
+    /// atomically, so an increment is either returned or kept for the next
+    /// call, and never lost.
+    fn take(&self) -> PeerStats {
+        PeerStats {
+            messages_sent: self.messages_sent.swap(0, Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.swap(0, Ordering::Relaxed),
+            messages_received: self.messages_received.swap(0, Ordering::Relaxed),
+            bytes_received: self.bytes_received.swap(0, Ordering::Relaxed),
+            send_errors: self.send_errors.swap(0, Ordering::Relaxed),
+            last_activity: self.last_activity(),
+            rtt: None,
+        }
+    }
+
+    fn last_activity(&self) -> Instant {
+        self.created + Duration::from_millis(self.last_activity.load(Ordering::Relaxed))
+    }
+}
+
+/// How many of the last send errors are kept for
+/// [Tunnel::debug_dump](crate::Tunnel::debug_dump).
+pub(crate) const ERROR_HISTORY_LEN: usize = 16;
+
+/// A send error kept in the history of [Metrics].
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorEvent {
+    pub peer: PublicKey,
+    /// The error, as displayed. Errors never display the data being sent.
+    pub message: String,
+    pub at: Instant,
+}
+
+/// The counters behind [MetricsSnapshot] and [PeerStats]. Shared by the
+/// sending and receiving sides of a tunnel.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    send_errors: AtomicU64,
+    bytes_before_compression: AtomicU64,
+    bytes_after_compression: AtomicU64,
+    /// How many snapshots were taken, which gives their ids.
+    snapshots: AtomicU64,
+    peers: ConnMap<PublicKey, Arc<PeerCounters>>,
+    /// The last [ERROR_HISTORY_LEN] send errors, oldest first.
+    errors: Mutex<VecDeque<ErrorEvent>>,
+}
+
+impl Metrics {
+    /// Returns the counters of a peer, creating them on its first activity.
+    fn peer(&self, peer: PublicKey) -> Arc<PeerCounters> {
+        // Looked up first, so the map is only locked for writing once per peer.
+        let counters = match self.peers.get(&peer) {
+            Some(counters) => counters,
+            None => {
+                self.peers
+                    .get_or_insert_with(peer, || Arc::new(PeerCounters::new()))
+                    .0
+            }
+        };
+
+        counters.touch();
+        counters
+    }
+
+    /// Records the outcome of sending a message of `bytes` bytes to `peer`.
+    pub fn record_send<T>(&self, peer: PublicKey, bytes: usize, result: &Result<T, TunnelError>) {
+        match result {
+            Ok(_) => {
+                let counters = self.peer(peer);
+
+                self.messages_sent.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+                counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .bytes_sent
+                    .fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            Err(error) => self.record_send_error(peer, error),
+        }
+    }
+
+    pub fn record_send_error(&self, peer: PublicKey, error: &TunnelError) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+        self.peer(peer).send_errors.fetch_add(1, Ordering::Relaxed);
+
+        let mut errors = self.errors.lock().unwrap();
+
+        if errors.len() == ERROR_HISTORY_LEN {
+            errors.pop_front();
+        }
+
+        errors.push_back(ErrorEvent {
+            peer,
+            message: error.to_string(),
+            at: Instant::now(),
+        });
+    }
+
+    /// Returns the last send errors, oldest first.
+    pub fn recent_errors(&self) -> Vec<ErrorEvent> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn record_message_received(&self, peer: PublicKey) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.peer(peer)
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes_received(&self, peer: PublicKey, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.peer(peer)
+            .bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records that a message of `before` bytes was compressed to `after`
+    /// bytes.
+    pub fn record_compression(&self, before: usize, after: usize) {
+        self.bytes_before_compression
+            .fetch_add(before as u64, Ordering::Relaxed);
+        self.bytes_after_compression
+            .fetch_add(after as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the counters of a peer, without its round-trip time. `None` if
+    /// no data was ever exchanged with it.
+    pub fn peer_snapshot(&self, peer: &PublicKey) -> Option<PeerStats> {
+        self.peers.get(peer).map(|counters| counters.snapshot())
+    }
+
+    /// Returns the counters of a peer and resets them, without its round-trip
+    /// time. `None` if no data was ever exchanged with it.
+    ///
+    /// The peer is kept, so increments which race with the reset are counted
+    /// by the next call instead of being lost.
+    pub fn take_peer_counters(&self, peer: &PublicKey) -> Option<PeerStats> {
+        self.peers.get(peer).map(|counters| counters.take())
+    }
+
+    /// Returns the counters of every peer, without their round-trip time.
+    pub fn peer_snapshots(&self) -> Vec<(PublicKey, PeerStats)> {
+        self.peers
+            .entries()
+            .into_iter()
+            .map(|(peer, counters)| (peer, counters.snapshot()))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            id: self.snapshots.fetch_add(1, Ordering::Relaxed),
+            taken_at: Instant::now(),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            bytes_before_compression: self.bytes_before_compression.load(Ordering::Relaxed),
+            bytes_after_compression: self.bytes_after_compression.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use iroh::{
+    Endpoint,
+    endpoint::{Connection, ReadError, ReadToEndError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use n0_future::{join_all, time::Instant};
+
+use crate::{
+    PublicKey, RecvStream, SendStream, TunnelError, TunnelProtocol, UNRESPONSIVE_CLOSE_CODE,
+    cache::ConnectionCache, connection_error, open_stream, write_error,
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of ping connections, whose
+/// bidirectional streams are each answered as soon as they are finished.
+const PING_ALPN_SUFFIX: &[u8] = b"/ping";
+
+/// Returns the ALPN of the ping connections of a tunnel using `alpn`.
+pub(crate) fn ping_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, PING_ALPN_SUFFIX].concat()
+}
+
+/// Pings another tunnel over its ping connection, and returns the round-trip
+/// time of the ping.
+pub(crate) async fn ping(
+    sender: &Endpoint,
+    connections: &ConnectionCache,
+    max_reconnect_attempts: u32,
+    address: PublicKey,
+) -> Result<Duration, TunnelError> {
+    let (_, (send, recv)) = open_stream(
+        sender,
+        connections,
+        max_reconnect_attempts,
+        address,
+        |conn| async move { conn.open_bi().await },
+    )
+    .await?;
+
+    exchange(address, send, recv).await
+}
+
+/// Sends an empty ping over a bidirectional stream, and waits for the other
+/// tunnel to finish its side of the stream.
+async fn exchange(
+    peer: PublicKey,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<Duration, TunnelError> {
+    let start = Instant::now();
+
+    send.finish().map_err(|e| write_error(peer, e.into()))?;
+
+    match recv.read_to_end(0).await {
+        Ok(_) => Ok(start.elapsed()),
+        Err(ReadToEndError::Read(ReadError::ConnectionLost(e))) => Err(connection_error(peer, e)),
+        Err(ReadToEndError::Read(ReadError::Reset(code))) => Err(TunnelError::RemoteStopped {
+            peer,
+            code: code.into_inner(),
+        }),
+        Err(_) => Err(TunnelError::InvalidPong { peer }),
+    }
+}
+
+/// Periodically pings the tunnels which this tunnel has a cached connection
+/// to, and closes the connections to the tunnels which missed `max_missed`
+/// pings in a row. See [TunnelBuilder::keep_alive](crate::TunnelBuilder::keep_alive).
+pub(crate) async fn keep_alive(
+    sender: Endpoint,
+    connections: Arc<ConnectionCache>,
+    pings: Arc<ConnectionCache>,
+    interval: Duration,
+    max_missed: u32,
+) {
+    let mut missed: HashMap<PublicKey, u32> = HashMap::new();
+
+    loop {
+        n0_future::time::sleep(interval).await;
+
+        let peers = connections.generations();
+
+        // A ping which is not answered before the next one is due is missed.
+        let answers = join_all(peers.iter().map(|&(address, _)| {
+            let ping = ping(&sender, &pings, 1, address);
+
+            async move { matches!(n0_future::time::timeout(interval, ping).await, Ok(Ok(_))) }
+        }))
+        .await;
+
+        // Tunnels whose connection is no longer cached start over.
+        missed.retain(|address, _| peers.iter().any(|(peer, _)| peer == address));
+
+        for ((address, generation), answered) in peers.into_iter().zip(answers) {
+            if answered {
+                missed.remove(&address);
+                continue;
+            }
+
+            let count = missed.entry(address).or_default();
+            *count += 1;
+
+            if *count < max_missed {
+                continue;
+            }
+
+            missed.remove(&address);
+
+            // Closing the connection notifies the disconnect callback.
+            if let Some(cached) = connections.remove_if_same(&address, generation) {
+                cached
+                    .conn
+                    .close(UNRESPONSIVE_CLOSE_CODE.into(), b"unresponsive");
+            }
+
+            if let Some(cached) = pings.remove(&address) {
+                cached
+                    .conn
+                    .close(UNRESPONSIVE_CLOSE_CODE.into(), b"unresponsive");
+            }
+        }
+    }
+}
+
+/// Accepts the ping connections of a tunnel, and answers their pings.
+#[derive(Debug)]
+pub(crate) struct PingProtocol(pub Arc<TunnelProtocol>);
+
+impl ProtocolHandler for PingProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        // Pings are not messages, so the peer callbacks are not notified.
+        if !self.0.allows(&connection) {
+            return Ok(());
+        }
+
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            if recv.read_to_end(0).await.is_ok() {
+                let _ = send.finish();
+            }
+        }
+
+        Ok(())
+    }
+}
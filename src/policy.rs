@@ -0,0 +1,231 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use iroh::{Endpoint, Watcher, endpoint::ConnectionType};
+
+use crate::PublicKey;
+
+/// Information about the origin of an incoming connection, which is given to
+/// an [AcceptPolicy] before any stream of the connection is read.
+#[derive(Debug, Clone)]
+pub struct ConnectionOrigin {
+    /// The address of the remote tunnel.
+    pub remote: PublicKey,
+    /// How the remote tunnel is reached, as observed by the receiver endpoint.
+    /// `None` if the receiver endpoint has no information about it.
+    pub conn_type: Option<ConnectionType>,
+}
+
+impl ConnectionOrigin {
+    pub(crate) fn observe(endpoint: &Endpoint, remote: PublicKey) -> Self {
+        Self {
+            remote,
+            conn_type: endpoint
+                .conn_type(remote)
+                .map(|mut conn_type| conn_type.get()),
+        }
+    }
+
+    /// Returns whether the connection only goes through a relay, without any
+    /// direct path.
+    pub fn is_relay_only(&self) -> bool {
+        matches!(self.conn_type, Some(ConnectionType::Relay(_)))
+    }
+}
+
+/// A trait implemented for objects which decide whether a tunnel accepts an
+/// incoming connection.
+///
+/// The policy is called once per connection, before any stream of it is read.
+/// Refused connections are closed right away, so none of their data is ever
+/// buffered.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [ConnectionOrigin] and returns a `bool`
+/// can be used as an [AcceptPolicy].
+pub trait AcceptPolicy: 'static + Send + Sync {
+    fn allow(&self, origin: &ConnectionOrigin) -> bool;
+}
+
+impl<Func> AcceptPolicy for Func
+where
+    Func: 'static + Send + Sync + Fn(&ConnectionOrigin) -> bool,
+{
+    fn allow(&self, origin: &ConnectionOrigin) -> bool {
+        self(origin)
+    }
+}
+
+/// An [AcceptPolicy] which refuses relay-only connections from tunnels which
+/// are not known in advance.
+///
+/// Known tunnels are always accepted, however they connect.
+#[derive(Debug, Clone, Default)]
+pub struct RequireDirectForUnknown {
+    known: HashSet<PublicKey>,
+}
+
+impl RequireDirectForUnknown {
+    /// Creates the policy, given the **sender addresses** of the known tunnels.
+    pub fn new(known: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self {
+            known: known.into_iter().collect(),
+        }
+    }
+}
+
+impl AcceptPolicy for RequireDirectForUnknown {
+    fn allow(&self, origin: &ConnectionOrigin) -> bool {
+        self.known.contains(&origin.remote) || !origin.is_relay_only()
+    }
+}
+
+/// Whether a [PeerFilter] lists the tunnels it accepts or the ones it refuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterMode {
+    Allow,
+    Deny,
+}
+
+/// An [AcceptPolicy] which accepts or refuses tunnels based on a list of their
+/// **sender addresses**, which can be changed while the tunnel is running.
+///
+/// The filter is cheap to clone, and clones share the same list. Keep a clone
+/// around to call [PeerFilter::allow_peer] and [PeerFilter::revoke_peer]
+/// after giving the filter to a tunnel. Changes apply to new connections;
+/// already accepted connections are kept open.
+#[derive(Debug, Clone)]
+pub struct PeerFilter {
+    mode: FilterMode,
+    peers: Arc<RwLock<HashSet<PublicKey>>>,
+}
+
+impl PeerFilter {
+    /// Creates a filter which only accepts the provided tunnels.
+    pub fn allow_list(peers: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self::new(FilterMode::Allow, peers)
+    }
+
+    /// Creates a filter which accepts every tunnel but the provided ones.
+    pub fn deny_list(peers: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self::new(FilterMode::Deny, peers)
+    }
+
+    fn new(mode: FilterMode, peers: impl IntoIterator<Item = PublicKey>) -> Self {
+        Self {
+            mode,
+            peers: Arc::new(RwLock::new(peers.into_iter().collect())),
+        }
+    }
+
+    /// Makes the filter accept a tunnel.
+    pub fn allow_peer(&self, peer: PublicKey) {
+        let mut peers = self.peers.write().unwrap();
+
+        match self.mode {
+            FilterMode::Allow => peers.insert(peer),
+            FilterMode::Deny => peers.remove(&peer),
+        };
+    }
+
+    /// Makes the filter refuse a tunnel.
+    pub fn revoke_peer(&self, peer: PublicKey) {
+        let mut peers = self.peers.write().unwrap();
+
+        match self.mode {
+            FilterMode::Allow => peers.remove(&peer),
+            FilterMode::Deny => peers.insert(peer),
+        };
+    }
+
+    /// Returns whether the filter accepts a tunnel.
+    pub fn is_allowed(&self, peer: &PublicKey) -> bool {
+        let listed = self.peers.read().unwrap().contains(peer);
+
+        match self.mode {
+            FilterMode::Allow => listed,
+            FilterMode::Deny => !listed,
+        }
+    }
+}
+
+impl AcceptPolicy for PeerFilter {
+    fn allow(&self, origin: &ConnectionOrigin) -> bool {
+        self.is_allowed(&origin.remote)
+    }
+}
+
+/// How many incoming connections a tunnel accepted and refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AcceptStats {
+    pub accepted: u64,
+    /// Connections refused by the [AcceptPolicy] of the tunnel.
+    pub refused: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AcceptCounters {
+    accepted: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl AcceptCounters {
+    pub fn record(&self, allowed: bool) {
+        let counter = if allowed {
+            &self.accepted
+        } else {
+            &self.refused
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> AcceptStats {
+        AcceptStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use iroh::endpoint::ConnectionType;
+
+    use super::{AcceptPolicy, ConnectionOrigin, RequireDirectForUnknown};
+    use crate::{RelayUrl, testing};
+
+    fn origin(seed: u8, conn_type: Option<ConnectionType>) -> ConnectionOrigin {
+        ConnectionOrigin {
+            remote: testing::peer(seed),
+            conn_type,
+        }
+    }
+
+    #[test]
+    fn unknown_tunnels_must_connect_directly() {
+        let relay: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let direct = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234));
+        let policy = RequireDirectForUnknown::new([testing::peer(1)]);
+
+        let refused = origin(2, Some(ConnectionType::Relay(relay.clone())));
+        assert!(refused.is_relay_only());
+        assert!(!policy.allow(&refused));
+
+        assert!(policy.allow(&origin(2, Some(ConnectionType::Direct(direct)))));
+        assert!(policy.allow(&origin(
+            2,
+            Some(ConnectionType::Mixed(direct, relay.clone()))
+        )));
+        assert!(policy.allow(&origin(2, None)));
+
+        assert!(policy.allow(&origin(1, Some(ConnectionType::Relay(relay)))));
+    }
+}
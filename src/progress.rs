@@ -0,0 +1,149 @@
+use std::future::Future;
+
+use tokio::sync::watch;
+
+use crate::{PROGRESS_INTERVAL, TunnelError};
+
+/// The progress of a write, updated by the write loop and reported to a
+/// callback by [with_progress].
+///
+/// Updating it only stores the latest values, so the write loop never waits
+/// for the callback.
+pub(crate) struct Progress {
+    tx: watch::Sender<(u64, u64)>,
+}
+
+impl Progress {
+    /// Records that `written` bytes out of `total` were written.
+    pub fn update(&self, written: u64, total: u64) {
+        self.tx.send_replace((written, total));
+    }
+}
+
+/// Runs `write` with a [Progress] which it updates, while `callback` is
+/// called from its own task with the latest update, at most once every
+/// [PROGRESS_INTERVAL]. Updates made while the callback runs are coalesced.
+///
+/// If `write` succeeds, the last update is reported before this returns. If
+/// it fails, the reporting task is stopped right away.
+pub(crate) async fn with_progress<T, F>(
+    callback: impl Fn(u64, u64) + Send + Sync + 'static,
+    write: impl FnOnce(Progress) -> F,
+) -> Result<T, TunnelError>
+where
+    F: Future<Output = Result<T, TunnelError>>,
+{
+    let (tx, mut rx) = watch::channel((0, 0));
+
+    let reporter = n0_future::task::spawn(async move {
+        // Ends once the write is done and its last update was reported.
+        while rx.changed().await.is_ok() {
+            let (written, total) = *rx.borrow_and_update();
+            callback(written, total);
+
+            n0_future::time::sleep(PROGRESS_INTERVAL).await;
+        }
+    });
+
+    let result = write(Progress { tx }).await;
+
+    if result.is_ok() {
+        let _ = reporter.await;
+    } else {
+        reporter.abort();
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{PublicKey, TunnelRecvStream, testing};
+
+    /// Reads incoming messages slowly, so the sender is rate limited by flow
+    /// control.
+    async fn slow_reader(_sender: PublicKey, mut stream: TunnelRecvStream) {
+        while let Ok(Some(_)) = stream.read_chunk().await {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn progress_is_monotonic_and_ends_at_the_payload_size() {
+        let receiver = testing::builder()
+            .streaming_handler(slow_reader)
+            .spawn()
+            .await
+            .unwrap();
+        let sender = testing::builder().spawn().await.unwrap();
+        testing::connect(&sender, &receiver).await;
+
+        let payload = vec![7u8; 4 * 1024 * 1024];
+        let reports = Arc::new(Mutex::new(Vec::new()));
+
+        sender
+            .send_with_progress(receiver.receiver_address().unwrap(), payload.clone(), {
+                let reports = Arc::clone(&reports);
+                move |written, total| reports.lock().unwrap().push((written, total))
+            })
+            .await
+            .unwrap();
+
+        let reports = reports.lock().unwrap();
+        let total = payload.len() as u64;
+
+        assert!(reports.len() > 1, "{reports:?}");
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(reports.iter().all(|&(_, reported)| reported == total));
+        assert_eq!(reports.last(), Some(&(total, total)));
+        assert_eq!(
+            reports
+                .iter()
+                .filter(|(written, _)| *written == total)
+                .count(),
+            1
+        );
+
+        sender.destroy().await.unwrap();
+        receiver.destroy().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_callback_does_not_stall_the_write() {
+        let receiver = testing::builder()
+            .handler(|_: PublicKey, _: Vec<u8>| {})
+            .spawn()
+            .await
+            .unwrap();
+        let sender = testing::builder().chunk_size(1024).spawn().await.unwrap();
+        testing::connect(&sender, &receiver).await;
+
+        let payload = vec![7u8; 1024 * 1024];
+        let reports = Arc::new(Mutex::new(Vec::new()));
+
+        sender
+            .send_with_progress(receiver.receiver_address().unwrap(), payload.clone(), {
+                let reports = Arc::clone(&reports);
+                move |written, _| {
+                    reports.lock().unwrap().push(written);
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+            })
+            .await
+            .unwrap();
+
+        // The write carried on while the first call slept, so its progress
+        // was coalesced into the few calls which followed.
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() <= 3, "{reports:?}");
+        assert_eq!(reports.last(), Some(&(payload.len() as u64)));
+
+        sender.destroy().await.unwrap();
+        receiver.destroy().await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use n0_future::Stream;
+use tokio::sync::mpsc;
+
+use crate::{AsyncDataHandler, Bytes, HandlerError, PublicKey};
+
+/// The incoming data of a tunnel, as a [Stream] of `(sender, data)` pairs.
+/// Returned by [Tunnel::new_with_receiver](crate::Tunnel::new_with_receiver),
+/// [Tunnel::incoming](crate::Tunnel::incoming) and
+/// [TunnelBuilder::receiver](crate::TunnelBuilder::receiver).
+///
+/// The receiver is backed by a bounded channel. While it is full, the tunnel
+/// stops reading from the connection the next message came from, which
+/// eventually makes sends from that peer wait. Once the receiver is dropped,
+/// incoming data is discarded.
+#[derive(Debug)]
+pub struct TunnelReceiver {
+    receiver: mpsc::Receiver<(PublicKey, Bytes)>,
+}
+
+impl TunnelReceiver {
+    /// Waits for the next message.
+    ///
+    /// Returns `None` once the tunnel was destroyed and every message
+    /// received before was returned.
+    pub async fn recv(&mut self) -> Option<(PublicKey, Bytes)> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for TunnelReceiver {
+    type Item = (PublicKey, Bytes);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// The [AsyncDataHandler] which feeds a [TunnelReceiver].
+pub(crate) struct ChannelHandler {
+    sender: mpsc::Sender<(PublicKey, Bytes)>,
+}
+
+impl AsyncDataHandler for ChannelHandler {
+    async fn process_incoming_data(
+        &self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Result<(), HandlerError> {
+        // Fails only if the receiver was dropped, in which case the data is
+        // discarded.
+        let _ = self.sender.send((sender, data)).await;
+        Ok(())
+    }
+}
+
+/// Creates a [TunnelReceiver] and the handler which feeds it.
+pub(crate) fn channel(capacity: usize) -> (ChannelHandler, TunnelReceiver) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    (ChannelHandler { sender }, TunnelReceiver { receiver })
+}
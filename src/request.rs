@@ -0,0 +1,180 @@
+use std::{panic::AssertUnwindSafe, sync::Arc};
+
+use iroh::{
+    endpoint::{Connection, ReadError, ReadToEndError, WriteError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+
+use crate::{
+    Bytes, GOING_AWAY_CLOSE_CODE, HANDLER_ERROR_CODE, HandlerError, INVALID_PAYLOAD_CODE,
+    MESSAGE_TOO_LARGE_CODE, NO_STREAM_HANDLER_CODE, PublicKey, RecvStream, SendStream, TunnelError,
+    TunnelProtocol, read_error, write_error,
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of request connections,
+/// whose bidirectional streams each carry a request and its response.
+const REQUEST_ALPN_SUFFIX: &[u8] = b"/request";
+
+/// Returns the ALPN of the request connections of a tunnel using `alpn`.
+pub(crate) fn request_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, REQUEST_ALPN_SUFFIX].concat()
+}
+
+/// A trait implemented for objects which can answer requests sent with
+/// [Tunnel::request](crate::Tunnel::request).
+///
+/// Each request is handled in its own task, so requests can be answered
+/// concurrently.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [PublicKey] and a `Vec<u8>` in this order
+/// and returns a `Vec<u8>` can be used as a [RequestHandler].
+pub trait RequestHandler: 'static + Send + Sync {
+    fn handle_request(&self, sender: PublicKey, data: Vec<u8>) -> Vec<u8>;
+}
+
+impl<Func> RequestHandler for Func
+where
+    Func: 'static + Send + Sync + Fn(PublicKey, Vec<u8>) -> Vec<u8>,
+{
+    fn handle_request(&self, sender: PublicKey, data: Vec<u8>) -> Vec<u8> {
+        self(sender, data)
+    }
+}
+
+/// Handles request connections, answering each of their bidirectional streams
+/// with the [RequestHandler] of the tunnel.
+///
+/// Requests have their own connections, so a tunnel can have both a
+/// [RequestHandler] and a [BiStreamHandler](crate::BiStreamHandler). Each
+/// request is answered in a task of the tunnel, so it is stopped when the
+/// tunnel is destroyed, and waited for while the tunnel shuts down.
+#[derive(Debug)]
+pub(crate) struct RequestProtocol(pub Arc<TunnelProtocol>);
+
+impl RequestProtocol {
+    fn handle_stream(&self, connection: &Connection, mut send: SendStream, mut recv: RecvStream) {
+        let sender = connection.remote_id();
+
+        // Refused right away, so the other end does not wait for a response
+        // which never comes.
+        let Some(handler) = self.0.request_handler.clone() else {
+            let _ = send.reset(NO_STREAM_HANDLER_CODE.into());
+            let _ = recv.stop(NO_STREAM_HANDLER_CODE.into());
+            return;
+        };
+
+        // Refused once the tunnel started shutting down.
+        let Some(active) = self.0.activity.enter() else {
+            let _ = send.reset(GOING_AWAY_CLOSE_CODE.into());
+            let _ = recv.stop(GOING_AWAY_CLOSE_CODE.into());
+            return;
+        };
+
+        let protocol = Arc::clone(&self.0);
+        let connection = connection.clone();
+
+        self.0.tasks.spawn(async move {
+            let request = match recv.read_to_end(protocol.max_message_size).await {
+                Ok(request) => request,
+                Err(ReadToEndError::TooLong) => {
+                    let _ = recv.stop(MESSAGE_TOO_LARGE_CODE.into());
+                    let _ = send.reset(MESSAGE_TOO_LARGE_CODE.into());
+                    protocol.message_too_large(sender);
+                    return;
+                }
+                // The requester is told the request was not read, rather than
+                // waiting for a response until it times out.
+                Err(ReadToEndError::Read(e)) => {
+                    let _ = send.reset(INVALID_PAYLOAD_CODE.into());
+                    protocol.stream_error(sender, e);
+                    return;
+                }
+            };
+
+            // Like data handlers, a panicking request handler is treated as a
+            // failed one, following the HandlerErrorPolicy of the tunnel.
+            let response = match std::panic::catch_unwind(AssertUnwindSafe(|| {
+                handler.handle_request(sender, request)
+            })) {
+                Ok(response) => response,
+                Err(_) => {
+                    let _ = send.reset(HANDLER_ERROR_CODE.into());
+                    protocol.handler_failed(&connection, Some(&mut recv), HandlerError::panicked());
+                    return;
+                }
+            };
+
+            if send.write_all(&response).await.is_ok() && send.finish().is_ok() {
+                // The tunnel is not idle until the response was read, so
+                // shutting down does not discard it.
+                let _ = send.stopped().await;
+            }
+
+            drop(active);
+        });
+    }
+}
+
+impl ProtocolHandler for RequestProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let Some(_disconnect) = self.0.admit(&connection) else {
+            return Ok(());
+        };
+
+        loop {
+            tokio::select! {
+                streams = connection.accept_bi() => {
+                    let Ok((send, recv)) = streams else {
+                        break;
+                    };
+
+                    self.handle_stream(&connection, send, recv);
+                }
+                _ = self.0.activity.closed() => {
+                    self.0.go_away(&connection).await;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends a request over a bidirectional stream and reads the response.
+pub(crate) async fn exchange(
+    peer: PublicKey,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    data: Bytes,
+    max_message_size: usize,
+) -> Result<Vec<u8>, TunnelError> {
+    match send.write_chunk(data).await {
+        Ok(()) => {}
+        Err(WriteError::Stopped(code))
+            if code.into_inner() == u64::from(NO_STREAM_HANDLER_CODE) =>
+        {
+            return Err(TunnelError::NoRequestHandler { peer });
+        }
+        Err(e) => return Err(write_error(peer, e)),
+    }
+
+    send.finish().map_err(|e| write_error(peer, e.into()))?;
+
+    match recv.read_to_end(max_message_size).await {
+        Ok(response) => Ok(response),
+        Err(ReadToEndError::Read(ReadError::Reset(code)))
+            if code.into_inner() == u64::from(NO_STREAM_HANDLER_CODE) =>
+        {
+            Err(TunnelError::NoRequestHandler { peer })
+        }
+        Err(ReadToEndError::Read(ReadError::Reset(code)))
+            if code.into_inner() == u64::from(MESSAGE_TOO_LARGE_CODE) =>
+        {
+            Err(TunnelError::MessageTooLarge { peer })
+        }
+        Err(ReadToEndError::Read(e)) => Err(read_error(peer, e)),
+        Err(ReadToEndError::TooLong) => Err(TunnelError::ResponseTooLarge { peer }),
+    }
+}
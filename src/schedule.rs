@@ -0,0 +1,327 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use n0_future::time::Instant;
+use tokio::sync::Notify;
+
+use crate::PublicKey;
+
+/// The weight of the peers for which none was set.
+pub(crate) const DEFAULT_PEER_WEIGHT: u32 = 1;
+
+/// How long a turn keeps other writes waiting at most, so a peer which stopped
+/// reading cannot stall the writes to every other peer.
+const MAX_TURN: Duration = Duration::from_millis(100);
+
+/// Scales the cost of writes, so dividing them by large weights keeps some
+/// precision.
+const COST_SCALE: u64 = 1024;
+
+/// Shares the outbound bandwidth of the chunked send paths across peers, in
+/// proportion to their weights.
+///
+/// Every chunk write waits for a turn. Turns are given one at a time, to the
+/// peer with a transfer in progress which was charged the least for its past
+/// writes, a write of `n` bytes costing `n / weight`. A peer which starts a
+/// transfer is charged as much as the last peer given a turn, so being idle
+/// earns no credit.
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    weights: HashMap<PublicKey, u32>,
+    /// The cost charged to each peer for its writes so far.
+    charged: HashMap<PublicKey, u64>,
+    /// How many transfers to each peer are in progress.
+    active: HashMap<PublicKey, usize>,
+    /// The charge of the peer given the last turn, before its write.
+    virtual_time: u64,
+    /// The id of the current turn, and when it was given.
+    current: Option<(u64, Instant)>,
+    next_turn: u64,
+}
+
+impl Scheduler {
+    /// Sets the weight of `peer`. A weight of zero is treated as one.
+    pub fn set_weight(&self, peer: PublicKey, weight: u32) {
+        let mut state = self.state.lock().unwrap();
+
+        match weight.max(1) {
+            DEFAULT_PEER_WEIGHT => state.weights.remove(&peer),
+            weight => state.weights.insert(peer, weight),
+        };
+    }
+
+    /// Returns the weight of `peer`.
+    pub fn weight(&self, peer: &PublicKey) -> u32 {
+        let state = self.state.lock().unwrap();
+        state.weight(peer)
+    }
+
+    /// Registers a transfer to `peer`, whose chunk writes wait for their turn
+    /// through the returned [Lane].
+    pub fn lane(&self, peer: PublicKey) -> Lane<'_> {
+        let mut state = self.state.lock().unwrap();
+
+        let virtual_time = state.virtual_time;
+        let charged = state.charged.entry(peer).or_insert(virtual_time);
+        *charged = (*charged).max(virtual_time);
+
+        *state.active.entry(peer).or_insert(0) += 1;
+
+        Lane {
+            scheduler: self,
+            peer,
+            turn: None,
+        }
+    }
+
+    /// Ends the turn `id`, unless it expired and was replaced by another one.
+    fn end_turn(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.current.is_some_and(|(current, _)| current == id) {
+            state.current = None;
+        }
+
+        drop(state);
+        self.notify.notify_waiters();
+    }
+}
+
+impl State {
+    fn weight(&self, peer: &PublicKey) -> u32 {
+        self.weights
+            .get(peer)
+            .copied()
+            .unwrap_or(DEFAULT_PEER_WEIGHT)
+    }
+
+    /// Gives a turn to `peer` if it is its turn, returning the id of the turn.
+    /// An `overdue` peer only waits for the current turn to end.
+    ///
+    /// Otherwise, returns how long to wait before trying again, unless woken
+    /// up earlier.
+    fn try_take(&mut self, peer: PublicKey, bytes: usize, overdue: bool) -> Result<u64, Duration> {
+        if let Some((_, given)) = self.current {
+            let elapsed = given.elapsed();
+
+            if elapsed < MAX_TURN {
+                return Err(MAX_TURN - elapsed);
+            }
+        }
+
+        let charged = self.charged.get(&peer).copied().unwrap_or(0);
+        let lowest = self
+            .active
+            .keys()
+            .filter_map(|active| self.charged.get(active))
+            .min()
+            .copied()
+            .unwrap_or(charged);
+
+        // Another transfer is between two writes, and its next one comes
+        // first, unless it does not ask for it within MAX_TURN.
+        if charged > lowest && !overdue {
+            return Err(MAX_TURN);
+        }
+
+        let cost = (bytes as u64).saturating_mul(COST_SCALE) / u64::from(self.weight(&peer));
+
+        self.virtual_time = charged;
+        self.charged.insert(peer, charged.saturating_add(cost));
+
+        // Peers charged less than the virtual time would be raised to it when
+        // they start a transfer again, so forgetting them changes nothing.
+        let virtual_time = self.virtual_time;
+        let active = &self.active;
+        self.charged
+            .retain(|peer, charged| *charged > virtual_time || active.contains_key(peer));
+
+        let id = self.next_turn;
+        self.next_turn += 1;
+        self.current = Some((id, Instant::now()));
+
+        Ok(id)
+    }
+}
+
+/// A transfer registered with [Scheduler::lane].
+///
+/// Its last turn lasts until it asks for the next one or is dropped, so the
+/// work done between two writes (e.g. reading the next chunk) does not let
+/// other peers skip ahead of it.
+pub(crate) struct Lane<'a> {
+    scheduler: &'a Scheduler,
+    peer: PublicKey,
+    turn: Option<u64>,
+}
+
+impl Lane<'_> {
+    /// Waits until this transfer may write a chunk of `bytes` bytes.
+    pub async fn turn(&mut self, bytes: usize) {
+        self.end_turn();
+
+        let asked = Instant::now();
+
+        loop {
+            let notified = self.scheduler.notify.notified();
+            let overdue = asked.elapsed() >= MAX_TURN;
+            let taken = self
+                .scheduler
+                .state
+                .lock()
+                .unwrap()
+                .try_take(self.peer, bytes, overdue);
+
+            match taken {
+                Ok(id) => {
+                    self.turn = Some(id);
+                    return;
+                }
+                Err(wait) => {
+                    let _ = n0_future::time::timeout(wait, notified).await;
+                }
+            }
+        }
+    }
+
+    /// Ends the last turn of this transfer, once its last chunk is written.
+    pub fn end_turn(&mut self) {
+        if let Some(id) = self.turn.take() {
+            self.scheduler.end_turn(id);
+        }
+    }
+}
+
+impl Drop for Lane<'_> {
+    fn drop(&mut self) {
+        self.end_turn();
+
+        let mut state = self.scheduler.state.lock().unwrap();
+
+        if let Some(count) = state.active.get_mut(&self.peer) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.active.remove(&self.peer);
+            }
+        }
+
+        drop(state);
+
+        // The lowest charge among the active transfers may have changed.
+        self.scheduler.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::Scheduler;
+    use crate::{PublicKey, testing};
+
+    /// Writes chunks to `peer` over a link which takes a millisecond per
+    /// chunk, until `stop` is set, counting the chunks written.
+    async fn write_chunks(
+        scheduler: Arc<Scheduler>,
+        peer: PublicKey,
+        written: Arc<AtomicUsize>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut lane = scheduler.lane(peer);
+
+        while !stop.load(Ordering::Acquire) {
+            lane.turn(1024).await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            written.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn turns_follow_the_weights() {
+        let scheduler = Arc::new(Scheduler::default());
+        let heavy = testing::peer(1);
+        let light = testing::peer(2);
+
+        scheduler.set_weight(heavy, 3);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let heavy_written = Arc::new(AtomicUsize::new(0));
+        let light_written = Arc::new(AtomicUsize::new(0));
+
+        let tasks = [
+            tokio::spawn(write_chunks(
+                Arc::clone(&scheduler),
+                heavy,
+                Arc::clone(&heavy_written),
+                Arc::clone(&stop),
+            )),
+            tokio::spawn(write_chunks(
+                Arc::clone(&scheduler),
+                light,
+                Arc::clone(&light_written),
+                Arc::clone(&stop),
+            )),
+        ];
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        stop.store(true, Ordering::Release);
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let heavy = heavy_written.load(Ordering::Acquire) as f64;
+        let light = light_written.load(Ordering::Acquire) as f64;
+        let ratio = heavy / light;
+
+        assert!((2.0..4.5).contains(&ratio), "{heavy} / {light}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_idle_peer_earns_no_credit() {
+        let scheduler = Scheduler::default();
+        let busy = testing::peer(1);
+        let idle = testing::peer(2);
+
+        let mut lane = scheduler.lane(busy);
+
+        for _ in 0..100 {
+            lane.turn(1024).await;
+        }
+
+        drop(lane);
+
+        // The idle peer starts where the busy one was before its last write,
+        // rather than getting the next 100 turns in a row.
+        scheduler.lane(idle).turn(1024).await;
+        let state = scheduler.state.lock().unwrap();
+        assert_eq!(state.charged[&idle], state.charged[&busy]);
+    }
+
+    #[test]
+    fn zero_and_default_weights_are_not_stored() {
+        let scheduler = Scheduler::default();
+        let peer = testing::peer(1);
+
+        scheduler.set_weight(peer, 0);
+        assert_eq!(scheduler.weight(&peer), 1);
+
+        scheduler.set_weight(peer, 5);
+        assert_eq!(scheduler.weight(&peer), 5);
+
+        scheduler.set_weight(peer, 1);
+        assert!(scheduler.state.lock().unwrap().weights.is_empty());
+    }
+}
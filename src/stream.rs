@@ -0,0 +1,170 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{
+    PublicKey, ReadError, RecvStream, SendStream, TunnelError, cache::InFlight, finish_stream,
+    metrics::Metrics, write_error,
+};
+
+/// The maximum size of the chunks returned by [TunnelRecvStream::read_chunk].
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A message being sent to another tunnel, one chunk at a time. Returned by
+/// [Tunnel::open_send_stream](crate::Tunnel::open_send_stream).
+///
+/// Writes wait while the other tunnel is slower than this one, so memory use
+/// stays bounded regardless of the size of the message.
+#[derive(Debug)]
+pub struct TunnelSendStream {
+    stream: SendStream,
+    peer: PublicKey,
+    written: usize,
+    failed: bool,
+    metrics: Arc<Metrics>,
+    _in_flight: InFlight,
+}
+
+impl TunnelSendStream {
+    pub(crate) fn new(
+        stream: SendStream,
+        peer: PublicKey,
+        in_flight: InFlight,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            stream,
+            peer,
+            written: 0,
+            failed: false,
+            metrics,
+            _in_flight: in_flight,
+        }
+    }
+
+    /// Writes a chunk of the message.
+    ///
+    /// Fails with [TunnelError::MessageTooLarge] if the other tunnel refused
+    /// the message for exceeding its maximum message size.
+    pub async fn write_chunk(&mut self, data: impl AsRef<[u8]>) -> Result<(), TunnelError> {
+        let data = data.as_ref();
+
+        match self.stream.write_all(data).await {
+            Ok(()) => {
+                self.written += data.len();
+                Ok(())
+            }
+            Err(e) => {
+                let error = write_error(self.peer, e);
+
+                // Counted once, even if the message is then finished.
+                if !self.failed {
+                    self.failed = true;
+                    self.metrics.record_send_error(self.peer, &error);
+                }
+
+                Err(error)
+            }
+        }
+    }
+
+    /// Ends the message, and waits for the other tunnel to acknowledge it.
+    ///
+    /// If the stream is dropped without calling this function, the message
+    /// is still ended, but there is no way to know whether it was received.
+    pub async fn finish(self) -> Result<(), TunnelError> {
+        let result = finish_stream(self.stream, self.peer).await;
+
+        if !self.failed {
+            self.metrics.record_send(self.peer, self.written, &result);
+        }
+
+        result
+    }
+}
+
+/// A message being received from another tunnel, one chunk at a time. Given
+/// to a [StreamingDataHandler].
+#[derive(Debug)]
+pub struct TunnelRecvStream {
+    stream: RecvStream,
+    peer: PublicKey,
+    metrics: Arc<Metrics>,
+}
+
+impl TunnelRecvStream {
+    pub(crate) fn new(stream: RecvStream, peer: PublicKey, metrics: Arc<Metrics>) -> Self {
+        Self {
+            stream,
+            peer,
+            metrics,
+        }
+    }
+
+    /// Reads the next chunk of the message, as soon as it arrives.
+    ///
+    /// Returns `None` once the sender ended the message.
+    pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, ReadError> {
+        let chunk = self.stream.read_chunk(READ_CHUNK_SIZE, true).await?;
+
+        Ok(chunk.map(|chunk| {
+            self.metrics
+                .record_bytes_received(self.peer, chunk.bytes.len());
+            chunk.bytes.to_vec()
+        }))
+    }
+}
+
+/// A trait implemented for objects which can handle incoming data from a
+/// tunnel as it arrives, rather than once each message was received in full.
+///
+/// Like an [AsyncDataHandler](crate::AsyncDataHandler), the returned future is
+/// awaited by the accept loop of the connection the message came from, along
+/// with the futures of up to
+/// [TunnelBuilder::max_concurrent_streams](crate::TunnelBuilder::max_concurrent_streams)
+/// other messages from the same connection. As the message is
+/// never buffered as a whole, the maximum message size of the tunnel does not
+/// apply.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [PublicKey] and a [TunnelRecvStream] in
+/// this order and returns a future can be used as a [StreamingDataHandler].
+pub trait StreamingDataHandler: 'static + Send + Sync {
+    fn process_incoming_stream(
+        &self,
+        sender: PublicKey,
+        stream: TunnelRecvStream,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+impl<Func, Fut> StreamingDataHandler for Func
+where
+    Func: 'static + Send + Sync + Fn(PublicKey, TunnelRecvStream) -> Fut,
+    Fut: Future<Output = ()> + Send,
+{
+    fn process_incoming_stream(
+        &self,
+        sender: PublicKey,
+        stream: TunnelRecvStream,
+    ) -> impl Future<Output = ()> + Send {
+        self(sender, stream)
+    }
+}
+
+/// An object safe version of [StreamingDataHandler], so it can be stored in a
+/// [TunnelProtocol](crate::TunnelProtocol).
+pub(crate) trait BoxedStreamingDataHandler: 'static + Send + Sync {
+    fn process_incoming_stream_boxed(
+        &self,
+        sender: PublicKey,
+        stream: TunnelRecvStream,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl<T: StreamingDataHandler> BoxedStreamingDataHandler for T {
+    fn process_incoming_stream_boxed(
+        &self,
+        sender: PublicKey,
+        stream: TunnelRecvStream,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.process_incoming_stream(sender, stream))
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+/// Keeps track of the background tasks spawned by a tunnel, so they can be
+/// stopped when the tunnel is destroyed.
+///
+/// Every task is raced against the cancellation of the registry, so it stops
+/// at its next await point once [TaskRegistry::shutdown] is called.
+#[derive(Debug, Default)]
+pub(crate) struct TaskRegistry {
+    live: AtomicUsize,
+    cancelled: AtomicBool,
+    cancel: Notify,
+    finished: Notify,
+}
+
+impl TaskRegistry {
+    /// Spawns a background task which is stopped by [TaskRegistry::shutdown].
+    ///
+    /// If the registry was already shut down, the task is dropped without
+    /// being run and `false` is returned.
+    pub fn spawn(self: &Arc<Self>, task: impl Future<Output = ()> + Send + 'static) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+
+        self.live.fetch_add(1, Ordering::AcqRel);
+        let registry = Arc::clone(self);
+
+        n0_future::task::spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = registry.cancelled() => {}
+            }
+
+            registry.live.fetch_sub(1, Ordering::AcqRel);
+            registry.finished.notify_waiters();
+        });
+
+        true
+    }
+
+    /// Returns whether the registry was shut down, in which case new tasks
+    /// are not run.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns how many background tasks are still running.
+    pub fn count(&self) -> usize {
+        self.live.load(Ordering::Acquire)
+    }
+
+    /// Cancels every background task, without waiting for them to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        self.cancel.notify_waiters();
+    }
+
+    /// Cancels every background task and waits for them to stop.
+    ///
+    /// Returns how many tasks did not stop before the timeout expired.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        self.cancel();
+
+        let _ = n0_future::time::timeout(timeout, async {
+            loop {
+                let finished = self.finished.notified();
+
+                if self.count() == 0 {
+                    return;
+                }
+
+                finished.await;
+            }
+        })
+        .await;
+
+        self.count()
+    }
+
+    async fn cancelled(&self) {
+        loop {
+            let cancel = self.cancel.notified();
+
+            if self.is_cancelled() {
+                return;
+            }
+
+            cancel.await;
+        }
+    }
+}
+
+/// Keeps track of the incoming streams a tunnel is handling, so it can stop
+/// accepting new ones and wait for the others to be handled when it is shut
+/// down.
+#[derive(Debug, Default)]
+pub(crate) struct Activity {
+    active: AtomicUsize,
+    closing: AtomicBool,
+    changed: Notify,
+}
+
+impl Activity {
+    /// Marks the start of the handling of a stream, which lasts until the
+    /// returned guard is dropped.
+    ///
+    /// Returns `None` once [Activity::close] was called, in which case the
+    /// stream should be discarded.
+    pub fn enter(self: &Arc<Self>) -> Option<ActiveGuard> {
+        if self.closing.load(Ordering::Acquire) {
+            return None;
+        }
+
+        self.active.fetch_add(1, Ordering::AcqRel);
+        Some(ActiveGuard(Arc::clone(self)))
+    }
+
+    /// Stops the handling of new streams.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Release);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until [Activity::close] is called.
+    pub async fn closed(&self) {
+        loop {
+            let changed = self.changed.notified();
+
+            if self.closing.load(Ordering::Acquire) {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Waits until no stream is being handled.
+    pub async fn idle(&self) {
+        loop {
+            let changed = self.changed.notified();
+
+            if self.active.load(Ordering::Acquire) == 0 {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+}
+
+/// Whether a tunnel stopped reading incoming messages, see
+/// [Tunnel::pause_receiving](crate::Tunnel::pause_receiving).
+#[derive(Debug, Default)]
+pub(crate) struct Pause {
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl Pause {
+    pub fn set(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+
+        if !paused {
+            self.resumed.notify_waiters();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Waits until receiving is resumed. Returns right away if it is not
+    /// paused.
+    pub async fn resumed(&self) {
+        loop {
+            let resumed = self.resumed.notified();
+
+            if !self.is_paused() {
+                return;
+            }
+
+            resumed.await;
+        }
+    }
+}
+
+/// A guard returned by [Activity::enter].
+pub(crate) struct ActiveGuard(Arc<Activity>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+        self.0.changed.notify_waiters();
+    }
+}
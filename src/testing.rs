@@ -0,0 +1,60 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{EndpointAddr, PublicKey, RelayMode, SecretKey, Tunnel, TunnelBuilder};
+
+/// Returns a builder for a tunnel which neither uses relays nor waits to go
+/// online, so tests only ever connect over loopback.
+pub(crate) fn builder() -> TunnelBuilder {
+    Tunnel::builder()
+        .relay_mode(RelayMode::Disabled)
+        .wait_online(false)
+}
+
+/// Returns the address of a peer which does not exist, derived from `seed`.
+pub(crate) fn peer(seed: u8) -> PublicKey {
+    SecretKey::from_bytes(&[seed; 32]).public()
+}
+
+/// Returns `len` bytes which do not compress, from a xorshift generator.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub(crate) fn incompressible(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Returns the address at which the receiver endpoint of `tunnel` can be
+/// reached over loopback.
+pub(crate) fn loopback_addr(tunnel: &Tunnel) -> EndpointAddr {
+    let endpoint = tunnel
+        .inner
+        .receiver
+        .as_ref()
+        .expect("the tunnel has no receiver endpoint")
+        .endpoint();
+
+    endpoint
+        .bound_sockets()
+        .into_iter()
+        .map(|socket| match socket {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, socket.port())),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::LOCALHOST, socket.port())),
+        })
+        .fold(EndpointAddr::new(endpoint.id()), EndpointAddr::with_ip_addr)
+}
+
+/// Makes `sender` remember how to reach `receiver` over loopback, and
+/// connects to it.
+pub(crate) async fn connect(sender: &Tunnel, receiver: &Tunnel) {
+    sender
+        .connect_to(loopback_addr(receiver))
+        .await
+        .expect("could not connect over loopback");
+}
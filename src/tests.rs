@@ -0,0 +1,1868 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::ConnectionError;
+use n0_future::join_all;
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    sync::{Mutex, RwLock, mpsc},
+};
+
+use crate::{
+    ABANDONED_STREAM_CODE, AsyncDataHandler, Bytes, ConnectionOrigin, EndpointAddr,
+    GOING_AWAY_CLOSE_CODE, HANDLER_ERROR_CODE, MAX_TOPIC_LENGTH, Mode, NoHandlerPolicy, PeerFilter,
+    PublicKey, ROTATED_CLOSE_CODE, RecvStream, RelayUrl, SecretKey, SendStream, SetupStage, Tunnel,
+    TunnelError, USER_CLOSE_CODE, testing,
+};
+
+/// How long tests wait for something which should happen over loopback.
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Returns whether `error` is an application close with `code`.
+fn closed_with(error: &ConnectionError, code: u32) -> bool {
+    match error {
+        ConnectionError::ApplicationClosed(close) => {
+            close.error_code.into_inner() == u64::from(code)
+        }
+        _ => false,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn graceful_close_reaches_the_peer_before_exiting() {
+    let (tx, mut disconnects) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .on_peer_disconnected(move |_, error| {
+            let _ = tx.send(error);
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    sender
+        .send(receiver.receiver_address().unwrap(), &b"hello"[..])
+        .await
+        .unwrap();
+
+    assert_eq!(sender.close_all_graceful(WAIT).await, 1);
+
+    // Dropping the tunnel without destroying it is what an exiting process
+    // does, so only the close sent by close_all_graceful reaches the peer.
+    drop(sender);
+
+    let error = tokio::time::timeout(WAIT, disconnects.recv())
+        .await
+        .expect("the peer saw no close, and would only notice a timeout")
+        .unwrap();
+
+    assert!(closed_with(&error, USER_CLOSE_CODE), "{error:?}");
+
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn graceful_close_does_not_count_closed_connections() {
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    // The other tunnel closes every connection, so none is left to close.
+    receiver.close_all();
+    receiver.destroy().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(sender.close_all_graceful(WAIT).await, 0);
+
+    sender.destroy().await.unwrap();
+}
+
+/// Binds `socket` again, retrying while the endpoint which bound it is still
+/// releasing it.
+async fn rebind(socket: SocketAddr) -> UdpSocket {
+    tokio::time::timeout(WAIT, async {
+        loop {
+            if let Ok(rebound) = UdpSocket::bind(socket) {
+                return rebound;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the socket was never released")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn receiver_bind_failure_releases_the_sender_socket() {
+    let relay: RelayUrl = "https://relay.example.com".parse().unwrap();
+
+    // A taken port would be replaced by a random one, so the receiver binds
+    // to an address reserved for documentation, which this host never has.
+    let error = Tunnel::builder()
+        .relay_url(relay.clone())
+        .wait_online(false)
+        .sender_bind_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind_addr(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 0))
+        .spawn()
+        .await
+        .unwrap_err();
+
+    let TunnelError::Setup {
+        stage,
+        bound_sockets,
+        relays,
+        ..
+    } = &error
+    else {
+        panic!("unexpected error: {error}");
+    };
+
+    assert_eq!(*stage, SetupStage::ReceiverBind);
+    assert_eq!(relays, &[relay.clone()]);
+    assert!(error.to_string().contains(relay.as_str()), "{error}");
+
+    let sender_socket = bound_sockets
+        .iter()
+        .find(|socket| socket.ip() == Ipv4Addr::LOCALHOST)
+        .expect("the sender socket is not reported");
+
+    rebind(*sender_socket).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn online_timeout_reports_the_discovery_stage() {
+    // Nothing listens on this port, so the endpoints never go online.
+    let relay: RelayUrl = "http://127.0.0.1:1".parse().unwrap();
+
+    let error = Tunnel::builder()
+        .relay_url(relay.clone())
+        .online_timeout(Duration::from_millis(200))
+        .spawn()
+        .await
+        .unwrap_err();
+
+    let TunnelError::Setup {
+        stage,
+        bound_sockets,
+        relays,
+        ..
+    } = &error
+    else {
+        panic!("unexpected error: {error}");
+    };
+
+    assert_eq!(*stage, SetupStage::Discovery);
+    assert_eq!(relays, &[relay]);
+
+    for socket in bound_sockets.iter().filter(|socket| socket.is_ipv4()) {
+        rebind(*socket).await;
+    }
+}
+
+/// Returns a reader of `len` bytes, produced a chunk at a time through `link`,
+/// which takes a millisecond per chunk. `produced` counts the bytes produced.
+fn rate_limited_reader(
+    len: usize,
+    link: Arc<Mutex<()>>,
+    produced: Arc<AtomicUsize>,
+) -> DuplexStream {
+    const CHUNK: usize = 16 * 1024;
+
+    let (reader, mut writer) = tokio::io::duplex(CHUNK);
+
+    tokio::spawn(async move {
+        for _ in 0..len / CHUNK {
+            {
+                let _link = link.lock().await;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            if writer.write_all(&[7; CHUNK]).await.is_err() {
+                return;
+            }
+
+            produced.fetch_add(CHUNK, Ordering::AcqRel);
+        }
+    });
+
+    reader
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chunked_sends_share_bandwidth_by_weight() {
+    const LEN: usize = 2 * 1024 * 1024;
+
+    let mut receivers = Vec::new();
+
+    for _ in 0..3 {
+        let receiver = testing::builder()
+            .handler(|_: PublicKey, _: Vec<u8>| {})
+            .spawn()
+            .await
+            .unwrap();
+        receivers.push(receiver);
+    }
+
+    let sender = testing::builder()
+        .chunk_size(16 * 1024)
+        .spawn()
+        .await
+        .unwrap();
+
+    for receiver in &receivers {
+        testing::connect(&sender, receiver).await;
+    }
+
+    let [heavy, light, control] = [0, 1, 2].map(|i| receivers[i].receiver_address().unwrap());
+    sender.set_peer_weight(heavy, 3);
+    assert_eq!(sender.peer_weight(heavy), 3);
+    assert_eq!(sender.peer_weight(light), 1);
+
+    // Both transfers read from the same slow link, so they compete for it.
+    let link = Arc::new(Mutex::new(()));
+    let heavy_produced = Arc::new(AtomicUsize::new(0));
+    let light_produced = Arc::new(AtomicUsize::new(0));
+
+    let heavy_send = sender.send_stream(
+        heavy,
+        rate_limited_reader(LEN, Arc::clone(&link), Arc::clone(&heavy_produced)),
+    );
+    let light_send = tokio::spawn({
+        let sender = sender.clone();
+        let reader = rate_limited_reader(LEN, Arc::clone(&link), Arc::clone(&light_produced));
+
+        async move { sender.send_stream(light, reader).await }
+    });
+
+    // Small messages are not paced, so they do not wait for the transfers.
+    let control_send = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tokio::time::timeout(Duration::from_secs(1), sender.send(control, &b"hello"[..])).await
+    };
+
+    let (heavy_sent, control_sent) = tokio::join!(heavy_send, control_send);
+    let light_fraction = light_produced.load(Ordering::Acquire) as f64 / LEN as f64;
+
+    assert_eq!(heavy_sent.unwrap(), LEN as u64);
+    control_sent
+        .expect("the control message waited for the transfers")
+        .unwrap();
+
+    // With equal weights, the light transfer would be nearly done as well.
+    assert!((0.15..0.6).contains(&light_fraction), "{light_fraction}");
+
+    assert_eq!(light_send.await.unwrap().unwrap(), LEN as u64);
+    assert_eq!(heavy_produced.load(Ordering::Acquire), LEN);
+
+    sender.destroy().await.unwrap();
+
+    for receiver in receivers {
+        receiver.destroy().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn debug_dump_reports_state_without_secrets_or_data() {
+    const MARKER: &[u8] = b"payload-marker";
+
+    let key = SecretKey::from_bytes(&[42; 32]);
+    let sender_key = SecretKey::from_bytes(&[43; 32]);
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .max_message_size(MARKER.len())
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .secret_key(key.clone())
+        .sender_secret_key(sender_key.clone())
+        .keep_alive(Duration::from_secs(5), 3)
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    sender.send(address, MARKER).await.unwrap();
+    sender.send(address, MARKER.repeat(2)).await.unwrap_err();
+
+    let dump = sender.debug_dump();
+    let report: serde_json::Value = serde_json::from_str(&dump).unwrap();
+
+    let fields: Vec<&str> = report
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            "accept",
+            "config",
+            "connections",
+            "crate_version",
+            "dump_version",
+            "errors",
+            "features",
+            "handlers",
+            "metrics",
+            "pending_sends",
+            "receiver",
+            "sender",
+            "tasks",
+        ]
+    );
+
+    assert_eq!(report["config"]["secret_key"], "<redacted>");
+    assert_eq!(report["config"]["sender_secret_key"], "<redacted>");
+    assert_eq!(report["config"]["keep_alive"]["max_missed"], 3);
+    assert_eq!(report["sender"]["address"], sender_key.public().to_string());
+    assert_eq!(report["errors"].as_array().unwrap().len(), 1);
+    assert_eq!(report["errors"][0]["peer"], address.to_string());
+
+    for key in [&key, &sender_key] {
+        let hex: String = key.to_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        assert!(!dump.contains(&hex), "a secret key leaked: {dump}");
+    }
+
+    let marker = std::str::from_utf8(MARKER).unwrap();
+    assert!(!dump.contains(marker), "the payload leaked: {dump}");
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+/// Returns a handler which forwards the data it receives to `tx`.
+fn forward_to(tx: mpsc::UnboundedSender<Vec<u8>>) -> impl FnMut(PublicKey, Vec<u8>) {
+    move |_, data| {
+        let _ = tx.send(data);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn swapped_handlers_receive_the_messages_sent_after_the_swap() {
+    let (setup_tx, mut setup) = mpsc::unbounded_channel();
+    let (run_tx, mut run) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(setup_tx))
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    sender.send(address, &b"first"[..]).await.unwrap();
+    let first = tokio::time::timeout(WAIT, setup.recv()).await.unwrap();
+    assert_eq!(first.as_deref(), Some(&b"first"[..]));
+
+    let previous = receiver.set_handler(Some(Arc::new(RwLock::new(forward_to(run_tx)))));
+    assert!(previous.is_some());
+
+    sender.send(address, &b"second"[..]).await.unwrap();
+    let second = tokio::time::timeout(WAIT, run.recv()).await.unwrap();
+    assert_eq!(second.as_deref(), Some(&b"second"[..]));
+
+    // A no-op handler drops the data without anything else seeing it.
+    receiver.set_handler(Some(Arc::new(RwLock::new(|_: PublicKey, _: Vec<u8>| {}))));
+    sender.send(address, &b"third"[..]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(setup.try_recv().is_err());
+    assert!(run.try_recv().is_err());
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connection_churn_loses_no_message_and_keeps_the_live_connection() {
+    const TASKS: u32 = 8;
+    const MESSAGES: u32 = 25;
+    const LAST: u32 = u32::MAX;
+
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .max_reconnect_attempts(3)
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Evicts the cached connection, and closes it from the other side so the
+    // watcher of the sender removes it, while the sends reconnect.
+    let churn = tokio::spawn({
+        let sender = sender.clone();
+        let receiver = receiver.clone();
+        let stop = Arc::clone(&stop);
+
+        async move {
+            while !stop.load(Ordering::Acquire) {
+                sender.close(address);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                receiver.close_all();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+    });
+
+    let sends: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                for message in task * MESSAGES..(task + 1) * MESSAGES {
+                    let mut attempts = 0;
+
+                    // A send fails when its connection is closed under it,
+                    // in which case it is retried like an application would.
+                    while let Err(e) = sender
+                        .send_acked(address, message.to_be_bytes().to_vec())
+                        .await
+                    {
+                        attempts += 1;
+                        assert!(attempts < 50, "message {message} never arrived: {e}");
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for send in sends {
+        send.await.unwrap();
+    }
+
+    stop.store(true, Ordering::Release);
+    churn.await.unwrap();
+
+    sender
+        .send_acked(address, LAST.to_be_bytes().to_vec())
+        .await
+        .unwrap();
+
+    // Gives the watchers of the closed connections the time to run, which
+    // must not remove the connection the last message was sent over.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(sender.connection_generation(&address).is_some());
+    assert!(sender.is_connected(&address));
+
+    let mut messages = HashSet::new();
+
+    while let Ok(data) = received.try_recv() {
+        messages.insert(u32::from_be_bytes(data.try_into().unwrap()));
+    }
+
+    let expected: HashSet<u32> = (0..TASKS * MESSAGES).chain([LAST]).collect();
+    assert_eq!(messages, expected);
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_only_tunnel_delivers_to_a_normal_tunnel() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .mode(Mode::SendOnly)
+        .spawn()
+        .await
+        .unwrap();
+
+    assert_eq!(sender.receiver_address(), None);
+    assert!(sender.receiver_router().is_none());
+
+    testing::connect(&sender, &receiver).await;
+    sender
+        .send(receiver.receiver_address().unwrap(), &b"hello"[..])
+        .await
+        .unwrap();
+
+    let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+    assert_eq!(data.as_deref(), Some(&b"hello"[..]));
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn receive_only_tunnel_refuses_to_send_without_dialing() {
+    let tunnel = testing::builder()
+        .mode(Mode::ReceiveOnly)
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .spawn()
+        .await
+        .unwrap();
+
+    assert_eq!(tunnel.sender_address(), None);
+    assert!(tunnel.sender_endpoint().is_none());
+
+    // The peer does not exist, so dialing it could only time out.
+    let sent = tokio::time::timeout(
+        Duration::from_millis(100),
+        tunnel.send(testing::peer(1), &b"x"[..]),
+    )
+    .await
+    .expect("the send went to the network");
+
+    assert!(matches!(
+        sent,
+        Err(TunnelError::WrongMode {
+            mode: Mode::ReceiveOnly
+        })
+    ));
+    assert_eq!(tunnel.cached_connection_count(), 0);
+    assert_eq!(tunnel.metrics().send_errors, 0);
+
+    tunnel.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn destroy_stops_every_background_task() {
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .keep_alive(Duration::from_millis(50), 3)
+        .idle_timeout(Duration::from_secs(60))
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    for _ in 0..10 {
+        sender.send_nowait(address, &b"queued"[..]).unwrap();
+    }
+
+    // The peer does not exist, so this send stays queued until destroyed.
+    sender.send_nowait(testing::peer(1), &b"stuck"[..]).unwrap();
+
+    assert!(sender.task_count() > 0);
+
+    let report = sender.clone().destroy().await.unwrap();
+
+    assert_eq!(report.unstopped_tasks, 0);
+    assert_eq!(sender.task_count(), 0);
+
+    receiver.clone().destroy().await.unwrap();
+    assert_eq!(receiver.task_count(), 0);
+}
+
+/// Returns whether `error` only tells that the sending tunnel was shutting
+/// down.
+fn is_shutting_down(error: &TunnelError) -> bool {
+    match error {
+        TunnelError::Destroyed => true,
+        TunnelError::ConnectionLost { source, .. } => {
+            matches!(source, ConnectionError::LocallyClosed)
+                || closed_with(source, GOING_AWAY_CLOSE_CODE)
+        }
+        _ => false,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_racing_a_shutdown_end_cleanly() {
+    const LOOPS: usize = 8;
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    let loops: Vec<_> = (0..LOOPS)
+        .map(|_| {
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                let mut results = Vec::new();
+
+                // Sends until the shutdown makes one fail.
+                for message in 0u32.. {
+                    let result = sender.send(address, message.to_be_bytes().to_vec()).await;
+                    let failed = result.is_err();
+
+                    results.push(result);
+
+                    if failed {
+                        break;
+                    }
+                }
+
+                results
+            })
+        })
+        .collect();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    sender.clone().shutdown(WAIT).await.unwrap();
+
+    for handle in loops {
+        let results = handle.await.expect("a send loop panicked");
+
+        assert!(results.len() > 1, "the loop was stopped before sending");
+
+        for result in results {
+            if let Err(error) = result {
+                assert!(is_shutting_down(&error), "{error:?}");
+            }
+        }
+    }
+
+    assert!(matches!(
+        sender.send(address, &b"late"[..]).await,
+        Err(TunnelError::Destroyed)
+    ));
+
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn flush_returns_after_destroying_a_tunnel_with_queued_sends() {
+    let sender = testing::builder().spawn().await.unwrap();
+
+    // The peer does not exist, so this send is still queued when destroyed.
+    sender.send_nowait(testing::peer(1), &b"stuck"[..]).unwrap();
+    assert_eq!(sender.pending_sends(), 1);
+
+    sender.clone().destroy().await.unwrap();
+
+    sender.flush(None, Some(WAIT)).await.unwrap();
+    assert_eq!(sender.pending_sends(), 0);
+
+    assert!(matches!(
+        sender.send_nowait(testing::peer(1), &b"late"[..]),
+        Err(TunnelError::Destroyed)
+    ));
+    assert_eq!(sender.pending_sends(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_waits_for_requests_being_answered() {
+    let (started_tx, mut started) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .request_handler(move |_: PublicKey, data: Vec<u8>| {
+            let _ = started_tx.send(());
+            std::thread::sleep(Duration::from_millis(200));
+            data
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let request = tokio::spawn({
+        let sender = sender.clone();
+        async move { sender.request(address, &b"answered"[..]).await }
+    });
+
+    recv(&mut started).await;
+
+    let report = receiver.shutdown(WAIT).await.unwrap();
+
+    assert!(report.drained);
+    assert_eq!(request.await.unwrap().unwrap(), b"answered");
+
+    sender.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_and_bi_stream_handlers_work_together() {
+    let receiver = testing::builder()
+        .request_handler(|_: PublicKey, data: Vec<u8>| [&b"request: "[..], &data].concat())
+        .bi_handler(|_: PublicKey, mut send: SendStream, mut recv: RecvStream| {
+            tokio::spawn(async move {
+                let data = recv.read_to_end(1024).await.unwrap();
+                let response = [&b"stream: "[..], &data].concat();
+
+                send.write_all(&response).await.unwrap();
+                send.finish().unwrap();
+            });
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    let (mut send, mut recv) = sender.open_bi(address).await.unwrap();
+    send.write_all(b"hi").await.unwrap();
+    send.finish().unwrap();
+
+    assert_eq!(recv.read_to_end(1024).await.unwrap(), b"stream: hi");
+    assert_eq!(
+        sender.request(address, &b"hi"[..]).await.unwrap(),
+        b"request: hi"
+    );
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_requests_are_reported_to_the_requester() {
+    let (errors_tx, mut errors) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .max_message_size(16)
+        .request_handler(|_: PublicKey, data: Vec<u8>| {
+            if data == b"panic" {
+                panic!("the request handler panicked");
+            }
+
+            data
+        })
+        .on_handler_error(move |_, error| {
+            let _ = errors_tx.send(error.is_panic());
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    assert!(matches!(
+        sender.request(address, vec![0u8; 64]).await,
+        Err(TunnelError::MessageTooLarge { .. })
+    ));
+
+    match sender.request(address, &b"panic"[..]).await {
+        Err(TunnelError::RemoteStopped { code, .. }) => {
+            assert_eq!(code, u64::from(HANDLER_ERROR_CODE));
+        }
+        other => panic!("unexpected result: {other:?}"),
+    }
+
+    assert!(recv(&mut errors).await);
+
+    // The panic only affected its own request.
+    assert_eq!(sender.request(address, &b"ok"[..]).await.unwrap(), b"ok");
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refused_connections_are_closed_before_any_payload_is_read() {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let origins = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let receiver = testing::builder()
+        .handler({
+            let handled = Arc::clone(&handled);
+            move |_: PublicKey, _: Vec<u8>| {
+                handled.fetch_add(1, Ordering::AcqRel);
+            }
+        })
+        .accept_policy({
+            let origins = Arc::clone(&origins);
+            move |origin: &ConnectionOrigin| {
+                origins.lock().unwrap().push(origin.clone());
+                false
+            }
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+
+    let sent = sender
+        .send_to(testing::loopback_addr(&receiver), vec![7; 64 * 1024])
+        .await;
+    assert!(sent.is_err());
+
+    let origins = origins.lock().unwrap().clone();
+    assert!(!origins.is_empty());
+    assert!(
+        origins
+            .iter()
+            .all(|origin| Some(origin.remote) == sender.sender_address())
+    );
+
+    let metrics = receiver.metrics();
+    assert_eq!(metrics.messages_received, 0);
+    assert_eq!(metrics.bytes_received, 0);
+    assert_eq!(handled.load(Ordering::Acquire), 0);
+
+    let stats = receiver.accept_stats();
+    assert_eq!(stats.accepted, 0);
+    assert_eq!(stats.refused, origins.len() as u64);
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rotated_connections_lose_no_message() {
+    const MESSAGES: u32 = 50;
+
+    let (tx, mut received) = mpsc::unbounded_channel();
+    let (disconnect_tx, mut disconnects) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .on_peer_disconnected(move |_, error| {
+            let _ = disconnect_tx.send(error);
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .max_connection_age(Duration::from_millis(200))
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let first = sender.connection_generation(&address).unwrap();
+
+    // The sends span several rotations.
+    for message in 0..MESSAGES {
+        sender
+            .send(address, message.to_be_bytes().to_vec())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let last = sender.connection_generation(&address).unwrap();
+    assert!(last > first, "the connection was never rotated");
+
+    // The peer is only notified when no connection was left open, as the
+    // next one may have been dialed before the old one was closed.
+    while let Ok(error) = disconnects.try_recv() {
+        assert!(closed_with(&error, ROTATED_CLOSE_CODE), "{error:?}");
+    }
+
+    let mut messages = Vec::new();
+
+    while messages.len() < MESSAGES as usize {
+        let data = tokio::time::timeout(WAIT, received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        messages.push(u32::from_be_bytes(data.try_into().unwrap()));
+    }
+
+    messages.sort_unstable();
+    assert_eq!(messages, (0..MESSAGES).collect::<Vec<_>>());
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_are_notified_once_whatever_they_send() {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let disconnect_tx = events_tx.clone();
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .on_peer_connected(move |peer| {
+            let _ = events_tx.send(("connected", peer));
+        })
+        .on_peer_disconnected(move |peer, _| {
+            let _ = disconnect_tx.send(("disconnected", peer));
+        })
+        .spawn()
+        .await
+        .unwrap();
+    receiver
+        .subscribe("topic", forward_named("topic", mpsc::unbounded_channel().0))
+        .unwrap();
+
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let peer = sender.sender_address().unwrap();
+
+    // Each kind of send uses its own connection.
+    sender.send(address, &b"regular"[..]).await.unwrap();
+    sender.send_acked(address, &b"acked"[..]).await.unwrap();
+    sender
+        .send_on(address, "topic", &b"topic"[..])
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&mut events).await, ("connected", peer));
+
+    sender.close_all_graceful(WAIT).await;
+
+    assert_eq!(recv(&mut events).await, ("disconnected", peer));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(events.try_recv().is_err());
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_sends_and_closes_do_not_hang() {
+    let mut receivers = Vec::new();
+    let sender = testing::builder().spawn().await.unwrap();
+
+    for _ in 0..2 {
+        let receiver = testing::builder()
+            .handler(|_: PublicKey, _: Vec<u8>| {})
+            .spawn()
+            .await
+            .unwrap();
+        testing::connect(&sender, &receiver).await;
+        receivers.push(receiver);
+    }
+
+    let addresses: Vec<_> = receivers
+        .iter()
+        .map(|receiver| receiver.receiver_address().unwrap())
+        .collect();
+
+    // Two tasks send to the same address, and a third one to another.
+    let sends = [addresses[0], addresses[0], addresses[1]].map(|address| {
+        let sender = sender.clone();
+
+        tokio::spawn(async move {
+            for _ in 0..50 {
+                // Sends fail when their connection is closed under them.
+                let _ = sender.send(address, vec![7; 16 * 1024]).await;
+            }
+        })
+    });
+
+    let closes = tokio::spawn({
+        let sender = sender.clone();
+        let addresses = addresses.clone();
+
+        async move {
+            for _ in 0..50 {
+                for address in &addresses {
+                    sender.close(*address);
+                }
+
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        }
+    });
+
+    tokio::time::timeout(WAIT * 4, async {
+        for send in sends {
+            send.await.unwrap();
+        }
+
+        closes.await.unwrap();
+    })
+    .await
+    .expect("the sends or closes hung");
+
+    // The connections are still usable afterwards.
+    for address in addresses {
+        sender.send(address, &b"after"[..]).await.unwrap();
+    }
+
+    sender.destroy().await.unwrap();
+
+    for receiver in receivers {
+        receiver.destroy().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_message_is_refused_and_the_receiver_stays_alive() {
+    const MIB: usize = 1024 * 1024;
+
+    let (tx, mut received) = mpsc::unbounded_channel();
+    let too_large = Arc::new(AtomicUsize::new(0));
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .max_message_size(MIB)
+        .on_message_too_large({
+            let too_large = Arc::clone(&too_large);
+            move |_| {
+                too_large.fetch_add(1, Ordering::AcqRel);
+            }
+        })
+        .spawn()
+        .await
+        .unwrap();
+    // The sender allows larger messages, so only the receiver refuses it.
+    let sender = testing::builder()
+        .max_message_size(200 * MIB)
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let sent = sender.send(address, vec![7; 100 * MIB]).await;
+
+    assert!(
+        matches!(sent, Err(TunnelError::MessageTooLarge { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    sender.send(address, &b"small"[..]).await.unwrap();
+    let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+    assert_eq!(data.as_deref(), Some(&b"small"[..]));
+    assert!(received.try_recv().is_err());
+    assert_eq!(too_large.load(Ordering::Acquire), 1);
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn secret_keys_set_the_addresses() {
+    let key = SecretKey::from_bytes(&[1; 32]);
+    let sender_key = SecretKey::from_bytes(&[2; 32]);
+
+    let tunnel = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .secret_key(key.clone())
+        .sender_secret_key(sender_key.clone())
+        .spawn()
+        .await
+        .unwrap();
+
+    assert_eq!(tunnel.receiver_address(), Some(key.public()));
+    assert_eq!(tunnel.sender_address(), Some(sender_key.public()));
+
+    tunnel.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tunnels_only_connect_with_the_same_alpn() {
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .alpn(b"tunnel-test/1")
+        .spawn()
+        .await
+        .unwrap();
+    let matching = testing::builder()
+        .alpn(b"tunnel-test/1")
+        .spawn()
+        .await
+        .unwrap();
+    let other = testing::builder()
+        .alpn(b"tunnel-test/2")
+        .spawn()
+        .await
+        .unwrap();
+
+    let addr = testing::loopback_addr(&receiver);
+
+    matching.send_to(addr.clone(), &b"hello"[..]).await.unwrap();
+    other.send_to(addr, &b"hello"[..]).await.unwrap_err();
+
+    matching.destroy().await.unwrap();
+    other.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn max_message_size_limits_incoming_messages() {
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .max_message_size(16)
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    sender.send(address, vec![7; 16]).await.unwrap();
+    let sent = sender.send(address, vec![7; 17]).await;
+    assert!(
+        matches!(sent, Err(TunnelError::MessageTooLarge { .. })),
+        "{sent:?}"
+    );
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_timeout_bounds_dialing() {
+    let timeout = Duration::from_millis(200);
+    let sender = testing::builder()
+        .connect_timeout(timeout)
+        .spawn()
+        .await
+        .unwrap();
+
+    // An address from a range reserved for documentation, which never answers.
+    let peer = testing::peer(1);
+    let unreachable = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 9));
+    let addr = EndpointAddr::new(peer).with_ip_addr(unreachable);
+
+    let connected = tokio::time::timeout(WAIT, sender.connect_to(addr))
+        .await
+        .expect("dialing was not bounded by the connect timeout");
+
+    assert!(
+        matches!(
+            connected,
+            Err(TunnelError::ConnectTimeout { peer: dialed, timeout: waited })
+                if dialed == peer && waited == timeout
+        ),
+        "{connected:?}"
+    );
+
+    sender.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restarted_tunnels_keep_the_addresses_of_their_keys() {
+    let key = SecretKey::from_bytes(&[1; 32]);
+    let sender_key = SecretKey::from_bytes(&[2; 32]);
+
+    let spawn = || {
+        testing::builder()
+            .handler(|_: PublicKey, _: Vec<u8>| {})
+            .secret_key(key.clone())
+            .sender_secret_key(sender_key.clone())
+            .spawn()
+    };
+
+    let tunnel = spawn().await.unwrap();
+    let receiver_address = tunnel.receiver_address();
+    let sender_address = tunnel.sender_address();
+    let exported = tunnel.secret_key().unwrap().to_bytes();
+    tunnel.destroy().await.unwrap();
+
+    let restarted = spawn().await.unwrap();
+    assert_eq!(restarted.receiver_address(), receiver_address);
+    assert_eq!(restarted.sender_address(), sender_address);
+    assert_eq!(restarted.secret_key().unwrap().to_bytes(), exported);
+    restarted.destroy().await.unwrap();
+
+    // Without a key, every tunnel gets a new one.
+    let first = testing::builder().spawn().await.unwrap();
+    let second = testing::builder().spawn().await.unwrap();
+    assert_ne!(first.receiver_address(), second.receiver_address());
+
+    first.destroy().await.unwrap();
+    second.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_reconnect_to_a_restarted_tunnel() {
+    let key = SecretKey::from_bytes(&[3; 32]);
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let spawn = |bind_addr| {
+        testing::builder()
+            .handler(forward_to(tx.clone()))
+            .secret_key(key.clone())
+            .bind_addr(bind_addr)
+            .spawn()
+    };
+
+    let receiver = spawn(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    sender.send(address, &b"before"[..]).await.unwrap();
+
+    let socket = receiver
+        .receiver_router()
+        .unwrap()
+        .endpoint()
+        .bound_sockets()
+        .into_iter()
+        .find_map(|socket| match socket {
+            SocketAddr::V4(socket) => Some(socket),
+            SocketAddr::V6(_) => None,
+        })
+        .unwrap();
+
+    receiver.destroy().await.unwrap();
+    drop(rebind(socket.into()).await);
+
+    // The restarted tunnel listens where the sender endpoint remembers it,
+    // while the connection cached by the sender is dead.
+    let restarted = spawn(socket).await.unwrap();
+    sender.send(address, &b"after"[..]).await.unwrap();
+
+    for expected in [&b"before"[..], &b"after"[..]] {
+        let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+        assert_eq!(data.as_deref(), Some(expected));
+    }
+
+    sender.destroy().await.unwrap();
+    restarted.destroy().await.unwrap();
+}
+
+/// Sends a message from `sender` to `receiver` over loopback.
+async fn send_over_loopback(sender: &Tunnel, receiver: &Tunnel) -> Result<(), TunnelError> {
+    sender
+        .send_to(testing::loopback_addr(receiver), &b"hello"[..])
+        .await
+}
+
+fn is_refused(sent: &Result<(), TunnelError>) -> bool {
+    matches!(sent, Err(TunnelError::Refused { .. }))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_filters_are_applied_and_can_change_at_runtime() {
+    let friend = testing::builder().spawn().await.unwrap();
+    let stranger = testing::builder().spawn().await.unwrap();
+
+    let filter = PeerFilter::allow_list([friend.sender_address().unwrap()]);
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .accept_policy(filter.clone())
+        .spawn()
+        .await
+        .unwrap();
+
+    send_over_loopback(&friend, &receiver).await.unwrap();
+
+    let sent = send_over_loopback(&stranger, &receiver).await;
+    assert!(is_refused(&sent), "{sent:?}");
+
+    filter.allow_peer(stranger.sender_address().unwrap());
+    send_over_loopback(&stranger, &receiver).await.unwrap();
+
+    // Accepted connections are kept open, only new ones are refused.
+    filter.revoke_peer(friend.sender_address().unwrap());
+    send_over_loopback(&friend, &receiver).await.unwrap();
+
+    friend.close_all();
+    let sent = send_over_loopback(&friend, &receiver).await;
+    assert!(is_refused(&sent), "{sent:?}");
+
+    friend.destroy().await.unwrap();
+    stranger.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deny_lists_refuse_only_the_listed_peers() {
+    let friend = testing::builder().spawn().await.unwrap();
+    let stranger = testing::builder().spawn().await.unwrap();
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .accept_policy(PeerFilter::deny_list([stranger.sender_address().unwrap()]))
+        .spawn()
+        .await
+        .unwrap();
+
+    send_over_loopback(&friend, &receiver).await.unwrap();
+
+    let sent = send_over_loopback(&stranger, &receiver).await;
+    assert!(is_refused(&sent), "{sent:?}");
+
+    let stats = receiver.accept_stats();
+    assert_eq!((stats.accepted, stats.refused), (1, 1));
+
+    friend.destroy().await.unwrap();
+    stranger.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn accept_callbacks_decide_per_peer() {
+    let friend = testing::builder().spawn().await.unwrap();
+    let stranger = testing::builder().spawn().await.unwrap();
+
+    let allowed = friend.sender_address().unwrap();
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .accept_policy(move |origin: &ConnectionOrigin| origin.remote == allowed)
+        .spawn()
+        .await
+        .unwrap();
+
+    send_over_loopback(&friend, &receiver).await.unwrap();
+
+    let sent = send_over_loopback(&stranger, &receiver).await;
+    assert!(is_refused(&sent), "{sent:?}");
+
+    friend.destroy().await.unwrap();
+    stranger.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_tunnels_deliver_every_kind_of_payload() {
+    use crate::Compression;
+
+    #[cfg(feature = "zstd")]
+    let compression = Compression::Zstd(0);
+    #[cfg(not(feature = "zstd"))]
+    let compression = Compression::Lz4;
+
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .compression(compression)
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder()
+        .compression(compression)
+        .compression_threshold(0)
+        .spawn()
+        .await
+        .unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let incompressible = testing::incompressible(64 * 1024);
+    let compressible = b"{\"key\": \"value\"}".repeat(1024);
+
+    let address = receiver.receiver_address().unwrap();
+
+    for payload in [Vec::new(), incompressible, compressible.clone()] {
+        sender.send(address, payload.clone()).await.unwrap();
+
+        let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+        assert_eq!(data, Some(payload));
+    }
+
+    let metrics = sender.metrics();
+    assert_eq!(metrics.bytes_before_compression, compressible.len() as u64);
+    assert!(metrics.bytes_after_compression < metrics.bytes_before_compression);
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn counter_deltas_add_up_under_concurrent_traffic() {
+    const LOOPS: u64 = 4;
+    const MESSAGES: u64 = 100;
+    const SIZE: u64 = 64;
+
+    let receiver = testing::builder()
+        .handler(|_: PublicKey, _: Vec<u8>| {})
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let loops: Vec<_> = (0..LOOPS)
+        .map(|_| {
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES {
+                    sender
+                        .send(address, vec![0u8; SIZE as usize])
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+
+    // Collects while the sends are running, like a periodic billing job.
+    let collector = tokio::spawn({
+        let sender = sender.clone();
+        let done = Arc::clone(&done);
+
+        async move {
+            let first = sender.metrics_snapshot();
+            let mut previous = first;
+            let (mut delta_bytes, mut taken_bytes, mut taken_messages) = (0, 0, 0);
+
+            while !done.load(Ordering::Acquire) {
+                let snapshot = sender.metrics_snapshot();
+                assert!(snapshot.id > previous.id);
+                delta_bytes += snapshot.diff(&previous).bytes_sent;
+                previous = snapshot;
+
+                if let Some(taken) = sender.take_and_reset_peer_counters(&address) {
+                    taken_bytes += taken.bytes_sent;
+                    taken_messages += taken.messages_sent;
+                }
+
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+
+            let last = sender.metrics_snapshot();
+            delta_bytes += last.diff(&previous).bytes_sent;
+            assert_eq!(delta_bytes, last.diff(&first).bytes_sent);
+
+            let taken = sender.take_and_reset_peer_counters(&address).unwrap();
+            taken_bytes += taken.bytes_sent;
+            taken_messages += taken.messages_sent;
+
+            (delta_bytes, taken_bytes, taken_messages)
+        }
+    });
+
+    for handle in loops {
+        handle.await.unwrap();
+    }
+
+    done.store(true, Ordering::Release);
+    let (delta_bytes, taken_bytes, taken_messages) = collector.await.unwrap();
+
+    assert_eq!(delta_bytes, LOOPS * MESSAGES * SIZE);
+    assert_eq!(taken_bytes, LOOPS * MESSAGES * SIZE);
+    assert_eq!(taken_messages, LOOPS * MESSAGES);
+
+    // The peer is kept after the reset, with nothing left to take.
+    let stats = sender.stats(&address).unwrap();
+    assert_eq!((stats.messages_sent, stats.bytes_sent), (0, 0));
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reset_stream_does_not_stop_the_following_messages() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+    let (error_tx, mut errors) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .on_stream_error(move |sender, error| {
+            let _ = error_tx.send((sender, error));
+        })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    // Writes half of a message over the cached connection, then gives up.
+    let cached = sender.inner.connections.get(&address).unwrap();
+    let mut stream = cached.conn.open_uni().await.unwrap();
+    stream.write_all(&[7; 32 * 1024]).await.unwrap();
+    stream.reset(ABANDONED_STREAM_CODE.into()).unwrap();
+
+    let (peer, _) = tokio::time::timeout(WAIT, errors.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(peer), sender.sender_address());
+
+    sender.send(address, &b"after"[..]).await.unwrap();
+
+    let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+    assert_eq!(data.as_deref(), Some(&b"after"[..]));
+    assert!(received.try_recv().is_err());
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+/// Starts a 50 MB message from `sender` to `receiver`, then sends a tiny one
+/// while the large one is still being written. Returns the sizes of the
+/// messages handled by `receiver` before the large one was finished.
+async fn tiny_message_after_a_large_one(
+    sender: &Tunnel,
+    receiver: &Tunnel,
+    received: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+) -> Vec<usize> {
+    const LARGE: usize = 50 * 1000 * 1000;
+    const CHUNK: usize = 1024 * 1024;
+
+    let address = receiver.receiver_address().unwrap();
+    let mut large = sender.open_send_stream(address).await.unwrap();
+    large.write_chunk(vec![7; CHUNK]).await.unwrap();
+
+    let tiny = sender.send(address, &b"tiny"[..]);
+    let _ = tokio::time::timeout(Duration::from_millis(500), tiny).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut handled = Vec::new();
+
+    while let Ok(data) = received.try_recv() {
+        handled.push(data.len());
+    }
+
+    for _ in 1..LARGE / CHUNK {
+        large.write_chunk(vec![7; CHUNK]).await.unwrap();
+    }
+
+    large.write_chunk(vec![7; LARGE % CHUNK]).await.unwrap();
+    large.finish().await.unwrap();
+
+    handled
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tiny_message_is_not_blocked_by_a_large_one() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .max_message_size(64 * 1024 * 1024)
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let handled = tiny_message_after_a_large_one(&sender, &receiver, &mut received).await;
+    assert_eq!(handled, [4]);
+
+    let large = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+    assert_eq!(large.map(|data| data.len()), Some(50 * 1000 * 1000));
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ordered_tunnels_handle_messages_in_order() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .max_message_size(64 * 1024 * 1024)
+        .ordered(true)
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let handled = tiny_message_after_a_large_one(&sender, &receiver, &mut received).await;
+    assert!(handled.is_empty(), "{handled:?}");
+
+    for expected in [50 * 1000 * 1000, 4] {
+        let data = tokio::time::timeout(WAIT, received.recv()).await.unwrap();
+        assert_eq!(data.map(|data| data.len()), Some(expected));
+    }
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+/// Receives the next value sent to `rx`, failing if none is sent in time.
+async fn recv<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> T {
+    tokio::time::timeout(WAIT, rx.recv())
+        .await
+        .expect("nothing was received in time")
+        .unwrap()
+}
+
+/// Returns a handler which forwards the data it receives to `tx`, along with
+/// `name`.
+fn forward_named(
+    name: &'static str,
+    tx: mpsc::UnboundedSender<(&'static str, Vec<u8>)>,
+) -> impl AsyncDataHandler {
+    move |_: PublicKey, data: Vec<u8>| {
+        let _ = tx.send((name, data));
+        async {}
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn topics_are_routed_to_their_handlers() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    let receiver = testing::builder()
+        .async_handler(forward_named("default", tx.clone()))
+        .spawn()
+        .await
+        .unwrap();
+    receiver
+        .subscribe("chat", forward_named("chat", tx.clone()))
+        .unwrap();
+    receiver
+        .subscribe("files", forward_named("files", tx))
+        .unwrap();
+
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+    sender.send_on(address, "chat", &b"hi"[..]).await.unwrap();
+    assert_eq!(recv(&mut received).await, ("chat", b"hi".to_vec()));
+
+    sender
+        .send_on(address, "files", &b"a.txt"[..])
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut received).await, ("files", b"a.txt".to_vec()));
+
+    // Topics nobody subscribed to, and plain sends, go to the regular handler.
+    sender
+        .send_on(address, "news", &b"extra"[..])
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut received).await, ("default", b"extra".to_vec()));
+
+    sender.send(address, &b"plain"[..]).await.unwrap();
+    assert_eq!(recv(&mut received).await, ("default", b"plain".to_vec()));
+
+    assert!(receiver.unsubscribe("chat"));
+    assert!(!receiver.unsubscribe("chat"));
+
+    sender.send_on(address, "chat", &b"bye"[..]).await.unwrap();
+    assert_eq!(recv(&mut received).await, ("default", b"bye".to_vec()));
+
+    let invalid = sender.send_on(address, "", &b"x"[..]).await;
+    assert!(
+        matches!(invalid, Err(TunnelError::InvalidTopic { .. })),
+        "{invalid:?}"
+    );
+
+    let long = "t".repeat(MAX_TOPIC_LENGTH + 1);
+    let invalid = receiver.subscribe(long, forward_named("long", mpsc::unbounded_channel().0));
+    assert!(
+        matches!(invalid, Err(TunnelError::InvalidTopic { .. })),
+        "{invalid:?}"
+    );
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_to_a_handlerless_tunnel_fail() {
+    let receiver = testing::builder().spawn().await.unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+
+    let address = receiver.receiver_address().unwrap();
+    let sent = send_over_loopback(&sender, &receiver).await;
+
+    assert!(
+        matches!(sent, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    // Every kind of send uses its own connections, which are refused too.
+    let acked = sender.send_acked(address, &b"acked"[..]).await;
+    assert!(
+        matches!(acked, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{acked:?}"
+    );
+
+    let sent = sender.send_on(address, "topic", &b"topic"[..]).await;
+    assert!(
+        matches!(sent, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    let sent = sender
+        .send_large(address, &b"large"[..], 5, |_, _| {})
+        .await;
+    assert!(
+        matches!(sent, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_fail_once_no_handler_can_process_them() {
+    let (tx, mut received) = mpsc::unbounded_channel();
+
+    // Accepted as it has a bi stream handler, but nothing handles messages.
+    let bi_only = testing::builder()
+        .bi_handler(|_: PublicKey, _: SendStream, _: RecvStream| {})
+        .spawn()
+        .await
+        .unwrap();
+    let receiver = testing::builder()
+        .handler(forward_to(tx))
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &bi_only).await;
+    testing::connect(&sender, &receiver).await;
+
+    // Large enough that the stream is stopped before all of it is received.
+    let message = vec![0u8; 1024 * 1024];
+
+    let address = bi_only.receiver_address().unwrap();
+    let sent = sender.send(address, message.clone()).await;
+    assert!(
+        matches!(sent, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    let address = receiver.receiver_address().unwrap();
+    sender.send(address, &b"handled"[..]).await.unwrap();
+    assert_eq!(recv(&mut received).await, b"handled");
+
+    // The connection was accepted while the handler was set.
+    receiver.set_handler(None);
+
+    let sent = sender.send(address, message).await;
+    assert!(
+        matches!(sent, Err(TunnelError::NoHandler { peer }) if peer == address),
+        "{sent:?}"
+    );
+
+    sender.destroy().await.unwrap();
+    bi_only.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn buffered_messages_are_delivered_once_a_handler_is_set() {
+    let receiver = testing::builder()
+        .no_handler_policy(NoHandlerPolicy::Buffer { max_messages: 2 })
+        .spawn()
+        .await
+        .unwrap();
+    let sender = testing::builder().spawn().await.unwrap();
+    testing::connect(&sender, &receiver).await;
+
+    let address = receiver.receiver_address().unwrap();
+
+    for data in [&b"first"[..], &b"second"[..]] {
+        sender.send(address, data).await.unwrap();
+    }
+
+    // The buffer is full, so this one is discarded.
+    let _ = sender.send(address, &b"third"[..]).await;
+
+    let (tx, mut received) = mpsc::unbounded_channel();
+    receiver.set_handler(Some(Arc::new(RwLock::new(forward_to(tx)))));
+
+    assert_eq!(recv(&mut received).await, b"first");
+    assert_eq!(recv(&mut received).await, b"second");
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(received.try_recv().is_err());
+
+    sender.destroy().await.unwrap();
+    receiver.destroy().await.unwrap();
+}
+
+/// Sends a 64 KiB message to 20 peers on a topic and with acknowledgements,
+/// either copying it for each peer, as sends did when they took slices, or
+/// sharing one buffer, and prints how long each broadcast took. Run it with
+/// `cargo test --release bench -- --ignored --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "benchmark"]
+async fn bench_broadcast_copied_and_shared() {
+    const PEERS: usize = 20;
+    const ROUNDS: u32 = 50;
+
+    let sender = testing::builder().spawn().await.unwrap();
+    let mut receivers = Vec::new();
+
+    for _ in 0..PEERS {
+        let receiver = testing::builder()
+            .handler(|_: PublicKey, _: Vec<u8>| {})
+            .spawn()
+            .await
+            .unwrap();
+        testing::connect(&sender, &receiver).await;
+        receivers.push(receiver);
+    }
+
+    let addresses: Vec<_> = receivers
+        .iter()
+        .map(|receiver| receiver.receiver_address().unwrap())
+        .collect();
+    let message = Bytes::from(vec![7u8; 64 * 1024]);
+
+    for (name, copied) in [("copied", true), ("shared", false)] {
+        let payload = || {
+            if copied {
+                Bytes::copy_from_slice(&message)
+            } else {
+                message.clone()
+            }
+        };
+
+        let start = Instant::now();
+
+        for _ in 0..ROUNDS {
+            let sends = addresses
+                .iter()
+                .map(|&address| sender.send_on(address, "bench", payload()));
+            assert!(join_all(sends).await.iter().all(Result::is_ok));
+        }
+
+        let topic = start.elapsed() / ROUNDS;
+        let start = Instant::now();
+
+        for _ in 0..ROUNDS {
+            let sends = addresses
+                .iter()
+                .map(|&address| sender.send_acked(address, payload()));
+            assert!(join_all(sends).await.iter().all(Result::is_ok));
+        }
+
+        let acked = start.elapsed() / ROUNDS;
+        println!("{name}: {topic:?} per topic broadcast, {acked:?} per acked broadcast");
+    }
+
+    sender.destroy().await.unwrap();
+
+    for receiver in receivers {
+        receiver.destroy().await.unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use iroh::{
+    endpoint::{Connection, ReadToEndError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use n0_future::{FuturesUnordered, StreamExt};
+
+use crate::{
+    BoxedAsyncDataHandler, Bytes, INVALID_PAYLOAD_CODE, MESSAGE_TOO_LARGE_CODE, RecvStream,
+    TunnelError, TunnelProtocol, call_async_handler,
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of topic connections,
+/// whose unidirectional streams each carry a message prefixed with its topic.
+const TOPIC_ALPN_SUFFIX: &[u8] = b"/topic";
+
+/// The maximum length of a topic, in bytes, as it is prefixed with its length
+/// as a `u8`.
+pub const MAX_TOPIC_LENGTH: usize = u8::MAX as usize;
+
+/// Returns the ALPN of the topic connections of a tunnel using `alpn`.
+pub(crate) fn topic_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, TOPIC_ALPN_SUFFIX].concat()
+}
+
+/// Fails with [TunnelError::InvalidTopic] if `topic` is empty or longer than
+/// [MAX_TOPIC_LENGTH].
+pub(crate) fn validate(topic: &str) -> Result<(), TunnelError> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LENGTH {
+        return Err(TunnelError::InvalidTopic {
+            topic: topic.to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the prefix of the messages on a topic, which must be valid. It is
+/// written before each message, so the message itself is never copied.
+pub(crate) fn header(topic: &str) -> Bytes {
+    let mut header = Vec::with_capacity(1 + topic.len());
+
+    header.push(topic.len() as u8);
+    header.extend_from_slice(topic.as_bytes());
+
+    header.into()
+}
+
+/// Splits a payload into its topic and message. Returns `None` if it is not
+/// prefixed with a valid topic.
+fn decode(payload: Vec<u8>) -> Option<(String, Bytes)> {
+    let (&len, rest) = payload.split_first()?;
+    let topic = std::str::from_utf8(rest.get(..len as usize)?)
+        .ok()?
+        .to_owned();
+
+    validate(&topic).ok()?;
+
+    let message = Bytes::from(payload).slice(1 + topic.len()..);
+    Some((topic, message))
+}
+
+/// The handlers subscribed to topics with
+/// [Tunnel::subscribe](crate::Tunnel::subscribe).
+#[derive(Default)]
+pub(crate) struct Topics(Mutex<HashMap<String, Arc<dyn BoxedAsyncDataHandler>>>);
+
+impl Topics {
+    /// Subscribes a handler to a topic, replacing its previous handler.
+    pub fn subscribe(&self, topic: String, handler: Arc<dyn BoxedAsyncDataHandler>) {
+        self.0.lock().unwrap().insert(topic, handler);
+    }
+
+    /// Returns whether a handler was subscribed to the topic.
+    pub fn unsubscribe(&self, topic: &str) -> bool {
+        self.0.lock().unwrap().remove(topic).is_some()
+    }
+
+    fn get(&self, topic: &str) -> Option<Arc<dyn BoxedAsyncDataHandler>> {
+        self.0.lock().unwrap().get(topic).cloned()
+    }
+
+    /// Returns whether a handler is subscribed to any topic.
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+/// Accepts the topic connections of a tunnel, and hands their messages to the
+/// handler subscribed to their topic.
+///
+/// Messages on a topic without a handler are handed to the regular handler of
+/// the tunnel instead, like messages sent with [Tunnel::send](crate::Tunnel::send).
+#[derive(Debug)]
+pub(crate) struct TopicProtocol(pub Arc<TunnelProtocol>);
+
+impl TopicProtocol {
+    async fn handle_stream(&self, connection: &Connection, mut stream: RecvStream) {
+        let sender = connection.remote_id();
+        let max_message_size = self.0.max_message_size();
+        let max_payload_size = max_message_size.saturating_add(1 + MAX_TOPIC_LENGTH);
+
+        let payload = match stream.read_to_end(max_payload_size).await {
+            Ok(payload) => payload,
+            Err(ReadToEndError::TooLong) => {
+                let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+                self.0.message_too_large(sender);
+                return;
+            }
+            Err(ReadToEndError::Read(e)) => {
+                self.0.stream_error(sender, e);
+                return;
+            }
+        };
+
+        let Some((topic, message)) = decode(payload) else {
+            let _ = stream.stop(INVALID_PAYLOAD_CODE.into());
+            return;
+        };
+
+        // The topic does not count towards the size of the message.
+        if message.len() > max_message_size {
+            let _ = stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+            self.0.message_too_large(sender);
+            return;
+        }
+
+        let Some(handler) = self.0.topics.get(&topic) else {
+            self.0
+                .dispatch_stream(connection, &mut stream, message)
+                .await;
+            return;
+        };
+
+        self.0.metrics.record_message_received(sender);
+        self.0.metrics.record_bytes_received(sender, message.len());
+
+        if let Err(e) = call_async_handler(&*handler, sender, message, false).await {
+            self.0.handler_failed(connection, Some(&mut stream), e);
+        }
+    }
+}
+
+impl ProtocolHandler for TopicProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let handled = self.0.has_handler() || !self.0.topics.is_empty();
+
+        if self.0.refuses_unhandled(&connection, handled) {
+            return Ok(());
+        }
+
+        let Some(_disconnect) = self.0.admit(&connection) else {
+            return Ok(());
+        };
+
+        // Like regular messages, up to the maximum number of concurrent
+        // streams are handled at once.
+        let mut handling = FuturesUnordered::new();
+        let limit = self.0.max_concurrent_streams;
+
+        let closing = loop {
+            let paused = self.0.pause.is_paused();
+
+            tokio::select! {
+                stream = connection.accept_uni(), if !paused && handling.len() < limit => {
+                    let Ok(stream) = stream else {
+                        break false;
+                    };
+
+                    // Discarded if the tunnel started shutting down meanwhile.
+                    let Some(active) = self.0.activity.enter() else {
+                        continue;
+                    };
+
+                    let connection = &connection;
+
+                    handling.push(async move {
+                        self.handle_stream(connection, stream).await;
+                        drop(active);
+                    });
+                }
+                _ = self.0.pause.resumed(), if paused => {}
+                Some(()) = handling.next(), if !handling.is_empty() => {}
+                _ = self.0.activity.closed() => break true,
+            }
+        };
+
+        while handling.next().await.is_some() {}
+
+        if closing {
+            self.0.go_away(&connection).await;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+use iroh::{
+    endpoint::{Connection, ReadExactError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+use n0_future::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    ABANDONED_STREAM_CODE, Bytes, DEFAULT_CHUNK_SIZE, MESSAGE_TOO_LARGE_CODE,
+    NO_STREAM_HANDLER_CODE, PROGRESS_INTERVAL, PublicKey, ReadError, RecvStream, SendStream,
+    TunnelError, TunnelProtocol, finish_stream, metrics::Metrics, progress::Progress,
+    schedule::Lane, write_error,
+};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of transfer connections,
+/// whose unidirectional streams each carry a payload prefixed with its length
+/// as a big endian `u64`.
+const TRANSFER_ALPN_SUFFIX: &[u8] = b"/transfer";
+
+/// Returns the ALPN of the transfer connections of a tunnel using `alpn`.
+pub(crate) fn transfer_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, TRANSFER_ALPN_SUFFIX].concat()
+}
+
+type ProgressCallback = Box<dyn FnMut(u64, u64) + Send + Sync>;
+
+/// Sends exactly `len` bytes read from `reader` over a stream of a transfer
+/// connection, in chunks of up to `chunk_size` bytes which each wait for their
+/// turn in `lane`, reporting the progress of the write like
+/// [Tunnel::send_with_progress](crate::Tunnel::send_with_progress).
+pub(crate) async fn send(
+    peer: PublicKey,
+    mut stream: SendStream,
+    mut reader: impl AsyncRead + Unpin,
+    len: u64,
+    chunk_size: usize,
+    lane: &mut Lane<'_>,
+    progress: &Progress,
+) -> Result<(), TunnelError> {
+    stream
+        .write_all(&len.to_be_bytes())
+        .await
+        .map_err(|e| write_error(peer, e))?;
+
+    let mut buffer = vec![0; chunk_size];
+    let mut sent = 0;
+
+    while sent < len {
+        let size = (len - sent).min(buffer.len() as u64) as usize;
+
+        let read = match reader.read(&mut buffer[..size]).await {
+            Ok(0) => {
+                let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+                return Err(TunnelError::LengthMismatch {
+                    expected: len,
+                    actual: Some(sent),
+                });
+            }
+            Ok(read) => read,
+            Err(source) => {
+                let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+                return Err(TunnelError::Source { source });
+            }
+        };
+
+        lane.turn(read).await;
+        stream
+            .write_all(&buffer[..read])
+            .await
+            .map_err(|e| write_error(peer, e))?;
+
+        sent += read as u64;
+
+        // The last update is made once the end of the reader is checked.
+        if sent < len {
+            progress.update(sent, len);
+        }
+    }
+
+    lane.end_turn();
+
+    // The transfer is abandoned if the reader does not end where announced,
+    // rather than silently truncated.
+    match reader.read(&mut buffer[..1]).await {
+        Ok(0) => {}
+        Ok(_) => {
+            let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+            return Err(TunnelError::LengthMismatch {
+                expected: len,
+                actual: None,
+            });
+        }
+        Err(source) => {
+            let _ = stream.reset(ABANDONED_STREAM_CODE.into());
+            return Err(TunnelError::Source { source });
+        }
+    }
+
+    progress.update(len, len);
+
+    finish_stream(stream, peer).await
+}
+
+/// An error which happened while receiving an [IncomingTransfer].
+#[derive(Debug)]
+pub enum TransferError {
+    /// The stream ended before the announced length was received, e.g. as the
+    /// sending tunnel cancelled the transfer.
+    Incomplete { expected: u64, received: u64 },
+    /// The sending tunnel sent more data than it announced.
+    TooLong { expected: u64 },
+    /// The transfer is larger than the limit given to
+    /// [IncomingTransfer::read_to_vec]. The sending tunnel is told that its
+    /// message is too large.
+    TooLarge { len: u64, limit: usize },
+    /// The stream could not be read, e.g. as the connection was lost.
+    Read(ReadError),
+    /// The data could not be written by [IncomingTransfer::copy_to].
+    Write(std::io::Error),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Incomplete { expected, received } => write!(
+                f,
+                "The transfer ended after {received} of {expected} bytes."
+            ),
+            Self::TooLong { expected } => {
+                write!(
+                    f,
+                    "The transfer is longer than the announced {expected} bytes."
+                )
+            }
+            Self::TooLarge { len, limit } => {
+                write!(
+                    f,
+                    "The transfer of {len} bytes exceeds the limit of {limit} bytes."
+                )
+            }
+            Self::Read(source) => write!(f, "Failed to read the transfer: {source}."),
+            Self::Write(source) => write!(f, "Failed to write the transfer: {source}."),
+        }
+    }
+}
+
+impl Error for TransferError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Read(source) => Some(source),
+            Self::Write(source) => Some(source),
+            Self::Incomplete { .. } | Self::TooLong { .. } | Self::TooLarge { .. } => None,
+        }
+    }
+}
+
+/// A payload being received from another tunnel, sent with
+/// [Tunnel::send_large](crate::Tunnel::send_large). Given to a
+/// [TransferHandler].
+///
+/// The length of the payload is known before any of it is read. It can be
+/// read one chunk at a time with [IncomingTransfer::read_chunk], collected
+/// with [IncomingTransfer::read_to_vec], or written to a file with
+/// [IncomingTransfer::copy_to].
+///
+/// Dropping the transfer before it was read in full cancels it, which the
+/// sending tunnel observes as [TunnelError::RemoteStopped].
+pub struct IncomingTransfer {
+    stream: RecvStream,
+    peer: PublicKey,
+    len: u64,
+    received: u64,
+    ended: bool,
+    metrics: Arc<Metrics>,
+    progress: Option<ProgressCallback>,
+    last_report: Instant,
+}
+
+impl IncomingTransfer {
+    fn new(stream: RecvStream, peer: PublicKey, len: u64, metrics: Arc<Metrics>) -> Self {
+        Self {
+            stream,
+            peer,
+            len,
+            received: 0,
+            ended: false,
+            metrics,
+            progress: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Returns the length of the payload announced by the sending tunnel.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether the payload is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns how many bytes of the payload were read so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Sets a function which is called with the amount of bytes read so far
+    /// and the length of the payload, at most once every 50 milliseconds.
+    /// Once all of the payload is read, it is called exactly once with both
+    /// values being equal.
+    pub fn with_progress(mut self, progress: impl FnMut(u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Reads the next chunk of the payload, as soon as it arrives.
+    ///
+    /// Returns `None` once all of the payload was read, and the sending
+    /// tunnel ended the stream where announced. Handlers reading the payload
+    /// this way should read until `None` is returned, so the sending tunnel
+    /// knows the transfer succeeded.
+    pub async fn read_chunk(&mut self) -> Result<Option<Bytes>, TransferError> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        // One more byte is allowed, so data past the announced length is
+        // noticed rather than ignored.
+        let remaining = self.len - self.received;
+        let size = remaining.clamp(1, DEFAULT_CHUNK_SIZE as u64) as usize;
+
+        let chunk = self
+            .stream
+            .read_chunk(size, true)
+            .await
+            .map_err(TransferError::Read)?;
+
+        match chunk {
+            Some(_) if remaining == 0 => {
+                let _ = self.stream.stop(0u32.into());
+                Err(TransferError::TooLong { expected: self.len })
+            }
+            Some(chunk) => {
+                self.received += chunk.bytes.len() as u64;
+                self.metrics
+                    .record_bytes_received(self.peer, chunk.bytes.len());
+                self.report();
+
+                Ok(Some(chunk.bytes))
+            }
+            None if remaining == 0 => {
+                self.ended = true;
+                self.report();
+
+                Ok(None)
+            }
+            None => Err(TransferError::Incomplete {
+                expected: self.len,
+                received: self.received,
+            }),
+        }
+    }
+
+    /// Reads all of the payload into a `Vec<u8>`.
+    ///
+    /// If the announced length exceeds `limit`, nothing is read, the sending
+    /// tunnel is told that its message is too large, and
+    /// [TransferError::TooLarge] is returned.
+    pub async fn read_to_vec(mut self, limit: usize) -> Result<Vec<u8>, TransferError> {
+        if self.len > limit as u64 {
+            let _ = self.stream.stop(MESSAGE_TOO_LARGE_CODE.into());
+            return Err(TransferError::TooLarge {
+                len: self.len,
+                limit,
+            });
+        }
+
+        let mut data = Vec::with_capacity(self.len as usize);
+
+        while let Some(chunk) = self.read_chunk().await? {
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(data)
+    }
+
+    /// Writes all of the payload to `writer`, e.g. a file, without holding
+    /// all of it in memory. Returns how many bytes were written.
+    ///
+    /// If `writer` fails, the transfer is cancelled.
+    pub async fn copy_to(
+        mut self,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<u64, TransferError> {
+        while let Some(chunk) = self.read_chunk().await? {
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(TransferError::Write)?;
+        }
+
+        writer.flush().await.map_err(TransferError::Write)?;
+
+        Ok(self.received)
+    }
+
+    fn report(&mut self) {
+        let Some(progress) = &mut self.progress else {
+            return;
+        };
+
+        if self.ended || self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            progress(self.received, self.len);
+            self.last_report = Instant::now();
+        }
+    }
+}
+
+impl Debug for IncomingTransfer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IncomingTransfer")
+            .field("peer", &self.peer)
+            .field("len", &self.len)
+            .field("received", &self.received)
+            .finish()
+    }
+}
+
+/// A trait implemented for objects which can handle the payloads sent with
+/// [Tunnel::send_large](crate::Tunnel::send_large).
+///
+/// Each transfer is handled in its own task, so transfers can be received
+/// concurrently. The maximum message size of the tunnel does not apply to
+/// transfers, as they are never buffered by the tunnel.
+///
+/// For convenience's sake, this trait is implemented for function pointers. As
+/// such, any function which takes a [PublicKey] and an [IncomingTransfer] in
+/// this order and returns a future can be used as a [TransferHandler].
+pub trait TransferHandler: 'static + Send + Sync {
+    fn process_incoming_transfer(
+        &self,
+        sender: PublicKey,
+        transfer: IncomingTransfer,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+impl<Func, Fut> TransferHandler for Func
+where
+    Func: 'static + Send + Sync + Fn(PublicKey, IncomingTransfer) -> Fut,
+    Fut: Future<Output = ()> + Send,
+{
+    fn process_incoming_transfer(
+        &self,
+        sender: PublicKey,
+        transfer: IncomingTransfer,
+    ) -> impl Future<Output = ()> + Send {
+        self(sender, transfer)
+    }
+}
+
+/// An object safe version of [TransferHandler], so it can be stored in a
+/// [TunnelProtocol].
+pub(crate) trait BoxedTransferHandler: 'static + Send + Sync {
+    fn process_incoming_transfer_boxed(
+        &self,
+        sender: PublicKey,
+        transfer: IncomingTransfer,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl<T: TransferHandler> BoxedTransferHandler for T {
+    fn process_incoming_transfer_boxed(
+        &self,
+        sender: PublicKey,
+        transfer: IncomingTransfer,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.process_incoming_transfer(sender, transfer))
+    }
+}
+
+/// Accepts the transfer connections of a tunnel, and hands their payloads to
+/// its [TransferHandler].
+#[derive(Debug)]
+pub(crate) struct TransferProtocol(pub Arc<TunnelProtocol>);
+
+impl TransferProtocol {
+    async fn handle_stream(
+        protocol: Arc<TunnelProtocol>,
+        handler: Arc<dyn BoxedTransferHandler>,
+        sender: PublicKey,
+        mut stream: RecvStream,
+    ) {
+        let mut len = [0; 8];
+
+        if let Err(e) = stream.read_exact(&mut len).await {
+            if let ReadExactError::ReadError(e) = e {
+                protocol.stream_error(sender, e);
+            }
+
+            return;
+        }
+
+        // Discarded if the tunnel started shutting down meanwhile.
+        let Some(_active) = protocol.activity.enter() else {
+            return;
+        };
+
+        protocol.metrics.record_message_received(sender);
+
+        let transfer = IncomingTransfer::new(
+            stream,
+            sender,
+            u64::from_be_bytes(len),
+            Arc::clone(&protocol.metrics),
+        );
+
+        handler
+            .process_incoming_transfer_boxed(sender, transfer)
+            .await;
+    }
+}
+
+impl ProtocolHandler for TransferProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let handled = self.0.transfer_handler.is_some();
+
+        if self.0.refuses_unhandled(&connection, handled) {
+            return Ok(());
+        }
+
+        let Some(_disconnect) = self.0.admit(&connection) else {
+            return Ok(());
+        };
+
+        loop {
+            let paused = self.0.pause.is_paused();
+
+            tokio::select! {
+                stream = connection.accept_uni(), if !paused => {
+                    let Ok(mut stream) = stream else {
+                        break;
+                    };
+
+                    // Refused right away, so the other end does not send a
+                    // payload which is never read.
+                    let Some(handler) = self.0.transfer_handler.clone() else {
+                        let _ = stream.stop(NO_STREAM_HANDLER_CODE.into());
+                        continue;
+                    };
+
+                    n0_future::task::spawn(Self::handle_stream(
+                        Arc::clone(&self.0),
+                        handler,
+                        connection.remote_id(),
+                        stream,
+                    ));
+                }
+                _ = self.0.pause.resumed(), if paused => {}
+                _ = self.0.activity.closed() => {
+                    self.0.go_away(&connection).await;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
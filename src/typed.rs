@@ -0,0 +1,203 @@
+use std::{
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Bytes, DataHandler, HandlerError, PublicKey, Tunnel, TunnelError};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A trait implemented for serialization formats, which turn typed messages
+/// into bytes and back. See [Tunnel::send_with_format] and
+/// [TypedHandler::with_format].
+///
+/// [Postcard] and [Json] are provided, and other formats can be plugged in by
+/// implementing this trait.
+pub trait Format: 'static + Send + Sync {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError>;
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BoxError>;
+}
+
+/// The compact binary format of the `postcard` crate. The default format of
+/// typed messages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+impl Format for Postcard {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(postcard::to_stdvec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BoxError> {
+        Ok(postcard::from_bytes(data)?)
+    }
+}
+
+/// JSON, which is larger and slower than [Postcard], but readable and easy to
+/// produce from other languages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Format for Json {
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+impl Tunnel {
+    /// Sends a typed message to another tunnel, serialized with [Postcard].
+    ///
+    /// This is a shorthand for [Tunnel::send_with_format] with [Postcard]. The
+    /// other tunnel can deserialize the message with a [TypedHandler].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `value`: The message to be sent.
+    pub async fn send_typed<T: Serialize>(
+        &self,
+        address: impl Into<PublicKey>,
+        value: &T,
+    ) -> Result<(), TunnelError> {
+        self.send_with_format(address, value, &Postcard).await
+    }
+
+    /// Sends a typed message to another tunnel, serialized with `format`.
+    ///
+    /// Fails with [TunnelError::Serialize] if the message cannot be
+    /// serialized, in which case nothing is sent. Otherwise, this behaves like
+    /// [Tunnel::send].
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send data to.
+    ///  Can be any value which can be converted to a [PublicKey].
+    /// - `value`: The message to be sent.
+    /// - `format`: The format to serialize the message with. The receiving
+    /// tunnel must deserialize it with the same format.
+    pub async fn send_with_format<T: Serialize, F: Format>(
+        &self,
+        address: impl Into<PublicKey>,
+        value: &T,
+        format: &F,
+    ) -> Result<(), TunnelError> {
+        let data = format
+            .serialize(value)
+            .map_err(|source| TunnelError::Serialize { source })?;
+
+        self.send(address, data).await
+    }
+}
+
+/// A message which could not be deserialized by a [TypedHandler].
+#[derive(Debug)]
+pub struct DeserializeError {
+    /// The message, as it was received.
+    pub data: Vec<u8>,
+    pub source: BoxError,
+}
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to deserialize a message of {} bytes: {}.",
+            self.data.len(),
+            self.source
+        )
+    }
+}
+
+impl Error for DeserializeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+type TypedCallback<T> = Box<dyn FnMut(PublicKey, T) + Send + Sync>;
+type ErrorCallback = Box<dyn FnMut(PublicKey, DeserializeError) + Send + Sync>;
+
+/// A [DataHandler] which deserializes incoming messages before handing them
+/// to a callback.
+///
+/// Messages are deserialized with [Postcard] unless another format is set
+/// with [TypedHandler::with_format]. Messages which cannot be deserialized
+/// are given to the callback set with [TypedHandler::on_error], along with
+/// their raw bytes, and discarded if there is none.
+///
+/// Typed messages are regular messages, so typed and untyped tunnels can
+/// talk to each other, as long as the application agrees on which messages
+/// are typed.
+pub struct TypedHandler<T, F = Postcard> {
+    handler: TypedCallback<T>,
+    on_error: Option<ErrorCallback>,
+    format: F,
+}
+
+impl<T: DeserializeOwned + 'static> TypedHandler<T> {
+    /// Creates a handler which calls `handler` with every message which
+    /// deserializes to a `T`.
+    pub fn new(handler: impl FnMut(PublicKey, T) + Send + Sync + 'static) -> Self {
+        Self {
+            handler: Box::new(handler),
+            on_error: None,
+            format: Postcard,
+        }
+    }
+}
+
+impl<T: DeserializeOwned + 'static, F: Format> TypedHandler<T, F> {
+    /// Sets the format messages are deserialized with.
+    pub fn with_format<G: Format>(self, format: G) -> TypedHandler<T, G> {
+        TypedHandler {
+            handler: self.handler,
+            on_error: self.on_error,
+            format,
+        }
+    }
+
+    /// Sets the function called with the messages which cannot be
+    /// deserialized.
+    pub fn on_error(
+        mut self,
+        callback: impl FnMut(PublicKey, DeserializeError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+}
+
+impl<T: DeserializeOwned + 'static, F: Format> DataHandler for TypedHandler<T, F> {
+    fn process_incoming_data(
+        &mut self,
+        sender: PublicKey,
+        data: Bytes,
+    ) -> Result<(), HandlerError> {
+        match self.format.deserialize(&data) {
+            Ok(value) => (self.handler)(sender, value),
+            Err(source) => {
+                if let Some(on_error) = &mut self.on_error {
+                    let data = data.into();
+                    on_error(sender, DeserializeError { data, source });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, F: Debug> Debug for TypedHandler<T, F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedHandler")
+            .field("format", &self.format)
+            .finish()
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::{Bytes, PublicKey, TunnelProtocol};
+
+/// What a tunnel does with incoming messages while it has no handler to give
+/// them to, see [TunnelBuilder::no_handler_policy](crate::TunnelBuilder::no_handler_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoHandlerPolicy {
+    /// Connections are refused with
+    /// [NO_HANDLER_CLOSE_CODE](crate::NO_HANDLER_CLOSE_CODE) while nothing
+    /// can handle what they carry, so sends to the tunnel fail with
+    /// [TunnelError::NoHandler](crate::TunnelError::NoHandler) instead of
+    /// being discarded silently. Regular connections need a handler of
+    /// incoming data or a [BiStreamHandler](crate::BiStreamHandler), topic
+    /// connections a handler or a subscribed topic, and the connections of
+    /// [Tunnel::send_large](crate::Tunnel::send_large) a
+    /// [TransferHandler](crate::TransferHandler).
+    ///
+    /// Messages which arrive over a connection accepted before the handler
+    /// was removed, or while the tunnel only has a
+    /// [BiStreamHandler](crate::BiStreamHandler), have their stream stopped
+    /// with the same code instead, so their sends fail the same way.
+    #[default]
+    Refuse,
+    /// Up to `max_messages` messages are kept until a handler is set, e.g.
+    /// with [Tunnel::set_handler](crate::Tunnel::set_handler), which then
+    /// receives them in the order they arrived. Messages which arrive while
+    /// the buffer is full are discarded.
+    ///
+    /// A [StreamingDataHandler](crate::StreamingDataHandler) only handles
+    /// whole streams, so the buffered messages are discarded once one is set.
+    ///
+    /// Datagrams and acknowledged messages are never buffered.
+    Buffer { max_messages: usize },
+}
+
+/// The messages kept by [NoHandlerPolicy::Buffer].
+#[derive(Debug, Default)]
+pub(crate) struct Backlog {
+    messages: Mutex<VecDeque<(PublicKey, Bytes)>>,
+    /// Notified when a handler is set, so the messages are delivered.
+    handler_set: Notify,
+}
+
+impl Backlog {
+    /// Keeps a message, unless `max_messages` are already kept. Returns
+    /// whether it was kept.
+    pub fn push(&self, sender: PublicKey, data: Bytes, max_messages: usize) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+
+        if messages.len() >= max_messages {
+            return false;
+        }
+
+        messages.push_back((sender, data));
+        true
+    }
+
+    /// Wakes up [deliver_backlog], so the kept messages are given to the new
+    /// handler.
+    pub fn handler_set(&self) {
+        self.handler_set.notify_one();
+    }
+
+    fn pop(&self) -> Option<(PublicKey, Bytes)> {
+        self.messages.lock().unwrap().pop_front()
+    }
+}
+
+/// Gives the messages kept by [NoHandlerPolicy::Buffer] to the handler of a
+/// tunnel whenever one is set, until the tunnel is destroyed.
+pub(crate) async fn deliver_backlog(protocol: Arc<TunnelProtocol>) {
+    loop {
+        protocol.backlog.handler_set.notified().await;
+
+        while protocol.has_handler()
+            && let Some((sender, data)) = protocol.backlog.pop()
+        {
+            // The connection of the message may be gone, so only the error
+            // callback is notified.
+            if let Err(e) = protocol.deliver(sender, data, false).await {
+                protocol.handler_error(sender, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use iroh::{
+    endpoint::{Connection, ReadError, ReadExactError},
+    protocol::{AcceptError, ProtocolHandler},
+};
+
+use crate::{PublicKey, TunnelError, VERSION_MISMATCH_CODE, connection_error, write_error};
+
+/// Appended to the ALPN of a tunnel to get the ALPN of versioned connections.
+/// Appended last, so framed connections can be versioned too.
+const VERSIONED_ALPN_SUFFIX: &[u8] = b"/versioned";
+
+/// Returns the ALPN of the versioned connections of a tunnel using `alpn`.
+pub(crate) fn versioned_alpn(alpn: &[u8]) -> Vec<u8> {
+    [alpn, VERSIONED_ALPN_SUFFIX].concat()
+}
+
+/// Announces the version of this tunnel over a new versioned connection, and
+/// checks that the other tunnel announces the same one.
+///
+/// The first bidirectional stream of a versioned connection carries the
+/// version of each side as a single byte. On mismatch, the connection is
+/// closed with [VERSION_MISMATCH_CODE].
+pub(crate) async fn negotiate(
+    peer: PublicKey,
+    connection: &Connection,
+    version: u8,
+) -> Result<(), TunnelError> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(|e| connection_error(peer, e))?;
+
+    send.write_all(&[version])
+        .await
+        .map_err(|e| write_error(peer, e))?;
+    send.finish().map_err(|e| write_error(peer, e.into()))?;
+
+    let mut remote = [0];
+
+    let remote = match recv.read_exact(&mut remote).await {
+        Ok(()) => Some(remote[0]),
+        Err(ReadExactError::ReadError(ReadError::ConnectionLost(e))) => {
+            return Err(connection_error(peer, e));
+        }
+        Err(_) => None,
+    };
+
+    if remote != Some(version) {
+        connection.close(VERSION_MISMATCH_CODE.into(), b"version mismatch");
+
+        return Err(TunnelError::VersionMismatch {
+            peer,
+            local: version,
+            remote,
+        });
+    }
+
+    Ok(())
+}
+
+/// Answers the version announcement of versioned connections, before handing
+/// them to the protocol handling their messages.
+#[derive(Debug)]
+pub(crate) struct VersionedProtocol<P> {
+    pub inner: P,
+    pub version: u8,
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for VersionedProtocol<P> {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+            return Ok(());
+        };
+
+        let mut remote = [0];
+
+        if recv.read_exact(&mut remote).await.is_err() {
+            connection.close(VERSION_MISMATCH_CODE.into(), b"version mismatch");
+            return Ok(());
+        }
+
+        // Answered even on mismatch, so the other tunnel can report both
+        // versions. It then closes the connection itself.
+        if send.write_all(&[self.version]).await.is_err() || send.finish().is_err() {
+            return Ok(());
+        }
+
+        if remote[0] != self.version {
+            connection.closed().await;
+            return Ok(());
+        }
+
+        self.inner.accept(connection).await
+    }
+}
@@ -0,0 +1,54 @@
+// @ts-check
+
+/**
+ * A small component showing the messages received from a peer and whether
+ * the peer is connected. It only depends on the interface of `Tunnel`, so its
+ * tests can give it a `MockTunnel` instead.
+ */
+export class PeerStatus {
+    /**
+     * @param {import("../pkg/tunnel_js.js").Tunnel} tunnel
+     * @param {import("../pkg/tunnel_js.js").PublicKey} address
+     */
+    constructor(tunnel, address) {
+        this.peer = tunnel.peer(address);
+        this.connected = this.peer.isConnected;
+        /** @type {string[]} */
+        this.messages = [];
+        /** @type {string | null} */
+        this.error = null;
+
+        this.watcher = this.peer.watch((connected) => {
+            this.connected = connected;
+        });
+
+        tunnel.setHandler((_sender, data) => {
+            this.messages.push(new TextDecoder().decode(data));
+        });
+    }
+
+    /**
+     * Sends a message to the peer, keeping the name of the error if it fails.
+     *
+     * @param {string} text
+     */
+    async say(text) {
+        try {
+            await this.peer.send(new TextEncoder().encode(text));
+            this.error = null;
+        } catch (error) {
+            this.error = error instanceof Error ? error.name : String(error);
+        }
+    }
+
+    render() {
+        const state = this.connected ? "online" : "offline";
+        const error = this.error ? ` (${this.error})` : "";
+
+        return `${state}${error}: ${this.messages.join(", ")}`;
+    }
+
+    dispose() {
+        this.peer.unwatch(this.watcher);
+    }
+}
@@ -0,0 +1,61 @@
+// @ts-check
+
+// Build the package for Node.js first, then run the test with Node.js:
+//
+//     wasm-pack build --target nodejs tunnel_js
+//     node --test tunnel_js/examples/
+
+import assert from "node:assert/strict";
+import { test } from "node:test";
+
+import { MockTunnel, PublicKey } from "../pkg/tunnel_js.js";
+import { PeerStatus } from "./peer_status.js";
+
+const PEER = new PublicKey("8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c");
+
+async function mount() {
+    const tunnel = await MockTunnel.new(() => {});
+    return { tunnel, status: new PeerStatus(tunnel, PEER) };
+}
+
+test("shows incoming messages", async () => {
+    const { tunnel, status } = await mount();
+
+    tunnel.emitData(PEER, new TextEncoder().encode("hello"));
+    tunnel.emitData(PEER, new TextEncoder().encode("world"));
+
+    assert.equal(status.render(), "offline: hello, world");
+});
+
+test("follows the connection of the peer", async () => {
+    const { tunnel, status } = await mount();
+
+    tunnel.setConnected(PEER, true);
+    assert.equal(status.render(), "online: ");
+
+    status.dispose();
+    tunnel.setConnected(PEER, false);
+    assert.equal(status.render(), "online: ");
+});
+
+test("sends messages to the peer", async () => {
+    const { tunnel, status } = await mount();
+
+    await status.say("hi");
+
+    const [sent] = tunnel.sent();
+    assert.equal(tunnel.sent().length, 1);
+    assert.equal(new TextDecoder().decode(sent.data), "hi");
+    assert.equal(tunnel.info(PEER).messagesSent, 1);
+});
+
+test("shows failed sends", async () => {
+    const { tunnel, status } = await mount();
+
+    tunnel.failNextSend("TimeoutError");
+    await status.say("hi");
+    assert.equal(status.render(), "offline (TimeoutError): ");
+
+    await status.say("hi again");
+    assert.equal(status.render(), "offline: ");
+});
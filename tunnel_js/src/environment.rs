@@ -0,0 +1,138 @@
+use js_sys::{Array, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+use crate::named_error;
+
+/// Returns the capabilities which a tunnel needs but the current environment
+/// lacks.
+///
+/// This only inspects globals, so it never binds sockets or contacts relays.
+pub(crate) fn missing_capabilities() -> Vec<&'static str> {
+    let mut missing = Vec::new();
+
+    // Only browsers define `isSecureContext`, other environments are fine.
+    if global("isSecureContext").as_bool() == Some(false) {
+        missing.push("secure-context");
+    }
+
+    if !global("WebSocket").is_function() {
+        missing.push("websocket");
+    }
+
+    let crypto = global("crypto");
+    let get_random_values = if crypto.is_object() {
+        Reflect::get(&crypto, &"getRandomValues".into()).unwrap_or(JsValue::UNDEFINED)
+    } else {
+        JsValue::UNDEFINED
+    };
+
+    if !get_random_values.is_function() {
+        missing.push("crypto");
+    }
+
+    missing
+}
+
+/// Returns a `{ supported, missing }` object describing whether the current
+/// environment can run a tunnel.
+pub(crate) fn support_report() -> Result<Object, JsValue> {
+    let missing = missing_capabilities();
+
+    let report = Object::new();
+    Reflect::set(&report, &"supported".into(), &missing.is_empty().into())?;
+    Reflect::set(&report, &"missing".into(), &to_array(&missing))?;
+
+    Ok(report)
+}
+
+/// Creates an `EnvironmentUnsupportedError`, whose `missing` field lists the
+/// missing capabilities.
+pub(crate) fn unsupported_error(missing: &[&str]) -> JsValue {
+    let error = named_error(
+        "EnvironmentUnsupportedError",
+        &format!(
+            "This environment cannot run a tunnel. Missing capabilities: {}.",
+            missing.join(", ")
+        ),
+    );
+
+    let _ = Reflect::set(&error, &"missing".into(), &to_array(missing));
+    error
+}
+
+fn global(name: &str) -> JsValue {
+    Reflect::get(&js_sys::global(), &name.into()).unwrap_or(JsValue::UNDEFINED)
+}
+
+fn to_array(values: &[&str]) -> Array {
+    values
+        .iter()
+        .map(|value| JsValue::from_str(value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use js_sys::{Array, Function, Reflect};
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use crate::Tunnel;
+
+    /// Replaces a global until dropped.
+    struct Stub {
+        name: &'static str,
+        original: JsValue,
+    }
+
+    impl Stub {
+        fn new(name: &'static str, value: JsValue) -> Self {
+            let original = super::global(name);
+            Reflect::set(&js_sys::global(), &name.into(), &value).unwrap();
+
+            Self { name, original }
+        }
+    }
+
+    impl Drop for Stub {
+        fn drop(&mut self) {
+            let global = js_sys::global();
+
+            if self.original.is_undefined() {
+                let _ = Reflect::delete_property(&global, &self.name.into());
+            } else {
+                let _ = Reflect::set(&global, &self.name.into(), &self.original);
+            }
+        }
+    }
+
+    fn field(value: &JsValue, name: &str) -> JsValue {
+        Reflect::get(value, &name.into()).unwrap()
+    }
+
+    fn missing(value: &JsValue) -> Vec<String> {
+        Array::from(&field(value, "missing"))
+            .iter()
+            .filter_map(|capability| capability.as_string())
+            .collect()
+    }
+
+    #[wasm_bindgen_test]
+    async fn missing_globals_are_reported_before_anything_is_bound() {
+        let _insecure = Stub::new("isSecureContext", false.into());
+        let _websocket = Stub::new("WebSocket", JsValue::UNDEFINED);
+
+        let report = JsValue::from(Tunnel::is_supported().await.unwrap());
+        assert_eq!(field(&report, "supported").as_bool(), Some(false));
+        assert_eq!(missing(&report), ["secure-context", "websocket"]);
+
+        let error = Tunnel::new(Function::new_no_args(""), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            field(&error, "name").as_string().as_deref(),
+            Some("EnvironmentUnsupportedError")
+        );
+        assert_eq!(missing(&error), ["secure-context", "websocket"]);
+    }
+}
@@ -1,16 +1,345 @@
-use std::str::FromStr;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, hash_map::Entry},
+    rc::{Rc, Weak},
+    str::FromStr,
+    time::Duration,
+};
 
-use ::tunnel::{PublicKey as NativePublicKey, Tunnel as NativeTunnel};
-use futures::{SinkExt, StreamExt, channel::mpsc::channel};
-use js_sys::{Function, Uint8Array};
+use ::tunnel::{PublicKey as NativePublicKey, Tunnel as NativeTunnel, TunnelBuilder, TunnelError};
+use js_sys::{Function, Object, Promise, Reflect, Uint8Array};
+use n0_future::{
+    future::zip,
+    time::{Instant, timeout},
+};
+use tokio::sync::{
+    mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded_channel},
+    oneshot,
+};
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+mod environment;
+mod mock;
+mod singleton;
+
+pub use mock::{MockPeer, MockTunnel};
+
+const DEFAULT_MAX_BATCH: usize = 64;
+const DEFAULT_MAX_DELAY_MS: f64 = 4.0;
+const DEFAULT_QUEUE_SIZE: usize = 32;
+
+const TUNNEL_DESTROYED_MSG: &str = "This tunnel was previously destroyed.";
 
 struct DataEvent {
     sender: NativePublicKey,
     data: Vec<u8>,
 }
 
+/// A connection of a tunnel which was established (`true`) or closed
+/// (`false`), with the address of the other tunnel.
+type PeerEvent = (NativePublicKey, bool);
+
+/// A callback registered with [Peer::watch] or [Tunnel::watch_peer].
+enum Watcher {
+    /// Called with whether the tunnel is connected to the peer.
+    Connected(Function),
+    /// Called with the state of the peer, whenever it differs from `last`.
+    State {
+        callback: Function,
+        last: Cell<&'static str>,
+    },
+}
+
+/// The callbacks registered with [Peer::watch] and [Tunnel::watch_peer],
+/// shared by a tunnel and its peers, along with what they are told about.
+#[derive(Default)]
+struct Watchers {
+    next_id: Cell<u32>,
+    callbacks: RefCell<Vec<(u32, NativePublicKey, Watcher)>>,
+    /// The peers with at least one open connection, incoming or outgoing.
+    connected: RefCell<HashSet<NativePublicKey>>,
+    /// How many operations of this tunnel are in flight for each peer.
+    connecting: RefCell<HashMap<NativePublicKey, usize>>,
+}
+
+impl Watchers {
+    fn add(&self, address: NativePublicKey, callback: Function) -> u32 {
+        self.insert(address, Watcher::Connected(callback))
+    }
+
+    /// Calls `callback` with the current `state` of a peer, then registers
+    /// it. If the callback throws, it is not registered and the error is
+    /// returned.
+    fn add_state(
+        &self,
+        address: NativePublicKey,
+        state: &'static str,
+        callback: Function,
+    ) -> Result<u32, JsValue> {
+        callback.call1(&JsValue::null(), &JsValue::from_str(state))?;
+
+        let last = Cell::new(state);
+        Ok(self.insert(address, Watcher::State { callback, last }))
+    }
+
+    fn insert(&self, address: NativePublicKey, watcher: Watcher) -> u32 {
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+
+        self.callbacks.borrow_mut().push((id, address, watcher));
+        id
+    }
+
+    fn remove(&self, id: u32) -> bool {
+        let mut callbacks = self.callbacks.borrow_mut();
+        let len = callbacks.len();
+
+        callbacks.retain(|(watcher, ..)| *watcher != id);
+        callbacks.len() != len
+    }
+
+    fn clear(&self) {
+        self.callbacks.borrow_mut().clear();
+    }
+
+    fn notify(&self, (address, connected): PeerEvent) {
+        if connected {
+            self.connected.borrow_mut().insert(address);
+        } else {
+            self.connected.borrow_mut().remove(&address);
+        }
+
+        // A callback may watch or unwatch, so the callbacks must not stay
+        // borrowed while they run.
+        let callbacks: Vec<Function> = self
+            .callbacks
+            .borrow()
+            .iter()
+            .filter_map(|(_, watched, watcher)| match watcher {
+                Watcher::Connected(callback) if *watched == address => Some(callback.clone()),
+                _ => None,
+            })
+            .collect();
+
+        call_all(&callbacks, &JsValue::from(connected));
+    }
+
+    /// Returns the state of a peer, as given to the callbacks registered with
+    /// [Tunnel::watch_peer].
+    fn state(&self, tunnel: &NativeTunnel, address: &NativePublicKey) -> &'static str {
+        // The events of a new connection may not have been received yet, so
+        // the connection cache is checked as well.
+        if tunnel.is_connected(address) || self.connected.borrow().contains(address) {
+            let direct = tunnel
+                .connection_status(address)
+                .is_some_and(|status| status.is_direct());
+
+            if direct {
+                "connected-direct"
+            } else {
+                "connected-relay"
+            }
+        } else if self.connecting.borrow().contains_key(address) {
+            "connecting"
+        } else {
+            "disconnected"
+        }
+    }
+
+    /// Tells the callbacks registered with [Tunnel::watch_peer] about the
+    /// `state` of a peer, if it changed since they were last called.
+    fn update(&self, address: NativePublicKey, state: &'static str) {
+        let callbacks: Vec<Function> = self
+            .callbacks
+            .borrow()
+            .iter()
+            .filter_map(|(_, watched, watcher)| match watcher {
+                Watcher::State { callback, last } if *watched == address && last.get() != state => {
+                    last.set(state);
+                    Some(callback.clone())
+                }
+                _ => None,
+            })
+            .collect();
+
+        call_all(&callbacks, &JsValue::from_str(state));
+    }
+
+    /// Marks a peer as `"connecting"` until the returned guard is dropped,
+    /// unless the tunnel is already connected to it.
+    fn connecting<'a>(
+        &'a self,
+        tunnel: &'a NativeTunnel,
+        address: NativePublicKey,
+    ) -> Connecting<'a> {
+        *self.connecting.borrow_mut().entry(address).or_default() += 1;
+        self.update(address, self.state(tunnel, &address));
+
+        Connecting {
+            watchers: self,
+            tunnel,
+            address,
+        }
+    }
+}
+
+/// An operation in flight for a peer, returned by [Watchers::connecting].
+struct Connecting<'a> {
+    watchers: &'a Watchers,
+    tunnel: &'a NativeTunnel,
+    address: NativePublicKey,
+}
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut entry) =
+            self.watchers.connecting.borrow_mut().entry(self.address)
+        {
+            *entry.get_mut() -= 1;
+
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+
+        let state = self.watchers.state(self.tunnel, &self.address);
+        self.watchers.update(self.address, state);
+    }
+}
+
+/// Calls each callback with `value`, reporting what they throw as unhandled
+/// promise rejections.
+fn call_all(callbacks: &[Function], value: &JsValue) {
+    for callback in callbacks {
+        if let Err(error) = callback.call1(&JsValue::null(), value) {
+            let _ = Promise::reject(&error);
+        }
+    }
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const PEER_INFO: &str = r#"
+/**
+ * The state of the connection to a peer, returned by `Tunnel.info` and
+ * `Peer.info`.
+ */
+export interface PeerInfo {
+    /** Whether the tunnel has a live connection to the peer. */
+    connected: boolean;
+    /** How long ago the connection was established, if one is cached. */
+    ageMs: number | null;
+    /** Whether the peer is reached without a relay, if known. */
+    direct: boolean | null;
+    /** The current round-trip time of the connection, if one is cached. */
+    rttMs: number | null;
+    messagesSent: number;
+    bytesSent: number;
+    /** Sends to the peer which failed, including those which timed out. */
+    sendErrors: number;
+}
+
+/** The state of the connection to a peer, given to `Tunnel.watchPeer` callbacks. */
+export type PeerState = "disconnected" | "connecting" | "connected-direct" | "connected-relay";
+"#;
+
+/// Controls how incoming data is coalesced before being dispatched to the
+/// handler.
+#[derive(Clone, Copy)]
+struct DispatchOptions {
+    max_batch: usize,
+    max_delay: Duration,
+}
+
+impl DispatchOptions {
+    /// Reads the `dispatch` field of the options object, if present.
+    fn from_options(options: &Object) -> Result<Option<Self>, JsError> {
+        let dispatch = get_field(options, "dispatch")?;
+
+        if dispatch.is_undefined() || dispatch.is_null() {
+            return Ok(None);
+        }
+
+        let max_batch = get_field(&dispatch, "maxBatch")?
+            .as_f64()
+            .map_or(DEFAULT_MAX_BATCH, |max_batch| max_batch.max(1.0) as usize);
+
+        let max_delay_ms = get_field(&dispatch, "maxDelayMs")?
+            .as_f64()
+            .unwrap_or(DEFAULT_MAX_DELAY_MS)
+            .max(0.0);
+
+        Ok(Some(Self {
+            max_batch,
+            max_delay: Duration::from_secs_f64(max_delay_ms / 1000.0),
+        }))
+    }
+}
+
+/// The options of a tunnel which control how incoming data reaches the
+/// callback.
+struct ReceiveOptions {
+    dispatch: Option<DispatchOptions>,
+    queue_size: usize,
+    on_error: Option<Function>,
+}
+
+impl ReceiveOptions {
+    fn from_options(options: Option<&Object>) -> Result<Self, JsError> {
+        let Some(options) = options else {
+            return Ok(Self {
+                dispatch: None,
+                queue_size: DEFAULT_QUEUE_SIZE,
+                on_error: None,
+            });
+        };
+
+        let queue_size = get_field(options, "queueSize")?
+            .as_f64()
+            .map_or(DEFAULT_QUEUE_SIZE, |queue_size| {
+                queue_size.max(1.0) as usize
+            });
+
+        let on_error = get_field(options, "onError")?;
+
+        let on_error = if on_error.is_undefined() || on_error.is_null() {
+            None
+        } else {
+            Some(
+                on_error
+                    .dyn_into()
+                    .map_err(|_| JsError::new("The `onError` option must be a function."))?,
+            )
+        };
+
+        Ok(Self {
+            dispatch: DispatchOptions::from_options(options)?,
+            queue_size,
+            on_error,
+        })
+    }
+}
+
+fn singleton_option(options: &Object) -> Result<Option<String>, JsError> {
+    let singleton = get_field(options, "singleton")?;
+
+    if singleton.is_undefined() || singleton.is_null() {
+        return Ok(None);
+    }
+
+    singleton
+        .as_string()
+        .map(Some)
+        .ok_or_else(|| JsError::new("The `singleton` option must be a string."))
+}
+
+fn get_field(object: &JsValue, field: &str) -> Result<JsValue, JsError> {
+    Reflect::get(object, &JsValue::from_str(field))
+        .map_err(|_| JsError::new(&format!("Could not read the `{field}` option.")))
+}
+
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct PublicKey(NativePublicKey);
 
 #[wasm_bindgen]
@@ -25,36 +354,130 @@ impl PublicKey {
 
 /// A tunnel used to send and receive data.
 #[wasm_bindgen]
-pub struct Tunnel(NativeTunnel);
+pub struct Tunnel {
+    inner: Rc<NativeTunnel>,
+    handler: Rc<RefCell<Option<Function>>>,
+    events: Sender<DataEvent>,
+    peer_events: UnboundedSender<PeerEvent>,
+    watchers: Rc<Watchers>,
+    dispatch_finished: oneshot::Receiver<()>,
+    dispatch_turns: Rc<Cell<u32>>,
+    /// The name and id under which this tunnel is registered as a singleton.
+    singleton: Option<(String, f64)>,
+}
 
 #[wasm_bindgen]
 impl Tunnel {
     /// Creates a new tunnel using the provided callback.
-    pub async fn new(handler: Function) -> Result<Self, JsError> {
-        let (tx, mut rx) = channel::<DataEvent>(32);
+    ///
+    /// # Options
+    ///
+    /// - `dispatch`: If present, incoming data is coalesced before being
+    /// dispatched, and the callback is called for each message of a batch
+    /// within a single task. Takes an object with the following fields:
+    ///   - `maxBatch`: The maximum amount of messages in a batch. Defaults to 64.
+    ///   - `maxDelayMs`: The maximum amount of milliseconds to wait for more
+    ///   messages before dispatching a batch. Defaults to 4.
+    /// - `queueSize`: How many messages wait for the callback before the
+    /// tunnel stops reading incoming data, so a slow callback slows down the
+    /// senders instead of letting memory grow. Defaults to 32.
+    /// - `onError`: A function called with whatever the callback throws. By
+    /// default, such errors are reported as unhandled promise rejections.
+    /// Either way, the following messages are still dispatched.
+    /// - `singleton`: If present, the tunnel is registered under this name,
+    /// and later calls with the same name return the same tunnel instead of
+    /// creating a new one (even if the wasm module is instantiated again, e.g.
+    /// by HMR). The callback and other options of later calls are ignored. The
+    /// tunnel stays registered until it is destroyed or `closeSingleton` is
+    /// called.
+    #[wasm_bindgen(unchecked_return_type = "Tunnel")]
+    pub async fn new(handler: Function, options: Option<Object>) -> Result<JsValue, JsValue> {
+        let receive = ReceiveOptions::from_options(options.as_ref())?;
+        let singleton = match &options {
+            Some(options) => singleton_option(options)?,
+            None => None,
+        };
 
-        let inner = NativeTunnel::new(move |sender: NativePublicKey, data: Vec<u8>| {
-            let mut tx_clone = tx.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                tx_clone.send(DataEvent { sender, data }).await.unwrap();
-            });
-        })
-        .await
-        .map_err(|e| JsError::new(&e.to_string()))?;
+        let Some(name) = singleton else {
+            return Ok(Self::spawn(handler, receive).await?.into());
+        };
 
-        wasm_bindgen_futures::spawn_local(async move {
-            while let Some(event) = rx.next().await {
-                handler
-                    .call2(
-                        &JsValue::null(),
-                        &JsValue::from(PublicKey(event.sender)),
-                        &JsValue::from(Uint8Array::from(event.data.as_slice())),
-                    )
-                    .unwrap();
-            }
-        });
+        if let Some(tunnel) = singleton::get(&name)? {
+            return Ok(tunnel);
+        }
+
+        let id = singleton::next_id()?;
+
+        let mut tunnel = Self::spawn(handler, receive).await?;
+        tunnel.singleton = Some((name.clone(), id));
+
+        let tunnel = JsValue::from(tunnel);
+        singleton::insert(&name, id, &tunnel)?;
+
+        Ok(tunnel)
+    }
+
+    /// Destroys the singleton tunnel registered under the provided name, if
+    /// it exists. This is meant to be called from HMR dispose hooks.
+    ///
+    /// Returns whether a tunnel was destroyed.
+    #[wasm_bindgen(js_name = closeSingleton)]
+    pub async fn close_singleton(name: String) -> Result<bool, JsValue> {
+        let Some(tunnel) = singleton::remove(&name)? else {
+            return Ok(false);
+        };
+
+        // The tunnel may belong to another instance of this module, so it can
+        // only be destroyed through JS.
+        let destroy: Function = Reflect::get(&tunnel, &"destroy".into())?.dyn_into()?;
+        let promise: Promise = destroy.call0(&tunnel)?.dyn_into()?;
+        JsFuture::from(promise).await?;
+
+        Ok(true)
+    }
+
+    /// Checks whether the current environment can run a tunnel, without
+    /// binding any socket or contacting any relay.
+    ///
+    /// Resolves to a `{ supported, missing }` object, where `missing` lists
+    /// the missing capabilities (`"secure-context"`, `"websocket"` or
+    /// `"crypto"`). Creating a tunnel in an unsupported environment fails with
+    /// an `EnvironmentUnsupportedError`, which has the same `missing` field.
+    #[wasm_bindgen(js_name = isSupported)]
+    pub async fn is_supported() -> Result<Object, JsValue> {
+        environment::support_report()
+    }
+
+    /// Returns how many dispatch tasks are running, across every instance of
+    /// this module. Each live tunnel has exactly one dispatch task.
+    #[wasm_bindgen(js_name = dispatchTaskCount)]
+    pub fn dispatch_task_count() -> Result<u32, JsValue> {
+        singleton::dispatch_tasks()
+    }
+
+    /// Removes the callback of this tunnel and every callback registered
+    /// with `Peer.watch` or `watchPeer`, without closing any connection.
+    ///
+    /// Data received while no callback is set is discarded. A new callback can
+    /// be set using `setHandler`, and peers can be watched again.
+    #[wasm_bindgen(js_name = detachAll)]
+    pub fn detach_all(&self) {
+        self.handler.borrow_mut().take();
+        self.watchers.clear();
+    }
+
+    /// Replaces the callback which is called when this tunnel receives data.
+    #[wasm_bindgen(js_name = setHandler)]
+    pub fn set_handler(&self, handler: Function) {
+        self.handler.borrow_mut().replace(handler);
+    }
 
-        Ok(Self(inner))
+    /// Returns how many times incoming data was dispatched to the handler.
+    ///
+    /// When the `dispatch` option is used, each batch counts as a single turn.
+    #[wasm_bindgen(getter, js_name = dispatchTurns)]
+    pub fn dispatch_turns(&self) -> u32 {
+        self.dispatch_turns.get()
     }
 
     /// Sends some data to another tunnel, given the provided address is valid.
@@ -66,44 +489,837 @@ impl Tunnel {
     ///
     /// - `address`: The **receiver address** of the tunnel to send data to.
     /// - `data`: The data to be sent.
-    pub async fn send(&self, address: &PublicKey, data: &Uint8Array) -> Result<(), JsError> {
-        self.0
-            .send(address.0, &data.to_vec())
+    ///
+    /// Fails with a `TunnelModeError` if the tunnel is receive-only.
+    pub async fn send(&self, address: &PublicKey, data: &Uint8Array) -> Result<(), JsValue> {
+        let _connecting = self.watchers.connecting(&self.inner, address.0);
+
+        self.inner
+            .send(address.0, data.to_vec())
             .await
-            .map_err(|e| JsError::new(&e.to_string()))
+            .map_err(send_error)
+    }
+
+    /// Sends a request to another tunnel and resolves to its response.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to send the request
+    /// to.
+    /// - `data`: The request.
+    ///
+    /// Fails with a `TunnelModeError` if the tunnel is receive-only.
+    pub async fn request(
+        &self,
+        address: &PublicKey,
+        data: &Uint8Array,
+    ) -> Result<Uint8Array, JsValue> {
+        let _connecting = self.watchers.connecting(&self.inner, address.0);
+        request(&self.inner, address, data).await
+    }
+
+    /// Measures the round-trip time to another tunnel, in milliseconds.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the tunnel to ping.
+    pub async fn ping(&self, address: &PublicKey) -> Result<f64, JsValue> {
+        let _connecting = self.watchers.connecting(&self.inner, address.0);
+        ping(&self.inner, address).await
+    }
+
+    /// Returns whether this tunnel has a live connection to another tunnel.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    #[wasm_bindgen(js_name = isConnected)]
+    pub fn is_connected(&self, address: &PublicKey) -> bool {
+        self.inner.is_connected(&address.0)
+    }
+
+    /// Returns the state of the connection to another tunnel.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    #[wasm_bindgen(unchecked_return_type = "PeerInfo")]
+    pub fn info(&self, address: &PublicKey) -> Result<Object, JsValue> {
+        peer_info(&self.inner, address)
+    }
+
+    /// Calls the provided callback with the state of the connection to
+    /// another tunnel right away, then whenever it changes, until the returned
+    /// function is called.
+    ///
+    /// A peer is `"connecting"` while data, a request or a ping is being sent
+    /// to it through this tunnel before a connection exists. A switch between
+    /// a relay and a direct path is reported the next time the connection
+    /// opens or closes, or something is sent to the peer. Watchers are removed
+    /// by `detachAll` and when the tunnel is destroyed.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    /// - `callback`: The function to call with the state of the connection.
+    #[wasm_bindgen(js_name = watchPeer, unchecked_return_type = "() => void")]
+    pub fn watch_peer(
+        &self,
+        address: &PublicKey,
+        #[wasm_bindgen(unchecked_param_type = "(state: PeerState) => void")] callback: Function,
+    ) -> Result<Function, JsValue> {
+        let state = self.watchers.state(&self.inner, &address.0);
+        let id = self.watchers.add_state(address.0, state, callback)?;
+        let watchers = Rc::downgrade(&self.watchers);
+
+        let unsubscribe = Closure::<dyn FnMut()>::new(move || {
+            if let Some(watchers) = watchers.upgrade() {
+                watchers.remove(id);
+            }
+        });
+
+        Ok(unsubscribe.into_js_value().unchecked_into())
     }
 
     /// Closes both the sender and the receiver endpoint and consumes this object.
     ///
     /// Ideally, this should be called before the execution of the program ends
     /// or before a tunnel is discarded.
-    pub async fn destroy(self) {
-        self.0.destroy().await
+    ///
+    /// The returned promise only resolves once all data received before the
+    /// tunnel was destroyed has been dispatched to the callback, after which
+    /// neither the callback nor any watcher is called again.
+    ///
+    /// The promise is rejected if the receiver endpoint could not be shut
+    /// down cleanly, in which case the tunnel is destroyed nonetheless.
+    ///
+    /// **Note:** any [Peer] obtained from this tunnel becomes unusable.
+    pub async fn destroy(self) -> Result<(), JsError> {
+        if let Some((name, id)) = &self.singleton {
+            let _ = singleton::remove_if_same(name, *id);
+        }
+
+        // Peers only hold a weak reference, so they become unusable as soon as
+        // this one is dropped, while their sends in flight fail once the
+        // tunnel is destroyed.
+        let inner = Rc::unwrap_or_clone(self.inner);
+        let result = inner.destroy().await.map(|_| ());
+
+        self.watchers.clear();
+
+        // The handlers of the tunnel only hold weak senders, so the channels
+        // close once these are dropped, and the dispatch task ends after the
+        // events already queued.
+        drop(self.events);
+        drop(self.peer_events);
+        let _ = self.dispatch_finished.await;
+
+        result.map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Returns a [Peer] object which sends data to the provided address
+    /// through this tunnel.
+    ///
+    /// # Arguments
+    ///
+    /// - `address`: The **receiver address** of the other tunnel.
+    pub fn peer(&self, address: &PublicKey) -> Peer {
+        Peer {
+            tunnel: Rc::downgrade(&self.inner),
+            watchers: Rc::downgrade(&self.watchers),
+            address: address.clone(),
+        }
     }
 
     /// Closes a connection to another tunnel, if it exists.
     pub fn close(&self, address: &PublicKey) {
-        self.0.close(address.0);
+        self.inner.close(address.0);
     }
 
     /// Closes all connections between this tunnel and other tunnels.
     pub fn close_all(&self) {
-        self.0.close_all();
+        self.inner.close_all();
     }
 
     /// Returns the address of the sender endpoint of this tunnel.
     ///
     /// The sender enpoint is responsible for sending data to other tunnels.
     /// As such, when sending data, this address will be cited as the source.
-    pub fn sender_address(&self) -> PublicKey {
-        PublicKey(self.0.sender_address())
+    pub fn sender_address(&self) -> Option<PublicKey> {
+        self.inner.sender_address().map(PublicKey)
     }
 
     /// Returns the address of the receiver endpoint of this tunnel.
     ///
     /// The receiver enpoint is responsible for receiving data from other tunnels.
     /// As such, senders should send data to this address.
-    pub fn receiver_address(&self) -> PublicKey {
-        PublicKey(self.0.receiver_address())
+    pub fn receiver_address(&self) -> Option<PublicKey> {
+        self.inner.receiver_address().map(PublicKey)
+    }
+}
+
+impl Tunnel {
+    async fn spawn(handler: Function, options: ReceiveOptions) -> Result<Self, JsValue> {
+        Self::spawn_with(NativeTunnel::builder(), handler, options).await
+    }
+
+    /// Spawns a tunnel from `builder`, which must not have a handler yet.
+    async fn spawn_with(
+        builder: TunnelBuilder,
+        handler: Function,
+        options: ReceiveOptions,
+    ) -> Result<Self, JsValue> {
+        let missing = environment::missing_capabilities();
+
+        if !missing.is_empty() {
+            return Err(environment::unsupported_error(&missing));
+        }
+
+        let ReceiveOptions {
+            dispatch,
+            queue_size,
+            on_error,
+        } = options;
+
+        // Only this object holds strong senders, so that destroying it closes
+        // the channels even while the native tunnel is still referenced.
+        let (events, rx) = channel::<DataEvent>(queue_size);
+        let tx = events.downgrade();
+
+        let (peer_events, peer_rx) = unbounded_channel::<PeerEvent>();
+        let connected = peer_events.downgrade();
+        let disconnected = peer_events.downgrade();
+
+        let inner = builder
+            .async_handler(move |sender: NativePublicKey, data: Vec<u8>| {
+                let tx = tx.upgrade();
+
+                // Waits while the queue is full, which stops the tunnel from
+                // reading more incoming data.
+                async move {
+                    if let Some(tx) = tx {
+                        let _ = tx.send(DataEvent { sender, data }).await;
+                    }
+                }
+            })
+            .on_peer_connected(move |address| {
+                if let Some(connected) = connected.upgrade() {
+                    let _ = connected.send((address, true));
+                }
+            })
+            .on_peer_disconnected(move |address, _| {
+                if let Some(disconnected) = disconnected.upgrade() {
+                    let _ = disconnected.send((address, false));
+                }
+            })
+            .spawn()
+            .await
+            .map_err(|e| JsValue::from(JsError::new(&e.to_string())))?;
+        let inner = Rc::new(inner);
+
+        let handler = Rc::new(RefCell::new(Some(handler)));
+        let current_handler = Rc::clone(&handler);
+
+        let (finished_tx, dispatch_finished) = oneshot::channel();
+
+        let dispatch_turns = Rc::new(Cell::new(0));
+        let turns = Rc::clone(&dispatch_turns);
+
+        let watchers = Rc::new(Watchers::default());
+        let current_watchers = Rc::clone(&watchers);
+
+        singleton::add_dispatch_tasks(1.0);
+
+        wasm_bindgen_futures::spawn_local(async move {
+            zip(
+                dispatch_loop(rx, current_handler, dispatch, on_error, turns),
+                watch_loop(peer_rx, Rc::downgrade(&inner), current_watchers),
+            )
+            .await;
+
+            singleton::add_dispatch_tasks(-1.0);
+            let _ = finished_tx.send(());
+        });
+
+        Ok(Self {
+            inner,
+            handler,
+            events,
+            peer_events,
+            watchers,
+            dispatch_finished,
+            dispatch_turns,
+            singleton: None,
+        })
+    }
+}
+
+/// Gives incoming data to the current handler until every sender of `rx` is
+/// gone, counting each dispatch turn in `turns`.
+async fn dispatch_loop(
+    mut rx: Receiver<DataEvent>,
+    current_handler: Rc<RefCell<Option<Function>>>,
+    dispatch: Option<DispatchOptions>,
+    on_error: Option<Function>,
+    turns: Rc<Cell<u32>>,
+) {
+    while let Some(event) = rx.recv().await {
+        let mut batch = vec![event];
+
+        if let Some(dispatch) = dispatch {
+            let deadline = Instant::now() + dispatch.max_delay;
+
+            while batch.len() < dispatch.max_batch {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                match timeout(remaining, rx.recv()).await {
+                    Ok(Some(event)) => batch.push(event),
+                    _ => break,
+                }
+            }
+        }
+
+        turns.set(turns.get() + 1);
+
+        for event in batch {
+            // The handler may be replaced by the handler itself, so it
+            // must not stay borrowed while it runs.
+            let Some(handler) = current_handler.borrow().clone() else {
+                continue;
+            };
+
+            let result = handler.call2(
+                &JsValue::null(),
+                &JsValue::from(PublicKey(event.sender)),
+                &JsValue::from(Uint8Array::from(event.data.as_slice())),
+            );
+
+            // A throwing callback must not stop the dispatch loop.
+            if let Err(error) = result {
+                match &on_error {
+                    Some(on_error) => {
+                        let _ = on_error.call1(&JsValue::null(), &error);
+                    }
+                    None => {
+                        let _ = Promise::reject(&error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Notifies the watchers of a tunnel of its connections until every sender of
+/// `rx` is gone.
+async fn watch_loop(
+    mut rx: UnboundedReceiver<PeerEvent>,
+    tunnel: Weak<NativeTunnel>,
+    watchers: Rc<Watchers>,
+) {
+    while let Some(event) = rx.recv().await {
+        watchers.notify(event);
+
+        if let Some(tunnel) = tunnel.upgrade() {
+            watchers.update(event.0, watchers.state(&tunnel, &event.0));
+        }
+    }
+}
+
+async fn request(
+    tunnel: &NativeTunnel,
+    address: &PublicKey,
+    data: &Uint8Array,
+) -> Result<Uint8Array, JsValue> {
+    let response = tunnel
+        .request(address.0, data.to_vec())
+        .await
+        .map_err(send_error)?;
+
+    Ok(Uint8Array::from(response.as_slice()))
+}
+
+async fn ping(tunnel: &NativeTunnel, address: &PublicKey) -> Result<f64, JsValue> {
+    let rtt = tunnel.ping(address.0).await.map_err(send_error)?;
+    Ok(rtt.as_secs_f64() * 1000.0)
+}
+
+/// Creates the `PeerInfo` object of another tunnel.
+fn peer_info(tunnel: &NativeTunnel, address: &PublicKey) -> Result<Object, JsValue> {
+    let status = tunnel.connection_status(&address.0);
+    let stats = tunnel.stats(&address.0);
+    let millis = |duration: Duration| JsValue::from(duration.as_secs_f64() * 1000.0);
+
+    let info = Object::new();
+    let fields = [
+        ("connected", JsValue::from(tunnel.is_connected(&address.0))),
+        (
+            "ageMs",
+            status
+                .as_ref()
+                .map_or(JsValue::null(), |status| millis(status.age)),
+        ),
+        (
+            "direct",
+            status.as_ref().map_or(JsValue::null(), |status| {
+                status
+                    .conn_type
+                    .as_ref()
+                    .map_or(JsValue::null(), |_| status.is_direct().into())
+            }),
+        ),
+        (
+            "rttMs",
+            stats
+                .and_then(|stats| stats.rtt)
+                .map_or(JsValue::null(), millis),
+        ),
+        (
+            "messagesSent",
+            stats.map_or(0.0, |stats| stats.messages_sent as f64).into(),
+        ),
+        (
+            "bytesSent",
+            stats.map_or(0.0, |stats| stats.bytes_sent as f64).into(),
+        ),
+        (
+            "sendErrors",
+            stats.map_or(0.0, |stats| stats.send_errors as f64).into(),
+        ),
+    ];
+
+    for (field, value) in fields {
+        Reflect::set(&info, &field.into(), &value)?;
+    }
+
+    Ok(info)
+}
+
+/// A convenience wrapper over a tunnel and the address of another tunnel.
+///
+/// A peer is just an address wrapper, so it remains valid across reconnects.
+/// After its tunnel is destroyed, every operation fails with a
+/// `TunnelDestroyedError`.
+#[wasm_bindgen]
+pub struct Peer {
+    tunnel: Weak<NativeTunnel>,
+    watchers: Weak<Watchers>,
+    address: PublicKey,
+}
+
+#[wasm_bindgen]
+impl Peer {
+    /// The **receiver address** of the other tunnel.
+    #[wasm_bindgen(getter)]
+    pub fn address(&self) -> PublicKey {
+        self.address.clone()
+    }
+
+    /// Sends some data to this peer. See [Tunnel::send].
+    pub async fn send(&self, data: &Uint8Array) -> Result<(), JsValue> {
+        let (tunnel, watchers) = (self.tunnel()?, self.watchers()?);
+        let _connecting = watchers.connecting(&tunnel, self.address.0);
+
+        tunnel
+            .send(self.address.0, data.to_vec())
+            .await
+            .map_err(send_error)
+    }
+
+    /// Sends a request to this peer and resolves to its response. See
+    /// [Tunnel::request].
+    pub async fn request(&self, data: &Uint8Array) -> Result<Uint8Array, JsValue> {
+        let (tunnel, watchers) = (self.tunnel()?, self.watchers()?);
+        let _connecting = watchers.connecting(&tunnel, self.address.0);
+
+        request(&tunnel, &self.address, data).await
+    }
+
+    /// Measures the round-trip time to this peer, in milliseconds. See
+    /// [Tunnel::ping].
+    pub async fn ping(&self) -> Result<f64, JsValue> {
+        let (tunnel, watchers) = (self.tunnel()?, self.watchers()?);
+        let _connecting = watchers.connecting(&tunnel, self.address.0);
+
+        ping(&tunnel, &self.address).await
+    }
+
+    /// Whether the tunnel has a live connection to this peer. See
+    /// [Tunnel::is_connected].
+    #[wasm_bindgen(getter, js_name = isConnected)]
+    pub fn is_connected(&self) -> Result<bool, JsValue> {
+        Ok(self.tunnel()?.is_connected(&self.address.0))
+    }
+
+    /// Returns the state of the connection to this peer. See [Tunnel::info].
+    #[wasm_bindgen(unchecked_return_type = "PeerInfo")]
+    pub fn info(&self) -> Result<Object, JsValue> {
+        peer_info(&self.tunnel()?, &self.address)
+    }
+
+    /// Calls the provided callback with `true` whenever the tunnel connects to
+    /// this peer, and with `false` whenever that connection is closed,
+    /// whatever closed it.
+    ///
+    /// Returns an id which can be passed to `unwatch`. Watchers are removed
+    /// by `Tunnel.detachAll` and when the tunnel is destroyed.
+    pub fn watch(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "(connected: boolean) => void")] callback: Function,
+    ) -> Result<u32, JsValue> {
+        Ok(self.watchers()?.add(self.address.0, callback))
+    }
+
+    /// Removes a callback registered with `watch`. Returns whether it was
+    /// still registered.
+    pub fn unwatch(&self, id: u32) -> Result<bool, JsValue> {
+        Ok(self.watchers()?.remove(id))
+    }
+
+    /// Closes the connection to this peer, if it exists. See [Tunnel::close].
+    pub fn close(&self) -> Result<(), JsValue> {
+        self.tunnel()?.close(self.address.0);
+        Ok(())
+    }
+
+    /// Returns the tunnel of this peer. The tunnel is cloned out, so it never
+    /// stays borrowed while an operation is in flight.
+    fn tunnel(&self) -> Result<NativeTunnel, JsValue> {
+        self.tunnel
+            .upgrade()
+            .map(|tunnel| NativeTunnel::clone(&tunnel))
+            .ok_or_else(destroyed_error)
+    }
+
+    fn watchers(&self) -> Result<Rc<Watchers>, JsValue> {
+        self.watchers.upgrade().ok_or_else(destroyed_error)
+    }
+}
+
+fn destroyed_error() -> JsValue {
+    named_error("TunnelDestroyedError", TUNNEL_DESTROYED_MSG)
+}
+
+/// Converts an error returned while sending into a JS error, giving mode
+/// errors the `TunnelModeError` name, and errors of destroyed tunnels the
+/// `TunnelDestroyedError` name.
+fn send_error(error: TunnelError) -> JsValue {
+    match error {
+        TunnelError::WrongMode { .. } => named_error("TunnelModeError", &error.to_string()),
+        TunnelError::Destroyed => destroyed_error(),
+        _ => JsError::new(&error.to_string()).into(),
+    }
+}
+
+/// Creates a JS error with a custom name, which can be checked with
+/// `error.name`.
+fn named_error(name: &str, message: &str) -> JsValue {
+    let error = js_sys::Error::new(message);
+    error.set_name(name);
+    error.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::tunnel::{RelayMode, SecretKey};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    fn sender() -> NativePublicKey {
+        SecretKey::from_bytes(&[1; 32]).public()
+    }
+
+    /// Sends `count` events numbered from 0 through a queue of `queue_size`,
+    /// then closes it.
+    fn queue(count: u16, queue_size: usize) -> Receiver<DataEvent> {
+        let (tx, rx) = channel(queue_size);
+
+        wasm_bindgen_futures::spawn_local(async move {
+            for i in 0..count {
+                let data = i.to_le_bytes().to_vec();
+                tx.send(DataEvent {
+                    sender: sender(),
+                    data,
+                })
+                .await
+                .unwrap();
+            }
+        });
+
+        rx
+    }
+
+    #[wasm_bindgen_test]
+    async fn batches_preserve_order() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let handler = Closure::<dyn FnMut(JsValue, Uint8Array)>::new({
+            let received = Rc::clone(&received);
+            move |_, data: Uint8Array| {
+                let data = data.to_vec();
+                received
+                    .borrow_mut()
+                    .push(u16::from_le_bytes([data[0], data[1]]));
+            }
+        });
+
+        let dispatch = DispatchOptions {
+            max_batch: 64,
+            max_delay: Duration::from_millis(4),
+        };
+        let turns = Rc::new(Cell::new(0));
+
+        dispatch_loop(
+            queue(500, DEFAULT_QUEUE_SIZE),
+            Rc::new(RefCell::new(Some(
+                handler.as_ref().unchecked_ref::<Function>().clone(),
+            ))),
+            Some(dispatch),
+            None,
+            Rc::clone(&turns),
+        )
+        .await;
+
+        assert_eq!(*received.borrow(), (0..500).collect::<Vec<_>>());
+        assert!(turns.get() < 100, "{} dispatch turns", turns.get());
+    }
+
+    #[wasm_bindgen_test]
+    async fn throwing_handler_does_not_stop_dispatch() {
+        let handled = Rc::new(Cell::new(0));
+        let handler = Closure::<dyn FnMut(JsValue, Uint8Array) -> Result<(), JsValue>>::new({
+            let handled = Rc::clone(&handled);
+            move |_, data: Uint8Array| {
+                handled.set(handled.get() + 1);
+
+                if data.get_index(0) % 2 == 0 {
+                    return Err(js_sys::Error::new("even").into());
+                }
+
+                Ok(())
+            }
+        });
+
+        let errors = Rc::new(Cell::new(0));
+        let on_error = Closure::<dyn FnMut(JsValue)>::new({
+            let errors = Rc::clone(&errors);
+            move |_| errors.set(errors.get() + 1)
+        });
+
+        dispatch_loop(
+            queue(10, 1),
+            Rc::new(RefCell::new(Some(
+                handler.as_ref().unchecked_ref::<Function>().clone(),
+            ))),
+            None,
+            Some(on_error.as_ref().unchecked_ref::<Function>().clone()),
+            Rc::new(Cell::new(0)),
+        )
+        .await;
+
+        assert_eq!(handled.get(), 10);
+        assert_eq!(errors.get(), 5);
+    }
+
+    fn recording_handler(received: &Rc<RefCell<Vec<Vec<u8>>>>) -> Function {
+        let received = Rc::clone(received);
+        let handler = Closure::<dyn FnMut(JsValue, Uint8Array)>::new(move |_, data: Uint8Array| {
+            received.borrow_mut().push(data.to_vec());
+        });
+
+        handler.into_js_value().unchecked_into()
+    }
+
+    fn error_name(error: &JsValue) -> Option<String> {
+        Reflect::get(error, &"name".into()).ok()?.as_string()
+    }
+
+    /// Spawns a tunnel which does not use relays, so it can only be reached
+    /// over loopback.
+    async fn loopback(handler: Function) -> Tunnel {
+        let builder = NativeTunnel::builder()
+            .relay_mode(RelayMode::Disabled)
+            .wait_online(false);
+
+        Tunnel::spawn_with(
+            builder,
+            handler,
+            ReceiveOptions::from_options(None).unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Makes `sender` remember how to reach `receiver` over loopback, then
+    /// closes the connection this took, so the next send reconnects.
+    async fn introduce(sender: &Tunnel, receiver: &Tunnel) {
+        let addr = receiver.inner.receiver_addr().unwrap();
+        sender.inner.connect_to(addr).await.unwrap();
+
+        sender.close(&receiver.receiver_address().unwrap());
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    fn recording_watcher(states: &Rc<RefCell<Vec<String>>>) -> Function {
+        let states = Rc::clone(states);
+        let watcher = Closure::<dyn FnMut(String)>::new(move |state| {
+            states.borrow_mut().push(state);
+        });
+
+        watcher.into_js_value().unchecked_into()
+    }
+
+    #[wasm_bindgen_test]
+    async fn watched_peers_report_their_state() {
+        let a = loopback(recording_handler(&Rc::default())).await;
+        let b = loopback(recording_handler(&Rc::default())).await;
+        let address = b.receiver_address().unwrap();
+
+        introduce(&a, &b).await;
+
+        let states = Rc::new(RefCell::new(Vec::new()));
+        let others = Rc::new(RefCell::new(Vec::new()));
+        let unsubscribe = a.watch_peer(&address, recording_watcher(&states)).unwrap();
+        let unsubscribe_others = a.watch_peer(&address, recording_watcher(&others)).unwrap();
+        assert_eq!(*states.borrow(), ["disconnected"]);
+
+        a.send(&address, &Uint8Array::from(&b"hi"[..]))
+            .await
+            .unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(states.borrow()[..2], ["disconnected", "connecting"]);
+        assert!(states.borrow()[2].starts_with("connected-"));
+        assert_eq!(states.borrow().len(), 3);
+        assert_eq!(*others.borrow(), *states.borrow());
+
+        unsubscribe_others.call0(&JsValue::null()).unwrap();
+        a.close(&address);
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(states.borrow().last().unwrap(), "disconnected");
+        assert_eq!(states.borrow().len(), 4);
+        assert_eq!(others.borrow().len(), 3);
+
+        // Destroying the tunnel removes the watchers, so the disconnection
+        // caused by it is not reported.
+        a.send(&address, &Uint8Array::from(&b"again"[..]))
+            .await
+            .unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+        let len = states.borrow().len();
+
+        a.destroy().await.unwrap();
+        b.destroy().await.unwrap();
+
+        assert_eq!(states.borrow().len(), len);
+        assert!(states.borrow().last().unwrap().starts_with("connected-"));
+        unsubscribe.call0(&JsValue::null()).unwrap();
+    }
+
+    #[wasm_bindgen_test]
+    async fn peers_exchange_data() {
+        let received_a = Rc::new(RefCell::new(Vec::new()));
+        let received_b = Rc::new(RefCell::new(Vec::new()));
+
+        let a = loopback(recording_handler(&received_a)).await;
+        let b = loopback(recording_handler(&received_b)).await;
+        introduce(&a, &b).await;
+        introduce(&b, &a).await;
+
+        let to_b = a.peer(&b.receiver_address().unwrap());
+        let to_a = b.peer(&a.receiver_address().unwrap());
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let watcher = Closure::<dyn FnMut(bool)>::new({
+            let events = Rc::clone(&events);
+            move |connected| events.borrow_mut().push(connected)
+        });
+        to_b.watch(watcher.as_ref().unchecked_ref::<Function>().clone())
+            .unwrap();
+
+        assert!(!to_b.is_connected().unwrap());
+
+        to_b.send(&Uint8Array::from(&b"ping"[..])).await.unwrap();
+        to_a.send(&Uint8Array::from(&b"pong"[..])).await.unwrap();
+
+        assert!(to_b.is_connected().unwrap());
+        assert!(to_b.ping().await.unwrap() >= 0.0);
+
+        let info = to_b.info().unwrap();
+        let field = |name: &str| Reflect::get(&info, &name.into()).unwrap();
+        assert_eq!(field("connected").as_bool(), Some(true));
+        assert_eq!(field("messagesSent").as_f64(), Some(1.0));
+
+        // JS tunnels cannot answer requests, so this only checks that the
+        // request reaches the other tunnel and fails there.
+        assert!(to_b.request(&Uint8Array::from(&b"?"[..])).await.is_err());
+
+        to_b.close().unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(*received_b.borrow(), [b"ping".to_vec()]);
+        assert_eq!(*received_a.borrow(), [b"pong".to_vec()]);
+        assert_eq!(*events.borrow(), [true, false]);
+
+        a.destroy().await.unwrap();
+        b.destroy().await.unwrap();
+
+        let error = to_b
+            .send(&Uint8Array::from(&b"late"[..]))
+            .await
+            .unwrap_err();
+        assert_eq!(error_name(&error).as_deref(), Some("TunnelDestroyedError"));
+        assert!(to_a.is_connected().is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn detached_tunnels_stay_connected_and_can_be_reattached() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        let a = loopback(recording_handler(&Rc::default())).await;
+        let b = loopback(recording_handler(&received)).await;
+        introduce(&a, &b).await;
+
+        let to_b = a.peer(&b.receiver_address().unwrap());
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let watcher = Closure::<dyn FnMut(bool)>::new({
+            let events = Rc::clone(&events);
+            move |connected| events.borrow_mut().push(connected)
+        });
+        to_b.watch(watcher.as_ref().unchecked_ref::<Function>().clone())
+            .unwrap();
+
+        to_b.send(&Uint8Array::from(&b"before"[..])).await.unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        a.detach_all();
+        b.detach_all();
+
+        to_b.send(&Uint8Array::from(&b"detached"[..]))
+            .await
+            .unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(to_b.is_connected().unwrap());
+        assert_eq!(*received.borrow(), [b"before".to_vec()]);
+
+        b.set_handler(recording_handler(&received));
+        to_b.send(&Uint8Array::from(&b"reattached"[..]))
+            .await
+            .unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            *received.borrow(),
+            [b"before".to_vec(), b"reattached".to_vec()]
+        );
+
+        // The watcher was removed, so closing the connection is not reported.
+        to_b.close().unwrap();
+        n0_future::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*events.borrow(), [true]);
+
+        a.destroy().await.unwrap();
+        b.destroy().await.unwrap();
     }
 }
@@ -0,0 +1,626 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    rc::Rc,
+};
+
+use ::tunnel::PublicKey as NativePublicKey;
+use js_sys::{Array, Function, Object, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use crate::{PublicKey, Watchers, get_field, named_error};
+
+const SIMULATED_FAILURE_MSG: &str = "Simulated failure.";
+const NO_REQUEST_HANDLER_MSG: &str = "No request handler was set with `setRequestHandler`.";
+
+// Makes the mocks assignable wherever a `Tunnel` or a `Peer` is accepted. The
+// declarations fail to compile if the mocks stop matching the real objects.
+#[wasm_bindgen(typescript_custom_section)]
+const MOCK_TYPES: &str = r#"
+export interface MockTunnel extends Tunnel {}
+export interface MockPeer extends Peer {}
+"#;
+
+struct SentData {
+    address: PublicKey,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MockState {
+    handler: RefCell<Option<Function>>,
+    sent: RefCell<Vec<SentData>>,
+    fail_next_send: RefCell<Option<String>>,
+    request_handler: RefCell<Option<Function>>,
+    connected: RefCell<HashSet<NativePublicKey>>,
+    watchers: Watchers,
+    dispatch_turns: Cell<u32>,
+}
+
+impl MockState {
+    fn fail_if_requested(&self) -> Result<(), JsValue> {
+        match self.fail_next_send.borrow_mut().take() {
+            Some(name) => Err(named_error(&name, SIMULATED_FAILURE_MSG)),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, address: &PublicKey, data: &Uint8Array) -> Result<(), JsValue> {
+        self.fail_if_requested()?;
+
+        self.sent.borrow_mut().push(SentData {
+            address: address.clone(),
+            data: data.to_vec(),
+        });
+
+        Ok(())
+    }
+
+    fn request(&self, address: &PublicKey, data: &Uint8Array) -> Result<Uint8Array, JsValue> {
+        self.fail_if_requested()?;
+
+        let handler = self
+            .request_handler
+            .borrow()
+            .clone()
+            .ok_or_else(|| JsValue::from(JsError::new(NO_REQUEST_HANDLER_MSG)))?;
+
+        let response = handler.call2(
+            &JsValue::null(),
+            &JsValue::from(address.clone()),
+            &JsValue::from(Uint8Array::from(data.to_vec().as_slice())),
+        )?;
+
+        Ok(Uint8Array::new(&response))
+    }
+
+    fn is_connected(&self, address: &PublicKey) -> bool {
+        self.connected.borrow().contains(&address.0)
+    }
+
+    /// Returns the state given to the callbacks registered with `watchPeer`.
+    /// Connected peers are always reported as reached directly.
+    fn peer_state(&self, address: &PublicKey) -> &'static str {
+        if self.is_connected(address) {
+            "connected-direct"
+        } else {
+            "disconnected"
+        }
+    }
+
+    fn info(&self, address: &PublicKey) -> Result<Object, JsValue> {
+        let (messages_sent, bytes_sent) = self
+            .sent
+            .borrow()
+            .iter()
+            .filter(|sent| sent.address.0 == address.0)
+            .fold((0, 0), |(messages, bytes), sent| {
+                (messages + 1, bytes + sent.data.len())
+            });
+
+        let info = Object::new();
+        let fields = [
+            ("connected", JsValue::from(self.is_connected(address))),
+            ("ageMs", JsValue::null()),
+            ("direct", JsValue::null()),
+            ("rttMs", JsValue::null()),
+            ("messagesSent", JsValue::from(messages_sent as f64)),
+            ("bytesSent", JsValue::from(bytes_sent as f64)),
+            ("sendErrors", JsValue::from(0.0)),
+        ];
+
+        for (field, value) in fields {
+            Reflect::set(&info, &field.into(), &value)?;
+        }
+
+        Ok(info)
+    }
+}
+
+/// A tunnel which never touches the network, meant for unit tests of code
+/// which takes a [Tunnel](crate::Tunnel).
+///
+/// A mock tunnel has the same methods as a real one. Sent data is recorded
+/// instead of being sent, and incoming data is simulated with `emitData`.
+#[wasm_bindgen]
+pub struct MockTunnel {
+    state: Rc<MockState>,
+    sender_address: Option<PublicKey>,
+    receiver_address: Option<PublicKey>,
+}
+
+#[wasm_bindgen]
+impl MockTunnel {
+    /// Creates a new mock tunnel using the provided callback.
+    ///
+    /// # Options
+    ///
+    /// - `senderAddress`: The address returned by `sender_address`, as a string.
+    /// - `receiverAddress`: The address returned by `receiver_address`, as a
+    /// string.
+    ///
+    /// Any other option accepted by `Tunnel.new` is ignored.
+    pub async fn new(handler: Function, options: Option<Object>) -> Result<Self, JsError> {
+        let (sender_address, receiver_address) = match &options {
+            Some(options) => (
+                address_option(options, "senderAddress")?,
+                address_option(options, "receiverAddress")?,
+            ),
+            None => (None, None),
+        };
+
+        let state = MockState::default();
+        state.handler.replace(Some(handler));
+
+        Ok(Self {
+            state: Rc::new(state),
+            sender_address,
+            receiver_address,
+        })
+    }
+
+    /// Calls the callback of this tunnel as if it received some data.
+    ///
+    /// Each call counts as a single dispatch turn.
+    #[wasm_bindgen(js_name = emitData)]
+    pub fn emit_data(&self, sender: &PublicKey, data: &Uint8Array) -> Result<(), JsValue> {
+        self.state
+            .dispatch_turns
+            .set(self.state.dispatch_turns.get() + 1);
+
+        let Some(handler) = self.state.handler.borrow().clone() else {
+            return Ok(());
+        };
+
+        handler.call2(
+            &JsValue::null(),
+            &JsValue::from(sender.clone()),
+            &JsValue::from(Uint8Array::from(data.to_vec().as_slice())),
+        )?;
+
+        Ok(())
+    }
+
+    /// Makes the next call to `send` or `request` fail with an error of the
+    /// provided name, e.g. `"TunnelModeError"`.
+    #[wasm_bindgen(js_name = failNextSend)]
+    pub fn fail_next_send(&self, name: String) {
+        self.state.fail_next_send.replace(Some(name));
+    }
+
+    /// Returns the data sent through this tunnel so far, as an array of
+    /// `{ address, data }` objects in the order in which it was sent.
+    pub fn sent(&self) -> Result<Array, JsValue> {
+        self.state
+            .sent
+            .borrow()
+            .iter()
+            .map(|sent| {
+                let entry = Object::new();
+                Reflect::set(&entry, &"address".into(), &sent.address.clone().into())?;
+                Reflect::set(
+                    &entry,
+                    &"data".into(),
+                    &Uint8Array::from(sent.data.as_slice()).into(),
+                )?;
+
+                Ok(JsValue::from(entry))
+            })
+            .collect()
+    }
+
+    /// Sets the function which answers requests, called with the address
+    /// and the data of each request. It must return a `Uint8Array`.
+    #[wasm_bindgen(js_name = setRequestHandler)]
+    pub fn set_request_handler(&self, handler: Function) {
+        self.state.request_handler.replace(Some(handler));
+    }
+
+    /// Marks another tunnel as connected or not, as returned by
+    /// `isConnected`, and notifies the watchers of its peers if this changes
+    /// its state.
+    #[wasm_bindgen(js_name = setConnected)]
+    pub fn set_connected(&self, address: &PublicKey, connected: bool) {
+        let changed = if connected {
+            self.state.connected.borrow_mut().insert(address.0)
+        } else {
+            self.state.connected.borrow_mut().remove(&address.0)
+        };
+
+        if changed {
+            self.state.watchers.notify((address.0, connected));
+            self.state
+                .watchers
+                .update(address.0, self.state.peer_state(address));
+        }
+    }
+
+    /// Removes the callback of this tunnel and every watcher of its peers.
+    /// See `Tunnel.detachAll`.
+    #[wasm_bindgen(js_name = detachAll)]
+    pub fn detach_all(&self) {
+        self.state.handler.borrow_mut().take();
+        self.state.watchers.clear();
+    }
+
+    /// Replaces the callback of this tunnel. See `Tunnel.setHandler`.
+    #[wasm_bindgen(js_name = setHandler)]
+    pub fn set_handler(&self, handler: Function) {
+        self.state.handler.borrow_mut().replace(handler);
+    }
+
+    /// Returns how many times `emitData` was called.
+    #[wasm_bindgen(getter, js_name = dispatchTurns)]
+    pub fn dispatch_turns(&self) -> u32 {
+        self.state.dispatch_turns.get()
+    }
+
+    /// Records some data as sent to the provided address, or fails if
+    /// `failNextSend` was called before.
+    pub async fn send(&self, address: &PublicKey, data: &Uint8Array) -> Result<(), JsValue> {
+        self.state.send(address, data)
+    }
+
+    /// Answers a request with the function set with `setRequestHandler`, or
+    /// fails if `failNextSend` was called before.
+    pub async fn request(
+        &self,
+        address: &PublicKey,
+        data: &Uint8Array,
+    ) -> Result<Uint8Array, JsValue> {
+        self.state.request(address, data)
+    }
+
+    /// Always resolves to 0, as a mock tunnel has no connections.
+    pub async fn ping(&self, _address: &PublicKey) -> f64 {
+        0.0
+    }
+
+    /// Returns whether another tunnel was marked as connected with
+    /// `setConnected`.
+    #[wasm_bindgen(js_name = isConnected)]
+    pub fn is_connected(&self, address: &PublicKey) -> bool {
+        self.state.is_connected(address)
+    }
+
+    /// Returns the state of another tunnel, with statistics counted from the
+    /// recorded data.
+    #[wasm_bindgen(unchecked_return_type = "PeerInfo")]
+    pub fn info(&self, address: &PublicKey) -> Result<Object, JsValue> {
+        self.state.info(address)
+    }
+
+    /// Calls the provided callback with the state of another tunnel right
+    /// away, then whenever it is changed with `setConnected`, until the
+    /// returned function is called. See `Tunnel.watchPeer`.
+    #[wasm_bindgen(js_name = watchPeer, unchecked_return_type = "() => void")]
+    pub fn watch_peer(
+        &self,
+        address: &PublicKey,
+        #[wasm_bindgen(unchecked_param_type = "(state: PeerState) => void")] callback: Function,
+    ) -> Result<Function, JsValue> {
+        let state = self.state.peer_state(address);
+        let id = self.state.watchers.add_state(address.0, state, callback)?;
+        let state = Rc::downgrade(&self.state);
+
+        let unsubscribe = Closure::<dyn FnMut()>::new(move || {
+            if let Some(state) = state.upgrade() {
+                state.watchers.remove(id);
+            }
+        });
+
+        Ok(unsubscribe.into_js_value().unchecked_into())
+    }
+
+    /// Consumes this object. Neither the callback nor any watcher is called
+    /// again.
+    pub async fn destroy(self) {
+        self.state.handler.borrow_mut().take();
+        self.state.watchers.clear();
+    }
+
+    /// Returns a [MockPeer] object which records data sent to the provided
+    /// address through this tunnel.
+    pub fn peer(&self, address: &PublicKey) -> MockPeer {
+        MockPeer {
+            state: Rc::clone(&self.state),
+            address: address.clone(),
+        }
+    }
+
+    /// Does nothing, as a mock tunnel has no connections.
+    pub fn close(&self, _address: &PublicKey) {}
+
+    /// Does nothing, as a mock tunnel has no connections.
+    pub fn close_all(&self) {}
+
+    /// Returns the `senderAddress` option, if it was provided.
+    pub fn sender_address(&self) -> Option<PublicKey> {
+        self.sender_address.clone()
+    }
+
+    /// Returns the `receiverAddress` option, if it was provided.
+    pub fn receiver_address(&self) -> Option<PublicKey> {
+        self.receiver_address.clone()
+    }
+}
+
+/// A peer obtained from a [MockTunnel].
+#[wasm_bindgen]
+pub struct MockPeer {
+    state: Rc<MockState>,
+    address: PublicKey,
+}
+
+#[wasm_bindgen]
+impl MockPeer {
+    /// The **receiver address** of the other tunnel.
+    #[wasm_bindgen(getter)]
+    pub fn address(&self) -> PublicKey {
+        self.address.clone()
+    }
+
+    /// Sends some data to this peer. See [MockTunnel::send].
+    pub async fn send(&self, data: &Uint8Array) -> Result<(), JsValue> {
+        self.state.send(&self.address, data)
+    }
+
+    /// Answers a request to this peer. See [MockTunnel::request].
+    pub async fn request(&self, data: &Uint8Array) -> Result<Uint8Array, JsValue> {
+        self.state.request(&self.address, data)
+    }
+
+    /// Always resolves to 0, as a mock tunnel has no connections.
+    pub async fn ping(&self) -> f64 {
+        0.0
+    }
+
+    /// Whether this peer was marked as connected. See
+    /// [MockTunnel::is_connected].
+    #[wasm_bindgen(getter, js_name = isConnected)]
+    pub fn is_connected(&self) -> bool {
+        self.state.is_connected(&self.address)
+    }
+
+    /// Returns the state of this peer. See [MockTunnel::info].
+    #[wasm_bindgen(unchecked_return_type = "PeerInfo")]
+    pub fn info(&self) -> Result<Object, JsValue> {
+        self.state.info(&self.address)
+    }
+
+    /// Calls the provided callback whenever this peer is marked as connected
+    /// or not with `setConnected`. See `Peer.watch`.
+    pub fn watch(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "(connected: boolean) => void")] callback: Function,
+    ) -> u32 {
+        self.state.watchers.add(self.address.0, callback)
+    }
+
+    /// Removes a callback registered with `watch`. Returns whether it was
+    /// still registered.
+    pub fn unwatch(&self, id: u32) -> bool {
+        self.state.watchers.remove(id)
+    }
+
+    /// Does nothing, as a mock tunnel has no connections.
+    pub fn close(&self) -> Result<(), JsValue> {
+        Ok(())
+    }
+}
+
+fn address_option(options: &Object, field: &str) -> Result<Option<PublicKey>, JsError> {
+    let value = get_field(options, field)?;
+
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+
+    let address = value
+        .as_string()
+        .ok_or_else(|| JsError::new(&format!("The `{field}` option must be a string.")))?;
+
+    PublicKey::new(&address).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use ::tunnel::SecretKey;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    fn address(seed: u8) -> PublicKey {
+        PublicKey(SecretKey::from_bytes(&[seed; 32]).public())
+    }
+
+    fn bytes(data: &[u8]) -> Uint8Array {
+        Uint8Array::from(data)
+    }
+
+    fn field(object: &JsValue, field: &str) -> JsValue {
+        Reflect::get(object, &field.into()).unwrap()
+    }
+
+    fn error_name(error: JsValue) -> String {
+        error.unchecked_into::<js_sys::Error>().name().into()
+    }
+
+    /// Returns a callback which records the data it receives, and the data
+    /// received so far. The callback must be kept alive while it is used.
+    fn recorder() -> (
+        Closure<dyn FnMut(JsValue, Uint8Array)>,
+        Rc<RefCell<Vec<Vec<u8>>>>,
+    ) {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let callback = Closure::<dyn FnMut(JsValue, Uint8Array)>::new({
+            let received = Rc::clone(&received);
+            move |_, data: Uint8Array| received.borrow_mut().push(data.to_vec())
+        });
+
+        (callback, received)
+    }
+
+    fn function(closure: &JsValue) -> Function {
+        closure.unchecked_ref::<Function>().clone()
+    }
+
+    async fn mock() -> MockTunnel {
+        MockTunnel::new(Function::new_no_args(""), None)
+            .await
+            .unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    async fn sends_are_recorded_in_order() {
+        let mock = mock().await;
+
+        mock.send(&address(1), &bytes(&[1])).await.unwrap();
+        mock.peer(&address(2)).send(&bytes(&[2, 3])).await.unwrap();
+
+        let sent = mock.sent().unwrap();
+        assert_eq!(sent.length(), 2);
+        assert_eq!(Uint8Array::new(&field(&sent.get(0), "data")).to_vec(), [1]);
+        assert_eq!(
+            Uint8Array::new(&field(&sent.get(1), "data")).to_vec(),
+            [2, 3]
+        );
+
+        let info = JsValue::from(mock.info(&address(2)).unwrap());
+        assert_eq!(field(&info, "messagesSent").as_f64(), Some(1.0));
+        assert_eq!(field(&info, "bytesSent").as_f64(), Some(2.0));
+    }
+
+    #[wasm_bindgen_test]
+    async fn fail_next_send_only_fails_one_call() {
+        let mock = mock().await;
+        mock.fail_next_send("TimeoutError".into());
+
+        let error = mock.send(&address(1), &bytes(&[1])).await.unwrap_err();
+        assert_eq!(error_name(error), "TimeoutError");
+
+        mock.send(&address(1), &bytes(&[1])).await.unwrap();
+        assert_eq!(mock.sent().unwrap().length(), 1);
+
+        // Requests fail the same way.
+        mock.fail_next_send("TunnelModeError".into());
+        let error = mock.request(&address(1), &bytes(&[1])).await.unwrap_err();
+        assert_eq!(error_name(error), "TunnelModeError");
+    }
+
+    #[wasm_bindgen_test]
+    async fn requests_are_answered_by_the_request_handler() {
+        let mock = mock().await;
+        assert!(mock.request(&address(1), &bytes(&[1])).await.is_err());
+
+        let handler =
+            Closure::<dyn FnMut(JsValue, Uint8Array) -> Uint8Array>::new(|_, data: Uint8Array| {
+                let mut data = data.to_vec();
+                data.reverse();
+                Uint8Array::from(data.as_slice())
+            });
+        mock.set_request_handler(function(handler.as_ref()));
+
+        let response = mock.request(&address(1), &bytes(&[1, 2, 3])).await;
+        assert_eq!(response.unwrap().to_vec(), [3, 2, 1]);
+
+        let response = mock.peer(&address(2)).request(&bytes(&[4, 5])).await;
+        assert_eq!(response.unwrap().to_vec(), [5, 4]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn emitted_data_reaches_the_handler_until_detached() {
+        let (callback, received) = recorder();
+        let mock = MockTunnel::new(function(callback.as_ref()), None)
+            .await
+            .unwrap();
+
+        mock.emit_data(&address(1), &bytes(&[7])).unwrap();
+        assert_eq!(*received.borrow(), [vec![7]]);
+
+        mock.detach_all();
+        mock.emit_data(&address(1), &bytes(&[8])).unwrap();
+        assert_eq!(received.borrow().len(), 1);
+
+        let (replacement, replaced) = recorder();
+        mock.set_handler(function(replacement.as_ref()));
+        mock.emit_data(&address(1), &bytes(&[9])).unwrap();
+
+        assert_eq!(*replaced.borrow(), [vec![9]]);
+        assert_eq!(mock.dispatch_turns(), 3);
+    }
+
+    #[wasm_bindgen_test]
+    async fn watchers_follow_connection_changes() {
+        let mock = mock().await;
+        let peer = mock.peer(&address(1));
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let watcher = Closure::<dyn FnMut(bool)>::new({
+            let events = Rc::clone(&events);
+            move |connected| events.borrow_mut().push(connected)
+        });
+        let id = peer.watch(function(watcher.as_ref()));
+
+        mock.set_connected(&address(1), true);
+        mock.set_connected(&address(1), true);
+        mock.set_connected(&address(2), true);
+
+        assert_eq!(*events.borrow(), [true]);
+        assert!(peer.is_connected());
+        assert!(mock.is_connected(&address(2)));
+
+        assert!(peer.unwatch(id));
+        assert!(!peer.unwatch(id));
+        mock.set_connected(&address(1), false);
+
+        assert_eq!(*events.borrow(), [true]);
+        assert!(!peer.is_connected());
+    }
+
+    #[wasm_bindgen_test]
+    async fn watched_peers_start_with_their_current_state() {
+        let mock = mock().await;
+        mock.set_connected(&address(1), true);
+
+        let states = Rc::new(RefCell::new(Vec::new()));
+        let watcher = Closure::<dyn FnMut(String)>::new({
+            let states = Rc::clone(&states);
+            move |state| states.borrow_mut().push(state)
+        });
+        let unsubscribe = mock
+            .watch_peer(&address(1), function(watcher.as_ref()))
+            .unwrap();
+        assert_eq!(*states.borrow(), ["connected-direct"]);
+
+        mock.set_connected(&address(1), false);
+        mock.set_connected(&address(2), true);
+        assert_eq!(*states.borrow(), ["connected-direct", "disconnected"]);
+
+        unsubscribe.call0(&JsValue::null()).unwrap();
+        mock.set_connected(&address(1), true);
+        assert_eq!(states.borrow().len(), 2);
+    }
+
+    #[wasm_bindgen_test]
+    async fn addresses_come_from_the_options() {
+        let options = Object::new();
+        let receiver = address(1).0.to_string();
+        Reflect::set(
+            &options,
+            &"receiverAddress".into(),
+            &receiver.clone().into(),
+        )
+        .unwrap();
+
+        let mock = MockTunnel::new(Function::new_no_args(""), Some(options))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.receiver_address().map(|key| key.0.to_string()),
+            Some(receiver)
+        );
+        assert!(mock.sender_address().is_none());
+    }
+}
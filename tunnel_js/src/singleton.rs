@@ -0,0 +1,104 @@
+use js_sys::{Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+/// The key of the registry on `globalThis`.
+const REGISTRY_KEY: &str = "__brasoniteTunnel";
+
+/// Returns the registry shared by every instance of this module.
+///
+/// The registry is stored on `globalThis` rather than in wasm memory, so that
+/// it survives re-instantiations of the module (e.g. by HMR in dev mode). It
+/// holds the singleton tunnels by name, as `{ id, tunnel }` entries, and the
+/// amount of running dispatch tasks.
+fn registry() -> Result<Object, JsValue> {
+    let global = js_sys::global();
+    let key = JsValue::from_str(REGISTRY_KEY);
+    let registry = Reflect::get(&global, &key)?;
+
+    if registry.is_object() {
+        return Ok(registry.unchecked_into());
+    }
+
+    let registry = Object::new();
+    Reflect::set(&registry, &"tunnels".into(), &Object::new())?;
+    Reflect::set(&registry, &"nextId".into(), &0.into())?;
+    Reflect::set(&registry, &"dispatchTasks".into(), &0.into())?;
+    Reflect::set(&global, &key, &registry)?;
+
+    Ok(registry)
+}
+
+fn tunnels() -> Result<Object, JsValue> {
+    Ok(Reflect::get(&registry()?, &"tunnels".into())?.unchecked_into())
+}
+
+fn entry(name: &str) -> Result<Option<JsValue>, JsValue> {
+    let entry = Reflect::get(&tunnels()?, &name.into())?;
+    Ok((!entry.is_undefined()).then_some(entry))
+}
+
+fn counter(field: &str) -> Result<f64, JsValue> {
+    Ok(Reflect::get(&registry()?, &field.into())?
+        .as_f64()
+        .unwrap_or(0.0))
+}
+
+fn add_to_counter(field: &str, amount: f64) -> Result<f64, JsValue> {
+    let value = counter(field)? + amount;
+    Reflect::set(&registry()?, &field.into(), &value.into())?;
+    Ok(value)
+}
+
+/// Returns the singleton tunnel with the provided name, if it exists.
+pub(crate) fn get(name: &str) -> Result<Option<JsValue>, JsValue> {
+    entry(name)?
+        .map(|entry| Reflect::get(&entry, &"tunnel".into()))
+        .transpose()
+}
+
+/// Returns a new id, which identifies a singleton tunnel across every
+/// instance of this module.
+pub(crate) fn next_id() -> Result<f64, JsValue> {
+    add_to_counter("nextId", 1.0)
+}
+
+/// Registers a singleton tunnel under the provided name and id.
+pub(crate) fn insert(name: &str, id: f64, tunnel: &JsValue) -> Result<(), JsValue> {
+    let entry = Object::new();
+    Reflect::set(&entry, &"id".into(), &id.into())?;
+    Reflect::set(&entry, &"tunnel".into(), tunnel)?;
+    Reflect::set(&tunnels()?, &name.into(), &entry)?;
+
+    Ok(())
+}
+
+/// Unregisters the singleton tunnel with the provided name, returning it.
+pub(crate) fn remove(name: &str) -> Result<Option<JsValue>, JsValue> {
+    let tunnel = get(name)?;
+    Reflect::delete_property(&tunnels()?, &name.into())?;
+    Ok(tunnel)
+}
+
+/// Unregisters the singleton tunnel with the provided name, unless it was
+/// replaced by another tunnel in the meantime.
+pub(crate) fn remove_if_same(name: &str, id: f64) -> Result<(), JsValue> {
+    let Some(entry) = entry(name)? else {
+        return Ok(());
+    };
+
+    if Reflect::get(&entry, &"id".into())?.as_f64() == Some(id) {
+        Reflect::delete_property(&tunnels()?, &name.into())?;
+    }
+
+    Ok(())
+}
+
+/// Returns how many dispatch tasks are running, across every instance of
+/// this module.
+pub(crate) fn dispatch_tasks() -> Result<u32, JsValue> {
+    Ok(counter("dispatchTasks")? as u32)
+}
+
+pub(crate) fn add_dispatch_tasks(amount: f64) {
+    let _ = add_to_counter("dispatchTasks", amount);
+}
@@ -1,9 +1,70 @@
-from collections.abc import Callable
+from collections.abc import Callable, Iterable, Iterator
+from typing import Literal, TypedDict
 
+class RuntimeMissingError(Exception): ...
 class PublicKeyParseError(Exception): ...
-class TunnelCreationError(Exception): ...
 class TunnelDestroyedError(Exception): ...
-class TunnelSendingError(Exception): ...
+class TunnelModeError(Exception): ...
+
+class TunnelCreationError(Exception):
+    """
+    The source errors of this exception are attached as its `__cause__`, so the whole chain is shown in tracebacks.
+    """
+
+    details: list[str]
+    """The messages of the whole error chain, starting with this exception's own message."""
+    kind: str
+    """The kind of operation which failed (`"setup"`, `"send"` or `"timeout"`)."""
+    peer: PublicKey | None
+    """The address of the peer involved in the operation, if any."""
+    timeout: float | None
+    """The timeout which expired, in seconds, if any."""
+
+class TunnelSendingError(Exception):
+    """
+    The source errors of this exception are attached as its `__cause__`, so the whole chain is shown in tracebacks.
+    """
+
+    details: list[str]
+    """The messages of the whole error chain, starting with this exception's own message."""
+    kind: str
+    """The kind of operation which failed (`"setup"`, `"send"` or `"timeout"`)."""
+    peer: PublicKey | None
+    """The address of the peer involved in the operation, if any."""
+    timeout: float | None
+    """The timeout which expired, in seconds, if any."""
+
+class TunnelTimeoutError(TimeoutError):
+    """
+    The source errors of this exception are attached as its `__cause__`, so the whole chain is shown in tracebacks.
+    """
+
+    details: list[str]
+    """The messages of the whole error chain, starting with this exception's own message."""
+    kind: str
+    """The kind of operation which failed (`"setup"`, `"send"` or `"timeout"`)."""
+    peer: PublicKey | None
+    """The address of the peer involved in the operation, if any."""
+    timeout: float | None
+    """The timeout which expired, in seconds, if any."""
+
+class PeerInfo(TypedDict):
+    """
+    The state of the connection to another tunnel, returned by `Tunnel.info` and `Peer.info`.
+    """
+
+    connected: bool
+    """Whether the tunnel has a live connection to the other tunnel."""
+    age: float | None
+    """How long ago the connection was established, in seconds, if one is cached."""
+    direct: bool | None
+    """Whether the other tunnel is reached without a relay, if known."""
+    rtt: float | None
+    """The current round-trip time of the connection, in seconds, if one is cached."""
+    messages_sent: int
+    bytes_sent: int
+    send_errors: int
+    """Sends to the other tunnel which failed, including those which timed out."""
 
 class PublicKey:
     def __init__(self, value: str) -> None:
@@ -14,18 +75,49 @@ class PublicKey:
         ...
 
 class Tunnel:
-    def __init__(self, handler: Callable) -> None:
+    def __init__(
+        self,
+        handler: Callable | None = None,
+        mode: Literal["send_receive", "send_only", "receive_only"] = "send_receive",
+        timeout: float | None = None,
+        request_handler: Callable[[PublicKey, bytes], bytes] | None = None,
+        relay_url: str | None = None,
+    ) -> None:
         """
         Creates a new Tunnel using the provided handler.
 
         Args:
-            `handler`: The callback which will be called when the Tunnel receives data.
+            `handler`: The callback which will be called when the Tunnel receives data. If not provided, the received data must instead be consumed by iterating over the Tunnel.
+            `mode`: The directions in which the Tunnel can transfer data. A `"send_only"` Tunnel does not bind a receiver endpoint, and a `"receive_only"` Tunnel does not bind a sender endpoint. Using the disabled direction raises a `TunnelModeError`.
+            `timeout`: If provided, the maximum amount of seconds to wait for the Tunnel to go online. If `0` is provided, the Tunnel is returned as soon as its endpoints are bound, and `wait_online` can be used to wait for connectivity later.
+            `request_handler`: The callback which answers the requests sent with `request`, called with the sender and the request and returning the response. If it raises, the exception is reported as unraisable and an empty response is sent. Without one, requests to the Tunnel fail.
+            `relay_url`: If provided, the URL of the only relay server the Tunnel uses, instead of the default ones.
+
+        The wait can be interrupted with Ctrl+C, which raises a `KeyboardInterrupt`. If the construction is aborted, any endpoint which was already bound is closed.
 
         Raises:
             `TunnelCreationError`: If there was a problem creating the Tunnel.
+            `TunnelModeError`: If a handler or a request handler is provided to a `"send_only"` Tunnel.
+            `TunnelTimeoutError`: If the Tunnel did not go online in time.
+            `ValueError`: If the mode, the timeout or the relay URL is invalid.
         """
         ...
 
+    def __iter__(self) -> Iterator[tuple[PublicKey, bytes]]:
+        """
+        Iterates over the data received by this tunnel, as `(sender, data)` tuples.
+
+        The wait for new data can be interrupted with Ctrl+C, which raises a `KeyboardInterrupt`. The iteration ends once the tunnel is destroyed (including from another thread).
+
+        It is safe to iterate from one thread while other threads use the tunnel (e.g. to send data). If multiple threads iterate at once, each message is received by only one of them.
+
+        Raises:
+            `TypeError`: If the tunnel was created with a handler.
+        """
+        ...
+
+    def __next__(self) -> tuple[PublicKey, bytes]: ...
+
     def send(self, address: PublicKey, data: bytes) -> None:
         """
         Sends some data to another tunnel, given the provided address is valid.
@@ -38,16 +130,155 @@ class Tunnel:
 
         Raises:
             `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelModeError`: If the tunnel is receive-only.
             `TunnelSendingError`: If there was a problem sending the data.
         """
         ...
 
+    def request(self, address: PublicKey, data: bytes) -> bytes:
+        """
+        Sends a request to another tunnel and returns its response.
+
+        The GIL is released while waiting.
+
+        Args:
+            `address`: The **receiver address** of the tunnel to send the request to.
+            `data`: The request.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelModeError`: If the tunnel is receive-only.
+            `TunnelSendingError`: If the request could not be sent or answered, e.g. because the other tunnel has no request handler.
+            `TunnelTimeoutError`: If no response arrived in time.
+        """
+        ...
+
+    def ping(self, address: PublicKey) -> float:
+        """
+        Measures the round-trip time to another tunnel, in seconds.
+
+        The GIL is released while waiting.
+
+        Args:
+            `address`: The **receiver address** of the tunnel to ping.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelModeError`: If the tunnel is receive-only.
+            `TunnelSendingError`: If the other tunnel could not be reached.
+            `TunnelTimeoutError`: If no answer arrived in time.
+        """
+        ...
+
+    def is_connected(self, address: PublicKey) -> bool:
+        """
+        Returns whether this tunnel has a live connection to another tunnel.
+
+        Args:
+            `address`: The **receiver address** of the other tunnel.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+        """
+        ...
+
+    def info(self, address: PublicKey) -> PeerInfo:
+        """
+        Returns the state of the connection to another tunnel.
+
+        Args:
+            `address`: The **receiver address** of the other tunnel.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+        """
+        ...
+
+    def map_send(
+        self,
+        pairs: Iterable[tuple[PublicKey, bytes]],
+        max_concurrency: int = 32,
+        return_exceptions: bool = True,
+    ) -> list[BaseException | None]:
+        """
+        Sends some data to many tunnels concurrently, blocking until every send has completed.
+
+        The sends are driven by the tunnel itself rather than by Python threads, and the GIL is released while waiting. The wait can be interrupted with Ctrl+C, in which case no new sends are started and the sends already in progress are given a short time to complete.
+
+        Args:
+            `pairs`: The `(address, data)` pairs to send, in the same format as the arguments of `send`.
+            `max_concurrency`: The maximum amount of sends in progress at once.
+            `return_exceptions`: If `True`, the exception of each failed send is returned in place of its result. If `False`, the exception of the first failed send (in input order) is raised instead.
+
+        Returns:
+            A list with the result of each send, in the same order as `pairs`. Successful sends are represented by `None`.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelModeError`: If `return_exceptions` is `False` and the tunnel is receive-only.
+            `TunnelSendingError`: If `return_exceptions` is `False` and one of the sends failed.
+            `ValueError`: If `max_concurrency` is `0`.
+        """
+        ...
+
+    def send_nowait(self, address: PublicKey, data: bytes) -> None:
+        """
+        Sends some data to another tunnel in the background, without waiting for the receiver to acknowledge it.
+
+        Errors which happen while sending are discarded. Use `flush` to wait until every background send has completed.
+
+        Args:
+            `address`: The **receiver address** of the tunnel to send data to.
+            `data`: The data to be sent.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelModeError`: If the tunnel is receive-only.
+        """
+        ...
+
+    def flush(self, address: PublicKey | None = None, timeout: float | None = None) -> None:
+        """
+        Blocks until all background sends started by `send_nowait` have completed.
+
+        The GIL is released while waiting.
+
+        Args:
+            `address`: If provided, only waits for sends to this address.
+            `timeout`: If provided, the maximum amount of seconds to wait for.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelTimeoutError`: If the sends did not complete in time.
+        """
+        ...
+
+    def wait_online(self, timeout: float | None = None) -> None:
+        """
+        Blocks until the endpoints of this tunnel are online, i.e. connected to a relay and aware of their direct addresses.
+
+        The GIL is released while waiting, and the wait can be interrupted with Ctrl+C.
+
+        Args:
+            `timeout`: If provided, the maximum amount of seconds to wait for.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
+            `TunnelTimeoutError`: If the tunnel did not go online in time.
+        """
+        ...
+
     def destroy(self) -> None:
         """
         Closes both the sender and the receiver endpoint and consumes this object.
 
         Ideally, this should be called before the execution of the program ends or before a tunnel is discarded.
 
+        Running handlers and background sends are given up to 5 seconds to complete. A `ResourceWarning` is emitted if there were background sends which did not complete in time, as these are dropped.
+
+        Raises:
+            `Exception`: If the receiver endpoint could not be shut down cleanly. The tunnel is destroyed nonetheless.
+
         **Note:** a tunnel **cannot** be used after this function is called. Using any of a tunnel's functionality whatsoever will raise a `TunnelDestroyedError` when that happens.
         """
         ...
@@ -72,6 +303,21 @@ class Tunnel:
         Returns the address of the sender endpoint of this tunnel.
 
         The sender enpoint is responsible for sending data to other tunnels. As such, when sending data, this address will be cited as the source.
+
+        Raises:
+            `TunnelModeError`: If the tunnel is receive-only, and thus has no sender endpoint.
+        """
+        ...
+
+    def peer(self, address: PublicKey) -> Peer:
+        """
+        Returns a `Peer` object which sends data to the provided address through this tunnel.
+
+        Args:
+            `address`: The **receiver address** of the other tunnel.
+
+        Raises:
+            `TunnelDestroyedError`: If the tunnel was previously destroyed.
         """
         ...
 
@@ -80,5 +326,73 @@ class Tunnel:
         Returns the address of the receiver endpoint of this tunnel.
 
         The receiver enpoint is responsible for receiving data from other tunnels. As such, senders should send data to this address.
+
+        Raises:
+            `TunnelModeError`: If the tunnel is send-only, and thus has no receiver endpoint.
+        """
+        ...
+
+class Peer:
+    """
+    A convenience wrapper over a tunnel and the address of another tunnel.
+
+    A peer is just an address wrapper, so it remains valid across reconnects. Peers compare and hash by their address, which makes them usable as dictionary keys.
+
+    **Note:** using a peer after its tunnel was destroyed raises a `TunnelDestroyedError`.
+    """
+
+    @property
+    def address(self) -> PublicKey:
+        """
+        The **receiver address** of the other tunnel.
+        """
+        ...
+
+    def send(self, data: bytes) -> None:
+        """
+        Sends some data to this peer. See `Tunnel.send`.
+        """
+        ...
+
+    def send_nowait(self, data: bytes) -> None:
+        """
+        Sends some data to this peer in the background. See `Tunnel.send_nowait`.
+        """
+        ...
+
+    def flush(self, timeout: float | None = None) -> None:
+        """
+        Blocks until all background sends to this peer have completed. See `Tunnel.flush`.
+        """
+        ...
+
+    def request(self, data: bytes) -> bytes:
+        """
+        Sends a request to this peer and returns its response. See `Tunnel.request`.
+        """
+        ...
+
+    def ping(self) -> float:
+        """
+        Measures the round-trip time to this peer, in seconds. See `Tunnel.ping`.
+        """
+        ...
+
+    @property
+    def is_connected(self) -> bool:
+        """
+        Whether the tunnel has a live connection to this peer. See `Tunnel.is_connected`.
+        """
+        ...
+
+    def info(self) -> PeerInfo:
+        """
+        Returns the state of the connection to this peer. See `Tunnel.info`.
+        """
+        ...
+
+    def close(self) -> None:
+        """
+        Closes the connection to this peer, if it exists. See `Tunnel.close`.
         """
         ...
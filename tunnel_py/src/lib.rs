@@ -1,11 +1,28 @@
-use std::str::FromStr;
+use std::{
+    error::Error,
+    ffi::{CStr, CString},
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::pin,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, channel},
+    },
+    time::{Duration, Instant},
+};
 
-use ::tunnel::{PublicKey as NativePublicKey, Tunnel as NativeTunnel};
+use ::tunnel::{Mode, PublicKey as NativePublicKey, RelayUrl, Tunnel as NativeTunnel, TunnelError};
+use futures::{StreamExt, stream};
 use pyo3::{
     create_exception,
-    exceptions::{PyException, PyValueError},
+    exceptions::{
+        PyBaseException, PyException, PyResourceWarning, PyTimeoutError, PyTypeError, PyValueError,
+    },
     prelude::*,
     sync::PyOnceLock,
+    type_object::PyTypeInfo,
+    types::PyDict,
 };
 use tokio::runtime::Runtime;
 
@@ -16,12 +33,33 @@ create_exception!(tunnel, RuntimeMissingError, PyException);
 create_exception!(tunnel, PublicKeyParseError, PyException);
 create_exception!(tunnel, TunnelCreationError, PyException);
 create_exception!(tunnel, TunnelDestroyedError, PyException);
+create_exception!(tunnel, TunnelModeError, PyException);
 create_exception!(tunnel, TunnelSendingError, PyException);
+create_exception!(tunnel, TunnelTimeoutError, PyTimeoutError);
 
 const RUNTIME_MISSING_MSG: &str = "No initialized Tokio runtime found.";
 const TUNNEL_DESTROYED_MSG: &str = "This tunnel was previously destroyed.";
+const NOT_ITERABLE_MSG: &str = "Only tunnels created without a handler can be iterated.";
+const SEND_ONLY_MSG: &str = "This tunnel is send-only and cannot receive data.";
+const RECEIVE_ONLY_MSG: &str = "This tunnel is receive-only and cannot send data.";
+
+/// How often a blocked iteration checks for signals (e.g. Ctrl+C).
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long an interrupted `map_send` waits for the sends which were already
+/// started.
+const MAP_SEND_INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+
+const ERROR_REPR: &CStr = cr#"
+def __repr__(self):
+    kind = getattr(self, "kind", None)
+    peer = getattr(self, "peer", None)
+    peer = None if peer is None else str(peer)
+    return f"{type(self).__name__}({str(self)!r}, kind={kind!r}, peer={peer!r})"
+"#;
 
 #[pyclass]
+#[derive(Clone)]
 pub struct PublicKey(NativePublicKey);
 
 #[pymethods]
@@ -42,24 +80,172 @@ impl PublicKey {
     }
 }
 
+enum IncomingEvent {
+    Data(NativePublicKey, Vec<u8>),
+    /// Pushed when the tunnel is destroyed, ending any iteration.
+    Closed,
+}
+
+/// The incoming data of a tunnel created without a handler, which is consumed
+/// by iterating over the tunnel.
+struct Incoming {
+    sender: Sender<IncomingEvent>,
+    receiver: Arc<Mutex<Receiver<IncomingEvent>>>,
+}
+
 #[pyclass]
 pub struct Tunnel {
     pub inner: Option<NativeTunnel>,
+    incoming: Option<Incoming>,
 }
 
 #[pymethods]
 impl Tunnel {
     #[new]
-    fn new(py: Python, handler: Py<PyAny>) -> PyResult<Self> {
-        let inner = runtime(py)?
-            .block_on(NativeTunnel::new(
-                move |sender: NativePublicKey, data: Vec<u8>| {
-                    Python::attach(|py| handler.call(py, (PublicKey(sender), data), None)).unwrap();
-                },
-            ))
-            .map_err(|e| TunnelCreationError::new_err(e.to_string()))?;
+    #[pyo3(signature = (
+        handler=None,
+        mode="send_receive",
+        timeout=None,
+        request_handler=None,
+        relay_url=None,
+    ))]
+    fn new(
+        py: Python,
+        handler: Option<Py<PyAny>>,
+        mode: &str,
+        timeout: Option<f64>,
+        request_handler: Option<Py<PyAny>>,
+        relay_url: Option<&str>,
+    ) -> PyResult<Self> {
+        let mode = match mode {
+            "send_receive" => Mode::SendReceive,
+            "send_only" => Mode::SendOnly,
+            "receive_only" => Mode::ReceiveOnly,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid mode `{mode}`. Expected `send_receive`, `send_only` or `receive_only`."
+                )));
+            }
+        };
+
+        let timeout = parse_timeout(timeout)?;
+
+        let runtime = runtime(py)?;
+        let mut builder = NativeTunnel::builder().mode(mode).wait_online(false);
 
-        Ok(Self { inner: Some(inner) })
+        if let Some(relay_url) = relay_url {
+            let relay_url = RelayUrl::from_str(relay_url)
+                .map_err(|e| PyValueError::new_err(format!("Invalid relay URL: {e}")))?;
+
+            builder = builder.relay_url(relay_url);
+        }
+
+        let mut incoming = None;
+
+        if let Some(request_handler) = request_handler {
+            if !mode.can_receive() {
+                return Err(TunnelModeError::new_err(SEND_ONLY_MSG));
+            }
+
+            builder = builder.request_handler(move |sender: NativePublicKey, data: Vec<u8>| {
+                Python::attach(|py| {
+                    request_handler
+                        .call(py, (PublicKey(sender), data), None)
+                        .and_then(|response| response.extract::<Vec<u8>>(py))
+                        // A request cannot be refused, so an exception is
+                        // reported and answered with an empty response.
+                        .unwrap_or_else(|e| {
+                            e.write_unraisable(py, Some(request_handler.bind(py)));
+                            Vec::new()
+                        })
+                })
+            });
+        }
+
+        let inner = match handler {
+            Some(_) if !mode.can_receive() => {
+                return Err(TunnelModeError::new_err(SEND_ONLY_MSG));
+            }
+            Some(handler) => runtime.block_on(
+                builder
+                    .handler(move |sender: NativePublicKey, data: Vec<u8>| {
+                        // Exceptions are reported as errors of the handler.
+                        Python::attach(|py| handler.call(py, (PublicKey(sender), data), None))
+                            .map(drop)
+                    })
+                    .spawn(),
+            ),
+            None if mode.can_receive() => {
+                let (sender, receiver) = channel();
+                let data_sender = sender.clone();
+
+                incoming = Some(Incoming {
+                    sender,
+                    receiver: Arc::new(Mutex::new(receiver)),
+                });
+
+                runtime.block_on(
+                    builder
+                        .handler(move |sender: NativePublicKey, data: Vec<u8>| {
+                            let _ = data_sender.send(IncomingEvent::Data(sender, data));
+                        })
+                        .spawn(),
+                )
+            }
+            None => runtime.block_on(builder.spawn()),
+        }
+        .map_err(|e| chained_error::<TunnelCreationError>(py, &e, "setup", None, None))?;
+
+        // The endpoints are already bound at this point, so they must be
+        // closed if the construction is aborted. A zero timeout skips the
+        // wait altogether.
+        if timeout != Some(Duration::ZERO)
+            && let Err(error) = wait_online(py, &inner, timeout)
+        {
+            let _ = py.detach(|| runtime.block_on(inner.destroy()));
+            return Err(error);
+        }
+
+        Ok(Self {
+            inner: Some(inner),
+            incoming,
+        })
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyResult<PyRef<'_, Self>> {
+        if slf.incoming.is_none() {
+            return Err(PyTypeError::new_err(NOT_ITERABLE_MSG));
+        }
+
+        Ok(slf)
+    }
+
+    fn __next__(slf: &Bound<'_, Self>) -> PyResult<Option<(PublicKey, Vec<u8>)>> {
+        let py = slf.py();
+
+        // The tunnel must not stay borrowed while waiting, so that it can be
+        // used (and destroyed) from other threads.
+        let (sender, receiver) = match &slf.borrow().incoming {
+            Some(incoming) => (incoming.sender.clone(), Arc::clone(&incoming.receiver)),
+            None => return Err(PyTypeError::new_err(NOT_ITERABLE_MSG)),
+        };
+
+        loop {
+            let event = py.detach(|| receiver.lock().unwrap().recv_timeout(SIGNAL_POLL_INTERVAL));
+
+            match event {
+                Ok(IncomingEvent::Data(address, data)) => {
+                    return Ok(Some((PublicKey(address), data)));
+                }
+                Ok(IncomingEvent::Closed) => {
+                    // Keeps the sentinel around so later iterations end too.
+                    let _ = sender.send(IncomingEvent::Closed);
+                    return Ok(None);
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => py.check_signals()?,
+            }
+        }
     }
 
     fn send(&self, py: Python, address: &PublicKey, data: &[u8]) -> PyResult<()> {
@@ -71,13 +257,267 @@ impl Tunnel {
         };
 
         runtime(py)?
-            .block_on(inner.send(address.0, data))
-            .map_err(|e| TunnelSendingError::new_err(e.to_string()))
+            .block_on(inner.send(address.0, data.to_vec()))
+            .map_err(|e| send_error(py, e, address.0))
+    }
+
+    fn request(&self, py: Python, address: &PublicKey, data: &[u8]) -> PyResult<Vec<u8>> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        let runtime = runtime(py)?;
+
+        py.detach(|| runtime.block_on(inner.request(address.0, data.to_vec())))
+            .map_err(|e| match e {
+                TunnelError::RequestTimeout { timeout, .. } => chained_error::<TunnelTimeoutError>(
+                    py,
+                    &e,
+                    "timeout",
+                    Some(address.0),
+                    Some(timeout),
+                ),
+                _ => send_error(py, e, address.0),
+            })
+    }
+
+    fn ping(&self, py: Python, address: &PublicKey) -> PyResult<f64> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        let runtime = runtime(py)?;
+
+        py.detach(|| runtime.block_on(inner.ping(address.0)))
+            .map(|rtt| rtt.as_secs_f64())
+            .map_err(|e| match e {
+                TunnelError::PingTimeout { timeout, .. } => chained_error::<TunnelTimeoutError>(
+                    py,
+                    &e,
+                    "timeout",
+                    Some(address.0),
+                    Some(timeout),
+                ),
+                _ => send_error(py, e, address.0),
+            })
+    }
+
+    fn is_connected(&self, address: &PublicKey) -> PyResult<bool> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        Ok(inner.is_connected(&address.0))
+    }
+
+    fn info<'py>(&self, py: Python<'py>, address: &PublicKey) -> PyResult<Bound<'py, PyDict>> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        let status = inner.connection_status(&address.0);
+        let stats = inner.stats(&address.0);
+
+        let info = PyDict::new(py);
+        info.set_item("connected", inner.is_connected(&address.0))?;
+        info.set_item(
+            "age",
+            status.as_ref().map(|status| status.age.as_secs_f64()),
+        )?;
+        info.set_item(
+            "direct",
+            status
+                .as_ref()
+                .and_then(|status| status.conn_type.as_ref().map(|_| status.is_direct())),
+        )?;
+        info.set_item(
+            "rtt",
+            stats
+                .and_then(|stats| stats.rtt)
+                .map(|rtt| rtt.as_secs_f64()),
+        )?;
+        info.set_item(
+            "messages_sent",
+            stats.map_or(0, |stats| stats.messages_sent),
+        )?;
+        info.set_item("bytes_sent", stats.map_or(0, |stats| stats.bytes_sent))?;
+        info.set_item("send_errors", stats.map_or(0, |stats| stats.send_errors))?;
+
+        Ok(info)
+    }
+
+    #[pyo3(signature = (pairs, max_concurrency=32, return_exceptions=true))]
+    fn map_send(
+        &self,
+        py: Python,
+        pairs: &Bound<'_, PyAny>,
+        max_concurrency: usize,
+        return_exceptions: bool,
+    ) -> PyResult<Vec<Option<Py<PyBaseException>>>> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        if max_concurrency == 0 {
+            return Err(PyValueError::new_err(
+                "`max_concurrency` must be at least 1.",
+            ));
+        }
+
+        let pairs = pairs
+            .try_iter()?
+            .map(|pair| pair?.extract::<(PublicKey, Vec<u8>)>())
+            .collect::<PyResult<Vec<_>>>()?;
+
+        let addresses: Vec<NativePublicKey> = pairs.iter().map(|(address, _)| address.0).collect();
+
+        let runtime = runtime(py)?;
+        let stopped = AtomicBool::new(false);
+
+        let mut sends = pin!(
+            stream::iter(pairs)
+                .map(|(address, data)| {
+                    let stopped = &stopped;
+
+                    async move {
+                        // Once interrupted, no new sends are started.
+                        if stopped.load(Ordering::Acquire) {
+                            return None;
+                        }
+
+                        Some(inner.send(address.0, data).await)
+                    }
+                })
+                .buffered(max_concurrency)
+                .collect::<Vec<_>>()
+        );
+
+        let results = loop {
+            let poll = py.detach(|| {
+                runtime.block_on(tokio::time::timeout(SIGNAL_POLL_INTERVAL, sends.as_mut()))
+            });
+
+            if let Ok(results) = poll {
+                break results;
+            }
+
+            if let Err(error) = py.check_signals() {
+                stopped.store(true, Ordering::Release);
+
+                let _ = py.detach(|| {
+                    runtime.block_on(tokio::time::timeout(
+                        MAP_SEND_INTERRUPT_GRACE,
+                        sends.as_mut(),
+                    ))
+                });
+
+                return Err(error);
+            }
+        };
+
+        let mut errors = Vec::with_capacity(results.len());
+
+        for (result, address) in results.into_iter().zip(addresses) {
+            // Sends are only skipped after an interrupt, which returns early.
+            let Some(Err(error)) = result else {
+                errors.push(None);
+                continue;
+            };
+
+            let error = send_error(py, error, address);
+
+            if !return_exceptions {
+                return Err(error);
+            }
+
+            errors.push(Some(error.into_value(py)));
+        }
+
+        Ok(errors)
+    }
+
+    fn send_nowait(&self, py: Python, address: &PublicKey, data: &[u8]) -> PyResult<()> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        let _guard = runtime(py)?.enter();
+
+        inner
+            .send_nowait(address.0, data.to_vec())
+            .map_err(|e| TunnelModeError::new_err(e.to_string()))
+    }
+
+    #[pyo3(signature = (address=None, timeout=None))]
+    fn flush(&self, py: Python, address: Option<&PublicKey>, timeout: Option<f64>) -> PyResult<()> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        let address = address.map(|address| address.0);
+        let timeout = parse_timeout(timeout)?;
+
+        let runtime = runtime(py)?;
+
+        py.detach(|| runtime.block_on(inner.flush(address, timeout)))
+            .map_err(|e| chained_error::<TunnelTimeoutError>(py, &e, "timeout", address, timeout))
+    }
+
+    #[pyo3(signature = (timeout=None))]
+    fn wait_online(&self, py: Python, timeout: Option<f64>) -> PyResult<()> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => {
+                return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+            }
+        };
+
+        wait_online(py, inner, parse_timeout(timeout)?)
     }
 
     fn destroy(&mut self, py: Python) -> PyResult<()> {
         if let Some(inner) = self.inner.take() {
-            runtime(py)?.block_on(inner.destroy());
+            let pending = inner.pending_sends();
+
+            let result = runtime(py)?.block_on(inner.destroy());
+
+            if let Some(incoming) = &self.incoming {
+                let _ = incoming.sender.send(IncomingEvent::Closed);
+            }
+
+            let report =
+                result.map_err(|e| chained_error::<PyException>(py, &e, "shutdown", None, None))?;
+
+            if pending > 0 && !report.drained {
+                let message = CString::new(format!(
+                    "Destroyed a tunnel with {pending} unflushed message(s). These messages were dropped."
+                ))
+                .unwrap();
+
+                PyErr::warn(py, py.get_type::<PyResourceWarning>().as_any(), &message, 1)?;
+            }
+
             Ok(())
         } else {
             Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG))
@@ -118,7 +558,21 @@ impl Tunnel {
             }
         };
 
-        Ok(PublicKey(inner.sender_address()))
+        inner
+            .sender_address()
+            .map(PublicKey)
+            .ok_or_else(|| TunnelModeError::new_err(RECEIVE_ONLY_MSG))
+    }
+
+    fn peer(slf: &Bound<'_, Self>, address: &PublicKey) -> PyResult<Peer> {
+        if slf.borrow().inner.is_none() {
+            return Err(TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG));
+        }
+
+        Ok(Peer {
+            tunnel: slf.clone().unbind(),
+            address: address.clone(),
+        })
     }
 
     fn receiver_address(&self) -> PyResult<PublicKey> {
@@ -129,7 +583,156 @@ impl Tunnel {
             }
         };
 
-        Ok(PublicKey(inner.receiver_address()))
+        inner
+            .receiver_address()
+            .map(PublicKey)
+            .ok_or_else(|| TunnelModeError::new_err(SEND_ONLY_MSG))
+    }
+}
+
+#[pyclass(frozen)]
+pub struct Peer {
+    tunnel: Py<Tunnel>,
+    address: PublicKey,
+}
+
+#[pymethods]
+impl Peer {
+    #[getter]
+    fn address(&self) -> PublicKey {
+        self.address.clone()
+    }
+
+    fn send(&self, py: Python, data: &[u8]) -> PyResult<()> {
+        self.tunnel.borrow(py).send(py, &self.address, data)
+    }
+
+    fn send_nowait(&self, py: Python, data: &[u8]) -> PyResult<()> {
+        self.tunnel.borrow(py).send_nowait(py, &self.address, data)
+    }
+
+    fn request(&self, py: Python, data: &[u8]) -> PyResult<Vec<u8>> {
+        self.tunnel.borrow(py).request(py, &self.address, data)
+    }
+
+    fn ping(&self, py: Python) -> PyResult<f64> {
+        self.tunnel.borrow(py).ping(py, &self.address)
+    }
+
+    #[getter]
+    fn is_connected(&self, py: Python) -> PyResult<bool> {
+        self.tunnel.borrow(py).is_connected(&self.address)
+    }
+
+    fn info<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.tunnel.borrow(py).info(py, &self.address)
+    }
+
+    #[pyo3(signature = (timeout=None))]
+    fn flush(&self, py: Python, timeout: Option<f64>) -> PyResult<()> {
+        self.tunnel
+            .borrow(py)
+            .flush(py, Some(&self.address), timeout)
+    }
+
+    fn close(&self, py: Python) -> PyResult<()> {
+        self.tunnel.borrow(py).close(&self.address)
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.address.0 == other.address.0
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.address.0.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Peer({})", self.address.0)
+    }
+}
+
+/// Creates an exception of type `T` from an error, preserving its chain of
+/// sources.
+///
+/// Each source is attached as the `__cause__` of the previous exception, and
+/// the messages of the whole chain are exposed through the `details`
+/// attribute. The `kind`, `peer` and `timeout` attributes are also set.
+fn chained_error<T: PyTypeInfo>(
+    py: Python,
+    error: &(dyn Error + 'static),
+    kind: &str,
+    peer: Option<NativePublicKey>,
+    timeout: Option<Duration>,
+) -> PyErr {
+    let details: Vec<String> = std::iter::successors(Some(error), |error| error.source())
+        .map(|error| error.to_string())
+        .collect();
+
+    let mut cause: Option<PyErr> = None;
+
+    for detail in details.iter().skip(1).rev() {
+        let error = PyException::new_err(detail.clone());
+        error.set_cause(py, cause.take());
+        cause = Some(error);
+    }
+
+    let error = PyErr::new::<T, _>(details[0].clone());
+    error.set_cause(py, cause);
+
+    let value = error.value(py);
+    let _ = value.setattr("details", details);
+    let _ = value.setattr("kind", kind);
+    let _ = value.setattr("peer", peer.map(PublicKey));
+    let _ = value.setattr("timeout", timeout.map(|timeout| timeout.as_secs_f64()));
+
+    error
+}
+
+/// Converts an error returned while sending into the matching Python
+/// exception.
+fn send_error(py: Python, error: TunnelError, address: NativePublicKey) -> PyErr {
+    match error {
+        TunnelError::WrongMode { .. } => TunnelModeError::new_err(error.to_string()),
+        TunnelError::Destroyed => TunnelDestroyedError::new_err(TUNNEL_DESTROYED_MSG),
+        _ => chained_error::<TunnelSendingError>(py, &error, "send", Some(address), None),
+    }
+}
+
+fn parse_timeout(timeout: Option<f64>) -> PyResult<Option<Duration>> {
+    timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {e}")))
+}
+
+/// Waits for a tunnel to go online, checking for signals (e.g. Ctrl+C) while
+/// waiting.
+fn wait_online(py: Python, inner: &NativeTunnel, timeout: Option<Duration>) -> PyResult<()> {
+    let runtime = runtime(py)?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    loop {
+        let poll = match deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(SIGNAL_POLL_INTERVAL),
+            None => SIGNAL_POLL_INTERVAL,
+        };
+
+        let result = py.detach(|| runtime.block_on(inner.wait_online(Some(poll))));
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                return Err(chained_error::<TunnelTimeoutError>(
+                    py, &e, "timeout", None, timeout,
+                ));
+            }
+            Err(_) => py.check_signals()?,
+        }
     }
 }
 
@@ -162,6 +765,24 @@ fn pytunnel(m: &Bound<'_, PyModule>) -> PyResult<()> {
 
     m.add_class::<PublicKey>()?;
     m.add_class::<Tunnel>()?;
+    m.add_class::<Peer>()?;
+
+    let py = m.py();
+    let repr = PyModule::from_code(py, ERROR_REPR, c"errors.py", c"pytunnel_errors")?
+        .getattr("__repr__")?;
+
+    for error in [
+        RuntimeMissingError::type_object(py),
+        PublicKeyParseError::type_object(py),
+        TunnelCreationError::type_object(py),
+        TunnelDestroyedError::type_object(py),
+        TunnelModeError::type_object(py),
+        TunnelSendingError::type_object(py),
+        TunnelTimeoutError::type_object(py),
+    ] {
+        error.setattr("__repr__", &repr)?;
+        m.add(error.name()?, error)?;
+    }
 
     Ok(())
 }
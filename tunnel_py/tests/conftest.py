@@ -0,0 +1,71 @@
+import threading
+import time
+
+import pytest
+
+from pytunnel import Tunnel
+
+
+def wait_for(predicate, timeout=10.0):
+    """Polls `predicate` until it returns a truthy value, failing after `timeout` seconds."""
+    deadline = time.monotonic() + timeout
+
+    while not predicate():
+        if time.monotonic() > deadline:
+            pytest.fail("The condition was not met in time.")
+
+        time.sleep(0.01)
+
+
+class Recorder:
+    """A handler which records the `(sender, data)` pairs it receives."""
+
+    def __init__(self):
+        self.received = []
+        self._lock = threading.Lock()
+
+    def __call__(self, sender, data):
+        with self._lock:
+            self.received.append((sender, data))
+
+    def payloads(self):
+        with self._lock:
+            return [data for _, data in self.received]
+
+
+@pytest.fixture
+def recorder():
+    return Recorder()
+
+
+@pytest.fixture
+def remote(recorder):
+    """A tunnel which records the data it receives and echoes requests back."""
+    tunnel = Tunnel(recorder, request_handler=lambda sender, data: data)
+    yield tunnel
+
+    try:
+        tunnel.destroy()
+    except Exception:
+        pass
+
+
+@pytest.fixture
+def local():
+    tunnel = Tunnel(lambda sender, data: None)
+    yield tunnel
+
+    try:
+        tunnel.destroy()
+    except Exception:
+        pass
+
+
+@pytest.fixture
+def offline_address():
+    """The address of a tunnel which was destroyed, so it can never be reached."""
+    tunnel = Tunnel(lambda sender, data: None)
+    address = tunnel.receiver_address()
+    tunnel.destroy()
+
+    return address
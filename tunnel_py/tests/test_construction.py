@@ -0,0 +1,44 @@
+import gc
+import time
+
+import pytest
+
+from pytunnel import Tunnel, TunnelTimeoutError
+
+# An address from a range reserved for documentation, which never answers.
+BLACKHOLED_RELAY = "http://192.0.2.1:3340"
+
+
+def live_tunnels():
+    gc.collect()
+    return sum(isinstance(o, Tunnel) for o in gc.get_objects())
+
+
+def test_construction_times_out_with_a_blackholed_relay():
+    baseline = live_tunnels()
+    started = time.monotonic()
+
+    with pytest.raises(TunnelTimeoutError) as info:
+        Tunnel(lambda sender, data: None, timeout=2, relay_url=BLACKHOLED_RELAY)
+
+    assert time.monotonic() - started < 3
+    assert info.value.timeout == pytest.approx(2)
+    assert live_tunnels() == baseline
+
+
+def test_wait_online_after_an_instant_construction():
+    started = time.monotonic()
+    tunnel = Tunnel(lambda sender, data: None, timeout=0, relay_url=BLACKHOLED_RELAY)
+
+    try:
+        assert time.monotonic() - started < 1
+
+        with pytest.raises(TunnelTimeoutError):
+            tunnel.wait_online(0.5)
+    finally:
+        tunnel.destroy()
+
+
+def test_invalid_relay_url():
+    with pytest.raises(ValueError, match="Invalid relay URL"):
+        Tunnel(relay_url="not a url")
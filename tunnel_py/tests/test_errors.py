@@ -0,0 +1,66 @@
+import traceback
+
+import pytest
+
+from pytunnel import TunnelSendingError, TunnelTimeoutError
+
+
+def send_failure(local):
+    # Connecting to the sending endpoint itself fails right away.
+    address = local.sender_address()
+
+    with pytest.raises(TunnelSendingError) as info:
+        local.send(address, b"x")
+
+    return address, info.value
+
+
+def test_send_error_attributes(local):
+    address, error = send_failure(local)
+
+    assert error.kind == "send"
+    assert str(error.peer) == str(address)
+    assert error.timeout is None
+
+    assert len(error.details) >= 2
+    assert error.details[0] == str(error)
+    assert str(error.__cause__) == error.details[1]
+
+    assert repr(error).startswith("TunnelSendingError(")
+    assert "kind='send'" in repr(error)
+    assert str(address) in repr(error)
+
+
+def test_send_error_chain_is_shown_in_tracebacks(local):
+    _, error = send_failure(local)
+
+    formatted = "".join(traceback.format_exception(error))
+    assert "The above exception was the direct cause" in formatted
+
+    for detail in error.details:
+        assert detail in formatted
+
+
+def test_raise_from_keeps_the_chain(local):
+    _, error = send_failure(local)
+
+    try:
+        raise RuntimeError("The upload failed.") from error
+    except RuntimeError as wrapped:
+        formatted = "".join(traceback.format_exception(wrapped))
+
+    assert formatted.count("The above exception was the direct cause") >= 2
+    assert "TunnelSendingError" in formatted
+    assert "The upload failed." in formatted
+
+
+def test_flush_timeout_attributes(local, offline_address):
+    local.send_nowait(offline_address, b"lost")
+
+    with pytest.raises(TunnelTimeoutError) as info:
+        local.flush(offline_address, timeout=0.2)
+
+    error = info.value
+    assert isinstance(error, TimeoutError)
+    assert error.kind == "timeout"
+    assert error.timeout == pytest.approx(0.2)
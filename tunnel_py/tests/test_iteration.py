@@ -0,0 +1,72 @@
+import _thread
+import threading
+import time
+
+import pytest
+
+from pytunnel import Tunnel
+
+
+@pytest.fixture
+def iterable():
+    """A tunnel created without a handler, whose incoming data is iterated."""
+    tunnel = Tunnel()
+    yield tunnel
+
+    try:
+        tunnel.destroy()
+    except Exception:
+        pass
+
+
+def test_iteration_receives_while_another_thread_sends(local, iterable):
+    address = iterable.receiver_address()
+    payloads = [i.to_bytes(1, "big") for i in range(10)]
+
+    sending = threading.Thread(target=lambda: [local.send(address, p) for p in payloads])
+    sending.start()
+
+    received = []
+
+    for sender, data in iterable:
+        assert str(sender) == str(local.sender_address())
+        received.append(data)
+
+        if len(received) == len(payloads):
+            break
+
+    sending.join()
+    assert sorted(received) == payloads
+
+
+def test_destroy_from_another_thread_ends_the_iteration(iterable):
+    received = []
+    iterating = threading.Thread(target=lambda: received.extend(iterable))
+    iterating.start()
+
+    time.sleep(0.3)
+    iterable.destroy()
+
+    iterating.join(timeout=5)
+    assert not iterating.is_alive()
+    assert received == []
+
+    # The iteration keeps ending once the tunnel is destroyed.
+    assert list(iterable) == []
+
+
+def test_signal_during_an_idle_wait_interrupts_the_iteration(iterable):
+    interrupted = []
+
+    def interrupt():
+        time.sleep(0.3)
+        interrupted.append(time.monotonic())
+        _thread.interrupt_main()
+
+    threading.Thread(target=interrupt).start()
+
+    with pytest.raises(KeyboardInterrupt):
+        for _ in iterable:
+            pass
+
+    assert time.monotonic() - interrupted[0] < 0.2
@@ -0,0 +1,67 @@
+import time
+
+import pytest
+
+from conftest import wait_for
+from pytunnel import TunnelSendingError
+
+BOGUS_SLOTS = {3, 50, 101, 150, 204}
+
+
+def pairs(local, remote):
+    address = remote.receiver_address()
+    # Sending to itself fails right away.
+    bogus = local.sender_address()
+
+    return [
+        (bogus, b"bogus") if i in BOGUS_SLOTS else (address, i.to_bytes(2, "big"))
+        for i in range(205)
+    ]
+
+
+def test_results_are_in_input_order(local, remote, recorder):
+    results = local.map_send(pairs(local, remote))
+
+    assert len(results) == 205
+
+    for i, result in enumerate(results):
+        if i in BOGUS_SLOTS:
+            assert isinstance(result, TunnelSendingError)
+            assert str(result.peer) == str(local.sender_address())
+        else:
+            assert result is None
+
+    wait_for(lambda: len(recorder.payloads()) == 200)
+    assert b"bogus" not in recorder.payloads()
+
+
+def test_sends_run_concurrently(local, remote):
+    address = remote.receiver_address()
+    # Warms the connection up, so it is not dialed by the timed sends.
+    local.send(address, b"warm-up")
+
+    started = time.monotonic()
+
+    for _ in range(20):
+        local.send(address, b"sequential")
+
+    sequential = (time.monotonic() - started) * 10
+
+    started = time.monotonic()
+    results = local.map_send([(address, b"concurrent")] * 200, max_concurrency=32)
+    concurrent = time.monotonic() - started
+
+    assert results == [None] * 200
+    assert concurrent < sequential / 2
+
+
+def test_first_failure_is_raised(local, remote):
+    with pytest.raises(TunnelSendingError) as info:
+        local.map_send(pairs(local, remote), return_exceptions=False)
+
+    assert str(info.value.peer) == str(local.sender_address())
+
+
+def test_zero_concurrency(local):
+    with pytest.raises(ValueError):
+        local.map_send([], max_concurrency=0)
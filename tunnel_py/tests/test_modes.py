@@ -0,0 +1,59 @@
+import pytest
+
+from conftest import Recorder, wait_for
+from pytunnel import Tunnel, TunnelModeError
+
+
+def test_send_only_tunnel_delivers(remote, recorder):
+    tunnel = Tunnel(mode="send_only")
+
+    try:
+        tunnel.send(remote.receiver_address(), b"hello")
+        wait_for(lambda: recorder.payloads() == [b"hello"])
+    finally:
+        tunnel.destroy()
+
+
+def test_send_only_tunnel_refuses_to_receive():
+    with pytest.raises(TunnelModeError):
+        Tunnel(lambda sender, data: None, mode="send_only")
+
+    tunnel = Tunnel(mode="send_only")
+
+    try:
+        with pytest.raises(TunnelModeError):
+            tunnel.receiver_address()
+
+        with pytest.raises(TypeError):
+            iter(tunnel)
+    finally:
+        tunnel.destroy()
+
+
+def test_receive_only_tunnel_receives(local):
+    recorder = Recorder()
+    tunnel = Tunnel(recorder, mode="receive_only")
+
+    try:
+        local.send(tunnel.receiver_address(), b"hello")
+        wait_for(lambda: recorder.payloads() == [b"hello"])
+    finally:
+        tunnel.destroy()
+
+
+def test_receive_only_tunnel_refuses_to_send(local):
+    tunnel = Tunnel(lambda sender, data: None, mode="receive_only")
+
+    try:
+        with pytest.raises(TunnelModeError):
+            tunnel.send(local.receiver_address(), b"hello")
+
+        with pytest.raises(TunnelModeError):
+            tunnel.sender_address()
+    finally:
+        tunnel.destroy()
+
+
+def test_invalid_mode():
+    with pytest.raises(ValueError, match="Invalid mode"):
+        Tunnel(mode="both")
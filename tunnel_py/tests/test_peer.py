@@ -0,0 +1,85 @@
+import pytest
+
+from conftest import wait_for
+from pytunnel import Peer, TunnelDestroyedError, TunnelSendingError
+
+
+def test_peer_surface(local, remote, recorder):
+    peer = local.peer(remote.receiver_address())
+
+    assert str(peer.address) == str(remote.receiver_address())
+    assert not peer.is_connected
+
+    peer.send(b"hello")
+    peer.send_nowait(b"world")
+    peer.flush(timeout=10)
+
+    wait_for(lambda: recorder.payloads() == [b"hello", b"world"])
+    assert peer.is_connected
+
+    assert peer.request(b"echo") == b"echo"
+    assert peer.ping() >= 0
+
+    info = peer.info()
+    assert info["connected"] is True
+    assert info["messages_sent"] >= 1
+    assert info["bytes_sent"] >= len(b"hello")
+    assert info["send_errors"] == 0
+    assert local.info(peer.address).keys() == info.keys()
+
+    peer.close()
+    assert not peer.is_connected
+
+    # A peer is just an address, so it reconnects on the next send.
+    peer.send(b"again")
+    wait_for(lambda: recorder.payloads()[-1] == b"again")
+
+
+def test_request_without_request_handler(local):
+    other = type(local)(lambda sender, data: None)
+
+    try:
+        with pytest.raises(TunnelSendingError):
+            local.peer(other.receiver_address()).request(b"?")
+    finally:
+        other.destroy()
+
+
+def test_peers_as_dict_keys(local, remote):
+    address = remote.receiver_address()
+
+    first = local.peer(address)
+    second = local.peer(address)
+    other = local.peer(local.receiver_address())
+
+    assert first == second
+    assert hash(first) == hash(second)
+    assert first != other
+
+    names = {first: "remote", other: "self"}
+    assert names[second] == "remote"
+    assert len({first, second, other}) == 2
+    assert repr(first) == f"Peer({address})"
+
+
+def test_peer_after_destroy(local, remote):
+    peer = local.peer(remote.receiver_address())
+    local.destroy()
+
+    for use in [
+        lambda: peer.send(b"data"),
+        lambda: peer.send_nowait(b"data"),
+        lambda: peer.flush(),
+        lambda: peer.request(b"data"),
+        lambda: peer.ping(),
+        lambda: peer.is_connected,
+        lambda: peer.info(),
+        lambda: peer.close(),
+    ]:
+        with pytest.raises(TunnelDestroyedError):
+            use()
+
+    with pytest.raises(TunnelDestroyedError):
+        local.peer(remote.receiver_address())
+
+    assert isinstance(peer, Peer)
@@ -0,0 +1,32 @@
+import pytest
+
+from conftest import wait_for
+from pytunnel import Tunnel, TunnelTimeoutError
+
+
+def test_flush_waits_for_every_background_send(local, remote, recorder):
+    address = remote.receiver_address()
+    payloads = [i.to_bytes(2, "big") for i in range(100)]
+
+    for payload in payloads:
+        local.send_nowait(address, payload)
+
+    local.flush(timeout=30)
+
+    wait_for(lambda: len(recorder.payloads()) == 100)
+    assert sorted(recorder.payloads()) == payloads
+
+
+def test_flush_timeout(local, offline_address):
+    local.send_nowait(offline_address, b"lost")
+
+    with pytest.raises(TunnelTimeoutError):
+        local.flush(offline_address, timeout=0.2)
+
+
+def test_destroy_warns_about_unflushed_messages(offline_address):
+    tunnel = Tunnel(lambda sender, data: None)
+    tunnel.send_nowait(offline_address, b"lost")
+
+    with pytest.warns(ResourceWarning, match="1 unflushed message"):
+        tunnel.destroy()